@@ -0,0 +1,527 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Codec of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::{collections::HashSet, fmt};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    statistics::{ColumnStats, TableStats},
+    structs::{
+        ast::{self, LocNode, Node},
+        plan::{self, JoinKind, LocPlan, Plan},
+        plan_group::PlanGroup,
+        Aggregate, DivisionKind, Expr, Loc, Pos, SortKey, Symbol, Value,
+    },
+    type_system::{resolve_field, Domain, Env, Lines, Record, SimpleType, TableName, Type},
+};
+
+/// Version of the serialized forms, bumped on every incompatible change.
+/// The binary form stores enum variants by index, new variants go last.
+pub const FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"RAE\0";
+
+// 二进制输入最多这么大, 防止长度字段让解码器分配过多内存
+const BINARY_LIMIT: u64 = 64 << 20;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Binary(bincode::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Invalid(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Json(e) => write!(f, "json: {}", e),
+            CodecError::Binary(e) => write!(f, "binary: {}", e),
+            CodecError::BadMagic => write!(f, "not a RAE binary"),
+            CodecError::UnsupportedVersion(v) => write!(
+                f,
+                "format version {} is not supported, expected {}",
+                v, FORMAT_VERSION
+            ),
+            CodecError::Invalid(e) => write!(f, "invalid input: {}", e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(e: serde_json::Error) -> Self {
+        CodecError::Json(e)
+    }
+}
+
+impl From<bincode::Error> for CodecError {
+    fn from(e: bincode::Error) -> Self {
+        CodecError::Binary(e)
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    version: u32,
+    payload: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+    payload: serde_json::Value,
+}
+
+/// `{"version": .., "payload": ..}`
+pub fn to_json<T: Serialize>(x: &T) -> Result<String, CodecError> {
+    Ok(serde_json::to_string_pretty(&Envelope {
+        version: FORMAT_VERSION,
+        payload: x,
+    })?)
+}
+
+pub fn from_json<T: DeserializeOwned + Validate>(s: &str) -> Result<T, CodecError> {
+    // 先看版本, 再解释内容
+    let h: Header = serde_json::from_str(s)?;
+    if h.version != FORMAT_VERSION {
+        return Err(CodecError::UnsupportedVersion(h.version));
+    }
+    let r: T = serde_json::from_value(h.payload)?;
+    r.validate().map_err(CodecError::Invalid)?;
+    Ok(r)
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_limit(BINARY_LIMIT)
+        .reject_trailing_bytes()
+}
+
+/// Magic bytes, the version as little endian u32, then the payload.
+pub fn to_binary<T: Serialize>(x: &T) -> Result<Vec<u8>, CodecError> {
+    let mut r = MAGIC.to_vec();
+    r.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    r.extend(options().serialize(x)?);
+    Ok(r)
+}
+
+pub fn from_binary<T: DeserializeOwned + Validate>(bytes: &[u8]) -> Result<T, CodecError> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        return Err(CodecError::BadMagic);
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&bytes[4..8]);
+    let version = u32::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(CodecError::UnsupportedVersion(version));
+    }
+    let r: T = options().deserialize(&bytes[8..])?;
+    r.validate().map_err(CodecError::Invalid)?;
+    Ok(r)
+}
+
+/// Checks that deserialized data could have been built by the engine.
+/// Only the structure is checked, plans are not type checked here, and
+/// nothing the type checker accepts is rejected.
+pub trait Validate {
+    fn validate(&self) -> Result<(), String>;
+}
+
+fn ensure(ok: bool, msg: impl FnOnce() -> String) -> Result<(), String> {
+    if ok {
+        Ok(())
+    } else {
+        Err(msg())
+    }
+}
+
+fn symbol(s: &Symbol) -> Result<(), String> {
+    ensure(
+        !s.0.is_empty() && s.1.as_ref().is_none_or(|c| !c.is_empty()),
+        || format!("empty name in {:?}", s),
+    )
+}
+
+fn names(v: &[Symbol]) -> Result<(), String> {
+    ensure(!v.is_empty(), || "empty name list".to_string())?;
+    let mut seen = HashSet::new();
+    for s in v {
+        symbol(s)?;
+        ensure(seen.insert(s), || format!("duplicate name {}", s))?;
+    }
+    Ok(())
+}
+
+fn table(name: &str) -> Result<(), String> {
+    ensure(!name.is_empty(), || "empty table name".to_string())
+}
+
+fn division(kind: &DivisionKind) -> Result<(), String> {
+    match kind {
+        DivisionKind::Grouped(g) => names(g),
+        _ => Ok(()),
+    }
+}
+
+// 分组属性可以没有, 但是总要输出点什么
+fn group_by(keys: &[Symbol], aggs: &[Aggregate]) -> Result<(), String> {
+    ensure(!keys.is_empty() || !aggs.is_empty(), || {
+        "grouping without keys or aggregates".to_string()
+    })?;
+    if !keys.is_empty() {
+        names(keys)?;
+    }
+    aggs.iter()
+        .flat_map(|a| a.op.symbol().into_iter().chain(a.alias.as_ref()))
+        .try_for_each(symbol)
+}
+
+fn sort(keys: &[SortKey]) -> Result<(), String> {
+    let fields: Vec<Symbol> = keys.iter().map(|k| k.field.clone()).collect();
+    names(&fields)
+}
+
+// 位置信息只是用来报错的, 什么值都可以
+impl Validate for Pos {
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Validate for Expr {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Mod(a, b)
+            | Expr::And(a, b)
+            | Expr::Or(a, b) => {
+                a.0.validate()?;
+                b.0.validate()
+            }
+            Expr::Not(a) => a.0.validate(),
+            Expr::Value(Loc(Value::Symbol(s), _)) => symbol(s),
+            Expr::Value(_) => Ok(()),
+        }
+    }
+}
+
+impl Validate for ast::CompExpr {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            ast::CompExpr::Eq(a, b)
+            | ast::CompExpr::Lt(a, b)
+            | ast::CompExpr::Gt(a, b)
+            | ast::CompExpr::In(a, b) => {
+                a.0.validate()?;
+                b.0.validate()
+            }
+        }
+    }
+}
+
+impl Validate for ast::FilterExpr {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            ast::FilterExpr::And(v) | ast::FilterExpr::Or(v) => {
+                v.iter().try_for_each(|c| c.0.validate())
+            }
+            ast::FilterExpr::Not(c) | ast::FilterExpr::Comp(c) => c.0.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for LocNode {
+    fn validate(&self) -> Result<(), String> {
+        match &self.0 {
+            Node::Selection(_, fs) => fs.iter().try_for_each(|f| f.0.validate())?,
+            Node::Projection(_, v) => names(v)?,
+            Node::Division(_, _, kind) => division(kind)?,
+            Node::GroupBy(_, keys, aggs) => group_by(keys, aggs)?,
+            Node::Sort(_, keys) => sort(keys)?,
+            Node::Rename(_, pairs) => {
+                let from: Vec<Symbol> = pairs.iter().map(|(a, _)| a.clone()).collect();
+                names(&from)?;
+                pairs.iter().try_for_each(|(_, b)| symbol(b))?;
+            }
+            Node::InnerJoin(_, _, fs)
+            | Node::LeftJoin(_, _, fs)
+            | Node::RightJoin(_, _, fs)
+            | Node::FullJoin(_, _, fs) => fs.iter().try_for_each(|f| f.validate())?,
+            Node::EquiJoin(_, _, keys, _) => {
+                ensure(!keys.is_empty(), || "equi join without keys".to_string())?;
+                for Loc(ast::EquiKey(a, b), _) in keys {
+                    symbol(a)?;
+                    symbol(b)?;
+                }
+            }
+            Node::Reduce(Loc(r, _)) => match r {
+                ast::ItemReduce::Count(_) => {}
+                ast::ItemReduce::Sum(_, s)
+                | ast::ItemReduce::Avg(_, s)
+                | ast::ItemReduce::Max(_, s)
+                | ast::ItemReduce::Min(_, s) => symbol(s)?,
+            },
+            Node::Table(TableName(n)) => table(n)?,
+            _ => {}
+        }
+        self.0.children().into_iter().try_for_each(|c| c.validate())
+    }
+}
+
+impl Validate for plan::CompExpr {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            plan::CompExpr::Eq(a, b) | plan::CompExpr::Lt(a, b) | plan::CompExpr::Gt(a, b) => {
+                a.validate()?;
+                b.validate()
+            }
+            plan::CompExpr::In(a, sub) => {
+                a.validate()?;
+                sub.validate()
+            }
+        }
+    }
+}
+
+impl Validate for plan::FilterExpr {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            plan::FilterExpr::And(v) | plan::FilterExpr::Or(v) => {
+                v.iter().try_for_each(|c| c.validate())
+            }
+            plan::FilterExpr::Not(c) | plan::FilterExpr::Comp(c) => c.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for LocPlan {
+    fn validate(&self) -> Result<(), String> {
+        match &self.0 {
+            Plan::Join(_, _, kind, fs) => {
+                // not in 的第一个条件必须是 in 的比较
+                if *kind == JoinKind::NullAwareAnti {
+                    ensure(
+                        matches!(fs.first(), Some(plan::FilterExpr::Comp(c))
+                            if matches!(c.as_ref(), plan::CompExpr::Eq(_, _))),
+                        || "null aware anti join without its equality".to_string(),
+                    )?;
+                }
+                fs.iter().try_for_each(|f| f.validate())?
+            }
+            Plan::Selection(_, f) => f.validate()?,
+            Plan::Projection(_, v) => names(v)?,
+            Plan::Division(_, _, kind) => division(kind)?,
+            Plan::GroupBy(_, keys, aggs) => group_by(keys, aggs)?,
+            Plan::Sort(_, keys) => sort(keys)?,
+            Plan::Reduce(r) => match r {
+                plan::ItemReduce::Count(a) => a.validate()?,
+                plan::ItemReduce::Sum(a, s)
+                | plan::ItemReduce::Avg(a, s)
+                | plan::ItemReduce::Max(a, s)
+                | plan::ItemReduce::Min(a, s) => {
+                    symbol(s)?;
+                    a.validate()?
+                }
+            },
+            Plan::Table(n) => table(n)?,
+            Plan::Empty(r) => r.validate()?,
+            _ => {}
+        }
+        self.0.children().into_iter().try_for_each(|c| c.validate())
+    }
+}
+
+// 组和它展开成的 plan 一样检查
+impl Validate for PlanGroup {
+    fn validate(&self) -> Result<(), String> {
+        LocPlan::from(self.clone()).validate()
+    }
+}
+
+fn domain<T: PartialOrd + fmt::Display>(d: &Option<Domain<T>>) -> Result<(), String> {
+    match d {
+        Some(Domain::Range(l, r)) => ensure(l <= r, || format!("domain {}..{} is empty", l, r)),
+        Some(Domain::Value(v)) => ensure(v.partial_cmp(v).is_some(), || {
+            "domain value is not a number".to_string()
+        }),
+        None => Ok(()),
+    }
+}
+
+impl Validate for Type {
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Type::Optional(t) => t.0.validate(),
+            Type::Record(r) => r.validate(),
+            Type::Simple(t) => match t {
+                SimpleType::Bool => Ok(()),
+                SimpleType::Int(d) => domain(d),
+                SimpleType::Uint(d) => domain(d),
+                SimpleType::Float(d) => domain(d),
+                SimpleType::String(e) => {
+                    let mut seen = HashSet::new();
+                    e.iter().try_for_each(|s| {
+                        ensure(seen.insert(s), || format!("duplicate string {:?}", s))
+                    })
+                }
+            },
+            Type::TableName(TableName(n)) => table(n),
+            Type::Table(Lines(r)) => r.validate(),
+        }
+    }
+}
+
+// 重复的列在反序列化 Record 的时候就已经拒绝了
+impl Validate for Record {
+    fn validate(&self) -> Result<(), String> {
+        self.0.iter().try_for_each(|(k, t)| {
+            symbol(k)?;
+            t.validate()
+        })
+    }
+}
+
+fn column_stats(c: &ColumnStats) -> Result<(), String> {
+    ensure((0.0..=1.0).contains(&c.null_fraction), || {
+        format!("null fraction {} is not in [0, 1]", c.null_fraction)
+    })?;
+    ensure(c.distinct.is_none_or(|d| d >= 0.0), || {
+        "negative distinct count".to_string()
+    })?;
+    let mut last: Option<&Value> = None;
+    for b in c.histogram.0.iter() {
+        let ordered = b.lower.compare(&b.upper).is_some_and(|o| o.is_le())
+            && last.is_none_or(|l| l.compare(&b.lower).is_some_and(|o| o.is_lt()));
+        ensure(ordered, || "histogram buckets are not ordered".to_string())?;
+        ensure(b.distinct <= b.rows, || {
+            "histogram bucket has more values than rows".to_string()
+        })?;
+        last = Some(&b.upper);
+    }
+    Ok(())
+}
+
+fn table_stats(t: &TableStats, r: &Record) -> Result<(), String> {
+    for (k, c) in t.columns.iter() {
+        ensure(resolve_field(&r.0, k).is_some(), || {
+            format!("statistics for unknown column {}", k)
+        })?;
+        column_stats(c)?;
+    }
+    Ok(())
+}
+
+impl Validate for Env {
+    fn validate(&self) -> Result<(), String> {
+        for (TableName(n), Lines(r)) in self.0.iter() {
+            table(n)?;
+            r.validate()?;
+        }
+        for (name, t) in self.1.iter() {
+            let Lines(r) = self
+                .get_table(name)
+                .ok_or_else(|| format!("statistics for unknown table {}", name.0))?;
+            table_stats(t, r)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        structs::ast::type_check::TypeInfer,
+        testing::{self, env, fixture, gt, project, queries, select, sym},
+    };
+
+    fn query() -> LocPlan {
+        project(select(testing::table("R"), gt(sym("a"), 1)), &[sym("b")])
+    }
+
+    #[test]
+    fn plans_and_envs_round_trip() {
+        let json = to_json(&query()).unwrap();
+        assert_eq!(from_json::<LocPlan>(&json).unwrap(), query());
+        let bytes = to_binary(&query()).unwrap();
+        assert_eq!(from_binary::<LocPlan>(&bytes).unwrap(), query());
+        let bytes = to_binary(&env()).unwrap();
+        assert_eq!(from_binary::<Env>(&bytes).unwrap(), env());
+        assert_eq!(to_json(&env()).unwrap(), to_json(&env()).unwrap());
+    }
+
+    #[test]
+    fn other_versions_and_magic_are_rejected() {
+        let json = to_json(&query()).unwrap().replacen(
+            &format!("\"version\": {}", FORMAT_VERSION),
+            "\"version\": 99",
+            1,
+        );
+        assert!(matches!(
+            from_json::<LocPlan>(&json),
+            Err(CodecError::UnsupportedVersion(99))
+        ));
+        let mut bytes = to_binary(&query()).unwrap();
+        bytes[0] = b'X';
+        assert!(matches!(
+            from_binary::<LocPlan>(&bytes),
+            Err(CodecError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn structurally_invalid_input_is_rejected() {
+        let p = project(testing::table("R"), &[]);
+        let bytes = to_binary(&p).unwrap();
+        assert!(matches!(
+            from_binary::<LocPlan>(&bytes),
+            Err(CodecError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn reversed_range_round_trips() {
+        let (_, env) = fixture();
+        let r = testing::sort(testing::table("R"), vec![SortKey::asc(sym("a"))]);
+        let p = select(r, plan::FilterExpr::Range(1, 0));
+        p.type_infer(&env).unwrap();
+        let json: LocPlan = from_json(&to_json(&p).unwrap()).unwrap();
+        assert_eq!(json, p);
+        let binary: LocPlan = from_binary(&to_binary(&p).unwrap()).unwrap();
+        assert_eq!(binary, p);
+    }
+
+    #[test]
+    fn accepted_plans_round_trip() {
+        let (_, env) = fixture();
+        for p in queries() {
+            p.type_infer(&env).unwrap();
+            let json: LocPlan = from_json(&to_json(&p).unwrap()).unwrap();
+            assert_eq!(json, p);
+            let binary: LocPlan = from_binary(&to_binary(&p).unwrap()).unwrap();
+            assert_eq!(binary, p);
+        }
+    }
+}
@@ -0,0 +1,505 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Executor of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+pub mod cursor;
+pub mod eval;
+pub mod parallel;
+pub mod vector;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use indexmap::IndexMap;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::{
+    optimizer::cse::NodeId,
+    physical::{planner::Planner, PhysicalPlan, Semantics},
+    storage::{Database, HashKey, IndexDef, Relation},
+    structs::{
+        plan::{type_check::get_plan_table_type, LocPlan},
+        Loc, Symbol, Value,
+    },
+    type_system::{resolve_field, Env, Lines, Record, TypeError},
+};
+
+pub type Row = Vec<Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecError {
+    Type(Box<Loc<TypeError>>),
+    TableNotFound(String),
+    IndexNotFound(String),
+    FieldNotFound(Symbol),
+    /// A field both inputs of a join have under the same name.
+    DuplicateField(Symbol),
+    /// Overflow, division by zero.
+    Arithmetic(String),
+}
+
+impl From<Loc<TypeError>> for ExecError {
+    fn from(e: Loc<TypeError>) -> Self {
+        ExecError::Type(Box::new(e))
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::Type(e) => write!(f, "type error: {:?}", e.0),
+            ExecError::TableNotFound(t) => write!(f, "table {} not found", t),
+            ExecError::IndexNotFound(i) => write!(f, "index {} not found", i),
+            ExecError::FieldNotFound(s) => write!(f, "field {} not found", s),
+            ExecError::DuplicateField(s) => write!(f, "field {} is on both sides", s),
+            ExecError::Arithmetic(e) => write!(f, "cannot evaluate {}", e),
+        }
+    }
+}
+
+/// Rows of a result with the type the checker gives to the plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypedRelation {
+    pub lines: Lines,
+    pub rows: Vec<Row>,
+}
+
+impl TypedRelation {
+    pub fn into_relation(self) -> Relation {
+        let Lines(Record(fields, name)) = self.lines;
+        Relation {
+            name,
+            header: fields.into_keys().collect(),
+            rows: self.rows,
+        }
+    }
+}
+
+/// The rows a correlated sub plan is evaluated for, innermost first.
+#[derive(Debug, Clone, Default)]
+pub struct Scope(Vec<(Record, Row)>);
+
+impl Scope {
+    pub fn push(&self, schema: &Record, row: &[Value]) -> Scope {
+        let mut frames = vec![(schema.clone(), row.to_vec())];
+        frames.extend(self.0.iter().cloned());
+        Scope(frames)
+    }
+
+    pub fn lookup(&self, s: &Symbol) -> Option<&Value> {
+        self.0
+            .iter()
+            .find_map(|(schema, row)| eval::column(schema, s).map(|i| &row[i]))
+    }
+
+    /// All the outer fields as one record, inner names hide outer ones.
+    pub fn record(&self) -> Record {
+        let mut r = Record(IndexMap::new(), String::new());
+        for (schema, _) in self.0.iter() {
+            for (k, t) in schema.0.iter() {
+                if resolve_field(&r.0, k).is_none() {
+                    r.0.insert(k.clone(), t.clone());
+                }
+            }
+        }
+        r
+    }
+}
+
+/// Values produced by the sub plan of an `in`.
+#[derive(Debug, Clone, Default)]
+pub struct SubResult {
+    pub keys: HashSet<HashKey>,
+    pub has_null: bool,
+    pub empty: bool,
+}
+
+/// Runs logical plans over the tables of a database.
+///
+/// A plan is turned into a physical plan by the planner, then evaluated by
+/// pulling rows one at a time from the root cursor. By default the results
+/// follow the set semantics of relational algebra: every operator, down
+/// to the table scans, drops duplicate rows. With `Semantics::Bag` they
+/// keep them as SQL does. A session keeps one executor for all its queries, a query may
+/// use an executor of its own to change the semantics. `execute_vectorized`
+/// gives the same rows, but its operators work on batches of columns.
+/// `execute_parallel` splits the work of each operator between threads.
+pub struct Executor<'a> {
+    db: &'a Database,
+    env: &'a Env,
+    indexes: Vec<IndexDef>,
+    semantics: Semantics,
+    // 没有的话用 rayon 全局的线程
+    pool: Option<ThreadPool>,
+    // 不相关的子查询只算一次, 按打印出来的计划找
+    cache: Mutex<HashMap<String, Arc<SubResult>>>,
+    // 正在执行的计划里共享节点的结果
+    shared: Mutex<HashMap<NodeId, Arc<Vec<Row>>>>,
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(db: &'a Database, env: &'a Env) -> Self {
+        Executor {
+            db,
+            env,
+            indexes: db.index_defs(),
+            semantics: Semantics::Set,
+            pool: None,
+            cache: Mutex::new(HashMap::new()),
+            shared: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run parallel queries on `n` threads of their own, 0 is one thread
+    /// per core.
+    pub fn with_threads(mut self, n: usize) -> Self {
+        self.pool = ThreadPoolBuilder::new().num_threads(n).build().ok();
+        self
+    }
+
+    pub fn with_semantics(mut self, semantics: Semantics) -> Self {
+        self.semantics = semantics;
+        self
+    }
+
+    pub fn semantics(&self) -> Semantics {
+        self.semantics
+    }
+
+    pub fn database(&self) -> &'a Database {
+        self.db
+    }
+
+    pub fn planner(&self) -> Planner<'_> {
+        Planner::new(self.env)
+            .with_indexes(&self.indexes)
+            .with_semantics(self.semantics)
+    }
+
+    pub fn execute(&self, plan: &LocPlan) -> Result<TypedRelation, ExecError> {
+        let physical = self.planner().plan(plan)?;
+        self.execute_physical(&physical)
+    }
+
+    pub fn execute_physical(&self, plan: &PhysicalPlan) -> Result<TypedRelation, ExecError> {
+        self.shared.lock().unwrap().clear();
+        let rows = self.run(plan, &Scope::default())?;
+        Ok(TypedRelation {
+            lines: Lines(plan.schema.clone()),
+            rows,
+        })
+    }
+
+    pub fn execute_vectorized(&self, plan: &LocPlan) -> Result<TypedRelation, ExecError> {
+        let physical = self.planner().plan(plan)?;
+        self.execute_physical_vectorized(&physical)
+    }
+
+    pub fn execute_physical_vectorized(
+        &self,
+        plan: &PhysicalPlan,
+    ) -> Result<TypedRelation, ExecError> {
+        self.shared.lock().unwrap().clear();
+        let mut c = vector::open(self, plan, &Scope::default())?;
+        let mut rows = vec![];
+        while let Some(b) = c.next()? {
+            rows.extend(b.rows());
+        }
+        Ok(TypedRelation {
+            lines: Lines(plan.schema.clone()),
+            rows,
+        })
+    }
+
+    pub fn execute_parallel(&self, plan: &LocPlan) -> Result<TypedRelation, ExecError> {
+        let physical = self.planner().plan(plan)?;
+        self.execute_physical_parallel(&physical)
+    }
+
+    /// The same rows in the same order as `execute_physical`, but float
+    /// sums may round differently.
+    pub fn execute_physical_parallel(
+        &self,
+        plan: &PhysicalPlan,
+    ) -> Result<TypedRelation, ExecError> {
+        self.shared.lock().unwrap().clear();
+        let run = || parallel::run(self, plan, &Scope::default());
+        let rows = match &self.pool {
+            Some(pool) => pool.install(run)?,
+            None => run()?,
+        };
+        Ok(TypedRelation {
+            lines: Lines(plan.schema.clone()),
+            rows,
+        })
+    }
+
+    fn run(&self, plan: &PhysicalPlan, scope: &Scope) -> Result<Vec<Row>, ExecError> {
+        let mut c = cursor::open(self, plan, scope)?;
+        let mut rows = vec![];
+        while let Some(r) = c.next()? {
+            rows.push(r);
+        }
+        Ok(rows)
+    }
+
+    /// Rows of the shared node `id`, `eval` gives them the first time.
+    pub(crate) fn shared(
+        &self,
+        id: NodeId,
+        eval: impl FnOnce() -> Result<Vec<Row>, ExecError>,
+    ) -> Result<Arc<Vec<Row>>, ExecError> {
+        if let Some(r) = self.shared.lock().unwrap().get(&id) {
+            return Ok(r.clone());
+        }
+        let rows = Arc::new(eval()?);
+        self.shared.lock().unwrap().insert(id, rows.clone());
+        Ok(rows)
+    }
+
+    pub(crate) fn sub_plan(
+        &self,
+        sub: &LocPlan,
+        scope: &Scope,
+    ) -> Result<Arc<SubResult>, ExecError> {
+        let key = sub.0.to_string();
+        // 算子查询时不拿着锁, 别的线程可能也在算同一个
+        if let Some(r) = self.cache.lock().unwrap().get(&key) {
+            return Ok(r.clone());
+        }
+        let correlated = get_plan_table_type(sub, self.env).is_err();
+        let rows = if correlated {
+            let outer = scope.record();
+            let plan = self.planner().with_outer(&outer).plan(sub)?;
+            self.run(&plan, scope)?
+        } else {
+            // 共享节点的编号只在一个计划里不重复
+            let plan = self.planner().with_sharing(false).plan(sub)?;
+            self.run(&plan, &Scope::default())?
+        };
+        let mut r = SubResult {
+            empty: rows.is_empty(),
+            ..Default::default()
+        };
+        for row in rows {
+            match row.first() {
+                Some(Value::Null) => r.has_null = true,
+                Some(v) => {
+                    r.keys.insert(HashKey::new([v]));
+                }
+                None => {}
+            }
+        }
+        let r = Arc::new(r);
+        if !correlated {
+            self.cache.lock().unwrap().insert(key, r.clone());
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        structs::{
+            plan::{CompExpr, FilterExpr, ItemReduce, JoinKind, Plan},
+            plan_group::ReduceOperator,
+            Aggregate, Expr, SortKey,
+        },
+        testing::*,
+    };
+
+    fn ints(rows: &[&[Option<i64>]]) -> Vec<Row> {
+        rows.iter()
+            .map(|r| r.iter().copied().map(int_value).collect())
+            .collect()
+    }
+
+    #[test]
+    fn projections_drop_duplicate_rows() {
+        let (db, env) = fixture();
+        let rows = run(&db, &env, &project(table("R"), &[sym("b")]), Semantics::Set);
+        assert_eq!(
+            sorted(rows),
+            sorted(ints(&[&[Some(10)], &[Some(20)], &[None], &[Some(30)]]))
+        );
+    }
+
+    #[test]
+    fn join_keys_never_match_null() {
+        let (db, env) = fixture();
+        let on = vec![eq(qualified("R", "b"), qualified("S", "b"))];
+        let p = plan(Plan::Join(
+            Box::new(table("R")),
+            Box::new(table("S")),
+            JoinKind::Inner,
+            on,
+        ));
+        let rows = run(&db, &env, &p, Semantics::Bag);
+        // 10 有一对, 两个 20 各对上两个
+        assert_eq!(rows.len(), 2 + 4);
+        assert!(rows.iter().all(|r| r[1] == r[2]));
+    }
+
+    #[test]
+    fn not_in_a_column_with_null_keeps_no_row() {
+        let (db, env) = fixture();
+        let sub = project(table("T"), &[sym("a")]);
+        let f = FilterExpr::Not(Box::new(CompExpr::In(field(sym("a")), Box::new(sub))));
+        assert!(run(&db, &env, &select(table("R"), f), Semantics::Set).is_empty());
+    }
+
+    #[test]
+    fn sum_skips_nulls_and_keeps_repeated_values() {
+        let (db, env) = fixture();
+        let p = plan(Plan::Reduce(ItemReduce::Sum(
+            Box::new(table("R")),
+            sym("a"),
+        )));
+        assert_eq!(
+            run(&db, &env, &p, Semantics::Bag),
+            ints(&[&[Some(1 + 2 + 2 + 3 + 4)]])
+        );
+    }
+
+    fn count(p: &LocPlan, semantics: Semantics) -> usize {
+        let (db, env) = fixture();
+        run(&db, &env, p, semantics).len()
+    }
+
+    #[test]
+    fn bag_semantics_keeps_duplicate_rows() {
+        let p = project(table("R"), &[sym("b")]);
+        assert_eq!(count(&p, Semantics::Bag), 6);
+        assert_eq!(count(&p, Semantics::Set), 4);
+        // 包语义下 distinct 明确去重
+        let p = plan(Plan::Distinct(Box::new(table("T"))));
+        assert_eq!(count(&p, Semantics::Bag), 4);
+    }
+
+    #[test]
+    fn set_semantics_gives_sets_from_every_operator() {
+        assert_eq!(count(&table("R"), Semantics::Bag), 6);
+        assert_eq!(count(&table("R"), Semantics::Set), 5);
+        let tt = plan(Plan::Product(Box::new(table("T")), Box::new(table("V"))));
+        assert_eq!(count(&tt, Semantics::Bag), 5 * 3);
+        assert_eq!(count(&tt, Semantics::Set), 4 * 3);
+        let (db, env) = fixture();
+        for p in queries() {
+            let rows = run(&db, &env, &p, Semantics::Set);
+            let mut set = sorted(rows.clone());
+            set.dedup();
+            assert_eq!(set.len(), rows.len(), "{}", p.0);
+        }
+    }
+
+    #[test]
+    fn bag_set_operations_count_each_row() {
+        let set = |f: fn(Box<LocPlan>, Box<LocPlan>) -> Plan| {
+            let ra = project(table("R"), &[sym("a")]);
+            plan(f(Box::new(table("T")), Box::new(ra)))
+        };
+        // T 有 1, 2, 2, 5, null; R 的 a 有 1, 2, 2, 3, null, 4
+        let cases = [
+            (set(Plan::Union), 6, 11),
+            (set(Plan::Intersect), 3, 4),
+            (set(Plan::Difference), 1, 1),
+        ];
+        for (p, in_set, in_bag) in cases {
+            assert_eq!(count(&p, Semantics::Set), in_set);
+            assert_eq!(count(&p, Semantics::Bag), in_bag);
+        }
+    }
+
+    fn groups(having: Option<FilterExpr>) -> Vec<Row> {
+        let (db, env) = fixture();
+        let count = Aggregate {
+            op: ReduceOperator::Count,
+            alias: Some(sym("n")),
+        };
+        let p = plan(Plan::GroupBy(
+            Box::new(table("R")),
+            vec![sym("a")],
+            vec![count],
+        ));
+        let p = match having {
+            Some(f) => select(p, f),
+            None => p,
+        };
+        sorted(run(&db, &env, &p, Semantics::Bag))
+    }
+
+    #[test]
+    fn null_keys_form_one_group() {
+        let rows = groups(None);
+        assert_eq!(rows.len(), 5);
+        assert!(rows.contains(&vec![Value::Null, Value::Uint(1)]));
+    }
+
+    #[test]
+    fn having_filters_the_groups() {
+        let one = Box::new(Expr::Value(Loc(Value::Uint(1), pos())));
+        let f = FilterExpr::Comp(Box::new(CompExpr::Gt(field(sym("n")), one)));
+        assert_eq!(groups(Some(f)), vec![vec![Value::Int(2), Value::Uint(2)]]);
+    }
+
+    fn by_d_desc() -> LocPlan {
+        plan(Plan::Sort(
+            Box::new(table("V")),
+            vec![SortKey::desc(sym("d"))],
+        ))
+    }
+
+    #[test]
+    fn sort_puts_nulls_first() {
+        let (db, env) = fixture();
+        let p = plan(Plan::Sort(
+            Box::new(table("R")),
+            vec![SortKey::asc(sym("a"))],
+        ));
+        let rows = run(&db, &env, &p, Semantics::Bag);
+        let a: Vec<&Value> = rows.iter().map(|r| &r[0]).collect();
+        let expected = [None, Some(1), Some(2), Some(2), Some(3), Some(4)].map(int_value);
+        assert_eq!(a, expected.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn positional_selections_follow_the_sort() {
+        let (db, env) = fixture();
+        let at = |f| run(&db, &env, &select(by_d_desc(), f), Semantics::Set);
+        assert_eq!(at(FilterExpr::GetFirst), ints(&[&[Some(900)]]));
+        assert_eq!(at(FilterExpr::GetLast), ints(&[&[Some(100)]]));
+        assert_eq!(
+            at(FilterExpr::Range(1, 3)),
+            ints(&[&[Some(200)], &[Some(100)]])
+        );
+        assert!(at(FilterExpr::Range(3, 1)).is_empty());
+    }
+
+    #[test]
+    fn executors_agree() {
+        let (db, env) = fixture();
+        for p in queries() {
+            for semantics in [Semantics::Set, Semantics::Bag] {
+                run(&db, &env, &p, semantics);
+            }
+        }
+    }
+}
@@ -0,0 +1,1219 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Cursors of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
+};
+
+use indexmap::IndexMap;
+
+use super::{
+    eval::{and, column, key_of, Context},
+    ExecError, Executor, Row, Scope,
+};
+use crate::{
+    optimizer::simplify::Truth,
+    physical::{Bound, DivisionFields, Lookup, Operator, PhysicalPlan, Semantics, SetOp, Side},
+    storage::{order, order_by, order_rows, HashKey, Relation},
+    structs::{
+        plan::{FilterExpr, JoinKind},
+        plan_group::ReduceOperator,
+        Aggregate, ArithOp, DivisionKind, SortKey, Symbol, Value,
+    },
+    type_system::{product_fields, Record},
+};
+
+/// A running operator, rows are pulled from it one at a time.
+pub trait Cursor {
+    /// The next row, None when there are no more.
+    fn next(&mut self) -> Result<Option<Row>, ExecError>;
+}
+
+pub type BoxCursor<'e> = Box<dyn Cursor + 'e>;
+
+pub fn drain(mut c: BoxCursor) -> Result<Vec<Row>, ExecError> {
+    let mut rows = vec![];
+    while let Some(r) = c.next()? {
+        rows.push(r);
+    }
+    Ok(rows)
+}
+
+pub fn columns(schema: &Record, names: &[Symbol]) -> Result<Vec<usize>, ExecError> {
+    names
+        .iter()
+        .map(|s| column(schema, s).ok_or_else(|| ExecError::FieldNotFound(s.clone())))
+        .collect()
+}
+
+pub fn sort_columns<'k>(
+    schema: &Record,
+    keys: &'k [SortKey],
+) -> Result<Vec<(usize, &'k SortKey)>, ExecError> {
+    keys.iter()
+        .map(|k| {
+            column(schema, &k.field)
+                .map(|i| (i, k))
+                .ok_or_else(|| ExecError::FieldNotFound(k.field.clone()))
+        })
+        .collect()
+}
+
+fn bound(b: &Option<Bound>) -> Option<(&Value, bool)> {
+    b.as_ref().map(|b| (&b.value, b.inclusive))
+}
+
+/// Start evaluating a physical plan.
+pub fn open<'e>(
+    ex: &'e Executor<'e>,
+    plan: &'e PhysicalPlan,
+    scope: &Scope,
+) -> Result<BoxCursor<'e>, ExecError> {
+    // 共享的节点第一次把结果读完存起来
+    if let Operator::Shared(id) = plan.op {
+        let rows = ex.shared(id, || drain(open(ex, &plan.children[0], scope)?))?;
+        return Ok(shared(rows));
+    }
+    let children = plan
+        .children
+        .iter()
+        .map(|c| open(ex, c, scope))
+        .collect::<Result<_, _>>()?;
+    operator(ex, plan, scope, children)
+}
+
+/// The cursor of the root operator of `plan`, reading the rows of its
+/// children from `children`.
+pub fn operator<'e>(
+    ex: &'e Executor<'e>,
+    plan: &'e PhysicalPlan,
+    scope: &Scope,
+    children: Vec<BoxCursor<'e>>,
+) -> Result<BoxCursor<'e>, ExecError> {
+    let mut inputs: Vec<Option<BoxCursor<'e>>> = children.into_iter().map(Some).collect();
+    let mut child = |i: usize| -> Result<BoxCursor<'e>, ExecError> {
+        Ok(inputs[i].take().expect("each input is read once"))
+    };
+    let schema = |i: usize| &plan.children[i].schema;
+    let table = |t: &str| {
+        ex.database()
+            .table(t)
+            .ok_or_else(|| ExecError::TableNotFound(t.to_string()))
+    };
+    Ok(match &plan.op {
+        Operator::TableScan(t) => Box::new(Scan {
+            rel: table(t)?,
+            ids: None,
+            next: 0,
+        }),
+        Operator::IndexScan(s) => {
+            let rel = table(&s.table)?;
+            let index = ex
+                .database()
+                .index(&s.index)
+                .ok_or_else(|| ExecError::IndexNotFound(s.index.clone()))?;
+            let ids = match &s.lookup {
+                Lookup::Eq(v) => index.lookup(rel, v),
+                Lookup::Range(l, u) => index.range(rel, bound(l), bound(u)),
+            };
+            Box::new(Scan {
+                rel,
+                ids: Some(ids),
+                next: 0,
+            })
+        }
+        // 只改了列名, 行原样往上传
+        Operator::Rename(_) => child(0)?,
+        Operator::Filter(f) if f.is_positional() => {
+            let (from, to) = match f {
+                FilterExpr::Range(from, to) => (*from, *to),
+                FilterExpr::GetItem(i) => (*i, i.saturating_add(1)),
+                FilterExpr::GetFirst => (0, 1),
+                _ => {
+                    // 最后一行要读完才知道, 只留着最后读到的一行
+                    let mut input = child(0)?;
+                    let mut last = None;
+                    while let Some(r) = input.next()? {
+                        last = Some(r);
+                    }
+                    return Ok(Box::new(Rows(
+                        last.into_iter().collect::<Vec<_>>().into_iter(),
+                    )));
+                }
+            };
+            Box::new(Limit {
+                input: child(0)?,
+                from,
+                to,
+                index: 0,
+            })
+        }
+        Operator::Limit(offset, count) => Box::new(Limit {
+            input: child(0)?,
+            from: *offset,
+            to: offset.saturating_add(*count),
+            index: 0,
+        }),
+        Operator::Filter(f) => Box::new(Filter {
+            ex,
+            input: child(0)?,
+            f,
+            schema: schema(0),
+            scope: scope.clone(),
+        }),
+        Operator::Project(names, sem) => Box::new(Project {
+            input: child(0)?,
+            columns: columns(schema(0), names)?,
+            seen: (*sem == Semantics::Set).then(HashSet::new),
+        }),
+        Operator::Distinct => Box::new(Project {
+            input: child(0)?,
+            columns: (0..schema(0).0.len()).collect(),
+            seen: Some(HashSet::new()),
+        }),
+        Operator::NestedLoopJoin(..) | Operator::HashJoin(_) | Operator::MergeJoin(_) => {
+            Box::new(Join::new(ex, plan, scope, child(0)?, child(1)?)?)
+        }
+        Operator::HashAggregate(op) => {
+            let rows = drain(child(0)?)?;
+            Box::new(Rows(vec![aggregate(&rows, schema(0), op)?].into_iter()))
+        }
+        Operator::HashGroupBy(keys, aggs) => {
+            let keys = columns(schema(0), keys)?;
+            let rows = drain(child(0)?)?;
+            Box::new(Rows(group_by(rows, &keys, schema(0), aggs)?.into_iter()))
+        }
+        Operator::Sort(keys) => {
+            let keys = sort_columns(schema(0), keys)?;
+            let mut rows = drain(child(0)?)?;
+            rows.sort_by(|a, b| order_by(a, b, &keys));
+            Box::new(Rows(rows.into_iter()))
+        }
+        Operator::TopN(keys, offset, count, last) => {
+            let keys = sort_columns(schema(0), keys)?;
+            let n = offset.saturating_add(*count);
+            let rows = top_n(child(0)?, &keys, n as usize, *last)?;
+            let offset = *offset as usize;
+            Box::new(Rows(
+                rows.into_iter()
+                    .skip(offset)
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ))
+        }
+        Operator::HashSetOp(op, sem) => {
+            // 并集的右边也是一行一行地读
+            let (right, mut other) = match op {
+                SetOp::Union => (Some(child(1)?), HashMap::new()),
+                _ => (None, HashMap::new()),
+            };
+            if right.is_none() {
+                for r in drain(child(1)?)? {
+                    *other.entry(HashKey::new(r.iter())).or_insert(0) += 1;
+                }
+            }
+            Box::new(HashSetOperation {
+                op: *op,
+                left: child(0)?,
+                right,
+                other,
+                seen: (*sem == Semantics::Set).then(HashSet::new),
+            })
+        }
+        Operator::SortSetOp(op, sem) => Box::new(SortSetOperation {
+            op: *op,
+            bag: *sem == Semantics::Bag,
+            left: child(0)?,
+            right: child(1)?,
+            l: None,
+            r: None,
+            started: false,
+            last: None,
+        }),
+        Operator::HashDivision(kind)
+        | Operator::SortDivision(kind)
+        | Operator::CountDivision(kind) => {
+            let d = Division::new(schema(0), schema(1), kind)?;
+            let (a, b) = (drain(child(0)?)?, drain(child(1)?)?);
+            let rows = match &plan.op {
+                Operator::HashDivision(_) => d.hash(&a, &b),
+                Operator::SortDivision(_) => d.sort(&a, &b),
+                _ => d.count(&a, &b),
+            };
+            Box::new(Rows(rows.into_iter()))
+        }
+        Operator::Empty => Box::new(Rows(vec![].into_iter())),
+        Operator::Shared(_) => child(0)?,
+    })
+}
+
+struct Rows(std::vec::IntoIter<Row>);
+
+/// A cursor over rows already read.
+pub fn rows<'e>(rows: Vec<Row>) -> BoxCursor<'e> {
+    Box::new(Rows(rows.into_iter()))
+}
+
+impl Cursor for Rows {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        Ok(self.0.next())
+    }
+}
+
+/// A cursor over the rows of a shared node.
+pub fn shared<'e>(rows: Arc<Vec<Row>>) -> BoxCursor<'e> {
+    Box::new(Shared { rows, next: 0 })
+}
+
+struct Shared {
+    rows: Arc<Vec<Row>>,
+    next: usize,
+}
+
+impl Cursor for Shared {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        self.next += 1;
+        Ok(self.rows.get(self.next - 1).cloned())
+    }
+}
+
+struct Scan<'e> {
+    rel: &'e Relation,
+    // 索引查出来的行号, None 是整张表
+    ids: Option<Vec<usize>>,
+    next: usize,
+}
+
+impl<'e> Cursor for Scan<'e> {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        let i = match &self.ids {
+            Some(ids) => match ids.get(self.next) {
+                Some(i) => *i,
+                None => return Ok(None),
+            },
+            None => self.next,
+        };
+        self.next += 1;
+        Ok(self.rel.rows.get(i).cloned())
+    }
+}
+
+struct Filter<'e> {
+    ex: &'e Executor<'e>,
+    input: BoxCursor<'e>,
+    f: &'e FilterExpr,
+    schema: &'e Record,
+    scope: Scope,
+}
+
+impl<'e> Cursor for Filter<'e> {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        while let Some(r) = self.input.next()? {
+            let ctx = Context {
+                schema: self.schema,
+                row: &r,
+                scope: &self.scope,
+            };
+            if ctx.filter(self.f, self.ex)? == Truth::True {
+                return Ok(Some(r));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// 第 from 行到第 to 行, 不含 to, 从 0 开始数
+struct Limit<'e> {
+    input: BoxCursor<'e>,
+    from: u64,
+    to: u64,
+    index: u64,
+}
+
+impl<'e> Cursor for Limit<'e> {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        while self.index < self.to {
+            let r = match self.input.next()? {
+                Some(r) => r,
+                None => return Ok(None),
+            };
+            self.index += 1;
+            if self.index > self.from {
+                return Ok(Some(r));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Keeps the rows not seen before, or every row when there is no `seen`.
+struct Project<'e> {
+    input: BoxCursor<'e>,
+    columns: Vec<usize>,
+    seen: Option<HashSet<HashKey>>,
+}
+
+impl<'e> Cursor for Project<'e> {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        while let Some(r) = self.input.next()? {
+            let r: Row = self.columns.iter().map(|i| r[*i].clone()).collect();
+            if fresh(&mut self.seen, &r) {
+                return Ok(Some(r));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Build rows by key, split into partitions by the hash of the key so
+/// that each partition can be built by a thread of its own.
+pub struct HashTable {
+    parts: Vec<HashMap<HashKey, Vec<usize>>>,
+    // 键里有 null 的行
+    nulls: Vec<usize>,
+}
+
+/// The partition of `parts` a key is kept in.
+pub fn partition(k: &HashKey, parts: usize) -> usize {
+    let mut h = DefaultHasher::new();
+    k.hash(&mut h);
+    (h.finish() % parts as u64) as usize
+}
+
+impl HashTable {
+    /// Every partition must list its rows in the order of `rows`.
+    pub fn new(parts: Vec<HashMap<HashKey, Vec<usize>>>, nulls: Vec<usize>) -> Self {
+        HashTable { parts, nulls }
+    }
+
+    /// One partition, keyed on `columns`.
+    pub fn build(rows: &[Row], columns: &[usize]) -> Self {
+        let mut table: HashMap<HashKey, Vec<usize>> = HashMap::new();
+        let mut nulls = vec![];
+        for (i, r) in rows.iter().enumerate() {
+            let k = key_of(r, columns);
+            // null 不等于任何值, 不进哈希表
+            if k.has_null() {
+                nulls.push(i);
+            } else {
+                table.entry(k).or_default().push(i);
+            }
+        }
+        HashTable::new(vec![table], nulls)
+    }
+
+    fn get(&self, k: &HashKey) -> Option<&Vec<usize>> {
+        self.parts[partition(k, self.parts.len())].get(k)
+    }
+}
+
+/// How the build rows that may match a probe row are found.
+enum Probe {
+    All,
+    Hash(HashTable),
+    /// Both sides are sorted on their keys.
+    Merge,
+}
+
+/// The input a join reads first and keeps.
+pub fn build_side(plan: &PhysicalPlan) -> Side {
+    match &plan.op {
+        Operator::HashJoin(j) => j.build,
+        _ => Side::Right,
+    }
+}
+
+/// The build side of a join of any kind. Each row of the other side is
+/// matched against the build rows found by the probe. Probe rows may be
+/// matched by several threads at once.
+pub struct JoinTable<'e> {
+    ex: &'e Executor<'e>,
+    scope: Scope,
+    build: Side,
+    rows: Vec<Row>,
+    matched: Vec<AtomicBool>,
+    kind: JoinKind,
+    // (左边的列, 右边的列)
+    keys: Vec<(usize, usize)>,
+    conds: &'e [FilterExpr],
+    // 条件按两边拼起来的名字写
+    product: Record,
+    probe: Probe,
+    widths: (usize, usize),
+}
+
+/// Fields of the joined rows, named as join conditions name them.
+pub fn product(ls: &Record, rs: &Record) -> Result<Record, ExecError> {
+    let side = |t: &Record| -> Vec<(Symbol, _)> {
+        t.0.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    };
+    let fields =
+        product_fields(side(ls), &ls.1, side(rs), &rs.1).map_err(ExecError::DuplicateField)?;
+    Ok(Record(
+        fields.into_iter().collect::<IndexMap<_, _>>(),
+        format!("{}*{}", ls.1, rs.1),
+    ))
+}
+
+fn equal(a: &Value, b: &Value) -> Truth {
+    if *a == Value::Null || *b == Value::Null {
+        return Truth::Unknown;
+    }
+    match a.compare(b) {
+        Some(Ordering::Equal) => Truth::True,
+        _ => Truth::False,
+    }
+}
+
+impl<'e> JoinTable<'e> {
+    /// `rows` are the rows of the build side, `hash` builds the hash table
+    /// of a hash join on the given columns of them.
+    pub fn new(
+        ex: &'e Executor<'e>,
+        plan: &'e PhysicalPlan,
+        scope: &Scope,
+        rows: Vec<Row>,
+        hash: impl FnOnce(&[Row], &[usize]) -> HashTable,
+    ) -> Result<Self, ExecError> {
+        let (kind, keys, conds, probe) = match &plan.op {
+            Operator::NestedLoopJoin(kind, conds) => (*kind, &[][..], &conds[..], Probe::All),
+            Operator::HashJoin(j) => (j.kind, &j.keys[..], &j.residual[..], Probe::All),
+            Operator::MergeJoin(j) => (j.kind, &j.keys[..], &j.residual[..], Probe::Merge),
+            _ => unreachable!("not a join"),
+        };
+        let (ls, rs) = (&plan.children[0].schema, &plan.children[1].schema);
+        let keys: Vec<(usize, usize)> = keys
+            .iter()
+            .map(|(l, r)| {
+                let l = column(ls, l).ok_or_else(|| ExecError::FieldNotFound(l.clone()))?;
+                let r = column(rs, r).ok_or_else(|| ExecError::FieldNotFound(r.clone()))?;
+                Ok((l, r))
+            })
+            .collect::<Result<_, ExecError>>()?;
+        let mut join = JoinTable {
+            ex,
+            scope: scope.clone(),
+            build: build_side(plan),
+            matched: rows.iter().map(|_| AtomicBool::new(false)).collect(),
+            rows,
+            kind,
+            keys,
+            conds,
+            product: product(ls, rs)?,
+            probe,
+            widths: (ls.0.len(), rs.0.len()),
+        };
+        if let Operator::HashJoin(_) = plan.op {
+            join.probe = Probe::Hash(hash(&join.rows, &join.key_columns(join.build)));
+        }
+        Ok(join)
+    }
+
+    fn key_columns(&self, side: Side) -> Vec<usize> {
+        self.keys
+            .iter()
+            .map(|(l, r)| match side {
+                Side::Left => *l,
+                Side::Right => *r,
+            })
+            .collect()
+    }
+
+    // pos 是归并连接里第一个还可能匹配的建表行
+    fn candidates(&self, p: &Row, pos: &mut usize) -> Vec<usize> {
+        let pkeys = self.key_columns(self.build.other());
+        let bkeys = self.key_columns(self.build);
+        let all = (0..self.rows.len()).collect();
+        let not_in = self.kind == JoinKind::NullAwareAnti;
+        match &self.probe {
+            Probe::All => all,
+            Probe::Hash(table) => {
+                let k = key_of(p, &pkeys);
+                // not in 和 null 比较是未知, 也要看其余的条件挑没挑到这一行
+                if k.has_null() {
+                    return if not_in { all } else { vec![] };
+                }
+                let mut r = table.get(&k).cloned().unwrap_or_default();
+                if not_in {
+                    r.extend(table.nulls.iter());
+                }
+                r
+            }
+            Probe::Merge if not_in => all,
+            Probe::Merge => {
+                let k: Vec<Value> = pkeys.iter().map(|i| p[*i].clone()).collect();
+                if k.contains(&Value::Null) {
+                    return vec![];
+                }
+                let rows = &self.rows;
+                let bk = |j: usize| -> Vec<Value> {
+                    bkeys.iter().map(|i| rows[j][*i].clone()).collect()
+                };
+                while *pos < rows.len() && order_rows(&bk(*pos), &k) == Ordering::Less {
+                    *pos += 1;
+                }
+                let mut r = vec![];
+                let mut j = *pos;
+                while j < rows.len() && order_rows(&bk(j), &k) == Ordering::Equal {
+                    if !bk(j).contains(&Value::Null) {
+                        r.push(j);
+                    }
+                    j += 1;
+                }
+                r
+            }
+        }
+    }
+
+    // (左边的行, 右边的行)
+    fn sides<'r>(&'r self, p: &'r Row, j: usize) -> (&'r Row, &'r Row) {
+        match self.build {
+            Side::Left => (&self.rows[j], p),
+            Side::Right => (p, &self.rows[j]),
+        }
+    }
+
+    // (键和第一个条件, 其余的条件), not in 分开看这两部分
+    fn test(&self, l: &Row, r: &Row) -> Result<(Truth, Truth), ExecError> {
+        let row: Row = l.iter().chain(r.iter()).cloned().collect();
+        let ctx = Context {
+            schema: &self.product,
+            row: &row,
+            scope: &self.scope,
+        };
+        let mut conds = self.conds.iter();
+        let mut first = Truth::True;
+        for (i, j) in self.keys.iter() {
+            first = and(first, equal(&l[*i], &r[*j]));
+        }
+        if self.keys.is_empty() && self.kind == JoinKind::NullAwareAnti {
+            if let Some(f) = conds.next() {
+                first = ctx.filter(f, self.ex)?;
+            }
+        }
+        let mut rest = Truth::True;
+        for f in conds {
+            rest = and(rest, ctx.filter(f, self.ex)?);
+        }
+        Ok((first, rest))
+    }
+
+    // 没有的一边补 null
+    fn joined(&self, l: Option<&Row>, r: Option<&Row>) -> Row {
+        let side = |x: Option<&Row>, n: usize| x.cloned().unwrap_or_else(|| vec![Value::Null; n]);
+        let mut row = side(l, self.widths.0);
+        row.extend(side(r, self.widths.1));
+        row
+    }
+
+    /// Match one probe row, the joined rows are added to `out`. Probe rows
+    /// read one after another share `pos`.
+    pub fn process(
+        &self,
+        p: Row,
+        pos: &mut usize,
+        out: &mut VecDeque<Row>,
+    ) -> Result<(), ExecError> {
+        let candidates = self.candidates(&p, pos);
+        // not in 总是建在右边
+        if self.kind == JoinKind::NullAwareAnti {
+            for j in candidates {
+                let (first, rest) = self.test(&p, &self.rows[j])?;
+                if rest == Truth::True && first != Truth::False {
+                    return Ok(());
+                }
+            }
+            out.push_back(p);
+            return Ok(());
+        }
+        let probing_left = self.build == Side::Right;
+        let mut any = false;
+        for j in candidates {
+            let (l, r) = self.sides(&p, j);
+            let (first, rest) = self.test(l, r)?;
+            if and(first, rest) != Truth::True {
+                continue;
+            }
+            let row = (!self.kind.is_semi()).then(|| self.joined(Some(l), Some(r)));
+            any = true;
+            self.matched[j].store(true, atomic::Ordering::Relaxed);
+            match row {
+                Some(row) => out.push_back(row),
+                // 左边每行只输出一次
+                None if probing_left => break,
+                None => {}
+            }
+        }
+        match self.kind {
+            JoinKind::Semi | JoinKind::Anti
+                if probing_left && any == (self.kind == JoinKind::Semi) =>
+            {
+                out.push_back(p)
+            }
+            kind if !any && self.build.other().outer(kind) => {
+                let row = match self.build {
+                    Side::Left => self.joined(None, Some(&p)),
+                    Side::Right => self.joined(Some(&p), None),
+                };
+                out.push_back(row);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// After every probe row: the build rows never matched padded with
+    /// nulls, or the rows of a semi join built on the left.
+    pub fn finish(&self, out: &mut VecDeque<Row>) {
+        if self.build == Side::Right && self.kind.is_semi() {
+            return;
+        }
+        for (r, matched) in self.rows.iter().zip(self.matched.iter()) {
+            let matched = matched.load(atomic::Ordering::Relaxed);
+            let row = match self.kind {
+                JoinKind::Semi if matched => r.clone(),
+                JoinKind::Anti if !matched => r.clone(),
+                kind if !matched && self.build.outer(kind) => match self.build {
+                    Side::Left => self.joined(Some(r), None),
+                    Side::Right => self.joined(None, Some(r)),
+                },
+                _ => continue,
+            };
+            out.push_back(row);
+        }
+    }
+}
+
+/// Reads the build side first, then the other side one row at a time.
+struct Join<'e> {
+    table: JoinTable<'e>,
+    input: BoxCursor<'e>,
+    pos: usize,
+    out: VecDeque<Row>,
+    finished: bool,
+}
+
+impl<'e> Join<'e> {
+    fn new(
+        ex: &'e Executor<'e>,
+        plan: &'e PhysicalPlan,
+        scope: &Scope,
+        left: BoxCursor<'e>,
+        right: BoxCursor<'e>,
+    ) -> Result<Self, ExecError> {
+        let (input, rows) = match build_side(plan) {
+            Side::Left => (right, drain(left)?),
+            Side::Right => (left, drain(right)?),
+        };
+        Ok(Join {
+            table: JoinTable::new(ex, plan, scope, rows, HashTable::build)?,
+            input,
+            pos: 0,
+            out: VecDeque::new(),
+            finished: false,
+        })
+    }
+}
+
+impl<'e> Cursor for Join<'e> {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        loop {
+            if let Some(r) = self.out.pop_front() {
+                return Ok(Some(r));
+            }
+            if self.finished {
+                return Ok(None);
+            }
+            match self.input.next()? {
+                Some(p) => self.table.process(p, &mut self.pos, &mut self.out)?,
+                None => {
+                    self.finished = true;
+                    self.table.finish(&mut self.out);
+                }
+            }
+        }
+    }
+}
+
+/// The first `n` rows of the input sorted on the keys, rows with equal keys
+/// keep their input order. At most `2 * n` rows are held at a time.
+fn top_n(
+    mut input: BoxCursor,
+    keys: &[(usize, &SortKey)],
+    n: usize,
+    last: bool,
+) -> Result<Vec<Row>, ExecError> {
+    if n == 0 {
+        return Ok(vec![]);
+    }
+    // 从排序的结尾读时, 键相同的行后来的在前
+    let cmp = |a: &(usize, Row), b: &(usize, Row)| {
+        let seq = if last { b.0.cmp(&a.0) } else { a.0.cmp(&b.0) };
+        order_by(&a.1, &b.1, keys).then(seq)
+    };
+    let mut rows: Vec<(usize, Row)> = vec![];
+    let mut seq = 0;
+    while let Some(r) = input.next()? {
+        rows.push((seq, r));
+        seq += 1;
+        // 攒够一倍就只留下最前面的 n 行
+        if rows.len() >= n.saturating_mul(2) {
+            rows.select_nth_unstable_by(n, cmp);
+            rows.truncate(n);
+        }
+    }
+    rows.sort_by(cmp);
+    rows.truncate(n);
+    Ok(rows.into_iter().map(|(_, r)| r).collect())
+}
+
+/// Aggregates skip nulls, and are null when there is nothing left.
+fn aggregate(rows: &[Row], schema: &Record, op: &ReduceOperator) -> Result<Row, ExecError> {
+    let s = match op.symbol() {
+        Some(s) => s,
+        None => return Ok(vec![Value::Uint(rows.len() as u64)]),
+    };
+    let i = column(schema, s).ok_or_else(|| ExecError::FieldNotFound(s.clone()))?;
+    let values: Vec<&Value> = rows
+        .iter()
+        .map(|r| &r[i])
+        .filter(|v| **v != Value::Null)
+        .collect();
+    if values.is_empty() {
+        return Ok(vec![Value::Null]);
+    }
+    let sum = || {
+        values.iter().skip(1).try_fold(values[0].clone(), |acc, v| {
+            acc.arith(ArithOp::Add, v)
+                .ok_or_else(|| ExecError::Arithmetic(format!("sum[{}] with {} and {}", s, acc, v)))
+        })
+    };
+    let pick = |want: Ordering| {
+        values
+            .iter()
+            .skip(1)
+            .fold(
+                values[0],
+                |acc, v| if order(v, acc) == want { v } else { acc },
+            )
+            .clone()
+    };
+    let r = match op {
+        ReduceOperator::Sum(_) => sum()?,
+        ReduceOperator::Avg(_) => {
+            let total: f64 = values.iter().filter_map(|v| v.as_f64()).sum();
+            Value::Float(total / values.len() as f64)
+        }
+        ReduceOperator::Max(_) => pick(Ordering::Greater),
+        ReduceOperator::Min(_) => pick(Ordering::Less),
+        ReduceOperator::Count => unreachable!(),
+    };
+    Ok(vec![r])
+}
+
+/// One row per group in the order the groups are first seen: the keys,
+/// then every aggregate over the rows of the group.
+fn group_by(
+    rows: Vec<Row>,
+    keys: &[usize],
+    schema: &Record,
+    aggs: &[Aggregate],
+) -> Result<Vec<Row>, ExecError> {
+    let mut groups: IndexMap<HashKey, Vec<Row>> = IndexMap::new();
+    if keys.is_empty() {
+        // 没有分组属性时, 空表也有一组
+        groups.insert(HashKey::new([].iter()), vec![]);
+    }
+    for r in rows {
+        let k = HashKey::new(keys.iter().map(|i| &r[*i]));
+        groups.entry(k).or_default().push(r);
+    }
+    groups
+        .into_values()
+        .map(|g| {
+            let mut row = g.first().map_or(vec![], |r| pick(r, keys));
+            for a in aggs {
+                row.extend(aggregate(&g, schema, &a.op)?);
+            }
+            Ok(row)
+        })
+        .collect()
+}
+
+struct HashSetOperation<'e> {
+    op: SetOp,
+    left: BoxCursor<'e>,
+    right: Option<BoxCursor<'e>>,
+    // 交集和差集时右边所有的行, 和还没有抵掉的个数
+    other: HashMap<HashKey, usize>,
+    seen: Option<HashSet<HashKey>>,
+}
+
+fn fresh(seen: &mut Option<HashSet<HashKey>>, row: &[Value]) -> bool {
+    seen.as_mut()
+        .is_none_or(|s| s.insert(HashKey::new(row.iter())))
+}
+
+impl<'e> Cursor for HashSetOperation<'e> {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        let bag = self.seen.is_none();
+        while let Some(r) = self.left.next()? {
+            // 包语义下右边的一行只抵掉左边的一行
+            let found = match self.other.get_mut(&HashKey::new(r.iter())) {
+                Some(n) if *n > 0 => {
+                    if bag {
+                        *n -= 1;
+                    }
+                    true
+                }
+                _ => false,
+            };
+            let keep = match self.op {
+                SetOp::Union => true,
+                SetOp::Intersect => found,
+                SetOp::Difference => !found,
+            };
+            if keep && fresh(&mut self.seen, &r) {
+                return Ok(Some(r));
+            }
+        }
+        if let Some(right) = &mut self.right {
+            while let Some(r) = right.next()? {
+                if fresh(&mut self.seen, &r) {
+                    return Ok(Some(r));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Both inputs are sorted on all their fields, they are read side by side.
+struct SortSetOperation<'e> {
+    op: SetOp,
+    // 包语义: 相等的两行互相抵掉, 不去重
+    bag: bool,
+    left: BoxCursor<'e>,
+    right: BoxCursor<'e>,
+    l: Option<Row>,
+    r: Option<Row>,
+    started: bool,
+    // 上一次输出的行, 用来去掉重复
+    last: Option<Row>,
+}
+
+impl<'e> SortSetOperation<'e> {
+    fn advance_left(&mut self) -> Result<Option<Row>, ExecError> {
+        Ok(std::mem::replace(&mut self.l, self.left.next()?))
+    }
+
+    fn advance_right(&mut self) -> Result<Option<Row>, ExecError> {
+        Ok(std::mem::replace(&mut self.r, self.right.next()?))
+    }
+
+    fn fresh(&mut self, row: Row) -> Option<Row> {
+        if self.bag {
+            return Some(row);
+        }
+        if let Some(last) = &self.last {
+            if order_rows(last, &row) == Ordering::Equal {
+                return None;
+            }
+        }
+        self.last = Some(row.clone());
+        Some(row)
+    }
+}
+
+impl<'e> Cursor for SortSetOperation<'e> {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        if !self.started {
+            self.started = true;
+            self.l = self.left.next()?;
+            self.r = self.right.next()?;
+        }
+        loop {
+            let c = match (&self.l, &self.r) {
+                (None, None) => return Ok(None),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(a), Some(b)) => order_rows(a, b),
+            };
+            let row = match (self.op, c) {
+                (SetOp::Union, Ordering::Greater) => self.advance_right()?,
+                (SetOp::Union, _) => self.advance_left()?,
+                (SetOp::Intersect, _) if self.l.is_none() || self.r.is_none() => return Ok(None),
+                (SetOp::Intersect, Ordering::Equal) => {
+                    if self.bag {
+                        self.advance_right()?;
+                    }
+                    self.advance_left()?
+                }
+                (SetOp::Difference, _) if self.l.is_none() => return Ok(None),
+                (SetOp::Difference, Ordering::Less) => self.advance_left()?,
+                (SetOp::Difference, Ordering::Equal) => {
+                    if self.bag {
+                        self.advance_right()?;
+                    }
+                    self.advance_left()?;
+                    None
+                }
+                (_, Ordering::Less) => {
+                    self.advance_left()?;
+                    None
+                }
+                (_, _) => {
+                    self.advance_right()?;
+                    None
+                }
+            };
+            if let Some(row) = row.and_then(|r| self.fresh(r)) {
+                return Ok(Some(row));
+            }
+        }
+    }
+}
+
+fn pick(row: &[Value], columns: &[usize]) -> Row {
+    columns.iter().map(|i| row[*i].clone()).collect()
+}
+
+/// Positions of the fields of a division, see `DivisionFields`.
+struct Division {
+    x: Vec<usize>,
+    y: Vec<usize>,
+    group: Vec<usize>,
+    // 除数里要比较的列
+    divisor_y: Vec<usize>,
+    simple: bool,
+}
+
+/// The groups of a divisor with the number of their `y`, and the group
+/// and position within it of every `y`.
+type Groups = (Vec<(Row, usize)>, HashMap<HashKey, Vec<(usize, usize)>>);
+
+/// Quotient candidates by their `x`, with what was found of each group.
+type Candidates<T> = IndexMap<HashKey, (Row, HashMap<usize, T>)>;
+
+impl Division {
+    fn new(rs: &Record, ss: &Record, kind: &DivisionKind) -> Result<Self, ExecError> {
+        let f = DivisionFields::new(rs, ss, kind);
+        Ok(Division {
+            x: columns(rs, &f.x)?,
+            y: columns(rs, &f.y)?,
+            group: columns(ss, &f.group)?,
+            divisor_y: columns(ss, &f.y)?,
+            simple: *kind == DivisionKind::Simple,
+        })
+    }
+
+    fn groups(&self, divisor: &[Row]) -> Groups {
+        let mut index = HashMap::new();
+        let mut groups = vec![];
+        // 简单除法总有一组, 除数为空时也是
+        if self.simple {
+            index.insert(HashKey::new([]), 0);
+            groups.push((vec![], 0));
+        }
+        let mut ys: HashMap<HashKey, Vec<(usize, usize)>> = HashMap::new();
+        for s in divisor {
+            let gv = pick(s, &self.group);
+            let g = *index.entry(HashKey::new(gv.iter())).or_insert_with(|| {
+                groups.push((gv, 0));
+                groups.len() - 1
+            });
+            let v = ys.entry(key_of(s, &self.divisor_y)).or_default();
+            if !v.iter().any(|(h, _)| *h == g) {
+                v.push((g, groups[g].1));
+                groups[g].1 += 1;
+            }
+        }
+        (groups, ys)
+    }
+
+    // 每组里 x 按在被除数里第一次出现的顺序
+    fn quotient<T>(
+        groups: &[(Row, usize)],
+        xs: &Candidates<T>,
+        count: impl Fn(&T) -> usize,
+    ) -> Vec<Row> {
+        let mut r = vec![];
+        for (g, (gv, size)) in groups.iter().enumerate() {
+            for (x, found) in xs.values() {
+                if found.get(&g).map_or(0, &count) == *size {
+                    r.push(x.iter().chain(gv.iter()).cloned().collect());
+                }
+            }
+        }
+        r
+    }
+
+    /// Every candidate `x` has a bitmap of the `y` of each group it was
+    /// seen with, so duplicate rows are counted once.
+    fn hash(&self, dividend: &[Row], divisor: &[Row]) -> Vec<Row> {
+        let (groups, ys) = self.groups(divisor);
+        let mut xs: Candidates<(Vec<bool>, usize)> = IndexMap::new();
+        for r in dividend {
+            let x = pick(r, &self.x);
+            let (_, found) = xs
+                .entry(HashKey::new(x.iter()))
+                .or_insert_with(|| (x, HashMap::new()));
+            for (g, i) in ys.get(&key_of(r, &self.y)).into_iter().flatten() {
+                let (bits, n) = found
+                    .entry(*g)
+                    .or_insert_with(|| (vec![false; groups[*g].1], 0));
+                if !bits[*i] {
+                    bits[*i] = true;
+                    *n += 1;
+                }
+            }
+        }
+        Self::quotient(&groups, &xs, |(_, n)| *n)
+    }
+
+    /// Counts the rows of every candidate `x` that are in a group, the
+    /// dividend must have no duplicate rows.
+    fn count(&self, dividend: &[Row], divisor: &[Row]) -> Vec<Row> {
+        let (groups, ys) = self.groups(divisor);
+        let mut xs: Candidates<usize> = IndexMap::new();
+        for r in dividend {
+            let x = pick(r, &self.x);
+            let (_, found) = xs
+                .entry(HashKey::new(x.iter()))
+                .or_insert_with(|| (x, HashMap::new()));
+            for (g, _) in ys.get(&key_of(r, &self.y)).into_iter().flatten() {
+                *found.entry(*g).or_default() += 1;
+            }
+        }
+        Self::quotient(&groups, &xs, |n| *n)
+    }
+
+    /// The dividend is sorted on `x` then `y`, the divisor on its groups
+    /// then `y`. Each run of rows with the same `x` is merged with the `y`
+    /// of every group.
+    fn sort(&self, dividend: &[Row], divisor: &[Row]) -> Vec<Row> {
+        fn runs(rows: &[Row], key: &[usize], value: &[usize], init: bool) -> Vec<(Row, Vec<Row>)> {
+            let mut r: Vec<(Row, Vec<Row>)> = vec![];
+            if init {
+                r.push((vec![], vec![]));
+            }
+            for row in rows {
+                let (k, v) = (pick(row, key), pick(row, value));
+                match r.last_mut() {
+                    Some((last, vs)) if order_rows(last, &k) == Ordering::Equal => vs.push(v),
+                    _ => r.push((k, vec![v])),
+                }
+            }
+            r
+        }
+        let groups = runs(divisor, &self.group, &self.divisor_y, self.simple);
+        let xs = runs(dividend, &self.x, &self.y, false);
+        let mut r = vec![];
+        for (gv, want) in groups.iter() {
+            for (x, have) in xs.iter() {
+                let mut i = 0;
+                let all = want.iter().all(|w| {
+                    while i < have.len() && order_rows(&have[i], w) == Ordering::Less {
+                        i += 1;
+                    }
+                    i < have.len() && order_rows(&have[i], w) == Ordering::Equal
+                });
+                if all {
+                    r.push(x.iter().chain(gv.iter()).cloned().collect());
+                }
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        physical::{EquiJoin, Side},
+        structs::plan::{JoinKind, Plan},
+        testing::*,
+    };
+
+    // 同一个连接分别用逐对比较和两种建表方向的 hash join 执行
+    fn joins(kind: JoinKind) -> Vec<Vec<Row>> {
+        let (db, env) = fixture();
+        let exec = Executor::new(&db, &env).with_semantics(Semantics::Bag);
+        let on = eq(qualified("R", "b"), qualified("S", "b"));
+        let p = plan(Plan::Join(
+            Box::new(table("R")),
+            Box::new(table("S")),
+            kind,
+            vec![on.clone()],
+        ));
+        let mut p = exec.planner().plan(&p).unwrap();
+        let mut ops = vec![Operator::NestedLoopJoin(kind, vec![on])];
+        for build in [Side::Left, Side::Right] {
+            ops.push(Operator::HashJoin(EquiJoin {
+                kind,
+                keys: vec![(sym("b"), sym("b"))],
+                residual: vec![],
+                build,
+            }));
+        }
+        ops.into_iter()
+            .map(|op| {
+                p.op = op;
+                sorted(exec.execute_physical(&p).unwrap().rows)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hash_join_gives_the_rows_of_the_nested_loop_join() {
+        for kind in [
+            JoinKind::Inner,
+            JoinKind::Left,
+            JoinKind::Right,
+            JoinKind::Full,
+            JoinKind::Semi,
+            JoinKind::Anti,
+        ] {
+            let rows = joins(kind);
+            assert_eq!(rows[0], rows[1], "{:?} build left", kind);
+            assert_eq!(rows[0], rows[2], "{:?} build right", kind);
+        }
+    }
+
+    #[test]
+    fn null_keys_match_nothing() {
+        let inner = joins(JoinKind::Inner).remove(1);
+        assert!(inner.iter().all(|r| r[1] != Value::Null));
+        // 两边 b 为 null 的行各自补 null
+        let full = joins(JoinKind::Full).remove(2);
+        let null_b = full
+            .iter()
+            .filter(|r| r[1] == Value::Null && r[2] == Value::Null)
+            .count();
+        assert_eq!(null_b, 2);
+        let anti = joins(JoinKind::Anti).remove(1);
+        assert_eq!(anti.len(), 2);
+        assert!(anti.iter().any(|r| r[1] == Value::Null));
+    }
+}
@@ -0,0 +1,167 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Expression Evaluation of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::cmp::Ordering;
+
+use super::{ExecError, Executor, Row, Scope};
+use crate::{
+    optimizer::simplify::Truth,
+    storage::HashKey,
+    structs::{
+        plan::{CompExpr, FilterExpr},
+        Expr, Loc, Symbol, Value,
+    },
+    type_system::{resolve_field, Record},
+};
+
+/// The row being evaluated, named by `schema`. Names it does not have are
+/// looked up in the outer rows.
+pub struct Context<'a> {
+    pub schema: &'a Record,
+    pub row: &'a [Value],
+    pub scope: &'a Scope,
+}
+
+pub fn and(a: Truth, b: Truth) -> Truth {
+    match (a, b) {
+        (Truth::False, _) | (_, Truth::False) => Truth::False,
+        (Truth::True, Truth::True) => Truth::True,
+        _ => Truth::Unknown,
+    }
+}
+
+pub fn or(a: Truth, b: Truth) -> Truth {
+    match (a, b) {
+        (Truth::True, _) | (_, Truth::True) => Truth::True,
+        (Truth::False, Truth::False) => Truth::False,
+        _ => Truth::Unknown,
+    }
+}
+
+pub fn not(a: Truth) -> Truth {
+    match a {
+        Truth::True => Truth::False,
+        Truth::False => Truth::True,
+        Truth::Unknown => Truth::Unknown,
+    }
+}
+
+fn to_truth(v: &Value) -> Truth {
+    match v {
+        Value::Bool(true) => Truth::True,
+        Value::Bool(false) => Truth::False,
+        _ => Truth::Unknown,
+    }
+}
+
+fn to_value(t: Truth) -> Value {
+    match t {
+        Truth::True => Value::Bool(true),
+        Truth::False => Value::Bool(false),
+        Truth::Unknown => Value::Null,
+    }
+}
+
+/// Position of a field in rows named by `schema`.
+pub fn column(schema: &Record, s: &Symbol) -> Option<usize> {
+    resolve_field(&schema.0, s).and_then(|(k, _)| schema.0.get_index_of(k))
+}
+
+impl<'a> Context<'a> {
+    pub fn lookup(&self, s: &Symbol) -> Result<Value, ExecError> {
+        match column(self.schema, s) {
+            Some(i) => Ok(self.row[i].clone()),
+            None => self
+                .scope
+                .lookup(s)
+                .cloned()
+                .ok_or_else(|| ExecError::FieldNotFound(s.clone())),
+        }
+    }
+
+    pub fn value(&self, e: &Expr) -> Result<Value, ExecError> {
+        let sub = |Loc(e, _): &Loc<Expr>| self.value(e);
+        match e {
+            Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Mod(a, b) => {
+                let (x, y) = (sub(a)?, sub(b)?);
+                let op = e.arith_op().unwrap();
+                x.arith(op, &y)
+                    .ok_or_else(|| ExecError::Arithmetic(format!("{} with {} and {}", e, x, y)))
+            }
+            Expr::And(a, b) => Ok(to_value(and(to_truth(&sub(a)?), to_truth(&sub(b)?)))),
+            Expr::Or(a, b) => Ok(to_value(or(to_truth(&sub(a)?), to_truth(&sub(b)?)))),
+            Expr::Not(a) => Ok(to_value(not(to_truth(&sub(a)?)))),
+            Expr::Value(Loc(Value::Symbol(s), _)) => self.lookup(s),
+            Expr::Value(Loc(v, _)) => Ok(v.clone()),
+        }
+    }
+
+    pub fn comp(&self, c: &CompExpr, ex: &Executor) -> Result<Truth, ExecError> {
+        let (a, b, want) = match c {
+            CompExpr::Eq(a, b) => (a, b, Ordering::Equal),
+            CompExpr::Lt(a, b) => (a, b, Ordering::Less),
+            CompExpr::Gt(a, b) => (a, b, Ordering::Greater),
+            CompExpr::In(a, sub) => {
+                let v = self.value(a)?;
+                let set = ex.sub_plan(sub, &self.scope.push(self.schema, self.row))?;
+                // 子查询为空时不管左边是什么都不成立
+                return Ok(if set.empty {
+                    Truth::False
+                } else if v == Value::Null {
+                    Truth::Unknown
+                } else if set.keys.contains(&HashKey::new([&v])) {
+                    Truth::True
+                } else if set.has_null {
+                    Truth::Unknown
+                } else {
+                    Truth::False
+                });
+            }
+        };
+        let (x, y) = (self.value(a)?, self.value(b)?);
+        if x == Value::Null || y == Value::Null {
+            return Ok(Truth::Unknown);
+        }
+        Ok(match x.compare(&y) {
+            Some(o) if o == want => Truth::True,
+            _ => Truth::False,
+        })
+    }
+
+    /// Value of a filter that does not depend on the row order.
+    pub fn filter(&self, f: &FilterExpr, ex: &Executor) -> Result<Truth, ExecError> {
+        match f {
+            FilterExpr::And(v) => v
+                .iter()
+                .try_fold(Truth::True, |t, c| Ok(and(t, self.comp(c, ex)?))),
+            FilterExpr::Or(v) => v
+                .iter()
+                .try_fold(Truth::False, |t, c| Ok(or(t, self.comp(c, ex)?))),
+            FilterExpr::Not(c) => Ok(not(self.comp(c, ex)?)),
+            FilterExpr::Comp(c) => self.comp(c, ex),
+            _ => Ok(Truth::True),
+        }
+    }
+}
+
+pub fn key_of(row: &Row, columns: &[usize]) -> HashKey {
+    HashKey::new(columns.iter().map(|i| &row[*i]))
+}
@@ -0,0 +1,520 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Parallel Execution of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{HashMap, HashSet, VecDeque},
+};
+
+use indexmap::IndexMap;
+use rayon::prelude::*;
+
+use super::{
+    cursor::{self, build_side, partition, HashTable, JoinTable},
+    eval::{column, key_of, Context},
+    ExecError, Executor, Row, Scope,
+};
+use crate::{
+    optimizer::simplify::Truth,
+    physical::{Operator, PhysicalPlan, Semantics, Side},
+    storage::{order, order_by, HashKey},
+    structs::{plan_group::ReduceOperator, ArithOp, SortKey, Symbol, Value},
+    type_system::Record,
+};
+
+/// Operators split their input into parts of this many rows, each part is
+/// a task for the next free thread. The split does not depend on the
+/// number of threads, so neither do the results.
+pub const MORSEL_SIZE: usize = 4096;
+
+/// Evaluate a physical plan one operator at a time on the threads of the
+/// current pool. The rows and their order are those of the row executor,
+/// except that float sums are added up one part at a time.
+pub fn run<'e>(
+    ex: &'e Executor<'e>,
+    plan: &'e PhysicalPlan,
+    scope: &Scope,
+) -> Result<Vec<Row>, ExecError> {
+    share(ex, plan, scope)?;
+    Ok(input(ex, plan, scope)?.into_owned())
+}
+
+// 两边同时算的时候共享的节点可能被算两次, 所以先从下往上把它们算好
+fn share<'e>(ex: &'e Executor<'e>, plan: &'e PhysicalPlan, scope: &Scope) -> Result<(), ExecError> {
+    for c in &plan.children {
+        share(ex, c, scope)?;
+    }
+    if let Operator::Shared(id) = plan.op {
+        ex.shared(id, || Ok(input(ex, &plan.children[0], scope)?.into_owned()))?;
+    }
+    Ok(())
+}
+
+// 表直接借用, 不用先复制一遍
+fn input<'e>(
+    ex: &'e Executor<'e>,
+    plan: &'e PhysicalPlan,
+    scope: &Scope,
+) -> Result<Cow<'e, [Row]>, ExecError> {
+    if let Operator::TableScan(t) = &plan.op {
+        let rel = ex
+            .database()
+            .table(t)
+            .ok_or_else(|| ExecError::TableNotFound(t.to_string()))?;
+        return Ok(Cow::Borrowed(&rel.rows));
+    }
+    if let Operator::Shared(id) = plan.op {
+        let rows = ex.shared(id, || Ok(input(ex, &plan.children[0], scope)?.into_owned()))?;
+        return Ok(Cow::Owned(rows.to_vec()));
+    }
+    if let Operator::Rename(_) = plan.op {
+        return input(ex, &plan.children[0], scope);
+    }
+    // 两边同时算
+    let mut children = match &plan.children[..] {
+        [a, b] => {
+            let (a, b) = rayon::join(|| input(ex, a, scope), || input(ex, b, scope));
+            vec![a?, b?]
+        }
+        cs => cs
+            .iter()
+            .map(|c| input(ex, c, scope))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let schema = |i: usize| &plan.children[i].schema;
+    let rows = match &plan.op {
+        Operator::Filter(f) if !f.is_positional() => {
+            let rows = &children[0];
+            morsels(rows, |part| {
+                let mut out = vec![];
+                for r in part {
+                    let ctx = Context {
+                        schema: schema(0),
+                        row: r,
+                        scope,
+                    };
+                    if ctx.filter(f, ex)? == Truth::True {
+                        out.push(r.clone());
+                    }
+                }
+                Ok(out)
+            })?
+        }
+        Operator::Project(names, sem) => {
+            let columns = cursor::columns(schema(0), names)?;
+            project(&children[0], &columns, *sem == Semantics::Set)
+        }
+        Operator::Distinct => {
+            let columns: Vec<usize> = (0..schema(0).0.len()).collect();
+            project(&children[0], &columns, true)
+        }
+        Operator::NestedLoopJoin(..) | Operator::HashJoin(_) | Operator::MergeJoin(_) => {
+            let right = children.pop().unwrap();
+            let left = children.pop().unwrap();
+            let (probe, build) = match build_side(plan) {
+                Side::Left => (right, left),
+                Side::Right => (left, right),
+            };
+            join(ex, plan, scope, &probe, build.into_owned())?
+        }
+        Operator::HashAggregate(op) => {
+            group_by(&children[0], &[], schema(0), std::slice::from_ref(op))?
+        }
+        Operator::HashGroupBy(keys, aggs) => {
+            let keys = cursor::columns(schema(0), keys)?;
+            let ops: Vec<ReduceOperator> = aggs.iter().map(|a| a.op.clone()).collect();
+            group_by(&children[0], &keys, schema(0), &ops)?
+        }
+        Operator::Sort(keys) => {
+            let keys = cursor::sort_columns(schema(0), keys)?;
+            let mut rows = children.pop().unwrap().into_owned();
+            // 稳定的排序, 键相同的行不换位置
+            rows.par_sort_by(|a, b| order_by(a, b, &keys));
+            rows
+        }
+        Operator::TopN(keys, offset, count, last) => {
+            let n = offset.saturating_add(*count) as usize;
+            let rows = top_n(&children[0], schema(0), keys, n, *last)?;
+            rows.into_iter().skip(*offset as usize).collect()
+        }
+        _ => {
+            // 其它的算子一行一行地算
+            let children = children
+                .into_iter()
+                .map(|c| cursor::rows(c.into_owned()))
+                .collect();
+            cursor::drain(cursor::operator(ex, plan, scope, children)?)?
+        }
+    };
+    Ok(Cow::Owned(rows))
+}
+
+/// Apply `f` to the parts of `rows` at the same time, the results are put
+/// back in the order of the parts. The error of the first part that fails
+/// is returned, as the row executor would.
+fn morsels<T: Send>(
+    rows: &[Row],
+    f: impl Fn(&[Row]) -> Result<Vec<T>, ExecError> + Sync + Send,
+) -> Result<Vec<T>, ExecError> {
+    let parts: Vec<Result<Vec<T>, ExecError>> = rows.par_chunks(MORSEL_SIZE).map(f).collect();
+    let mut out = vec![];
+    for p in parts {
+        out.extend(p?);
+    }
+    Ok(out)
+}
+
+// 去重时留下第一次出现的行
+fn project(rows: &[Row], columns: &[usize], set: bool) -> Vec<Row> {
+    let parts: Vec<Vec<Row>> = rows
+        .par_chunks(MORSEL_SIZE)
+        .map(|part| {
+            part.iter()
+                .map(|r| columns.iter().map(|i| r[*i].clone()).collect())
+                .collect()
+        })
+        .collect();
+    if !set {
+        return parts.into_iter().flatten().collect();
+    }
+    let keys: Vec<Vec<HashKey>> = parts
+        .par_iter()
+        .map(|part| part.iter().map(|r| HashKey::new(r.iter())).collect())
+        .collect();
+    let mut seen = HashSet::new();
+    parts
+        .into_iter()
+        .flatten()
+        .zip(keys.into_iter().flatten())
+        .filter_map(|(r, k)| seen.insert(k).then_some(r))
+        .collect()
+}
+
+/// The keys of each part are hashed at the same time, then every
+/// partition of the table is filled by a thread of its own.
+fn hash_table(rows: &[Row], columns: &[usize]) -> HashTable {
+    let n = rayon::current_num_threads().max(1);
+    // 每一块: (每个分区的 (键, 行号)), 键里有 null 的行号)
+    type Part = (Vec<Vec<(HashKey, usize)>>, Vec<usize>);
+    let parts: Vec<Part> = rows
+        .par_chunks(MORSEL_SIZE)
+        .enumerate()
+        .map(|(m, part)| {
+            let mut buckets = vec![vec![]; n];
+            let mut nulls = vec![];
+            for (i, r) in part.iter().enumerate() {
+                let id = m * MORSEL_SIZE + i;
+                let k = key_of(r, columns);
+                if k.has_null() {
+                    nulls.push(id);
+                } else {
+                    buckets[partition(&k, n)].push((k, id));
+                }
+            }
+            (buckets, nulls)
+        })
+        .collect();
+    let tables = (0..n)
+        .into_par_iter()
+        .map(|p| {
+            let mut t: HashMap<HashKey, Vec<usize>> = HashMap::new();
+            for (buckets, _) in parts.iter() {
+                for (k, id) in buckets[p].iter() {
+                    t.entry(k.clone()).or_default().push(*id);
+                }
+            }
+            t
+        })
+        .collect();
+    let nulls = parts.into_iter().flat_map(|(_, nulls)| nulls).collect();
+    HashTable::new(tables, nulls)
+}
+
+/// Joins of every kind: the build side is hashed in parallel, then the
+/// parts of the probe side are matched at the same time.
+fn join<'e>(
+    ex: &'e Executor<'e>,
+    plan: &'e PhysicalPlan,
+    scope: &Scope,
+    probe: &[Row],
+    build: Vec<Row>,
+) -> Result<Vec<Row>, ExecError> {
+    let table = JoinTable::new(ex, plan, scope, build, hash_table)?;
+    let mut rows = morsels(probe, |part| {
+        let (mut pos, mut out) = (0, VecDeque::new());
+        for p in part {
+            table.process(p.clone(), &mut pos, &mut out)?;
+        }
+        Ok(out.into())
+    })?;
+    let mut out = VecDeque::new();
+    table.finish(&mut out);
+    rows.extend(out);
+    Ok(rows)
+}
+
+/// An aggregate over the rows of one part, merged with the following parts
+/// in their order.
+#[derive(Clone)]
+struct Partial {
+    count: u64,
+    value: Option<Value>,
+    // 平均值的和
+    total: f64,
+}
+
+impl Partial {
+    fn new() -> Self {
+        Partial {
+            count: 0,
+            value: None,
+            total: -0.0,
+        }
+    }
+
+    fn add(&mut self, op: &ReduceOperator, v: &Value) -> Result<(), ExecError> {
+        if let ReduceOperator::Count = op {
+            self.count += 1;
+            return Ok(());
+        }
+        if *v == Value::Null {
+            return Ok(());
+        }
+        let one = Partial {
+            count: 1,
+            value: Some(v.clone()),
+            total: v.as_f64().unwrap_or(0.0),
+        };
+        self.merge(op, one)
+    }
+
+    fn merge(&mut self, op: &ReduceOperator, other: Partial) -> Result<(), ExecError> {
+        self.count += other.count;
+        self.total += other.total;
+        let (a, b) = match (self.value.take(), other.value) {
+            (a, None) => {
+                self.value = a;
+                return Ok(());
+            }
+            (None, b) => {
+                self.value = b;
+                return Ok(());
+            }
+            (Some(a), Some(b)) => (a, b),
+        };
+        self.value = Some(match op {
+            ReduceOperator::Sum(s) => a
+                .arith(ArithOp::Add, &b)
+                .ok_or_else(|| ExecError::Arithmetic(format!("sum[{}] with {} and {}", s, a, b)))?,
+            // 相等时留着前面的
+            ReduceOperator::Max(_) if order(&b, &a) == Ordering::Greater => b,
+            ReduceOperator::Min(_) if order(&b, &a) == Ordering::Less => b,
+            _ => a,
+        });
+        Ok(())
+    }
+
+    fn finish(self, op: &ReduceOperator) -> Value {
+        match op {
+            ReduceOperator::Count => Value::Uint(self.count),
+            _ if self.count == 0 => Value::Null,
+            ReduceOperator::Avg(_) => Value::Float(self.total / self.count as f64),
+            _ => self.value.unwrap_or(Value::Null),
+        }
+    }
+}
+
+type Groups = IndexMap<HashKey, (Row, Vec<Partial>)>;
+
+/// Groups are aggregated in each part at the same time, then merged in the
+/// order the groups are first seen. Without keys there is one group.
+fn group_by(
+    rows: &[Row],
+    keys: &[usize],
+    schema: &Record,
+    ops: &[ReduceOperator],
+) -> Result<Vec<Row>, ExecError> {
+    let find = |s: &Symbol| column(schema, s).ok_or_else(|| ExecError::FieldNotFound(s.clone()));
+    let fields: Vec<Option<usize>> = ops
+        .iter()
+        .map(|op| op.symbol().map(find).transpose())
+        .collect::<Result<_, _>>()?;
+    let fresh = || vec![Partial::new(); ops.len()];
+    let parts: Vec<Result<Groups, ExecError>> = rows
+        .par_chunks(MORSEL_SIZE)
+        .map(|part| {
+            let mut groups: Groups = IndexMap::new();
+            for r in part {
+                let k = key_of(r, keys);
+                let (_, partials) = groups
+                    .entry(k)
+                    .or_insert_with(|| (keys.iter().map(|i| r[*i].clone()).collect(), fresh()));
+                for ((p, op), f) in partials.iter_mut().zip(ops).zip(&fields) {
+                    p.add(op, f.map_or(&Value::Null, |i| &r[i]))?;
+                }
+            }
+            Ok(groups)
+        })
+        .collect();
+    let mut groups: Groups = IndexMap::new();
+    if keys.is_empty() {
+        // 没有分组属性时, 空表也有一组
+        groups.insert(HashKey::new([].iter()), (vec![], fresh()));
+    }
+    for part in parts {
+        for (k, (key, partials)) in part? {
+            let (_, all) = groups.entry(k).or_insert_with(|| (key, fresh()));
+            for ((a, p), op) in all.iter_mut().zip(partials).zip(ops) {
+                a.merge(op, p)?;
+            }
+        }
+    }
+    Ok(groups
+        .into_values()
+        .map(|(mut row, partials)| {
+            row.extend(partials.into_iter().zip(ops).map(|(p, op)| p.finish(op)));
+            row
+        })
+        .collect())
+}
+
+/// The first `n` rows of each part are found at the same time, then the
+/// first `n` of them. Rows with equal keys keep their input order.
+fn top_n(
+    rows: &[Row],
+    schema: &Record,
+    keys: &[SortKey],
+    n: usize,
+    last: bool,
+) -> Result<Vec<Row>, ExecError> {
+    let keys = cursor::sort_columns(schema, keys)?;
+    let cmp = |a: &(usize, &Row), b: &(usize, &Row)| {
+        let seq = if last { b.0.cmp(&a.0) } else { a.0.cmp(&b.0) };
+        order_by(a.1, b.1, &keys).then(seq)
+    };
+    // 最前面的 n 行, 还没有排好
+    fn first(
+        mut v: Vec<(usize, &Row)>,
+        n: usize,
+        cmp: impl Fn(&(usize, &Row), &(usize, &Row)) -> Ordering,
+    ) -> Vec<(usize, &Row)> {
+        if v.len() > n && n > 0 {
+            v.select_nth_unstable_by(n, cmp);
+        }
+        v.truncate(n);
+        v
+    }
+    let parts: Vec<Vec<(usize, &Row)>> = rows
+        .par_chunks(MORSEL_SIZE)
+        .enumerate()
+        .map(|(m, part)| {
+            first(
+                part.iter()
+                    .enumerate()
+                    .map(|(i, r)| (m * MORSEL_SIZE + i, r))
+                    .collect(),
+                n,
+                cmp,
+            )
+        })
+        .collect();
+    let mut rows = first(parts.into_iter().flatten().collect(), n, cmp);
+    rows.sort_by(cmp);
+    Ok(rows.into_iter().map(|(_, r)| r.clone()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        physical::Semantics,
+        storage::{Database, Relation},
+        structs::{
+            plan::{FilterExpr, JoinKind, LocPlan, Plan},
+            plan_group::ReduceOperator,
+            Aggregate,
+        },
+        testing::{self, eq, fixture, gt, int_value, plan, qualified, select, sym, table},
+        type_system::{Env, TableName},
+    };
+
+    // R 有好几份的行, 每个算子都要分成几块
+    fn big() -> (Database, Env) {
+        let (mut db, mut env) = fixture();
+        let mut r = Relation::new("R", vec![sym("a"), sym("b")]);
+        let n = 3 * MORSEL_SIZE as i64 + 5;
+        r.rows = (0..n)
+            .map(|i| {
+                let b = if i % 5 == 0 { None } else { Some(i % 40 * 10) };
+                vec![int_value(Some(i % 7)), int_value(b)]
+            })
+            .collect();
+        env.analyze(&TableName("R".to_string()), &r);
+        db.insert(r);
+        (db, env)
+    }
+
+    #[test]
+    fn parts_are_put_back_in_order() {
+        let (db, env) = big();
+        let on = vec![eq(qualified("R", "b"), qualified("S", "b"))];
+        let count = Aggregate {
+            op: ReduceOperator::Count,
+            alias: None,
+        };
+        let sort = plan(Plan::Sort(
+            Box::new(table("R")),
+            vec![SortKey::desc(sym("b"))],
+        ));
+        let plans: Vec<LocPlan> = vec![
+            select(table("R"), gt(sym("a"), 3)),
+            testing::project(table("R"), &[sym("b")]),
+            plan(Plan::Join(
+                Box::new(table("R")),
+                Box::new(table("S")),
+                JoinKind::Left,
+                on,
+            )),
+            plan(Plan::GroupBy(
+                Box::new(table("R")),
+                vec![sym("a")],
+                vec![count],
+            )),
+            select(sort, FilterExpr::Range(10, 20)),
+        ];
+        // run 比较行执行器和两个线程的结果, 顺序也要一样
+        for p in plans.iter() {
+            for semantics in [Semantics::Set, Semantics::Bag] {
+                assert!(!testing::run(&db, &env, p, semantics).is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_rows() {
+        let (db, env) = big();
+        let p = testing::project(select(table("R"), gt(sym("b"), 100)), &[sym("a")]);
+        let rows = |n| {
+            let exec = Executor::new(&db, &env)
+                .with_semantics(Semantics::Bag)
+                .with_threads(n);
+            exec.execute_parallel(&p).unwrap().rows
+        };
+        assert_eq!(rows(1), rows(4));
+    }
+}
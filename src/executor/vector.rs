@@ -0,0 +1,1053 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Vectorized Execution of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+pub mod kernel;
+
+use std::collections::{HashMap, HashSet};
+
+use indexmap::IndexMap;
+
+use self::kernel::{compare, Accumulator, Context, Truths};
+use super::{
+    cursor::{self, product, BoxCursor, Cursor},
+    eval::column,
+    ExecError, Executor, Row, Scope,
+};
+use crate::{
+    optimizer::simplify::Truth,
+    physical::{EquiJoin, Operator, PhysicalPlan, Semantics, Side},
+    storage::{HashKey, Relation},
+    structs::{
+        plan::{FilterExpr, JoinKind},
+        plan_group::ReduceOperator,
+        Aggregate as GroupAggregate, Symbol, Value,
+    },
+    type_system::{Optional, Record, SimpleType, Type},
+};
+
+/// Operators pass rows to each other this many at a time.
+pub const BATCH_SIZE: usize = 1024;
+
+/// One bit per row.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Bitmap {
+    pub fn new(len: usize, value: bool) -> Self {
+        let fill = if value { !0 } else { 0 };
+        let mut b = Bitmap {
+            words: vec![fill; len.div_ceil(64)],
+            len,
+        };
+        b.trim();
+        b
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        (self.words[i / 64] >> (i % 64)) & 1 == 1
+    }
+
+    pub fn set(&mut self, i: usize, value: bool) {
+        let bit = 1 << (i % 64);
+        if value {
+            self.words[i / 64] |= bit;
+        } else {
+            self.words[i / 64] &= !bit;
+        }
+    }
+
+    pub fn push(&mut self, value: bool) {
+        if self.len.is_multiple_of(64) {
+            self.words.push(0);
+        }
+        self.len += 1;
+        self.set(self.len - 1, value);
+    }
+
+    // 最后一个字里超出长度的位要是 0
+    fn trim(&mut self) {
+        let rest = self.len % 64;
+        if let (true, Some(w)) = (rest != 0, self.words.last_mut()) {
+            *w &= (1 << rest) - 1;
+        }
+    }
+
+    fn zip(&self, other: &Bitmap, f: impl Fn(u64, u64) -> u64) -> Bitmap {
+        debug_assert_eq!(self.len, other.len);
+        let mut b = Bitmap {
+            words: self
+                .words
+                .iter()
+                .zip(other.words.iter())
+                .map(|(x, y)| f(*x, *y))
+                .collect(),
+            len: self.len,
+        };
+        b.trim();
+        b
+    }
+
+    pub fn and(&self, other: &Bitmap) -> Bitmap {
+        self.zip(other, |x, y| x & y)
+    }
+
+    pub fn or(&self, other: &Bitmap) -> Bitmap {
+        self.zip(other, |x, y| x | y)
+    }
+
+    pub fn and_not(&self, other: &Bitmap) -> Bitmap {
+        self.zip(other, |x, y| x & !y)
+    }
+
+    pub fn not(&self) -> Bitmap {
+        self.zip(self, |x, _| !x)
+    }
+
+    pub fn any(&self) -> bool {
+        self.words.iter().any(|w| *w != 0)
+    }
+
+    /// Positions of the set bits, in order. Used as a selection vector.
+    pub fn ones(&self) -> Vec<usize> {
+        let mut r = vec![];
+        for (i, w) in self.words.iter().enumerate() {
+            let mut w = *w;
+            while w != 0 {
+                r.push(i * 64 + w.trailing_zeros() as usize);
+                w &= w - 1;
+            }
+        }
+        r
+    }
+}
+
+/// Values of a column, one vector for each simple type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Data {
+    Bool(Vec<bool>),
+    Int(Vec<i64>),
+    Uint(Vec<u64>),
+    Float(Vec<f64>),
+    String(Vec<String>),
+    /// Symbols, and columns with values of more than one kind.
+    Any(Vec<Value>),
+}
+
+impl Data {
+    fn for_type(t: &Type) -> Self {
+        let t = match t {
+            Type::Optional(Optional(t)) => t,
+            t => t,
+        };
+        match t {
+            Type::Simple(SimpleType::Bool) => Data::Bool(vec![]),
+            Type::Simple(SimpleType::Int(_)) => Data::Int(vec![]),
+            Type::Simple(SimpleType::Uint(_)) => Data::Uint(vec![]),
+            Type::Simple(SimpleType::Float(_)) => Data::Float(vec![]),
+            Type::Simple(SimpleType::String(_)) => Data::String(vec![]),
+            _ => Data::Any(vec![]),
+        }
+    }
+
+    fn for_value(v: &Value) -> Self {
+        match v {
+            Value::Bool(_) => Data::Bool(vec![]),
+            Value::Int(_) => Data::Int(vec![]),
+            Value::Uint(_) => Data::Uint(vec![]),
+            Value::Float(_) => Data::Float(vec![]),
+            Value::String(_) => Data::String(vec![]),
+            Value::Null | Value::Symbol(_) => Data::Any(vec![]),
+        }
+    }
+
+    fn fits(&self, v: &Value) -> bool {
+        matches!(
+            (self, v),
+            (_, Value::Null)
+                | (Data::Any(_), _)
+                | (Data::Bool(_), Value::Bool(_))
+                | (Data::Int(_), Value::Int(_))
+                | (Data::Uint(_), Value::Uint(_))
+                | (Data::Float(_), Value::Float(_))
+                | (Data::String(_), Value::String(_))
+        )
+    }
+}
+
+/// A column of a batch. The data of a null row is a placeholder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector {
+    pub data: Data,
+    pub nulls: Bitmap,
+}
+
+macro_rules! each_data {
+    ($data:expr, $v:ident => $e:expr) => {
+        match $data {
+            Data::Bool($v) => Data::Bool($e),
+            Data::Int($v) => Data::Int($e),
+            Data::Uint($v) => Data::Uint($e),
+            Data::Float($v) => Data::Float($e),
+            Data::String($v) => Data::String($e),
+            Data::Any($v) => Data::Any($e),
+        }
+    };
+}
+
+impl Vector {
+    /// An empty vector for values of type `t`.
+    pub fn new(t: &Type) -> Self {
+        Vector {
+            data: Data::for_type(t),
+            nulls: Bitmap::default(),
+        }
+    }
+
+    /// A vector typed by the first value that is not null.
+    pub fn infer(values: Vec<Value>) -> Self {
+        let first = values.iter().find(|v| **v != Value::Null);
+        let mut r = Vector {
+            data: first.map_or(Data::Any(vec![]), Data::for_value),
+            nulls: Bitmap::default(),
+        };
+        for v in values {
+            r.push(v);
+        }
+        r
+    }
+
+    pub fn repeat(v: &Value, len: usize) -> Self {
+        Vector::infer(vec![v.clone(); len])
+    }
+
+    pub fn nulls(t: &Type, len: usize) -> Self {
+        let mut r = Vector::new(t);
+        for _ in 0..len {
+            r.push(Value::Null);
+        }
+        r
+    }
+
+    pub fn len(&self) -> usize {
+        self.nulls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nulls.is_empty()
+    }
+
+    pub fn is_null(&self, i: usize) -> bool {
+        self.nulls.get(i)
+    }
+
+    pub fn get(&self, i: usize) -> Value {
+        if self.is_null(i) {
+            return Value::Null;
+        }
+        match &self.data {
+            Data::Bool(d) => Value::Bool(d[i]),
+            Data::Int(d) => Value::Int(d[i]),
+            Data::Uint(d) => Value::Uint(d[i]),
+            Data::Float(d) => Value::Float(d[i]),
+            Data::String(d) => Value::String(d[i].clone()),
+            Data::Any(d) => d[i].clone(),
+        }
+    }
+
+    pub fn values(&self) -> Vec<Value> {
+        (0..self.len()).map(|i| self.get(i)).collect()
+    }
+
+    /// Values that do not fit the type of the vector turn it into `Any`.
+    pub fn push(&mut self, v: Value) {
+        if !self.data.fits(&v) {
+            self.data = Data::Any(self.values());
+        }
+        self.nulls.push(v == Value::Null);
+        match (&mut self.data, v) {
+            (Data::Any(d), v) => d.push(v),
+            (Data::Bool(d), Value::Bool(x)) => d.push(x),
+            (Data::Int(d), Value::Int(x)) => d.push(x),
+            (Data::Uint(d), Value::Uint(x)) => d.push(x),
+            (Data::Float(d), Value::Float(x)) => d.push(x),
+            (Data::String(d), Value::String(x)) => d.push(x),
+            (Data::Bool(d), _) => d.push(false),
+            (Data::Int(d), _) => d.push(0),
+            (Data::Uint(d), _) => d.push(0),
+            (Data::Float(d), _) => d.push(0.0),
+            (Data::String(d), _) => d.push(String::new()),
+        }
+    }
+
+    pub fn append(&mut self, other: &Vector) {
+        match (&mut self.data, &other.data) {
+            (Data::Bool(a), Data::Bool(b)) => a.extend_from_slice(b),
+            (Data::Int(a), Data::Int(b)) => a.extend_from_slice(b),
+            (Data::Uint(a), Data::Uint(b)) => a.extend_from_slice(b),
+            (Data::Float(a), Data::Float(b)) => a.extend_from_slice(b),
+            (Data::String(a), Data::String(b)) => a.extend_from_slice(b),
+            (Data::Any(a), Data::Any(b)) => a.extend_from_slice(b),
+            _ => {
+                for v in other.values() {
+                    self.push(v);
+                }
+                return;
+            }
+        }
+        for i in 0..other.len() {
+            self.nulls.push(other.is_null(i));
+        }
+    }
+
+    /// The rows at the positions of `sel`.
+    pub fn gather(&self, sel: &[usize]) -> Vector {
+        let mut nulls = Bitmap::new(sel.len(), false);
+        for (i, j) in sel.iter().enumerate() {
+            nulls.set(i, self.is_null(*j));
+        }
+        Vector {
+            data: each_data!(&self.data, d => sel.iter().map(|j| &d[*j]).cloned().collect()),
+            nulls,
+        }
+    }
+
+    /// Like `gather`, `None` gives a null row.
+    pub fn gather_opt(&self, sel: &[Option<usize>]) -> Vector {
+        let mut r = Vector {
+            data: each_data!(&self.data, _d => vec![]),
+            nulls: Bitmap::default(),
+        };
+        for j in sel {
+            r.push(j.map_or(Value::Null, |j| self.get(j)));
+        }
+        r
+    }
+}
+
+/// Rows stored as one vector per field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub columns: Vec<Vector>,
+    pub len: usize,
+}
+
+impl Batch {
+    pub fn new(schema: &Record) -> Self {
+        Batch {
+            columns: schema.0.values().map(Vector::new).collect(),
+            len: 0,
+        }
+    }
+
+    pub fn from_rows(schema: &Record, rows: impl IntoIterator<Item = Row>) -> Self {
+        let mut b = Batch::new(schema);
+        for row in rows {
+            for (c, v) in b.columns.iter_mut().zip(row) {
+                c.push(v);
+            }
+            b.len += 1;
+        }
+        b
+    }
+
+    pub fn row(&self, i: usize) -> Row {
+        self.columns.iter().map(|c| c.get(i)).collect()
+    }
+
+    pub fn rows(&self) -> Vec<Row> {
+        (0..self.len).map(|i| self.row(i)).collect()
+    }
+
+    pub fn key(&self, i: usize, columns: &[usize]) -> HashKey {
+        let values: Vec<Value> = columns.iter().map(|c| self.columns[*c].get(i)).collect();
+        HashKey::new(values.iter())
+    }
+
+    pub fn gather(&self, sel: &[usize]) -> Batch {
+        Batch {
+            columns: self.columns.iter().map(|c| c.gather(sel)).collect(),
+            len: sel.len(),
+        }
+    }
+
+    pub fn gather_opt(&self, sel: &[Option<usize>]) -> Batch {
+        Batch {
+            columns: self.columns.iter().map(|c| c.gather_opt(sel)).collect(),
+            len: sel.len(),
+        }
+    }
+
+    pub fn append(&mut self, other: &Batch) {
+        for (a, b) in self.columns.iter_mut().zip(other.columns.iter()) {
+            a.append(b);
+        }
+        self.len += other.len;
+    }
+
+    // 左右两边的列拼在一起
+    fn concat(self, other: Batch) -> Batch {
+        debug_assert_eq!(self.len, other.len);
+        let mut columns = self.columns;
+        columns.extend(other.columns);
+        Batch {
+            columns,
+            len: self.len,
+        }
+    }
+}
+
+/// A running vectorized operator, it gives its rows one batch at a time.
+/// Batches are never empty.
+pub trait BatchCursor {
+    fn next(&mut self) -> Result<Option<Batch>, ExecError>;
+}
+
+pub type BoxBatchCursor<'e> = Box<dyn BatchCursor + 'e>;
+
+/// Start evaluating a physical plan on batches. Operators without a
+/// vectorized form run the row cursors over the batches of their inputs.
+pub fn open<'e>(
+    ex: &'e Executor<'e>,
+    plan: &'e PhysicalPlan,
+    scope: &Scope,
+) -> Result<BoxBatchCursor<'e>, ExecError> {
+    let child = |i: usize| open(ex, &plan.children[i], scope);
+    let schema = |i: usize| &plan.children[i].schema;
+    Ok(match &plan.op {
+        Operator::TableScan(t) => Box::new(Scan {
+            rel: ex
+                .database()
+                .table(t)
+                .ok_or_else(|| ExecError::TableNotFound(t.clone()))?,
+            schema: &plan.schema,
+            next: 0,
+        }),
+        Operator::Rename(_) => child(0)?,
+        Operator::Filter(f) if !f.is_positional() => Box::new(Filter {
+            ex,
+            input: child(0)?,
+            f,
+            schema: schema(0),
+            scope: scope.clone(),
+        }),
+        Operator::Project(names, sem) => Box::new(Project {
+            input: child(0)?,
+            columns: names
+                .iter()
+                .map(|s| column(schema(0), s).ok_or_else(|| ExecError::FieldNotFound(s.clone())))
+                .collect::<Result<_, _>>()?,
+            seen: (*sem == Semantics::Set).then(HashSet::new),
+        }),
+        Operator::Distinct => Box::new(Project {
+            input: child(0)?,
+            columns: (0..schema(0).0.len()).collect(),
+            seen: Some(HashSet::new()),
+        }),
+        Operator::HashJoin(j) => Box::new(HashJoin::new(ex, plan, scope, j, child(0)?, child(1)?)?),
+        Operator::HashAggregate(op) => Box::new(Aggregate {
+            input: Some(child(0)?),
+            input_schema: schema(0),
+            schema: &plan.schema,
+            op,
+        }),
+        Operator::HashGroupBy(keys, aggs) => {
+            let find = |s: &Symbol| {
+                column(schema(0), s).ok_or_else(|| ExecError::FieldNotFound(s.clone()))
+            };
+            Box::new(GroupBy {
+                input: Some(child(0)?),
+                keys: keys.iter().map(find).collect::<Result<_, _>>()?,
+                columns: aggs
+                    .iter()
+                    .map(|a| a.op.symbol().map(find).transpose())
+                    .collect::<Result<_, _>>()?,
+                aggs,
+                schema: &plan.schema,
+            })
+        }
+        Operator::Shared(id) => {
+            // 第一次把输入的批读完存起来
+            let rows = ex.shared(*id, || {
+                let mut input = child(0)?;
+                let mut rows = vec![];
+                while let Some(b) = input.next()? {
+                    rows.extend(b.rows());
+                }
+                Ok(rows)
+            })?;
+            Box::new(Batches {
+                input: cursor::shared(rows),
+                schema: &plan.schema,
+            })
+        }
+        _ => {
+            // 其它的算子一行一行地算
+            let children = plan
+                .children
+                .iter()
+                .map(|c| Ok(Box::new(Unbatch::new(open(ex, c, scope)?)) as BoxCursor<'e>))
+                .collect::<Result<_, ExecError>>()?;
+            Box::new(Batches {
+                input: cursor::operator(ex, plan, scope, children)?,
+                schema: &plan.schema,
+            })
+        }
+    })
+}
+
+/// Rows of batches, for the row cursors.
+struct Unbatch<'e> {
+    input: BoxBatchCursor<'e>,
+    batch: Option<Batch>,
+    next: usize,
+}
+
+impl<'e> Unbatch<'e> {
+    fn new(input: BoxBatchCursor<'e>) -> Self {
+        Unbatch {
+            input,
+            batch: None,
+            next: 0,
+        }
+    }
+}
+
+impl<'e> Cursor for Unbatch<'e> {
+    fn next(&mut self) -> Result<Option<Row>, ExecError> {
+        loop {
+            if let Some(b) = &self.batch {
+                if self.next < b.len {
+                    self.next += 1;
+                    return Ok(Some(b.row(self.next - 1)));
+                }
+            }
+            match self.input.next()? {
+                Some(b) => {
+                    self.batch = Some(b);
+                    self.next = 0;
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Batches of the rows of a row cursor.
+struct Batches<'e> {
+    input: BoxCursor<'e>,
+    schema: &'e Record,
+}
+
+impl<'e> BatchCursor for Batches<'e> {
+    fn next(&mut self) -> Result<Option<Batch>, ExecError> {
+        let mut rows = vec![];
+        while rows.len() < BATCH_SIZE {
+            match self.input.next()? {
+                Some(r) => rows.push(r),
+                None => break,
+            }
+        }
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Batch::from_rows(self.schema, rows)))
+    }
+}
+
+struct Scan<'e> {
+    rel: &'e Relation,
+    schema: &'e Record,
+    next: usize,
+}
+
+impl<'e> BatchCursor for Scan<'e> {
+    fn next(&mut self) -> Result<Option<Batch>, ExecError> {
+        let rows = &self.rel.rows;
+        if self.next >= rows.len() {
+            return Ok(None);
+        }
+        let end = (self.next + BATCH_SIZE).min(rows.len());
+        let b = Batch::from_rows(self.schema, rows[self.next..end].iter().cloned());
+        self.next = end;
+        Ok(Some(b))
+    }
+}
+
+struct Filter<'e> {
+    ex: &'e Executor<'e>,
+    input: BoxBatchCursor<'e>,
+    f: &'e FilterExpr,
+    schema: &'e Record,
+    scope: Scope,
+}
+
+impl<'e> BatchCursor for Filter<'e> {
+    fn next(&mut self) -> Result<Option<Batch>, ExecError> {
+        while let Some(b) = self.input.next()? {
+            let ctx = Context {
+                schema: self.schema,
+                batch: &b,
+                scope: &self.scope,
+            };
+            let sel = ctx.filter(self.f, self.ex)?.selection();
+            if sel.len() == b.len {
+                return Ok(Some(b));
+            }
+            if !sel.is_empty() {
+                return Ok(Some(b.gather(&sel)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Keeps the rows not seen before, or every row when there is no `seen`.
+struct Project<'e> {
+    input: BoxBatchCursor<'e>,
+    columns: Vec<usize>,
+    seen: Option<HashSet<HashKey>>,
+}
+
+impl<'e> BatchCursor for Project<'e> {
+    fn next(&mut self) -> Result<Option<Batch>, ExecError> {
+        while let Some(b) = self.input.next()? {
+            let all: Vec<usize> = (0..self.columns.len()).collect();
+            let b = Batch {
+                columns: self.columns.iter().map(|i| b.columns[*i].clone()).collect(),
+                len: b.len,
+            };
+            let seen = match &mut self.seen {
+                Some(seen) => seen,
+                None => return Ok(Some(b)),
+            };
+            let sel: Vec<usize> = (0..b.len)
+                .filter(|i| seen.insert(b.key(*i, &all)))
+                .collect();
+            if !sel.is_empty() {
+                return Ok(Some(b.gather(&sel)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Hash join of every kind. The build side is read into one batch and
+/// hashed on its keys, each batch of the other side is probed at once and
+/// the conditions are checked on all the candidate pairs together.
+struct HashJoin<'e> {
+    ex: &'e Executor<'e>,
+    scope: Scope,
+    input: BoxBatchCursor<'e>,
+    input_schema: &'e Record,
+    rows: Batch,
+    table: HashMap<HashKey, Vec<usize>>,
+    // 键里有 null 的行
+    nulls: Vec<usize>,
+    matched: Vec<bool>,
+    join: &'e EquiJoin,
+    keys: Vec<(usize, usize)>,
+    left_width: usize,
+    product: Record,
+    finished: bool,
+}
+
+impl<'e> HashJoin<'e> {
+    fn new(
+        ex: &'e Executor<'e>,
+        plan: &'e PhysicalPlan,
+        scope: &Scope,
+        join: &'e EquiJoin,
+        left: BoxBatchCursor<'e>,
+        right: BoxBatchCursor<'e>,
+    ) -> Result<Self, ExecError> {
+        let (ls, rs) = (&plan.children[0].schema, &plan.children[1].schema);
+        let keys: Vec<(usize, usize)> = join
+            .keys
+            .iter()
+            .map(|(l, r)| {
+                let l = column(ls, l).ok_or_else(|| ExecError::FieldNotFound(l.clone()))?;
+                let r = column(rs, r).ok_or_else(|| ExecError::FieldNotFound(r.clone()))?;
+                Ok((l, r))
+            })
+            .collect::<Result<_, ExecError>>()?;
+        let (input, mut build, input_schema, build_schema) = match join.build {
+            Side::Left => (right, left, rs, ls),
+            Side::Right => (left, right, ls, rs),
+        };
+        let mut rows = Batch::new(build_schema);
+        while let Some(b) = build.next()? {
+            rows.append(&b);
+        }
+        let columns = key_columns(&keys, join.build);
+        let mut table: HashMap<HashKey, Vec<usize>> = HashMap::new();
+        let mut nulls = vec![];
+        for i in 0..rows.len {
+            let k = rows.key(i, &columns);
+            // null 不等于任何值, 不进哈希表
+            if k.has_null() {
+                nulls.push(i);
+            } else {
+                table.entry(k).or_default().push(i);
+            }
+        }
+        Ok(HashJoin {
+            ex,
+            scope: scope.clone(),
+            input,
+            input_schema,
+            matched: vec![false; rows.len],
+            rows,
+            table,
+            nulls,
+            join,
+            keys,
+            left_width: ls.0.len(),
+            product: product(ls, rs)?,
+            finished: false,
+        })
+    }
+
+    fn probe(&mut self, b: &Batch) -> Result<Option<Batch>, ExecError> {
+        let (kind, build) = (self.join.kind, self.join.build);
+        let not_in = kind == JoinKind::NullAwareAnti;
+        let columns = key_columns(&self.keys, build.other());
+        // 所有候选的行对, 输入一边的行号是递增的
+        let (mut ps, mut bs) = (vec![], vec![]);
+        for i in 0..b.len {
+            let k = b.key(i, &columns);
+            // not in 和 null 比较是未知, 也要看其余的条件挑没挑到这一行
+            if k.has_null() {
+                if not_in {
+                    ps.extend(std::iter::repeat_n(i, self.rows.len));
+                    bs.extend(0..self.rows.len);
+                }
+                continue;
+            }
+            let nulls: &[usize] = if not_in { &self.nulls } else { &[] };
+            for j in self.table.get(&k).into_iter().flatten().chain(nulls) {
+                ps.push(i);
+                bs.push(*j);
+            }
+        }
+        let (probed, built) = (b.gather(&ps), self.rows.gather(&bs));
+        let pairs = match build {
+            Side::Left => built.concat(probed),
+            Side::Right => probed.concat(built),
+        };
+        let mut first = Truths::all(pairs.len, Truth::True);
+        for (i, j) in self.keys.iter() {
+            let eq = compare(
+                std::cmp::Ordering::Equal,
+                &pairs.columns[*i],
+                &pairs.columns[self.left_width + *j],
+            );
+            first = first.and(&eq);
+        }
+        let ctx = Context {
+            schema: &self.product,
+            batch: &pairs,
+            scope: &self.scope,
+        };
+        let mut rest = Truths::all(pairs.len, Truth::True);
+        for f in self.join.residual.iter() {
+            rest = rest.and(&ctx.filter(f, self.ex)?);
+        }
+        if not_in {
+            // 挑到的行里有相等或者未知的, 左边的行就不要了
+            let mut dropped = Bitmap::new(b.len, false);
+            for p in rest.truth.and_not(&first.falsity()).ones() {
+                dropped.set(ps[p], true);
+            }
+            return Ok(Some(b.gather(&dropped.not().ones())));
+        }
+        let hits = first.and(&rest).selection();
+        if kind == JoinKind::Inner {
+            return Ok(Some(pairs.gather(&hits)));
+        }
+        if kind.is_semi() {
+            if build == Side::Left {
+                // 建在左边时最后才知道哪些行有匹配
+                for p in hits {
+                    self.matched[bs[p]] = true;
+                }
+                return Ok(None);
+            }
+            let mut found = Bitmap::new(b.len, false);
+            for p in hits.iter() {
+                found.set(ps[*p], true);
+            }
+            let keep = if kind == JoinKind::Semi {
+                found
+            } else {
+                found.not()
+            };
+            return Ok(Some(b.gather(&keep.ones())));
+        }
+        // 外连接按输入的行依次输出, 没有匹配的补 null
+        let (mut psel, mut bsel) = (vec![], vec![]);
+        let mut hits = hits.into_iter().peekable();
+        for i in 0..b.len {
+            let mut any = false;
+            while let Some(p) = hits.next_if(|p| ps[*p] == i) {
+                any = true;
+                self.matched[bs[p]] = true;
+                psel.push(Some(i));
+                bsel.push(Some(bs[p]));
+            }
+            if !any && build.other().outer(kind) {
+                psel.push(Some(i));
+                bsel.push(None);
+            }
+        }
+        let (probed, built) = (b.gather_opt(&psel), self.rows.gather_opt(&bsel));
+        Ok(Some(match build {
+            Side::Left => built.concat(probed),
+            Side::Right => probed.concat(built),
+        }))
+    }
+
+    // 建表的一边没有匹配过的行补 null, 建在左边的半连接在这里输出
+    fn finish(&self) -> Option<Batch> {
+        let (kind, build) = (self.join.kind, self.join.build);
+        let semi = kind.is_semi();
+        if (semi && build == Side::Right) || (!semi && !build.outer(kind)) {
+            return None;
+        }
+        let sel: Vec<usize> = (0..self.rows.len)
+            .filter(|j| self.matched[*j] == (kind == JoinKind::Semi))
+            .collect();
+        let rows = self.rows.gather(&sel);
+        if semi {
+            return Some(rows);
+        }
+        let nulls = Batch {
+            columns: self
+                .input_schema
+                .0
+                .values()
+                .map(|t| Vector::nulls(t, sel.len()))
+                .collect(),
+            len: sel.len(),
+        };
+        Some(match build {
+            Side::Left => rows.concat(nulls),
+            Side::Right => nulls.concat(rows),
+        })
+    }
+}
+
+fn key_columns(keys: &[(usize, usize)], side: Side) -> Vec<usize> {
+    keys.iter()
+        .map(|(l, r)| match side {
+            Side::Left => *l,
+            Side::Right => *r,
+        })
+        .collect()
+}
+
+impl<'e> BatchCursor for HashJoin<'e> {
+    fn next(&mut self) -> Result<Option<Batch>, ExecError> {
+        while !self.finished {
+            let out = match self.input.next()? {
+                Some(b) => self.probe(&b)?,
+                None => {
+                    self.finished = true;
+                    self.finish()
+                }
+            };
+            if let Some(out) = out.filter(|b| b.len > 0) {
+                return Ok(Some(out));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Aggregates fold the input one batch at a time.
+struct Aggregate<'e> {
+    input: Option<BoxBatchCursor<'e>>,
+    input_schema: &'e Record,
+    schema: &'e Record,
+    op: &'e ReduceOperator,
+}
+
+impl<'e> BatchCursor for Aggregate<'e> {
+    fn next(&mut self) -> Result<Option<Batch>, ExecError> {
+        let mut input = match self.input.take() {
+            Some(input) => input,
+            None => return Ok(None),
+        };
+        let i = match self.op.symbol() {
+            Some(s) => Some(
+                column(self.input_schema, s).ok_or_else(|| ExecError::FieldNotFound(s.clone()))?,
+            ),
+            None => None,
+        };
+        let mut acc = Accumulator::new(self.op);
+        while let Some(b) = input.next()? {
+            match i {
+                Some(i) => acc.update(&b.columns[i])?,
+                None => acc.count += b.len as u64,
+            }
+        }
+        Ok(Some(Batch::from_rows(
+            self.schema,
+            vec![vec![acc.finish()]],
+        )))
+    }
+}
+
+type Groups<'e> = IndexMap<HashKey, (Row, Vec<Accumulator<'e>>)>;
+
+/// Groups fold the input one batch at a time, every aggregate of a group
+/// has its own accumulator.
+struct GroupBy<'e> {
+    input: Option<BoxBatchCursor<'e>>,
+    keys: Vec<usize>,
+    // 聚合的列, count 没有
+    columns: Vec<Option<usize>>,
+    aggs: &'e [GroupAggregate],
+    schema: &'e Record,
+}
+
+impl<'e> BatchCursor for GroupBy<'e> {
+    fn next(&mut self) -> Result<Option<Batch>, ExecError> {
+        let mut input = match self.input.take() {
+            Some(input) => input,
+            None => return Ok(None),
+        };
+        let aggs = self.aggs;
+        let fresh = || aggs.iter().map(|a| Accumulator::new(&a.op)).collect();
+        let mut groups: Groups<'e> = IndexMap::new();
+        if self.keys.is_empty() {
+            // 没有分组属性时, 空表也有一组
+            groups.insert(HashKey::new([].iter()), (vec![], fresh()));
+        }
+        while let Some(b) = input.next()? {
+            // 这一批里每一组的行
+            let mut sel: IndexMap<usize, Vec<usize>> = IndexMap::new();
+            for i in 0..b.len {
+                let k = b.key(i, &self.keys);
+                let g = match groups.get_index_of(&k) {
+                    Some(g) => g,
+                    None => {
+                        let key = self.keys.iter().map(|c| b.columns[*c].get(i)).collect();
+                        groups.insert_full(k, (key, fresh())).0
+                    }
+                };
+                sel.entry(g).or_default().push(i);
+            }
+            for (g, rows) in sel {
+                let (_, (_, accs)) = groups.get_index_mut(g).unwrap();
+                for (acc, c) in accs.iter_mut().zip(&self.columns) {
+                    match c {
+                        Some(c) => acc.update(&b.columns[*c].gather(&rows))?,
+                        None => acc.count += rows.len() as u64,
+                    }
+                }
+            }
+        }
+        if groups.is_empty() {
+            return Ok(None);
+        }
+        let rows = groups
+            .into_values()
+            .map(|(mut row, accs)| {
+                row.extend(accs.into_iter().map(|a| a.finish()));
+                row
+            })
+            .collect::<Vec<_>>();
+        Ok(Some(Batch::from_rows(self.schema, rows)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        physical::Semantics,
+        structs::plan::{JoinKind, Plan},
+        testing::*,
+        type_system::TableName,
+    };
+
+    #[test]
+    fn bitmaps_combine_bit_by_bit() {
+        let mut a = Bitmap::new(70, false);
+        a.set(1, true);
+        a.set(65, true);
+        let mut b = Bitmap::new(70, false);
+        b.set(65, true);
+        assert_eq!(a.and(&b).ones(), vec![65]);
+        assert_eq!(a.and_not(&b).ones(), vec![1]);
+        assert_eq!(a.not().ones().len(), 68);
+        assert!(!Bitmap::new(70, false).any());
+    }
+
+    #[test]
+    fn batches_keep_rows_and_nulls() {
+        let (_, env) = fixture();
+        let rows: Vec<Row> = relations().remove(0).rows;
+        let schema = env
+            .get_table(&TableName("R".to_string()))
+            .unwrap()
+            .0
+            .clone();
+        let batch = Batch::from_rows(&schema, rows.clone());
+        assert_eq!(batch.rows(), rows);
+        assert_eq!(
+            batch.gather(&[4, 0]).rows(),
+            vec![rows[4].clone(), rows[0].clone()]
+        );
+    }
+
+    #[test]
+    fn vectorized_operators_give_the_rows_of_the_row_executor() {
+        let (db, env) = fixture();
+        let join = |kind| {
+            plan(Plan::Join(
+                Box::new(table("R")),
+                Box::new(table("S")),
+                kind,
+                vec![eq(qualified("R", "b"), qualified("S", "b"))],
+            ))
+        };
+        // run 会比较两个执行器的结果
+        for p in [
+            select(table("R"), gt(sym("a"), 1)),
+            project(table("R"), &[sym("b")]),
+            join(JoinKind::Inner),
+            join(JoinKind::Left),
+            join(JoinKind::Anti),
+        ] {
+            for semantics in [Semantics::Set, Semantics::Bag] {
+                run(&db, &env, &p, semantics);
+            }
+        }
+    }
+}
@@ -0,0 +1,442 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Vector Kernels of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::cmp::Ordering;
+
+use super::{Batch, Bitmap, Data, Vector};
+use crate::{
+    executor::{eval, ExecError, Executor, Scope},
+    optimizer::simplify::Truth,
+    storage::order,
+    structs::{
+        plan::{CompExpr, FilterExpr},
+        plan_group::ReduceOperator,
+        ArithOp, Expr, Loc, Symbol, Value,
+    },
+    type_system::Record,
+};
+
+/// Three valued results of a batch. A row is unknown when its bit in
+/// `unknown` is set, true when its bit in `truth` is set and false when
+/// neither is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Truths {
+    pub truth: Bitmap,
+    pub unknown: Bitmap,
+}
+
+impl Truths {
+    pub fn all(len: usize, t: Truth) -> Self {
+        Truths {
+            truth: Bitmap::new(len, t == Truth::True),
+            unknown: Bitmap::new(len, t == Truth::Unknown),
+        }
+    }
+
+    /// Rows that are false.
+    pub fn falsity(&self) -> Bitmap {
+        self.truth.or(&self.unknown).not()
+    }
+
+    fn with(truth: Bitmap, falsity: Bitmap) -> Self {
+        Truths {
+            unknown: truth.or(&falsity).not(),
+            truth,
+        }
+    }
+
+    pub fn and(&self, other: &Truths) -> Self {
+        Truths::with(
+            self.truth.and(&other.truth),
+            self.falsity().or(&other.falsity()),
+        )
+    }
+
+    pub fn or(&self, other: &Truths) -> Self {
+        Truths::with(
+            self.truth.or(&other.truth),
+            self.falsity().and(&other.falsity()),
+        )
+    }
+
+    pub fn not(&self) -> Self {
+        Truths {
+            truth: self.falsity(),
+            unknown: self.unknown.clone(),
+        }
+    }
+
+    pub fn set(&mut self, i: usize, t: Truth) {
+        self.truth.set(i, t == Truth::True);
+        self.unknown.set(i, t == Truth::Unknown);
+    }
+
+    /// `true` and `false` values, anything else is unknown.
+    pub fn from_vector(v: &Vector) -> Self {
+        let mut r = Truths::all(v.len(), Truth::Unknown);
+        match &v.data {
+            Data::Bool(d) => {
+                let mut truth = Bitmap::new(v.len(), false);
+                for (i, b) in d.iter().enumerate() {
+                    truth.set(i, *b);
+                }
+                let falsity = truth.or(&v.nulls).not();
+                r = Truths::with(truth.and_not(&v.nulls), falsity);
+            }
+            Data::Any(d) => {
+                for (i, x) in d.iter().enumerate() {
+                    match x {
+                        Value::Bool(true) => r.set(i, Truth::True),
+                        Value::Bool(false) => r.set(i, Truth::False),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        r
+    }
+
+    pub fn into_vector(self) -> Vector {
+        let len = self.truth.len();
+        Vector {
+            data: Data::Bool((0..len).map(|i| self.truth.get(i)).collect()),
+            nulls: self.unknown,
+        }
+    }
+
+    /// Rows that are true.
+    pub fn selection(&self) -> Vec<usize> {
+        self.truth.ones()
+    }
+}
+
+macro_rules! checked {
+    ($op:expr, $x:expr, $y:expr) => {
+        match $op {
+            ArithOp::Add => $x.checked_add($y),
+            ArithOp::Sub => $x.checked_sub($y),
+            ArithOp::Mul => $x.checked_mul($y),
+            ArithOp::Div => $x.checked_div($y),
+            ArithOp::Mod => $x.checked_rem($y),
+        }
+    };
+}
+
+fn float(op: ArithOp, x: f64, y: f64) -> Option<f64> {
+    match op {
+        ArithOp::Add => Some(x + y),
+        ArithOp::Sub => Some(x - y),
+        ArithOp::Mul => Some(x * y),
+        ArithOp::Div if y != 0.0 => Some(x / y),
+        ArithOp::Mod if y != 0.0 => Some(x % y),
+        _ => None,
+    }
+}
+
+/// Arithmetic on two vectors, row by row as `Value::arith` does it. Fails
+/// with the first row that overflows or divides by zero.
+pub fn arith(op: ArithOp, a: &Vector, b: &Vector) -> Result<Vector, usize> {
+    let nulls = a.nulls.or(&b.nulls);
+    macro_rules! typed {
+        ($x:expr, $y:expr, $variant:ident, $zero:expr, $f:expr) => {{
+            let mut out = Vec::with_capacity($x.len());
+            for (i, (x, y)) in $x.iter().zip($y.iter()).enumerate() {
+                if nulls.get(i) {
+                    out.push($zero);
+                } else {
+                    out.push($f(*x, *y).ok_or(i)?);
+                }
+            }
+            Ok(Vector {
+                data: Data::$variant(out),
+                nulls,
+            })
+        }};
+    }
+    match (&a.data, &b.data) {
+        (Data::Int(x), Data::Int(y)) => typed!(x, y, Int, 0, |x: i64, y| checked!(op, x, y)),
+        (Data::Uint(x), Data::Uint(y)) => typed!(x, y, Uint, 0, |x: u64, y| checked!(op, x, y)),
+        (Data::Float(x), Data::Float(y)) => typed!(x, y, Float, 0.0, |x, y| float(op, x, y)),
+        _ => {
+            let mut out = Vec::with_capacity(a.len());
+            for i in 0..a.len() {
+                out.push(a.get(i).arith(op, &b.get(i)).ok_or(i)?);
+            }
+            Ok(Vector::infer(out))
+        }
+    }
+}
+
+/// `a want b` for each row, as `Value::compare` orders values. Rows with a
+/// null are unknown, values that cannot be compared are false.
+pub fn compare(want: Ordering, a: &Vector, b: &Vector) -> Truths {
+    let unknown = a.nulls.or(&b.nulls);
+    let mut truth = Bitmap::new(a.len(), false);
+    macro_rules! typed {
+        ($x:expr, $y:expr) => {
+            for (i, (x, y)) in $x.iter().zip($y.iter()).enumerate() {
+                if x.partial_cmp(y) == Some(want) {
+                    truth.set(i, true);
+                }
+            }
+        };
+    }
+    match (&a.data, &b.data) {
+        (Data::Bool(x), Data::Bool(y)) => typed!(x, y),
+        (Data::Int(x), Data::Int(y)) => typed!(x, y),
+        (Data::Uint(x), Data::Uint(y)) => typed!(x, y),
+        (Data::Float(x), Data::Float(y)) => typed!(x, y),
+        (Data::String(x), Data::String(y)) => typed!(x, y),
+        _ => {
+            for i in 0..a.len() {
+                if a.get(i).compare(&b.get(i)) == Some(want) {
+                    truth.set(i, true);
+                }
+            }
+        }
+    }
+    Truths {
+        truth: truth.and_not(&unknown),
+        unknown,
+    }
+}
+
+/// The batch being evaluated, named by `schema`. Names it does not have
+/// are looked up in the outer rows.
+pub struct Context<'a> {
+    pub schema: &'a Record,
+    pub batch: &'a Batch,
+    pub scope: &'a Scope,
+}
+
+impl<'a> Context<'a> {
+    fn lookup(&self, s: &Symbol) -> Result<Vector, ExecError> {
+        match eval::column(self.schema, s) {
+            Some(i) => Ok(self.batch.columns[i].clone()),
+            None => self
+                .scope
+                .lookup(s)
+                .map(|v| Vector::repeat(v, self.batch.len))
+                .ok_or_else(|| ExecError::FieldNotFound(s.clone())),
+        }
+    }
+
+    pub fn value(&self, e: &Expr) -> Result<Vector, ExecError> {
+        let sub = |Loc(e, _): &Loc<Expr>| self.value(e);
+        let truths = |e: &Loc<Expr>| Ok::<_, ExecError>(Truths::from_vector(&sub(e)?));
+        match e {
+            Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Mod(a, b) => {
+                let (x, y) = (sub(a)?, sub(b)?);
+                let op = e.arith_op().unwrap();
+                arith(op, &x, &y).map_err(|i| {
+                    ExecError::Arithmetic(format!("{} with {} and {}", e, x.get(i), y.get(i)))
+                })
+            }
+            Expr::And(a, b) => Ok(truths(a)?.and(&truths(b)?).into_vector()),
+            Expr::Or(a, b) => Ok(truths(a)?.or(&truths(b)?).into_vector()),
+            Expr::Not(a) => Ok(truths(a)?.not().into_vector()),
+            Expr::Value(Loc(Value::Symbol(s), _)) => self.lookup(s),
+            Expr::Value(Loc(v, _)) => Ok(Vector::repeat(v, self.batch.len)),
+        }
+    }
+
+    pub fn comp(&self, c: &CompExpr, ex: &Executor) -> Result<Truths, ExecError> {
+        let (a, b, want) = match c {
+            CompExpr::Eq(a, b) => (a, b, Ordering::Equal),
+            CompExpr::Lt(a, b) => (a, b, Ordering::Less),
+            CompExpr::Gt(a, b) => (a, b, Ordering::Greater),
+            CompExpr::In(..) => {
+                // 子查询可能用到这一行的值, 一行一行地算
+                let mut r = Truths::all(self.batch.len, Truth::False);
+                for i in 0..self.batch.len {
+                    let row = self.batch.row(i);
+                    let ctx = eval::Context {
+                        schema: self.schema,
+                        row: &row,
+                        scope: self.scope,
+                    };
+                    r.set(i, ctx.comp(c, ex)?);
+                }
+                return Ok(r);
+            }
+        };
+        Ok(compare(want, &self.value(a)?, &self.value(b)?))
+    }
+
+    /// Value of a filter that does not depend on the row order.
+    pub fn filter(&self, f: &FilterExpr, ex: &Executor) -> Result<Truths, ExecError> {
+        let len = self.batch.len;
+        match f {
+            FilterExpr::And(v) => v.iter().try_fold(Truths::all(len, Truth::True), |t, c| {
+                Ok(t.and(&self.comp(c, ex)?))
+            }),
+            FilterExpr::Or(v) => v.iter().try_fold(Truths::all(len, Truth::False), |t, c| {
+                Ok(t.or(&self.comp(c, ex)?))
+            }),
+            FilterExpr::Not(c) => Ok(self.comp(c, ex)?.not()),
+            FilterExpr::Comp(c) => self.comp(c, ex),
+            _ => Ok(Truths::all(len, Truth::True)),
+        }
+    }
+}
+
+/// Running value of an aggregate. Values are folded in the order the row
+/// executor folds them, so float results are the same.
+pub struct Accumulator<'a> {
+    op: &'a ReduceOperator,
+    pub count: u64,
+    value: Option<Value>,
+    total: f64,
+}
+
+impl<'a> Accumulator<'a> {
+    pub fn new(op: &'a ReduceOperator) -> Self {
+        Accumulator {
+            op,
+            count: 0,
+            value: None,
+            // Iterator::sum 也是从 -0.0 开始加
+            total: -0.0,
+        }
+    }
+
+    /// Add the values of a column, nulls are skipped.
+    pub fn update(&mut self, v: &Vector) -> Result<(), ExecError> {
+        let present = (0..v.len()).filter(|i| !v.is_null(*i));
+        match self.op {
+            ReduceOperator::Count => self.count += v.len() as u64,
+            ReduceOperator::Sum(s) => self.sum(v, s)?,
+            ReduceOperator::Avg(_) => {
+                for i in present {
+                    self.count += 1;
+                    match &v.data {
+                        Data::Int(d) => self.total += d[i] as f64,
+                        Data::Uint(d) => self.total += d[i] as f64,
+                        Data::Float(d) => self.total += d[i],
+                        _ => {
+                            if let Some(x) = v.get(i).as_f64() {
+                                self.total += x;
+                            }
+                        }
+                    }
+                }
+            }
+            ReduceOperator::Max(_) => self.pick(v, Ordering::Greater),
+            ReduceOperator::Min(_) => self.pick(v, Ordering::Less),
+        }
+        Ok(())
+    }
+
+    fn sum(&mut self, v: &Vector, s: &Symbol) -> Result<(), ExecError> {
+        let fail = |acc: &Value, x: &Value| {
+            ExecError::Arithmetic(format!("sum[{}] with {} and {}", s, acc, x))
+        };
+        macro_rules! typed {
+            ($d:expr, $variant:ident) => {{
+                let mut acc = match self.value {
+                    Some(Value::$variant(x)) => Some(x),
+                    _ => None,
+                };
+                for (i, x) in $d.iter().enumerate() {
+                    if v.is_null(i) {
+                        continue;
+                    }
+                    acc = Some(match acc {
+                        None => *x,
+                        Some(a) => a
+                            .checked_add(*x)
+                            .ok_or_else(|| fail(&Value::$variant(a), &Value::$variant(*x)))?,
+                    });
+                }
+                self.value = acc.map(Value::$variant);
+            }};
+        }
+        match (&v.data, &self.value) {
+            (Data::Int(d), None | Some(Value::Int(_))) => typed!(d, Int),
+            (Data::Uint(d), None | Some(Value::Uint(_))) => typed!(d, Uint),
+            (Data::Float(d), None | Some(Value::Float(_))) => {
+                let mut acc = match self.value {
+                    Some(Value::Float(x)) => Some(x),
+                    _ => None,
+                };
+                for (i, x) in d.iter().enumerate() {
+                    if !v.is_null(i) {
+                        acc = Some(acc.map_or(*x, |a| a + x));
+                    }
+                }
+                self.value = acc.map(Value::Float);
+            }
+            _ => {
+                for x in v.values().into_iter().filter(|x| *x != Value::Null) {
+                    self.value = Some(match self.value.take() {
+                        None => x,
+                        Some(a) => a.arith(ArithOp::Add, &x).ok_or_else(|| fail(&a, &x))?,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 相等时留下先看到的值
+    fn pick(&mut self, v: &Vector, want: Ordering) {
+        macro_rules! typed {
+            ($d:expr, $variant:ident) => {{
+                let mut acc = match &self.value {
+                    Some(Value::$variant(x)) => Some(x.clone()),
+                    _ => None,
+                };
+                for (i, x) in $d.iter().enumerate() {
+                    if v.is_null(i) {
+                        continue;
+                    }
+                    if acc.as_ref().is_none_or(|a| x.partial_cmp(a) == Some(want)) {
+                        acc = Some(x.clone());
+                    }
+                }
+                self.value = acc.map(Value::$variant);
+            }};
+        }
+        match (&v.data, &self.value) {
+            (Data::Int(d), None | Some(Value::Int(_))) => typed!(d, Int),
+            (Data::Uint(d), None | Some(Value::Uint(_))) => typed!(d, Uint),
+            (Data::Float(d), None | Some(Value::Float(_))) => typed!(d, Float),
+            (Data::String(d), None | Some(Value::String(_))) => typed!(d, String),
+            _ => {
+                for x in v.values().into_iter().filter(|x| *x != Value::Null) {
+                    if self.value.as_ref().is_none_or(|a| order(&x, a) == want) {
+                        self.value = Some(x);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> Value {
+        match self.op {
+            ReduceOperator::Count => Value::Uint(self.count),
+            ReduceOperator::Avg(_) if self.count == 0 => Value::Null,
+            ReduceOperator::Avg(_) => Value::Float(self.total / self.count as f64),
+            _ => self.value.unwrap_or(Value::Null),
+        }
+    }
+}
@@ -0,0 +1,499 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Explain of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use serde::Serialize;
+
+use crate::{
+    optimizer::{
+        cardinality::{Cardinality, Estimator},
+        cse::{NodeId, PlanDag},
+    },
+    physical::{Operator, PhysicalPlan},
+    structs::{
+        ast::{
+            lower::{lower_step, Lowered},
+            type_check::node_type,
+            LocNode, Node,
+        },
+        group_label,
+        plan::{
+            self,
+            type_check::{plan_schemas, Schemas},
+            LocPlan, Plan,
+        },
+        plan_group::{self, OperItem, PlanGroup},
+        rename_label, sort_label,
+    },
+    type_system::{Env, Lines, Record, Type},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Dot,
+}
+
+/// One operator of an explained plan.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExplainNode {
+    pub operator: String,
+    /// Output fields, empty when the node does not type check.
+    pub schema: Vec<Column>,
+    /// Estimated output rows.
+    pub rows: Option<f64>,
+    pub filters: Vec<String>,
+    pub children: Vec<ExplainNode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+}
+
+pub trait Explain {
+    fn explain(&self, env: &Env) -> ExplainNode;
+}
+
+pub fn explain(x: &dyn Explain, env: &Env, format: Format) -> String {
+    x.explain(env).render(format)
+}
+
+fn schema(r: &Record) -> Vec<Column> {
+    r.0.iter()
+        .map(|(k, t)| Column {
+            name: k.to_string(),
+            type_: t.to_string(),
+        })
+        .collect()
+}
+
+impl ExplainNode {
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Text => self.to_text(),
+            Format::Json => self.to_json(),
+            Format::Dot => self.to_dot(),
+        }
+    }
+
+    fn summary(&self) -> (String, String) {
+        let rows = match self.rows {
+            Some(r) => format!("rows: {:.0}", r),
+            None => "rows: ?".to_string(),
+        };
+        let fields: Vec<String> = self
+            .schema
+            .iter()
+            .map(|c| format!("{}: {}", c.name, c.type_))
+            .collect();
+        (rows, format!("{{{}}}", fields.join(", ")))
+    }
+
+    /// Indented tree, one operator per line, filters below it.
+    pub fn to_text(&self) -> String {
+        let mut r = String::new();
+        self.write_text(&mut r, 0);
+        r
+    }
+
+    fn write_text(&self, r: &mut String, depth: usize) {
+        let indent = "  ".repeat(depth);
+        let (rows, fields) = self.summary();
+        let _ = writeln!(r, "{}{}  {}  {}", indent, self.operator, rows, fields);
+        for f in self.filters.iter() {
+            let _ = writeln!(r, "{}  | {}", indent, f);
+        }
+        for c in self.children.iter() {
+            c.write_text(r, depth + 1);
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Graphviz digraph, edges go from an operator to its inputs.
+    pub fn to_dot(&self) -> String {
+        let mut r = String::from("digraph plan {\n  node [shape=box];\n");
+        self.write_dot(&mut r, &mut 0);
+        r.push_str("}\n");
+        r
+    }
+
+    fn write_dot(&self, r: &mut String, next: &mut usize) -> usize {
+        let id = *next;
+        *next += 1;
+        let (rows, fields) = self.summary();
+        let mut label = vec![self.operator.clone(), rows, fields];
+        label.extend(self.filters.iter().cloned());
+        let label: Vec<String> = label.iter().map(|l| escape(l)).collect();
+        let _ = writeln!(r, "  n{} [label=\"{}\"];", id, label.join("\\n"));
+        for c in self.children.iter() {
+            let child = c.write_dot(r, next);
+            let _ = writeln!(r, "  n{} -> n{};", id, child);
+        }
+        id
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Explain for LocPlan {
+    fn explain(&self, env: &Env) -> ExplainNode {
+        // 出现不止一次的子计划第一次写成 #id = ..., 后面只写 #id
+        let (dag, subs) = PlanDag::with_sub_plans(self);
+        let shared = subs
+            .into_iter()
+            .filter(|(_, id)| dag.is_shared(*id))
+            .map(|(p, id)| (p as *const LocPlan, id))
+            .collect();
+        let card = Estimator(env).cardinality(self);
+        let schemas = plan_schemas(self, env);
+        plan_node(self, &card, &schemas, &shared, &mut HashSet::new())
+    }
+}
+
+fn plan_node(
+    plan: &LocPlan,
+    card: &Cardinality,
+    schemas: &Schemas,
+    shared: &HashMap<*const LocPlan, NodeId>,
+    shown: &mut HashSet<NodeId>,
+) -> ExplainNode {
+    let (operator, filters) = match &plan.0 {
+        Plan::Selection(_, f) => ("σ".to_string(), vec![f.to_string()]),
+        Plan::Join(_, _, kind, fs) => (
+            kind.symbol().to_string(),
+            fs.iter().map(|f| f.to_string()).collect(),
+        ),
+        p => (p.label(), vec![]),
+    };
+    let schema = schemas.record.as_ref().map(schema).unwrap_or_default();
+    let operator = match shared.get(&(plan as *const LocPlan)) {
+        Some(id) if !shown.insert(*id) => {
+            return ExplainNode {
+                operator: format!("#{}", id),
+                schema,
+                rows: Some(card.rows),
+                filters: vec![],
+                children: vec![],
+            }
+        }
+        Some(id) => format!("#{} = {}", id, operator),
+        None => operator,
+    };
+    ExplainNode {
+        operator,
+        schema,
+        rows: Some(card.rows),
+        filters,
+        children: plan
+            .0
+            .children()
+            .into_iter()
+            .zip(card.children.iter().zip(schemas.children.iter()))
+            .map(|(c, (card, s))| plan_node(c, card, s, shared, shown))
+            .collect(),
+    }
+}
+
+impl Explain for LocNode {
+    fn explain(&self, env: &Env) -> ExplainNode {
+        node_explain(self, env).0
+    }
+}
+
+// 表头和 plan 都从下面的算, 子树不再检查和转换
+fn node_explain(node: &LocNode, env: &Env) -> (ExplainNode, Option<Record>, Option<Lowered>) {
+    let mut children = vec![];
+    let mut records = vec![];
+    let mut inputs = vec![];
+    for c in node.0.children() {
+        let (e, r, l) = node_explain(c, env);
+        children.push(e);
+        records.push(r);
+        inputs.push(l);
+    }
+    let record = records
+        .into_iter()
+        .collect::<Option<Vec<Record>>>()
+        .and_then(|rs| node_type(node, rs, env).ok())
+        .and_then(|t| match t {
+            Type::Table(Lines(r)) => Some(r),
+            _ => None,
+        });
+    // 类型检查不过的不转换
+    let lowered = inputs
+        .into_iter()
+        .collect::<Option<Vec<Lowered>>>()
+        .filter(|_| record.is_some())
+        .and_then(|ls| lower_step(node, ls, env).ok());
+    let (operator, filters) = match &node.0 {
+        Node::Selection(_, fs) => (
+            "σ".to_string(),
+            fs.iter().map(|f| f.0.to_string()).collect(),
+        ),
+        Node::InnerJoin(_, _, fs) => ("⋈".to_string(), fs.iter().map(|f| f.to_string()).collect()),
+        Node::LeftJoin(_, _, fs) => ("⟕".to_string(), fs.iter().map(|f| f.to_string()).collect()),
+        Node::RightJoin(_, _, fs) => ("⟖".to_string(), fs.iter().map(|f| f.to_string()).collect()),
+        Node::FullJoin(_, _, fs) => ("⟗".to_string(), fs.iter().map(|f| f.to_string()).collect()),
+        n => (n.label(), vec![]),
+    };
+    let e = ExplainNode {
+        operator,
+        schema: record.as_ref().map(schema).unwrap_or_default(),
+        rows: lowered.as_ref().map(|(p, _)| Estimator(env).rows(p)),
+        filters,
+        children,
+    };
+    (e, record, lowered)
+}
+
+fn show(f: plan_group::FilterExpr) -> String {
+    plan::FilterExpr::from(f).to_string()
+}
+
+/// A group is one node: the operator followed by its projection and reduce,
+/// the selections are its filters.
+impl Explain for PlanGroup {
+    fn explain(&self, env: &Env) -> ExplainNode {
+        let plan: LocPlan = self.clone().into();
+        let card = Estimator(env).cardinality(&plan);
+        let schemas = plan_schemas(&plan, env);
+        group_node(self, &plan, &card, &schemas)
+    }
+}
+
+// plan 是这个组转换出来的计划, 组里的选择, 投影和聚合在最上面
+fn group_node(g: &PlanGroup, plan: &LocPlan, card: &Cardinality, s: &Schemas) -> ExplainNode {
+    let (mut operator, mut filters, children): (String, Vec<String>, Vec<&PlanGroup>) =
+        match &g.oper_item.0 {
+            OperItem::Product(a, b) => ("×".to_string(), vec![], vec![a, b]),
+            OperItem::Join(a, b, kind, fs) => (
+                kind.symbol().to_string(),
+                fs.iter().map(|f| show(f.clone())).collect(),
+                vec![a, b],
+            ),
+            OperItem::Difference(a, b) => ("−".to_string(), vec![], vec![a, b]),
+            OperItem::Intersect(a, b) => ("∩".to_string(), vec![], vec![a, b]),
+            OperItem::Division(a, b, kind) => (kind.to_string(), vec![], vec![a, b]),
+            OperItem::Union(a, b) => ("∪".to_string(), vec![], vec![a, b]),
+            OperItem::Distinct(a) => ("δ".to_string(), vec![], vec![a]),
+            OperItem::GroupBy(a, keys, aggs) => (group_label(keys, aggs), vec![], vec![a]),
+            OperItem::Sort(a, keys) => (sort_label(keys), vec![], vec![a]),
+            OperItem::Rename(a, names) => (rename_label(names), vec![], vec![a]),
+            OperItem::Group(g) => ("group".to_string(), vec![], vec![g]),
+            OperItem::Table(t) => (t.clone(), vec![], vec![]),
+            OperItem::Empty(_) => ("∅".to_string(), vec![], vec![]),
+        };
+    filters.extend(g.selection.iter().map(|f| show(f.0.clone())));
+    if let Some(names) = &g.projection {
+        let names: Vec<String> = names.0.iter().map(|s| s.to_string()).collect();
+        let _ = write!(operator, " → π[{}]", names.join(", "));
+    }
+    if let Some(r) = &g.item_reduce {
+        let _ = match r.0.symbol() {
+            Some(s) => write!(operator, " → {}[{}]", r.0.name(), s),
+            None => write!(operator, " → {}", r.0.name()),
+        };
+    }
+    // 往下走过聚合, 投影和选择, 到这个组的运算
+    let above = g.selection.len() + g.projection.iter().count() + g.item_reduce.iter().count();
+    let (mut p, mut c, mut sc) = (plan, card, s);
+    for _ in 0..above {
+        if let (Some(a), Some(ac), Some(asc)) = (
+            p.0.children().first(),
+            c.children.first(),
+            sc.children.first(),
+        ) {
+            (p, c, sc) = (*a, ac, asc);
+        }
+    }
+    let children = match &g.oper_item.0 {
+        // 里面的组转换出来就是这个计划
+        OperItem::Group(inner) => vec![group_node(inner, p, c, sc)],
+        _ => children
+            .into_iter()
+            .zip(p.0.children())
+            .zip(c.children.iter().zip(sc.children.iter()))
+            .map(|((g, p), (c, sc))| group_node(g, p, c, sc))
+            .collect(),
+    };
+    ExplainNode {
+        operator,
+        schema: s.record.as_ref().map(schema).unwrap_or_default(),
+        rows: Some(card.rows),
+        filters,
+        children,
+    }
+}
+
+// 物理计划里已经有表头和行数
+impl Explain for PhysicalPlan {
+    fn explain(&self, _: &Env) -> ExplainNode {
+        physical_node(self, &mut HashSet::new())
+    }
+}
+
+// 共享的节点第一次写成 #id = 它的输入, 后面只写 #id
+fn physical_node(p: &PhysicalPlan, shown: &mut HashSet<NodeId>) -> ExplainNode {
+    if let Operator::Shared(id) = p.op {
+        if !shown.insert(id) {
+            return ExplainNode {
+                operator: p.op.label(),
+                schema: schema(&p.schema),
+                rows: Some(p.rows),
+                filters: vec![],
+                children: vec![],
+            };
+        }
+        let mut node = physical_node(&p.children[0], shown);
+        node.operator = format!("{} = {}", p.op.label(), node.operator);
+        return node;
+    }
+    ExplainNode {
+        operator: p.op.label(),
+        schema: schema(&p.schema),
+        rows: Some(p.rows),
+        filters: p.op.conditions(),
+        children: p.children.iter().map(|c| physical_node(c, shown)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        structs::{
+            ast::{lower::Lower, type_check::TypeInfer},
+            plan::type_check::get_plan_table_type,
+        },
+        testing::*,
+    };
+
+    fn query() -> LocPlan {
+        select(table("V"), gt(sym("d"), 150))
+    }
+
+    #[test]
+    fn text_prints_one_operator_per_line() {
+        let text = explain(&query(), &env(), Format::Text);
+        assert_eq!(
+            text,
+            "σ  rows: 2  {d: int}\n  | d > 150\n  V  rows: 3  {d: int}\n"
+        );
+    }
+
+    #[test]
+    fn query_rows_are_estimated_on_the_lowered_plan() {
+        let n = table_node("V").explain(&env());
+        assert_eq!(n.rows, Some(3.0));
+        assert_eq!(n.schema, query().explain(&env()).schema);
+    }
+
+    #[test]
+    fn dot_links_an_operator_to_its_inputs() {
+        let dot = explain(&query(), &env(), Format::Dot);
+        assert!(dot.starts_with("digraph plan {\n"));
+        assert!(dot.contains("  n0 -> n1;\n"));
+    }
+
+    #[test]
+    fn json_names_the_column_type() {
+        let json = explain(&query(), &env(), Format::Json);
+        assert!(json.contains("\"type\": \"int\""));
+        assert!(json.contains("\"operator\": \"V\""));
+    }
+
+    fn same_as_each_node(n: &LocNode, e: &ExplainNode, env: &Env) {
+        let rows = n.lower(env).ok().map(|p| Estimator(env).rows(&p));
+        assert_eq!(e.rows, rows, "{:?}", n);
+        match n.type_infer(env) {
+            Ok(Type::Table(Lines(r))) => assert_eq!(e.schema, schema(&r)),
+            _ => assert!(e.schema.is_empty()),
+        }
+        for (c, ce) in n.0.children().into_iter().zip(e.children.iter()) {
+            same_as_each_node(c, ce, env);
+        }
+    }
+
+    #[test]
+    fn query_nodes_are_explained_from_their_inputs() {
+        let (_, env) = fixture();
+        let renamed = node(Node::Rename(
+            Box::new(table_node("T")),
+            vec![(sym("a"), sym("c"))],
+        ));
+        let product = node(Node::CrossProduct(
+            Box::new(renamed),
+            Box::new(table_node("T")),
+        ));
+        let join = node(Node::NatureJoin(
+            Box::new(table_node("R")),
+            Box::new(table_node("S")),
+        ));
+        for q in [node(Node::Distinct(Box::new(product))), join] {
+            same_as_each_node(&q, &q.explain(&env), &env);
+        }
+    }
+
+    fn sub_groups(g: &PlanGroup) -> Vec<&PlanGroup> {
+        match &g.oper_item.0 {
+            OperItem::Product(a, b)
+            | OperItem::Join(a, b, _, _)
+            | OperItem::Difference(a, b)
+            | OperItem::Intersect(a, b)
+            | OperItem::Division(a, b, _)
+            | OperItem::Union(a, b) => vec![a, b],
+            OperItem::Distinct(a)
+            | OperItem::GroupBy(a, _, _)
+            | OperItem::Sort(a, _)
+            | OperItem::Rename(a, _)
+            | OperItem::Group(a) => vec![a],
+            OperItem::Table(_) | OperItem::Empty(_) => vec![],
+        }
+    }
+
+    fn same_as_each_group(g: &PlanGroup, e: &ExplainNode, env: &Env) {
+        let p: LocPlan = g.clone().into();
+        assert_eq!(e.rows, Some(Estimator(env).rows(&p)), "{:?}", p);
+        let r = get_plan_table_type(&p, env).unwrap();
+        assert_eq!(e.schema, schema(&r));
+        for (c, ce) in sub_groups(g).into_iter().zip(e.children.iter()) {
+            same_as_each_group(c, ce, env);
+        }
+    }
+
+    #[test]
+    fn groups_are_explained_from_their_inputs() {
+        let (_, env) = fixture();
+        for p in queries() {
+            let g = PlanGroup::from(p);
+            same_as_each_group(&g, &g.explain(&env), &env);
+        }
+    }
+}
@@ -15,10 +15,6 @@ You should have received a copy of the GNU General Public License
 along with RAE; see the file COPYING3.  If not see
 <http://www.gnu.org/licenses/>.  */
 
-mod parser;
-mod structs;
-mod type_system;
-
 fn main() {
     println!("Hello, world!");
 }
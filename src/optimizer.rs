@@ -0,0 +1,110 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Optimizer of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+pub mod cardinality;
+pub mod cost;
+pub mod cse;
+pub mod decorrelate;
+pub mod egraph;
+pub mod join_order;
+pub mod projection;
+pub mod pushdown;
+pub mod simplify;
+
+use crate::{
+    physical::Semantics,
+    structs::{
+        plan::{type_check::get_plan_table_type, LocPlan},
+        plan_group::PlanGroup,
+        Loc,
+    },
+    type_system::{Env, TypeError},
+};
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn run(&self, plan: LocPlan, env: &Env) -> LocPlan;
+}
+
+/// Run a pass and check the result against the type checker: the fields of
+/// the plan and their order must not change.
+pub fn run_pass(pass: &dyn Pass, plan: LocPlan, env: &Env) -> Result<LocPlan, Loc<TypeError>> {
+    let before = get_plan_table_type(&plan, env)?;
+    let pos = plan.1;
+    let plan = pass.run(plan, env);
+    let after = get_plan_table_type(&plan, env)?;
+    if before != after || !before.0.keys().eq(after.0.keys()) {
+        return Err(Loc(
+            TypeError::SchemaChanged(Box::new(before), Box::new(after)),
+            pos,
+        ));
+    }
+    Ok(plan)
+}
+
+pub struct Optimizer(pub Vec<Box<dyn Pass>>);
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Optimizer::new(Semantics::Set)
+    }
+}
+
+impl Optimizer {
+    /// The default passes, for plans run with `semantics`.
+    pub fn new(semantics: Semantics) -> Self {
+        Optimizer(vec![
+            Box::new(simplify::Simplify { semantics }),
+            Box::new(decorrelate::Decorrelate),
+            Box::new(pushdown::PredicatePushdown),
+            Box::new(join_order::JoinOrder::default()),
+            Box::new(projection::ProjectionPushdown { semantics }),
+        ])
+    }
+
+    pub fn optimize(&self, plan: LocPlan, env: &Env) -> Result<LocPlan, Loc<TypeError>> {
+        self.0
+            .iter()
+            .try_fold(plan, |plan, pass| run_pass(pass.as_ref(), plan, env))
+    }
+
+    pub fn optimize_group(&self, group: PlanGroup, env: &Env) -> Result<PlanGroup, Loc<TypeError>> {
+        self.optimize(group.into(), env).map(PlanGroup::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn every_pass_keeps_rows() {
+        for semantics in [Semantics::Set, Semantics::Bag] {
+            let mut passes = Optimizer::new(semantics).0;
+            passes.push(Box::new(egraph::Saturation::default()));
+            for p in queries() {
+                // 每个 pass 单独跑, 也接着前一个的结果跑
+                let mut after = p.clone();
+                for pass in passes.iter() {
+                    check_pass(pass.as_ref(), &p, semantics);
+                    after = check_pass(pass.as_ref(), &after, semantics);
+                }
+            }
+        }
+    }
+}
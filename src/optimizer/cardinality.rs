@@ -0,0 +1,555 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Cardinality Estimation of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::cmp::Ordering;
+
+use indexmap::IndexMap;
+
+use crate::{
+    statistics::{ColumnStats, Histogram},
+    structs::{
+        plan::{type_check::node_type, CompExpr, FilterExpr, JoinKind, LocPlan, Plan},
+        Expr, Loc, Symbol, Value,
+    },
+    type_system::{product_fields, resolve_field, Env, Record, TableName},
+};
+
+pub const DEFAULT_TABLE_ROWS: f64 = 1000.0;
+const EQ_SELECTIVITY: f64 = 0.1;
+const RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+const IN_SELECTIVITY: f64 = 0.5;
+
+/// Expected output of a plan node: the number of rows and what is known
+/// about each output field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub rows: f64,
+    pub columns: IndexMap<Symbol, ColumnStats>,
+}
+
+/// Estimated rows of a node and of its sub plans, in the order of
+/// `Plan::children`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cardinality {
+    pub rows: f64,
+    pub children: Vec<Cardinality>,
+}
+
+// 估计和表头, 表头类型检查不过时是 None
+type Typed = (Estimate, Option<Record>);
+
+/// Row estimates from the statistics in `Env`, or from the declared domains
+/// for tables that were never analyzed.
+pub struct Estimator<'a>(pub &'a Env);
+
+enum Operand<'a> {
+    Column(&'a ColumnStats),
+    Const(&'a Value),
+    Unknown,
+}
+
+fn operand<'a>(e: &'a Expr, input: &'a Estimate) -> Operand<'a> {
+    match e {
+        Expr::Value(Loc(Value::Symbol(s), _)) => match resolve_field(&input.columns, s) {
+            Some((_, c)) => Operand::Column(c),
+            None => Operand::Unknown,
+        },
+        Expr::Value(Loc(v, _)) => Operand::Const(v),
+        _ => Operand::Unknown,
+    }
+}
+
+fn clamp(x: f64) -> f64 {
+    x.clamp(0.0, 1.0)
+}
+
+// 落在 [lower, upper] 里的比例, 只有数字能插值
+fn interpolate(lower: &Value, upper: &Value, v: &Value) -> f64 {
+    match (lower.as_f64(), upper.as_f64(), v.as_f64()) {
+        (Some(l), Some(u), Some(v)) if u > l => clamp((v - l) / (u - l)),
+        _ => 0.5,
+    }
+}
+
+fn rows_of(h: &Histogram) -> f64 {
+    h.0.iter().map(|b| b.rows as f64).sum()
+}
+
+/// Fraction of the non null values equal to `v`.
+fn eq_fraction(c: &ColumnStats, v: &Value) -> f64 {
+    let total = rows_of(&c.histogram);
+    if total > 0.0 {
+        return c
+            .histogram
+            .0
+            .iter()
+            .find(|b| {
+                v.compare(&b.lower) != Some(Ordering::Less)
+                    && v.compare(&b.upper) != Some(Ordering::Greater)
+            })
+            .map_or(0.0, |b| b.rows as f64 / b.distinct.max(1) as f64 / total);
+    }
+    let outside = |bound: &Option<Value>, o: Ordering| {
+        bound.as_ref().is_some_and(|b| v.compare(b) == Some(o))
+    };
+    if outside(&c.min, Ordering::Less) || outside(&c.max, Ordering::Greater) {
+        return 0.0;
+    }
+    c.distinct.map_or(EQ_SELECTIVITY, |d| 1.0 / d.max(1.0))
+}
+
+/// Fraction of the non null values smaller than `v`.
+fn below_fraction(c: &ColumnStats, v: &Value) -> f64 {
+    let total = rows_of(&c.histogram);
+    if total > 0.0 {
+        let mut acc = 0.0;
+        for b in c.histogram.0.iter() {
+            if v.compare(&b.upper) == Some(Ordering::Greater) {
+                acc += b.rows as f64;
+            } else {
+                if v.compare(&b.lower) == Some(Ordering::Greater) {
+                    acc += b.rows as f64 * interpolate(&b.lower, &b.upper, v);
+                }
+                break;
+            }
+        }
+        return acc / total;
+    }
+    match (&c.min, &c.max) {
+        (Some(l), Some(u)) => interpolate(l, u, v),
+        _ => RANGE_SELECTIVITY,
+    }
+}
+
+fn not_null(c: &ColumnStats) -> f64 {
+    1.0 - c.null_fraction
+}
+
+// 合并两边同一位置的列, 用于并集
+fn merge(a: &ColumnStats, arows: f64, b: &ColumnStats, brows: f64) -> ColumnStats {
+    let pick = |x: &Option<Value>, y: &Option<Value>, o: Ordering| match (x, y) {
+        (Some(x), Some(y)) if y.compare(x) == Some(o) => Some(y.clone()),
+        (Some(x), _) => Some(x.clone()),
+        (None, y) => y.clone(),
+    };
+    let total = (arows + brows).max(1.0);
+    ColumnStats {
+        null_fraction: (a.null_fraction * arows + b.null_fraction * brows) / total,
+        distinct: match (a.distinct, b.distinct) {
+            (Some(x), Some(y)) => Some((x + y).min(total)),
+            _ => None,
+        },
+        min: pick(&a.min, &b.min, Ordering::Less),
+        max: pick(&a.max, &b.max, Ordering::Greater),
+        histogram: Histogram::default(),
+    }
+}
+
+// 行数变少以后, 不同值的个数不会比行数多
+fn shrink(mut columns: IndexMap<Symbol, ColumnStats>, rows: f64) -> IndexMap<Symbol, ColumnStats> {
+    for c in columns.values_mut() {
+        c.distinct = c.distinct.map(|d| d.min(rows.max(1.0)));
+    }
+    columns
+}
+
+impl<'a> Estimator<'a> {
+    pub fn rows(&self, plan: &LocPlan) -> f64 {
+        self.estimate(plan).rows
+    }
+
+    pub fn cardinality(&self, plan: &LocPlan) -> Cardinality {
+        self.cardinality_typed(plan).0
+    }
+
+    fn cardinality_typed(&self, plan: &LocPlan) -> (Cardinality, Typed) {
+        let (children, inputs): (Vec<Cardinality>, Vec<Typed>) = plan
+            .0
+            .children()
+            .into_iter()
+            .map(|c| self.cardinality_typed(c))
+            .unzip();
+        let t = self.node(plan, inputs);
+        let c = Cardinality {
+            rows: t.0.rows,
+            children,
+        };
+        (c, t)
+    }
+
+    pub fn estimate(&self, plan: &LocPlan) -> Estimate {
+        self.typed(plan).0
+    }
+
+    fn typed(&self, plan: &LocPlan) -> Typed {
+        let inputs = plan
+            .0
+            .children()
+            .into_iter()
+            .map(|c| self.typed(c))
+            .collect();
+        self.node(plan, inputs)
+    }
+
+    // 从下面的估计和表头算这个节点的, inputs 按 Plan::children 的顺序
+    fn node(&self, plan: &LocPlan, inputs: Vec<Typed>) -> Typed {
+        let record = inputs
+            .iter()
+            .map(|(_, r)| r.clone())
+            .collect::<Option<Vec<Record>>>()
+            .and_then(|rs| node_type(plan, rs, self.0, None).ok());
+        let mut inputs = inputs.into_iter();
+        let mut input = || inputs.next().unwrap();
+        let e = match &plan.0 {
+            Plan::Table(name) => self.table(&TableName(name.clone())),
+            Plan::Selection(_, f) => {
+                let a = input().0;
+                let rows = match f.as_ref() {
+                    FilterExpr::Range(from, to) => a.rows.min(to.saturating_sub(*from) as f64),
+                    FilterExpr::GetItem(_) | FilterExpr::GetFirst | FilterExpr::GetLast => {
+                        a.rows.min(1.0)
+                    }
+                    f => a.rows * self.selectivity(f, &a),
+                };
+                Estimate {
+                    rows,
+                    columns: shrink(a.columns, rows),
+                }
+            }
+            Plan::Projection(_, names) => {
+                let mut a = input().0;
+                a.columns.retain(|k, _| names.contains(k));
+                a
+            }
+            Plan::Distinct(_) => {
+                let a = input().0;
+                // 最多是各列取值的组合数, 有不知道的列就不管
+                let known = record
+                    .as_ref()
+                    .is_some_and(|r| r.0.keys().all(|k| a.columns.contains_key(k)));
+                let groups: f64 = a
+                    .columns
+                    .values()
+                    .map(|c| c.distinct.unwrap_or(a.rows))
+                    .product();
+                let rows = if known { a.rows.min(groups) } else { a.rows };
+                Estimate {
+                    rows,
+                    columns: shrink(a.columns, rows),
+                }
+            }
+            // 排序不改变行数
+            Plan::Sort(_, _) => input().0,
+            Plan::Rename(_, names) => {
+                let a = input().0;
+                let columns = a
+                    .columns
+                    .into_iter()
+                    .map(|(k, c)| match names.iter().find(|(old, _)| *old == k) {
+                        Some((_, new)) => (new.clone(), c),
+                        None => (k, c),
+                    })
+                    .collect();
+                Estimate {
+                    rows: a.rows,
+                    columns,
+                }
+            }
+            Plan::GroupBy(_, keys, _) => {
+                let a = input().0;
+                // 每组一行, 最多是分组属性取值的组合数; 没有分组属性时总是一行
+                let groups: f64 = keys
+                    .iter()
+                    .map(|k| a.columns.get(k).and_then(|c| c.distinct).unwrap_or(a.rows))
+                    .product();
+                let rows = if keys.is_empty() {
+                    1.0
+                } else {
+                    a.rows.min(groups)
+                };
+                let mut columns = a.columns;
+                columns.retain(|k, _| keys.contains(k));
+                Estimate {
+                    rows,
+                    columns: shrink(columns, rows),
+                }
+            }
+            Plan::Product(_, _) => self.join(input(), input(), JoinKind::Inner, &[]),
+            Plan::Join(_, _, kind, fs) => self.join(input(), input(), *kind, fs),
+            Plan::Union(_, _) => {
+                let ((ae, at), (be, bt)) = (input(), input());
+                let rows = ae.rows + be.rows;
+                // 按位置合并, 名字取左边的
+                let columns = match (at, bt) {
+                    (Some(at), Some(bt)) => {
+                        at.0.keys()
+                            .zip(bt.0.keys())
+                            .filter_map(|(x, y)| {
+                                let (cx, cy) = (ae.columns.get(x)?, be.columns.get(y)?);
+                                Some((x.clone(), merge(cx, ae.rows, cy, be.rows)))
+                            })
+                            .collect()
+                    }
+                    _ => IndexMap::new(),
+                };
+                Estimate { rows, columns }
+            }
+            Plan::Difference(_, _) => {
+                let (a, b) = (input().0, input().0.rows);
+                let rows = (a.rows - b / 2.0).max(0.0);
+                Estimate {
+                    rows,
+                    columns: shrink(a.columns, rows),
+                }
+            }
+            Plan::Intersect(_, _) => {
+                let (a, b) = (input().0, input().0.rows);
+                let rows = a.rows.min(b) / 2.0;
+                Estimate {
+                    rows,
+                    columns: shrink(a.columns, rows),
+                }
+            }
+            Plan::Division(_, _, _) => {
+                let (a, b) = (input().0, input().0.rows);
+                let (arows, mut columns) = (a.rows, a.columns);
+                match &record {
+                    Some(r) => columns.retain(|k, _| r.0.contains_key(k)),
+                    None => columns.clear(),
+                }
+                // 商最多是被除数里商属性的组合数
+                let groups: f64 = columns
+                    .values()
+                    .map(|c| c.distinct.unwrap_or(arows))
+                    .product();
+                let rows = (arows / b.max(1.0)).min(groups);
+                Estimate {
+                    rows,
+                    columns: shrink(columns, rows),
+                }
+            }
+            Plan::Reduce(_) => Estimate {
+                rows: 1.0,
+                columns: IndexMap::new(),
+            },
+            Plan::Empty(_) => Estimate {
+                rows: 0.0,
+                columns: IndexMap::new(),
+            },
+        };
+        (e, record)
+    }
+
+    fn table(&self, name: &TableName) -> Estimate {
+        let columns = match self.0.get_table(name) {
+            Some(t) => {
+                t.0 .0
+                    .keys()
+                    .filter_map(|k| Some((k.clone(), self.0.column_stats(name, k)?)))
+                    .collect()
+            }
+            None => IndexMap::new(),
+        };
+        let rows = self
+            .0
+            .get_stats(name)
+            .map_or(DEFAULT_TABLE_ROWS, |s| s.rows as f64);
+        Estimate { rows, columns }
+    }
+
+    fn join(
+        &self,
+        (ae, at): Typed,
+        (be, bt): Typed,
+        kind: JoinKind,
+        fs: &[FilterExpr],
+    ) -> Estimate {
+        let columns = match (at, bt) {
+            (Some(at), Some(bt)) => {
+                let side = |t: &Record, e: &Estimate| -> Vec<(Symbol, Option<ColumnStats>)> {
+                    t.0.keys()
+                        .map(|k| (k.clone(), e.columns.get(k).cloned()))
+                        .collect()
+                };
+                product_fields(side(&at, &ae), &at.1, side(&bt, &be), &bt.1)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|(k, c)| Some((k, c?)))
+                    .collect()
+            }
+            _ => IndexMap::new(),
+        };
+        let product = Estimate {
+            rows: ae.rows * be.rows,
+            columns,
+        };
+        let selectivity: f64 = fs.iter().map(|f| self.selectivity(f, &product)).product();
+        let inner = product.rows * selectivity;
+        // 半连接: 左边有匹配的行数不会超过左边
+        let semi = inner.min(ae.rows);
+        let rows = match kind {
+            JoinKind::Inner => inner,
+            JoinKind::Left => inner.max(ae.rows),
+            JoinKind::Right => inner.max(be.rows),
+            JoinKind::Full => inner.max(ae.rows + be.rows),
+            JoinKind::Semi => semi,
+            JoinKind::Anti | JoinKind::NullAwareAnti => ae.rows - semi,
+        };
+        if kind.is_semi() {
+            return Estimate {
+                rows,
+                columns: shrink(ae.columns, rows),
+            };
+        }
+        Estimate {
+            rows,
+            columns: shrink(product.columns, rows),
+        }
+    }
+
+    /// Fraction of the rows of `input` kept by `f`.
+    pub fn selectivity(&self, f: &FilterExpr, input: &Estimate) -> f64 {
+        let comp = |c: &CompExpr| self.comp_selectivity(c, input);
+        match f {
+            FilterExpr::And(v) => v.iter().map(|c| comp(c)).product(),
+            FilterExpr::Or(v) => 1.0 - v.iter().map(|c| 1.0 - comp(c)).product::<f64>(),
+            FilterExpr::Not(c) => 1.0 - comp(c),
+            FilterExpr::Comp(c) => comp(c),
+            FilterExpr::Range(_, _)
+            | FilterExpr::GetItem(_)
+            | FilterExpr::GetFirst
+            | FilterExpr::GetLast => 1.0,
+        }
+    }
+
+    fn comp_selectivity(&self, c: &CompExpr, input: &Estimate) -> f64 {
+        let r = match c {
+            CompExpr::Eq(a, b) => match (operand(a, input), operand(b, input)) {
+                (Operand::Column(x), Operand::Column(y)) => {
+                    let d = x.distinct.unwrap_or(0.0).max(y.distinct.unwrap_or(0.0));
+                    let s = if d > 0.0 { 1.0 / d } else { EQ_SELECTIVITY };
+                    s * not_null(x) * not_null(y)
+                }
+                (Operand::Column(x), Operand::Const(v))
+                | (Operand::Const(v), Operand::Column(x)) => eq_fraction(x, v) * not_null(x),
+                (Operand::Const(x), Operand::Const(y)) => {
+                    if x.compare(y) == Some(Ordering::Equal) {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                }
+                _ => EQ_SELECTIVITY,
+            },
+            CompExpr::Lt(a, b) | CompExpr::Gt(a, b) => {
+                let lt = matches!(c, CompExpr::Lt(_, _));
+                // 常量在左边时反过来
+                let (x, v, lt) = match (operand(a, input), operand(b, input)) {
+                    (Operand::Column(x), Operand::Const(v)) => (x, v, lt),
+                    (Operand::Const(v), Operand::Column(x)) => (x, v, !lt),
+                    _ => return RANGE_SELECTIVITY,
+                };
+                let below = below_fraction(x, v);
+                let s = if lt {
+                    below
+                } else {
+                    1.0 - below - eq_fraction(x, v)
+                };
+                clamp(s) * not_null(x)
+            }
+            CompExpr::In(a, p) => match operand(a, input) {
+                Operand::Column(x) => {
+                    let rows = self.rows(p);
+                    let s = x.distinct.map_or(IN_SELECTIVITY, |d| rows / d.max(1.0));
+                    s.min(1.0) * not_null(x)
+                }
+                _ => IN_SELECTIVITY,
+            },
+        };
+        clamp(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::testing::*;
+
+    fn literal(v: i64) -> Box<Expr> {
+        Box::new(Expr::Value(Loc(Value::Int(v), pos())))
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn tables_use_the_analyzed_row_count() {
+        let env = env();
+        assert_eq!(Estimator(&env).rows(&table("R")), 6.0);
+        let fresh = Env::new(env.0.clone());
+        assert_eq!(Estimator(&fresh).rows(&table("R")), DEFAULT_TABLE_ROWS);
+        let empty = Env::new(HashMap::new());
+        assert_eq!(Estimator(&empty).rows(&table("W")), DEFAULT_TABLE_ROWS);
+    }
+
+    #[test]
+    fn equality_with_a_constant_reads_the_histogram() {
+        let env = env();
+        // a 的 5 个非空值里有两个 2, 另有一行是空
+        let f = FilterExpr::Comp(Box::new(CompExpr::Eq(field(sym("a")), literal(2))));
+        let rows = Estimator(&env).rows(&select(table("R"), f));
+        assert!(close(rows, 2.0), "{}", rows);
+        let f = FilterExpr::Comp(Box::new(CompExpr::Eq(field(sym("a")), literal(9))));
+        assert_eq!(Estimator(&env).rows(&select(table("R"), f)), 0.0);
+    }
+
+    #[test]
+    fn join_selectivity_uses_distinct_keys_and_nulls() {
+        let env = env();
+        let p = plan(Plan::Join(
+            Box::new(table("R")),
+            Box::new(table("S")),
+            JoinKind::Inner,
+            vec![eq(qualified("R", "b"), qualified("S", "b"))],
+        ));
+        // 30 行, 3 个不同的键, R.b 有 1/6 是空, S.b 有 1/5 是空
+        let c = Estimator(&env).cardinality(&p);
+        let children: Vec<f64> = c.children.iter().map(|c| c.rows).collect();
+        assert_eq!(children, [6.0, 5.0]);
+        let expected = 30.0 / 3.0 * (5.0 / 6.0) * (4.0 / 5.0);
+        assert!(close(c.rows, expected), "{}", c.rows);
+    }
+
+    fn same_rows(e: &Estimator, p: &LocPlan, c: &Cardinality) {
+        assert_eq!(e.rows(p), c.rows, "{:?}", p);
+        for (p, c) in p.0.children().into_iter().zip(c.children.iter()) {
+            same_rows(e, p, c);
+        }
+    }
+
+    #[test]
+    fn cardinality_gives_the_rows_of_every_sub_plan() {
+        let env = env();
+        let e = Estimator(&env);
+        for p in queries() {
+            same_rows(&e, &p, &e.cardinality(&p));
+        }
+    }
+}
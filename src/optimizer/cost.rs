@@ -0,0 +1,55 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Cost Model of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use super::cardinality::Estimator;
+use crate::{
+    structs::plan::{FilterExpr, LocPlan},
+    type_system::Env,
+};
+
+/// What the join enumerator asks about a plan.
+///
+/// Implement it to plug in another estimate or another notion of cost.
+pub trait CostModel {
+    /// Expected number of output rows.
+    fn rows(&self, plan: &LocPlan, env: &Env) -> f64;
+
+    /// Fraction of the rows of `input` kept by `f`, the fields of `f` are
+    /// named as in the output of `input`.
+    fn selectivity(&self, f: &FilterExpr, input: &LocPlan, env: &Env) -> f64;
+
+    /// Cost of joining `left` rows with `right` rows into `out` rows.
+    fn join_cost(&self, left: f64, right: f64, out: f64) -> f64 {
+        left + right + out
+    }
+}
+
+/// Rows from the cardinality estimator, cost from the default
+/// `join_cost`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultCost;
+
+impl CostModel for DefaultCost {
+    fn rows(&self, plan: &LocPlan, env: &Env) -> f64 {
+        Estimator(env).rows(plan)
+    }
+
+    fn selectivity(&self, f: &FilterExpr, input: &LocPlan, env: &Env) -> f64 {
+        let e = Estimator(env);
+        e.selectivity(f, &e.estimate(input))
+    }
+}
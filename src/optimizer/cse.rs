@@ -0,0 +1,228 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Common Subexpression Elimination of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::{collections::HashMap, fmt};
+
+use crate::structs::plan::{LocPlan, Plan};
+
+pub type NodeId = usize;
+
+/// A plan where structurally equal sub plans are stored once.
+///
+/// Two sub plans are equal when their operators print the same and their
+/// children are the same nodes, source positions are not compared. Empty
+/// relations also compare their fields. A node with more than one parent is
+/// evaluated once and its result reused.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanDag {
+    pub nodes: Vec<DagNode>,
+    pub root: NodeId,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DagNode {
+    /// The whole sub plan as first seen.
+    pub plan: LocPlan,
+    /// Sub plans in the order of `Plan::children`.
+    pub children: Vec<NodeId>,
+    pub parents: usize,
+}
+
+type Key = (String, Vec<NodeId>);
+
+// 空关系打印出来都一样, 要加上表头
+fn label(plan: &Plan) -> String {
+    match plan {
+        Plan::Empty(r) => format!("{}{:?}", plan.label(), r),
+        p => p.label(),
+    }
+}
+
+impl PlanDag {
+    pub fn new(plan: &LocPlan) -> Self {
+        PlanDag::with_sub_plans(plan).0
+    }
+
+    /// The DAG and the node of every sub plan of `plan`, children first.
+    pub fn with_sub_plans(plan: &LocPlan) -> (Self, Vec<(&LocPlan, NodeId)>) {
+        let mut dag = PlanDag {
+            nodes: vec![],
+            root: 0,
+        };
+        let mut seen = HashMap::new();
+        let mut subs = vec![];
+        dag.root = dag.intern(plan, &mut seen, &mut subs);
+        (dag, subs)
+    }
+
+    fn intern<'p>(
+        &mut self,
+        plan: &'p LocPlan,
+        seen: &mut HashMap<Key, NodeId>,
+        subs: &mut Vec<(&'p LocPlan, NodeId)>,
+    ) -> NodeId {
+        let children: Vec<NodeId> = plan
+            .0
+            .children()
+            .into_iter()
+            .map(|c| self.intern(c, seen, subs))
+            .collect();
+        let key = (label(&plan.0), children);
+        if let Some(id) = seen.get(&key) {
+            subs.push((plan, *id));
+            return *id;
+        }
+        for c in key.1.iter() {
+            self.nodes[*c].parents += 1;
+        }
+        let id = self.nodes.len();
+        self.nodes.push(DagNode {
+            plan: plan.clone(),
+            children: key.1.clone(),
+            parents: 0,
+        });
+        seen.insert(key, id);
+        subs.push((plan, id));
+        id
+    }
+
+    pub fn is_shared(&self, id: NodeId) -> bool {
+        self.nodes[id].parents > 1
+    }
+
+    pub fn shared(&self) -> Vec<NodeId> {
+        (0..self.nodes.len())
+            .filter(|i| self.is_shared(*i))
+            .collect()
+    }
+
+    pub fn to_plan(&self) -> LocPlan {
+        self.nodes[self.root].plan.clone()
+    }
+
+    fn write(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        id: NodeId,
+        depth: usize,
+        printed: &mut Vec<bool>,
+    ) -> fmt::Result {
+        let indent = "  ".repeat(depth);
+        let label = self.nodes[id].plan.0.label();
+        // 共享的节点第一次完整打印, 之后只写编号
+        if self.is_shared(id) {
+            if printed[id] {
+                return writeln!(f, "{}#{}", indent, id);
+            }
+            printed[id] = true;
+            writeln!(f, "{}#{} = {}", indent, id, label)?;
+        } else {
+            writeln!(f, "{}{}", indent, label)?;
+        }
+        for c in self.nodes[id].children.iter() {
+            self.write(f, *c, depth + 1, printed)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PlanDag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut printed = vec![false; self.nodes.len()];
+        self.write(f, self.root, 0, &mut printed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::Executor,
+        explain::{explain, Format},
+        physical::{Operator, PhysicalPlan, Semantics},
+        structs::plan::Plan,
+        testing::*,
+        type_system::Record,
+    };
+
+    // σ[a > 1](R) − σ[b > 10](σ[a > 1](R))
+    fn twice() -> LocPlan {
+        let s = select(table("R"), gt(sym("a"), 1));
+        let p = Plan::Difference(Box::new(s.clone()), Box::new(select(s, gt(sym("b"), 10))));
+        plan(p)
+    }
+
+    #[test]
+    fn equal_sub_plans_are_stored_once() {
+        let dag = PlanDag::new(&twice());
+        assert_eq!(dag.nodes.len(), 4);
+        assert_eq!(dag.shared(), [1]);
+        assert_eq!(dag.nodes[1].parents, 2);
+        assert_eq!(dag.to_plan(), twice());
+        let dag = PlanDag::new(&select(table("R"), gt(sym("a"), 1)));
+        assert!(dag.shared().is_empty());
+    }
+
+    #[test]
+    fn shared_node_is_printed_in_full_once() {
+        let text = PlanDag::new(&twice()).to_string();
+        assert_eq!(text.matches("#1 = ").count(), 1, "{}", text);
+        assert_eq!(text.matches("#1").count(), 2, "{}", text);
+        assert_eq!(text.matches("R").count(), 1, "{}", text);
+    }
+
+    fn difference(a: LocPlan, b: LocPlan) -> LocPlan {
+        plan(Plan::Difference(Box::new(a), Box::new(b)))
+    }
+
+    fn empty(name: &str) -> LocPlan {
+        let fields = vec![(sym(name), int())].into_iter().collect();
+        plan(Plan::Empty(Record(fields, String::new())))
+    }
+
+    fn shared(p: &PhysicalPlan) -> usize {
+        let n = matches!(p.op, Operator::Shared(_)) as usize;
+        n + p.children.iter().map(shared).sum::<usize>()
+    }
+
+    #[test]
+    fn empty_relations_with_other_fields_are_not_merged() {
+        let a = empty("a");
+        let b = empty("b");
+        let dag = PlanDag::new(&difference(a.clone(), b));
+        assert_eq!(dag.nodes.len(), 3);
+        let dag = PlanDag::new(&difference(a.clone(), a));
+        assert_eq!(dag.nodes.len(), 2);
+    }
+
+    #[test]
+    fn shared_sub_plan_is_planned_once() {
+        let (db, env) = fixture();
+        let s = select(table("R"), gt(sym("a"), 1));
+        let p = difference(s.clone(), select(s, gt(sym("b"), 10)));
+        let physical = Executor::new(&db, &env).planner().plan(&p).unwrap();
+        assert_eq!(shared(&physical), 2);
+        let text = explain(&physical, &env, Format::Text);
+        assert!(text.contains("#1 = "), "{}", text);
+        assert_eq!(text.matches("#1").count(), 2, "{}", text);
+        let text = explain(&p, &env, Format::Text);
+        assert!(text.contains("#1 = "), "{}", text);
+        for semantics in [Semantics::Set, Semantics::Bag] {
+            assert_eq!(run(&db, &env, &p, semantics).len(), 2);
+        }
+    }
+}
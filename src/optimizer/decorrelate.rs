@@ -0,0 +1,248 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Subquery Decorrelation of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use indexmap::IndexMap;
+
+use super::{pushdown::wrap, Pass};
+use crate::{
+    structs::{
+        plan::{
+            type_check::{get_plan_table_type, get_plan_table_type_in},
+            CompExpr, FilterExpr, JoinKind, LocPlan, Plan,
+        },
+        Expr, Loc, Pos, Symbol, Value,
+    },
+    type_system::{product_fields, resolve_field, Env, Record},
+};
+
+/// Turn `in` and `not in` filters into semi joins and anti joins.
+///
+/// `a in s` becomes a semi join on `a = c`, where `c` is the column of `s`.
+/// `not in` becomes a null aware anti join, so a null on either side still
+/// makes the filter unknown. The sub plan may use the fields of the outer
+/// relation in its selections, these predicates are pulled up into the join
+/// condition. Sub plans that cannot be unnested are left as filters.
+pub struct Decorrelate;
+
+impl Pass for Decorrelate {
+    fn name(&self) -> &'static str {
+        "decorrelate"
+    }
+
+    fn run(&self, plan: LocPlan, env: &Env) -> LocPlan {
+        rewrite(plan, env)
+    }
+}
+
+fn rewrite(plan: LocPlan, env: &Env) -> LocPlan {
+    let Loc(p, pos) = plan;
+    match p.map_children(|c| rewrite(c, env)) {
+        Plan::Selection(a, f) if !f.is_positional() => {
+            let mut a = *a;
+            let mut rest = vec![];
+            for f in f.conjuncts() {
+                match unnest(&a, &f, pos, env) {
+                    Some(p) => a = p,
+                    None => rest.push(Loc(f, pos)),
+                }
+            }
+            wrap(a, rest)
+        }
+        p => Loc(p, pos),
+    }
+}
+
+// 改名失败就放弃
+fn rename(f: FilterExpr, to: &dyn Fn(&Symbol) -> Option<Symbol>) -> Option<FilterExpr> {
+    let mut ok = true;
+    let f = f.map_symbols(&mut |s| match to(&s) {
+        Some(t) => t,
+        None => {
+            ok = false;
+            s
+        }
+    });
+    if ok {
+        Some(f)
+    } else {
+        None
+    }
+}
+
+fn unnest(a: &LocPlan, f: &FilterExpr, pos: Pos, env: &Env) -> Option<LocPlan> {
+    let (c, kind) = match f {
+        FilterExpr::Comp(c) => (c, JoinKind::Semi),
+        FilterExpr::Not(c) => (c, JoinKind::NullAwareAnti),
+        _ => return None,
+    };
+    let (e, sub) = match c.as_ref() {
+        CompExpr::In(e, sub) => (e, sub),
+        _ => return None,
+    };
+    let at = get_plan_table_type(a, env).ok()?;
+    let sub = rewrite(sub.as_ref().clone(), env);
+    let st = get_plan_table_type_in(&sub, env, Some(&at)).ok()?;
+    let col = st.0.keys().next()?.clone();
+    let mut correlated = vec![];
+    let body = strip(sub, &at, env, &mut correlated)?;
+    // 剩下的部分不能再用外面的列
+    let bt = get_plan_table_type(&body, env).ok()?;
+
+    // 下面的名字 -> 连接结果的名字
+    let side = |t: &Record, left: bool| -> Vec<(Symbol, (bool, Symbol))> {
+        t.0.keys().map(|k| (k.clone(), (left, k.clone()))).collect()
+    };
+    // 两边同名的表, 连接后分不清
+    let fields = product_fields(side(&at, true), &at.1, side(&bt, false), &bt.1).ok()?;
+    let names = |left: bool| -> IndexMap<Symbol, Symbol> {
+        fields
+            .iter()
+            .filter(|(_, (l, _))| *l == left)
+            .map(|(k, (_, c))| (c.clone(), k.clone()))
+            .collect()
+    };
+    let (left, right) = (names(true), names(false));
+    let to_left = |s: &Symbol| resolve_field(&left, s).map(|(_, v)| v.clone());
+    let to_any = |s: &Symbol| {
+        resolve_field(&right, s)
+            .or_else(|| resolve_field(&left, s))
+            .map(|(_, v)| v.clone())
+    };
+
+    // in 左边的表达式只用外面的列
+    let mut ok = true;
+    let e = e.as_ref().clone().map_symbols(&mut |s| {
+        to_left(&s).unwrap_or_else(|| {
+            ok = false;
+            s
+        })
+    });
+    if !ok {
+        return None;
+    }
+    let col = Expr::Value(Loc(Value::Symbol(to_any(&col)?), pos));
+    let mut conds = vec![FilterExpr::Comp(Box::new(CompExpr::Eq(
+        Box::new(e),
+        Box::new(col),
+    )))];
+    for f in correlated {
+        conds.push(rename(f, &to_any)?);
+    }
+    let p = Plan::Join(Box::new(a.clone()), Box::new(body), kind, conds);
+    Some(Loc(p, pos))
+}
+
+// 去掉子查询上面的投影和去重, 把用到外面的列的条件拿出来
+fn strip(
+    plan: LocPlan,
+    outer: &Record,
+    env: &Env,
+    correlated: &mut Vec<FilterExpr>,
+) -> Option<LocPlan> {
+    let Loc(p, pos) = plan;
+    match p {
+        Plan::Projection(a, _) | Plan::Distinct(a) => strip(*a, outer, env, correlated),
+        Plan::Selection(a, f) => {
+            let rt = get_plan_table_type_in(&a, env, Some(outer)).ok()?;
+            let body = strip(*a, outer, env, correlated)?;
+            if f.is_positional() {
+                // 拿出来的条件不能越过按位置的选择
+                return if correlated.is_empty() {
+                    Some(Loc(Plan::Selection(Box::new(body), f), pos))
+                } else {
+                    None
+                };
+            }
+            let bt = get_plan_table_type(&body, env).ok()?;
+            let mut local = vec![];
+            for f in f.conjuncts() {
+                let symbols = f.symbols();
+                let inner = |s: &&Symbol| resolve_field(&rt.0, s).is_some();
+                if symbols.iter().all(inner) {
+                    local.push(Loc(f, pos));
+                    continue;
+                }
+                // 外面的名字在去掉投影后不能被里面的列挡住
+                let visible = symbols.iter().all(|s| {
+                    inner(s)
+                        || (resolve_field(&outer.0, s).is_some()
+                            && resolve_field(&bt.0, s).is_none())
+                });
+                if !visible {
+                    return None;
+                }
+                correlated.push(f);
+            }
+            Some(wrap(body, local))
+        }
+        p => Some(Loc(p, pos)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimizer::run_pass, testing::*};
+
+    fn is_in(a: Symbol, sub: LocPlan) -> Box<CompExpr> {
+        Box::new(CompExpr::In(field(a), Box::new(sub)))
+    }
+
+    fn decorrelated(p: LocPlan) -> LocPlan {
+        run_pass(&Decorrelate, p, &env()).unwrap()
+    }
+
+    #[test]
+    fn in_becomes_a_semi_join_and_not_in_an_anti_join() {
+        let sub = || project(table("S"), &[sym("c")]);
+        let p = select(table("V"), FilterExpr::Comp(is_in(sym("d"), sub())));
+        let join = |kind| {
+            plan(Plan::Join(
+                Box::new(table("V")),
+                Box::new(table("S")),
+                kind,
+                vec![eq(sym("d"), sym("c"))],
+            ))
+        };
+        assert_eq!(decorrelated(p), join(JoinKind::Semi));
+        let p = select(table("V"), FilterExpr::Not(is_in(sym("d"), sub())));
+        assert_eq!(decorrelated(p), join(JoinKind::NullAwareAnti));
+    }
+
+    #[test]
+    fn correlated_selections_move_into_the_join_condition() {
+        let sub = project(
+            select(select(table("S"), gt(sym("b"), 10)), eq(sym("c"), sym("d"))),
+            &[sym("c")],
+        );
+        let p = select(table("V"), FilterExpr::Comp(is_in(sym("d"), sub)));
+        let join = Plan::Join(
+            Box::new(table("V")),
+            Box::new(select(table("S"), gt(sym("b"), 10))),
+            JoinKind::Semi,
+            vec![eq(sym("d"), sym("c")), eq(sym("c"), sym("d"))],
+        );
+        assert_eq!(decorrelated(p), plan(join));
+    }
+
+    #[test]
+    fn same_named_sides_are_left_as_a_filter() {
+        let sub = project(table("R"), &[sym("a")]);
+        let p = select(table("R"), FilterExpr::Comp(is_in(sym("a"), sub)));
+        assert_eq!(decorrelated(p.clone()), p);
+    }
+}
@@ -0,0 +1,516 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Equality Saturation of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+pub mod laws;
+
+use std::collections::HashMap;
+
+use super::{
+    cost::{CostModel, DefaultCost},
+    Pass,
+};
+use crate::{
+    structs::{
+        plan::{type_check::get_plan_table_type, LocPlan, Plan},
+        Loc, Pos,
+    },
+    type_system::{Env, Record},
+};
+
+pub use laws::{laws, Law, Pattern, Subst};
+
+pub type ClassId = usize;
+
+/// One operator whose sub plans are e-classes.
+///
+/// `op` keeps the payload of the operator, its own sub plans are only
+/// placeholders. A selection by position is kept whole as a leaf, nothing
+/// below it may be reordered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ENode {
+    pub op: Plan,
+    pub pos: Pos,
+    pub children: Vec<ClassId>,
+}
+
+/// Equal plans. They all have the same fields in the same order.
+#[derive(Debug, Clone)]
+pub struct EClass {
+    pub nodes: Vec<ENode>,
+    pub record: Record,
+}
+
+type Key = (String, Vec<ClassId>);
+
+impl ENode {
+    fn new(plan: &LocPlan) -> (Self, Vec<&LocPlan>) {
+        let Loc(p, pos) = plan;
+        if matches!(p, Plan::Selection(_, f) if f.is_positional()) {
+            let node = ENode {
+                op: p.clone(),
+                pos: *pos,
+                children: vec![],
+            };
+            return (node, vec![]);
+        }
+        let op = p
+            .clone()
+            .map_children(|c| Loc(Plan::Table(String::new()), c.1));
+        let node = ENode {
+            op,
+            pos: *pos,
+            children: vec![],
+        };
+        (node, p.children())
+    }
+
+    fn key(&self) -> Key {
+        let op = match &self.op {
+            Plan::Empty(r) => format!("∅{:?}", r.0.keys().collect::<Vec<_>>()),
+            p if self.children.is_empty() => p.to_string(),
+            p => p.label(),
+        };
+        (op, self.children.clone())
+    }
+
+    /// The node with its sub plans filled in.
+    pub fn build(&self, children: Vec<LocPlan>) -> LocPlan {
+        if self.children.is_empty() {
+            return Loc(self.op.clone(), self.pos);
+        }
+        let mut it = children.into_iter();
+        Loc(
+            self.op.clone().map_children(|c| it.next().unwrap_or(c)),
+            self.pos,
+        )
+    }
+}
+
+pub struct EGraph<'a> {
+    pub env: &'a Env,
+    parent: Vec<ClassId>,
+    classes: Vec<EClass>,
+    memo: HashMap<Key, ClassId>,
+}
+
+impl<'a> EGraph<'a> {
+    pub fn new(env: &'a Env) -> Self {
+        EGraph {
+            env,
+            parent: vec![],
+            classes: vec![],
+            memo: HashMap::new(),
+        }
+    }
+
+    pub fn find(&self, mut id: ClassId) -> ClassId {
+        while self.parent[id] != id {
+            id = self.parent[id];
+        }
+        id
+    }
+
+    pub fn class(&self, id: ClassId) -> &EClass {
+        &self.classes[self.find(id)]
+    }
+
+    pub fn record(&self, id: ClassId) -> &Record {
+        &self.class(id).record
+    }
+
+    /// Ids of the classes that were not merged into another one.
+    pub fn roots(&self) -> Vec<ClassId> {
+        (0..self.classes.len())
+            .filter(|i| self.parent[*i] == *i)
+            .collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.roots()
+            .into_iter()
+            .map(|i| self.classes[i].nodes.len())
+            .sum()
+    }
+
+    pub fn add_plan(&mut self, plan: &LocPlan) -> Option<ClassId> {
+        let (mut node, children) = ENode::new(plan);
+        for c in children {
+            node.children.push(self.add_plan(c)?);
+        }
+        self.add(node)
+    }
+
+    /// Add a node, `None` when it does not type check.
+    pub fn add(&mut self, mut node: ENode) -> Option<ClassId> {
+        for c in node.children.iter_mut() {
+            *c = self.find(*c);
+        }
+        let key = node.key();
+        if let Some(id) = self.memo.get(&key) {
+            return Some(self.find(*id));
+        }
+        // 子节点换成只有表头的空关系来检查类型
+        let probe = node.build(
+            node.children
+                .iter()
+                .map(|c| Loc(Plan::Empty(self.record(*c).clone()), node.pos))
+                .collect(),
+        );
+        let record = get_plan_table_type(&probe, self.env).ok()?;
+        let id = self.classes.len();
+        self.parent.push(id);
+        self.classes.push(EClass {
+            nodes: vec![node],
+            record,
+        });
+        self.memo.insert(key, id);
+        Some(id)
+    }
+
+    /// Merge two classes, refused when their fields differ.
+    pub fn merge(&mut self, a: ClassId, b: ClassId) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b
+            || !self.classes[a]
+                .record
+                .0
+                .keys()
+                .eq(self.classes[b].record.0.keys())
+        {
+            return false;
+        }
+        self.parent[b] = a;
+        let nodes = std::mem::take(&mut self.classes[b].nodes);
+        self.classes[a].nodes.extend(nodes);
+        true
+    }
+
+    // 合并之后子节点的编号变了, 重新找出相同的节点直到不再合并
+    fn rebuild(&mut self) {
+        loop {
+            let mut memo: HashMap<Key, ClassId> = HashMap::new();
+            let mut pending = vec![];
+            for id in self.roots() {
+                let mut nodes = std::mem::take(&mut self.classes[id].nodes);
+                for n in nodes.iter_mut() {
+                    for c in n.children.iter_mut() {
+                        *c = self.find(*c);
+                    }
+                }
+                let mut kept: Vec<ENode> = vec![];
+                for n in nodes {
+                    let key = n.key();
+                    match memo.get(&key) {
+                        Some(other) if *other == id => (),
+                        Some(other) => pending.push((*other, id)),
+                        None => {
+                            memo.insert(key, id);
+                            kept.push(n);
+                        }
+                    }
+                }
+                self.classes[id].nodes = kept;
+            }
+            self.memo = memo;
+            let merged = pending
+                .into_iter()
+                .fold(false, |r, (a, b)| self.merge(a, b) || r);
+            if !merged {
+                return;
+            }
+        }
+    }
+
+    /// Apply every law until nothing changes or a limit is reached.
+    pub fn saturate(&mut self, laws: &[Law], iterations: usize, node_limit: usize) {
+        for _ in 0..iterations {
+            let mut matches = vec![];
+            for law in laws.iter() {
+                for id in self.roots() {
+                    for mut s in self.matches(&law.lhs, id, Subst::default()) {
+                        if law.bind.is_none_or(|bind| bind(&mut s, self)) {
+                            matches.push((law, id, s));
+                        }
+                    }
+                }
+            }
+            let mut changed = false;
+            for (law, id, s) in matches {
+                if self.size() > node_limit {
+                    break;
+                }
+                let pos = self.class(id).nodes[0].pos;
+                if let Some(new) = self.instantiate(&law.rhs, &s, id, pos) {
+                    changed |= self.merge(id, new);
+                }
+            }
+            self.rebuild();
+            if !changed || self.size() > node_limit {
+                return;
+            }
+        }
+    }
+
+    fn matches(&self, p: &Pattern, id: ClassId, s: Subst) -> Vec<Subst> {
+        let id = self.find(id);
+        if let Pattern::Var(v) = p {
+            return match s.classes.get(v) {
+                Some(c) if self.find(*c) == id => vec![s],
+                Some(_) => vec![],
+                None => {
+                    let mut s = s;
+                    s.classes.insert(v, id);
+                    vec![s]
+                }
+            };
+        }
+        let mut r = vec![];
+        for n in self.classes[id].nodes.iter() {
+            if n.children.is_empty() {
+                continue;
+            }
+            let mut s = s.clone();
+            let children: Vec<&Pattern> = match (p, &n.op) {
+                (Pattern::Product(a, b), Plan::Product(_, _))
+                | (Pattern::Union(a, b), Plan::Union(_, _))
+                | (Pattern::Intersect(a, b), Plan::Intersect(_, _))
+                | (Pattern::Difference(a, b), Plan::Difference(_, _)) => vec![a, b],
+                (Pattern::Selection(a, v), Plan::Selection(_, f)) => {
+                    if !bind(&mut s.filters, v, f.as_ref()) {
+                        continue;
+                    }
+                    vec![a]
+                }
+                (Pattern::Projection(a, v), Plan::Projection(_, names)) => {
+                    if !bind(&mut s.names, v, names) {
+                        continue;
+                    }
+                    vec![a]
+                }
+                _ => continue,
+            };
+            let mut found = vec![s];
+            for (p, c) in children.into_iter().zip(n.children.iter()) {
+                found = found
+                    .into_iter()
+                    .flat_map(|s| self.matches(p, *c, s))
+                    .collect();
+            }
+            r.extend(found);
+        }
+        r
+    }
+
+    // root: 左边匹配到的类, Reorder 按它的列排
+    fn instantiate(&mut self, p: &Pattern, s: &Subst, root: ClassId, pos: Pos) -> Option<ClassId> {
+        let hole = || Box::new(Loc(Plan::Table(String::new()), pos));
+        let (op, children): (Plan, Vec<&Pattern>) = match p {
+            Pattern::Var(v) => return s.classes.get(v).map(|c| self.find(*c)),
+            Pattern::Product(a, b) => (Plan::Product(hole(), hole()), vec![a, b]),
+            Pattern::Union(a, b) => (Plan::Union(hole(), hole()), vec![a, b]),
+            Pattern::Intersect(a, b) => (Plan::Intersect(hole(), hole()), vec![a, b]),
+            Pattern::Difference(a, b) => (Plan::Difference(hole(), hole()), vec![a, b]),
+            Pattern::Selection(a, v) => {
+                let f = s.filters.get(v)?.clone();
+                (Plan::Selection(hole(), Box::new(f)), vec![a])
+            }
+            Pattern::Projection(a, v) => {
+                let names = s.names.get(v)?.clone();
+                (Plan::Projection(hole(), names), vec![a])
+            }
+            Pattern::Reorder(a) => {
+                let names = self.record(root).0.keys().cloned().collect();
+                (Plan::Projection(hole(), names), vec![a])
+            }
+        };
+        let children = children
+            .into_iter()
+            .map(|c| self.instantiate(c, s, root, pos))
+            .collect::<Option<_>>()?;
+        self.add(ENode { op, pos, children })
+    }
+
+    /// The cheapest plan of the class.
+    pub fn extract(&self, root: ClassId, model: &dyn CostModel) -> Option<LocPlan> {
+        // 类 -> (代价, 行数, 计划)
+        let mut best: HashMap<ClassId, (f64, f64, LocPlan)> = HashMap::new();
+        let roots = self.roots();
+        for _ in 0..=roots.len() {
+            let mut changed = false;
+            for id in roots.iter() {
+                for n in self.classes[*id].nodes.iter() {
+                    let children: Option<Vec<&(f64, f64, LocPlan)>> = n
+                        .children
+                        .iter()
+                        .map(|c| best.get(&self.find(*c)))
+                        .collect();
+                    let children = match children {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    let plan = n.build(children.iter().map(|c| c.2.clone()).collect());
+                    let rows = model.rows(&plan, self.env);
+                    let local = match (&n.op, children.as_slice()) {
+                        (Plan::Product(_, _), [l, r]) | (Plan::Join(_, _, _, _), [l, r]) => {
+                            model.join_cost(l.1, r.1, rows)
+                        }
+                        (_, []) => rows,
+                        (_, c) => c.iter().map(|c| c.1).sum(),
+                    };
+                    let cost = local + children.iter().map(|c| c.0).sum::<f64>();
+                    if best.get(id).is_none_or(|b| cost < b.0) {
+                        best.insert(*id, (cost, rows, plan));
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        best.remove(&self.find(root)).map(|b| b.2)
+    }
+}
+
+fn bind<T: Clone + PartialEq>(vars: &mut HashMap<&'static str, T>, v: &'static str, x: &T) -> bool {
+    match vars.get(v) {
+        Some(y) => y == x,
+        None => {
+            vars.insert(v, x.clone());
+            true
+        }
+    }
+}
+
+/// Optimize by equality saturation.
+///
+/// The plan is put into an e-graph, the laws are applied until nothing new
+/// is found, then the cheapest equal plan is taken. A plan whose fields
+/// come out different, for example because a relation name used to qualify
+/// a column changed, is not taken. The laws that project back to the
+/// matched fields keep every column, so they hold under set semantics too.
+pub struct Saturation {
+    pub model: Box<dyn CostModel>,
+    pub laws: Vec<Law>,
+    pub iterations: usize,
+    pub node_limit: usize,
+}
+
+impl Default for Saturation {
+    fn default() -> Self {
+        Saturation {
+            model: Box::new(DefaultCost),
+            laws: laws(),
+            iterations: 8,
+            node_limit: 4096,
+        }
+    }
+}
+
+impl Pass for Saturation {
+    fn name(&self) -> &'static str {
+        "equality saturation"
+    }
+
+    fn run(&self, plan: LocPlan, env: &Env) -> LocPlan {
+        let before = match get_plan_table_type(&plan, env) {
+            Ok(r) => r,
+            Err(_) => return plan,
+        };
+        let mut g = EGraph::new(env);
+        let root = match g.add_plan(&plan) {
+            Some(root) => root,
+            None => return plan,
+        };
+        g.saturate(&self.laws, self.iterations, self.node_limit);
+        match g.extract(root, self.model.as_ref()) {
+            Some(r) if same_fields(&r, &before, env) => r,
+            _ => plan,
+        }
+    }
+}
+
+fn same_fields(plan: &LocPlan, before: &Record, env: &Env) -> bool {
+    get_plan_table_type(plan, env).is_ok_and(|r| r.0.keys().eq(before.0.keys()) && r == *before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimizer::run_pass, physical::Semantics, testing::*};
+
+    fn union(a: LocPlan, b: LocPlan) -> LocPlan {
+        plan(Plan::Union(Box::new(a), Box::new(b)))
+    }
+
+    fn product(a: LocPlan, b: LocPlan) -> LocPlan {
+        plan(Plan::Product(Box::new(a), Box::new(b)))
+    }
+
+    #[test]
+    fn equal_sub_plans_share_a_class() {
+        let env = env();
+        let mut g = EGraph::new(&env);
+        let root = g.add_plan(&union(table("T"), table("T"))).unwrap();
+        assert_eq!(g.size(), 2);
+        assert_eq!(g.class(root).nodes[0].children, vec![0, 0]);
+        assert_eq!(g.add_plan(&table("T")), Some(0));
+    }
+
+    #[test]
+    fn commuted_plans_land_in_the_same_class() {
+        let env = env();
+        let mut g = EGraph::new(&env);
+        let small = || select(table("T"), gt(sym("a"), 1));
+        let root = g.add_plan(&union(table("T"), small())).unwrap();
+        g.saturate(&laws(), 8, 4096);
+        let other = g.add_plan(&union(small(), table("T"))).unwrap();
+        assert_eq!(g.find(root), g.find(other));
+    }
+
+    #[test]
+    fn saturation_keeps_the_fields_of_the_plan() {
+        let env = env();
+        let p = select(
+            product(product(table("T"), table("V")), table("S")),
+            eq(sym("c"), sym("d")),
+        );
+        let before = get_plan_table_type(&p, &env).unwrap();
+        let r = run_pass(&Saturation::default(), p, &env).unwrap();
+        assert_eq!(get_plan_table_type(&r, &env).unwrap(), before);
+    }
+
+    #[test]
+    fn reordered_products_keep_rows_under_both_semantics() {
+        let (_, env) = fixture();
+        let tv = product(table("T"), table("V"));
+        let p = project(tv.clone(), &[sym("a"), sym("d")]);
+        for semantics in [Semantics::Set, Semantics::Bag] {
+            check_pass(&Saturation::default(), &p, semantics);
+            check_pass(&Saturation::default(), &tv, semantics);
+        }
+        // 交换以后要投影回原来的顺序
+        let mut g = EGraph::new(&env);
+        let root = g.add_plan(&tv).unwrap();
+        g.saturate(&laws(), 8, 4096);
+        let vt = Plan::Projection(
+            Box::new(product(table("V"), table("T"))),
+            vec![sym("a"), sym("d")],
+        );
+        assert_eq!(g.add_plan(&plan(vt)).map(|c| g.find(c)), Some(g.find(root)));
+    }
+}
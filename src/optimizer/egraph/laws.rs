@@ -0,0 +1,287 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Algebraic Laws of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::collections::HashMap;
+
+use indexmap::IndexMap;
+
+use super::{ClassId, EGraph};
+use crate::{
+    structs::{plan::FilterExpr, Symbol},
+    type_system::{product_fields, resolve_field, Record},
+};
+
+/// A plan with holes. `Var` matches any e-class, the names given to
+/// selections and projections bind their filter and their field names.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Var(&'static str),
+    Product(Box<Pattern>, Box<Pattern>),
+    Union(Box<Pattern>, Box<Pattern>),
+    Intersect(Box<Pattern>, Box<Pattern>),
+    Difference(Box<Pattern>, Box<Pattern>),
+    Selection(Box<Pattern>, &'static str),
+    Projection(Box<Pattern>, &'static str),
+    /// Only on the right side: project back to the fields of the matched
+    /// plan, in their order.
+    Reorder(Box<Pattern>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Subst {
+    pub classes: HashMap<&'static str, ClassId>,
+    pub filters: HashMap<&'static str, FilterExpr>,
+    pub names: HashMap<&'static str, Vec<Symbol>>,
+}
+
+/// `lhs => rhs`. `bind` computes the filters and names only found on the
+/// right side, or rejects the match by returning false.
+pub struct Law {
+    pub name: &'static str,
+    pub lhs: Pattern,
+    pub rhs: Pattern,
+    pub bind: Option<fn(&mut Subst, &EGraph) -> bool>,
+}
+
+impl Law {
+    pub fn new(name: &'static str, lhs: Pattern, rhs: Pattern) -> Self {
+        Law {
+            name,
+            lhs,
+            rhs,
+            bind: None,
+        }
+    }
+
+    pub fn with(mut self, bind: fn(&mut Subst, &EGraph) -> bool) -> Self {
+        self.bind = Some(bind);
+        self
+    }
+}
+
+fn var(v: &'static str) -> Pattern {
+    Pattern::Var(v)
+}
+
+fn product(a: Pattern, b: Pattern) -> Pattern {
+    Pattern::Product(Box::new(a), Box::new(b))
+}
+
+fn union(a: Pattern, b: Pattern) -> Pattern {
+    Pattern::Union(Box::new(a), Box::new(b))
+}
+
+fn intersect(a: Pattern, b: Pattern) -> Pattern {
+    Pattern::Intersect(Box::new(a), Box::new(b))
+}
+
+fn difference(a: Pattern, b: Pattern) -> Pattern {
+    Pattern::Difference(Box::new(a), Box::new(b))
+}
+
+fn select(a: Pattern, f: &'static str) -> Pattern {
+    Pattern::Selection(Box::new(a), f)
+}
+
+fn project(a: Pattern, names: &'static str) -> Pattern {
+    Pattern::Projection(Box::new(a), names)
+}
+
+fn reorder(a: Pattern) -> Pattern {
+    Pattern::Reorder(Box::new(a))
+}
+
+/// The laws used by default.
+pub fn laws() -> Vec<Law> {
+    let (a, b, c) = (|| var("a"), || var("b"), || var("c"));
+    vec![
+        Law::new(
+            "product-commute",
+            product(a(), b()),
+            reorder(product(b(), a())),
+        ),
+        Law::new(
+            "product-associate",
+            product(product(a(), b()), c()),
+            reorder(product(a(), product(b(), c()))),
+        ),
+        Law::new(
+            "product-associate-right",
+            product(a(), product(b(), c())),
+            reorder(product(product(a(), b()), c())),
+        ),
+        Law::new("union-commute", union(a(), b()), union(b(), a())),
+        Law::new(
+            "union-associate",
+            union(union(a(), b()), c()),
+            union(a(), union(b(), c())),
+        ),
+        Law::new(
+            "intersect-commute",
+            intersect(a(), b()),
+            intersect(b(), a()),
+        ),
+        Law::new(
+            "intersect-associate",
+            intersect(intersect(a(), b()), c()),
+            intersect(a(), intersect(b(), c())),
+        ),
+        Law::new(
+            "selection-split",
+            select(a(), "f"),
+            select(select(a(), "h"), "g"),
+        )
+        .with(split),
+        Law::new(
+            "selection-merge",
+            select(select(a(), "g"), "f"),
+            select(a(), "h"),
+        )
+        .with(merge),
+        Law::new(
+            "selection-commute",
+            select(select(a(), "g"), "f"),
+            select(select(a(), "f"), "g"),
+        ),
+        Law::new(
+            "projection-cascade",
+            project(project(a(), "m"), "n"),
+            project(a(), "n"),
+        ),
+        Law::new(
+            "selection-union",
+            select(union(a(), b()), "f"),
+            union(select(a(), "f"), select(b(), "g")),
+        )
+        .with(to_right),
+        Law::new(
+            "selection-intersect",
+            select(intersect(a(), b()), "f"),
+            intersect(select(a(), "f"), select(b(), "g")),
+        )
+        .with(to_right),
+        Law::new(
+            "selection-difference",
+            select(difference(a(), b()), "f"),
+            difference(select(a(), "f"), select(b(), "g")),
+        )
+        .with(to_right),
+        Law::new(
+            "selection-product-left",
+            select(product(a(), b()), "f"),
+            product(select(a(), "g"), b()),
+        )
+        .with(|s, g| to_side(s, g, true)),
+        Law::new(
+            "selection-product-right",
+            select(product(a(), b()), "f"),
+            product(a(), select(b(), "g")),
+        )
+        .with(|s, g| to_side(s, g, false)),
+    ]
+}
+
+// σ[f and g] => σ[f](σ[g])
+fn split(s: &mut Subst, _: &EGraph) -> bool {
+    let mut v = match s.filters.get("f") {
+        Some(FilterExpr::And(v)) if v.len() > 1 => v.clone(),
+        _ => return false,
+    };
+    let first = v.remove(0);
+    let rest = if v.len() == 1 {
+        FilterExpr::Comp(v.remove(0))
+    } else {
+        FilterExpr::And(v)
+    };
+    s.filters.insert("g", FilterExpr::Comp(first));
+    s.filters.insert("h", rest);
+    true
+}
+
+// 只有比较能放进 and 里
+fn merge(s: &mut Subst, _: &EGraph) -> bool {
+    let mut v = vec![];
+    for f in [&s.filters["f"], &s.filters["g"]] {
+        for f in f.clone().conjuncts() {
+            match f {
+                FilterExpr::Comp(c) => v.push(c),
+                _ => return false,
+            }
+        }
+    }
+    s.filters.insert("h", FilterExpr::And(v));
+    true
+}
+
+// 右边按位置换成自己的名字
+fn to_right(s: &mut Subst, g: &EGraph) -> bool {
+    let (a, b) = (g.record(s.classes["a"]), g.record(s.classes["b"]));
+    let f = s.filters["f"].clone();
+    match rename(f, &|k| {
+        let (k, _) = resolve_field(&a.0, k)?;
+        b.0.get_index(a.0.get_index_of(k)?).map(|(k, _)| k.clone())
+    }) {
+        Some(f) => {
+            s.filters.insert("g", f);
+            true
+        }
+        None => false,
+    }
+}
+
+// 条件只用到一边的列时放到那一边
+fn to_side(s: &mut Subst, g: &EGraph, left: bool) -> bool {
+    let (a, b): (&Record, &Record) = (g.record(s.classes["a"]), g.record(s.classes["b"]));
+    let side = |t: &Record, left: bool| -> Vec<(Symbol, (bool, Symbol))> {
+        t.0.keys().map(|k| (k.clone(), (left, k.clone()))).collect()
+    };
+    let names: IndexMap<Symbol, (bool, Symbol)> =
+        match product_fields(side(a, true), &a.1, side(b, false), &b.1) {
+            Ok(names) => names.into_iter().collect(),
+            Err(_) => return false,
+        };
+    let f = &s.filters["f"];
+    if f.symbols().is_empty() {
+        return false;
+    }
+    match rename(f.clone(), &|k| match resolve_field(&names, k) {
+        Some((_, (l, child))) if *l == left => Some(child.clone()),
+        _ => None,
+    }) {
+        Some(f) => {
+            s.filters.insert("g", f);
+            true
+        }
+        None => false,
+    }
+}
+
+fn rename(f: FilterExpr, to: &dyn Fn(&Symbol) -> Option<Symbol>) -> Option<FilterExpr> {
+    let mut ok = true;
+    let f = f.map_symbols(&mut |s| match to(&s) {
+        Some(t) => t,
+        None => {
+            ok = false;
+            s
+        }
+    });
+    if ok {
+        Some(f)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,523 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Join Order of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use super::{
+    cost::{CostModel, DefaultCost},
+    Pass,
+};
+use crate::{
+    structs::{
+        plan::{type_check::get_plan_table_type, FilterExpr, JoinKind, LocPlan, Plan},
+        Loc, Pos, Symbol,
+    },
+    type_system::{product_fields, resolve_field, Env, Record},
+};
+
+/// Reorder chains of Product and inner Join.
+///
+/// Up to `dp_limit` relations are enumerated by dynamic programming over
+/// subsets, larger chains are joined greedily. Join conditions are placed on
+/// the lowest join that sees all of their fields, and a Projection on top
+/// restores the original column order. An order is only taken when it is
+/// cheaper than the original one and keeps every output name: a Projection
+/// cannot rename, so orders that qualify a clashing field differently are
+/// dropped. The Projection keeps every column, so with set semantics it
+/// removes no rows either: the joins below already give sets.
+pub struct JoinOrder {
+    pub model: Box<dyn CostModel>,
+    pub dp_limit: usize,
+}
+
+impl Default for JoinOrder {
+    fn default() -> Self {
+        JoinOrder {
+            model: Box::new(DefaultCost),
+            dp_limit: 8,
+        }
+    }
+}
+
+impl Pass for JoinOrder {
+    fn name(&self) -> &'static str {
+        "join order"
+    }
+
+    fn run(&self, plan: LocPlan, env: &Env) -> LocPlan {
+        self.reorder(plan, false, env)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tree {
+    Leaf(usize),
+    Join(Box<Tree>, Box<Tree>),
+}
+
+impl Tree {
+    fn mask(&self) -> u64 {
+        match self {
+            Tree::Leaf(i) => 1 << i,
+            Tree::Join(a, b) => a.mask() | b.mask(),
+        }
+    }
+}
+
+// 一串连接: 叶子, 条件(用整串输出的名字)和每个条件用到的叶子
+struct Region {
+    leaves: Vec<LocPlan>,
+    offsets: Vec<usize>,
+    record: Record,
+    conds: Vec<(FilterExpr, u64)>,
+    rows: Vec<f64>,
+    selectivity: Vec<f64>,
+}
+
+impl Region {
+    fn rows(&self, mask: u64) -> f64 {
+        let leaves: f64 = (0..self.leaves.len())
+            .filter(|i| mask & (1 << i) != 0)
+            .map(|i| self.rows[i])
+            .product();
+        let conds: f64 = self
+            .conds
+            .iter()
+            .zip(&self.selectivity)
+            .filter(|((_, m), _)| *m != 0 && m & !mask == 0)
+            .map(|(_, s)| *s)
+            .product();
+        leaves * conds
+    }
+
+    fn leaf_of(&self, column: usize) -> usize {
+        self.offsets.iter().rposition(|o| *o <= column).unwrap()
+    }
+}
+
+// 按位置算连接后的名字, 带上每一列在整串输出里的序号
+fn joined_names(
+    a: &Record,
+    ai: &[usize],
+    b: &Record,
+    bi: &[usize],
+) -> Option<Vec<(Symbol, usize)>> {
+    let zip = |t: &Record, i: &[usize]| -> Vec<(Symbol, usize)> {
+        t.0.keys().cloned().zip(i.iter().copied()).collect()
+    };
+    product_fields(zip(a, ai), &a.1, zip(b, bi), &b.1).ok()
+}
+
+fn rename(f: FilterExpr, from: &Record, to: &[(Symbol, usize)]) -> FilterExpr {
+    f.map_symbols(&mut |s| {
+        resolve_field(&from.0, &s)
+            .and_then(|(k, _)| from.0.get_index_of(k))
+            .and_then(|i| to.get(i))
+            .map(|(k, _)| k.clone())
+            .unwrap_or(s)
+    })
+}
+
+fn resolvable(f: &FilterExpr, t: &Record) -> bool {
+    f.symbols()
+        .into_iter()
+        .all(|s| resolve_field(&t.0, s).is_some())
+}
+
+impl JoinOrder {
+    // keep_name: 上面会用这个关系的名字给重名的列加前缀
+    fn reorder(&self, plan: LocPlan, keep_name: bool, env: &Env) -> LocPlan {
+        match &plan.0 {
+            Plan::Product(_, _) | Plan::Join(_, _, JoinKind::Inner, _) => {
+                if let Some(r) = self.reorder_region(&plan, keep_name, env) {
+                    return r;
+                }
+                let Loc(p, pos) = plan;
+                Loc(p.map_children(|c| self.reorder(c, true, env)), pos)
+            }
+            Plan::Join(_, _, _, _) => {
+                let Loc(p, pos) = plan;
+                Loc(p.map_children(|c| self.reorder(c, true, env)), pos)
+            }
+            _ => {
+                let Loc(p, pos) = plan;
+                Loc(p.map_children(|c| self.reorder(c, keep_name, env)), pos)
+            }
+        }
+    }
+
+    // 条件用这个节点输出的名字
+    fn flatten(
+        &self,
+        plan: &LocPlan,
+        leaves: &mut Vec<LocPlan>,
+        env: &Env,
+    ) -> Option<(Tree, Vec<FilterExpr>)> {
+        let (a, b, fs) = match &plan.0 {
+            Plan::Product(a, b) => (a, b, vec![]),
+            Plan::Join(a, b, JoinKind::Inner, fs) => (a, b, fs.clone()),
+            _ => {
+                leaves.push(plan.clone());
+                return Some((Tree::Leaf(leaves.len() - 1), vec![]));
+            }
+        };
+        let (at, ac) = self.flatten(a, leaves, env)?;
+        let (bt, bc) = self.flatten(b, leaves, env)?;
+        let ar = get_plan_table_type(a, env).ok()?;
+        let br = get_plan_table_type(b, env).ok()?;
+        let ai: Vec<usize> = (0..ar.0.len()).collect();
+        let bi: Vec<usize> = (ar.0.len()..ar.0.len() + br.0.len()).collect();
+        let names = joined_names(&ar, &ai, &br, &bi)?;
+        if !ac.iter().all(|f| resolvable(f, &ar)) || !bc.iter().all(|f| resolvable(f, &br)) {
+            return None;
+        }
+        // 右边的序号接在左边后面
+        let right: Vec<(Symbol, usize)> = names[ar.0.len()..].to_vec();
+        let mut conds: Vec<FilterExpr> = ac.into_iter().map(|f| rename(f, &ar, &names)).collect();
+        conds.extend(bc.into_iter().map(|f| rename(f, &br, &right)));
+        conds.extend(fs.into_iter().flat_map(|f| f.conjuncts()));
+        Some((Tree::Join(Box::new(at), Box::new(bt)), conds))
+    }
+
+    fn region(&self, plan: &LocPlan, env: &Env) -> Option<(Region, Tree)> {
+        let mut leaves = vec![];
+        let (tree, conds) = self.flatten(plan, &mut leaves, env)?;
+        if leaves.len() > 63 {
+            return None;
+        }
+        let leaves: Vec<LocPlan> = leaves
+            .into_iter()
+            .map(|l| self.reorder(l, true, env))
+            .collect();
+        let record = get_plan_table_type(plan, env).ok()?;
+        let mut offsets = vec![];
+        let mut width = 0;
+        for l in leaves.iter() {
+            offsets.push(width);
+            width += get_plan_table_type(l, env).ok()?.0.len();
+        }
+        // 名字重复了就分不清是哪一列
+        if width != record.0.len() {
+            return None;
+        }
+        let mut region = Region {
+            rows: leaves.iter().map(|l| self.model.rows(l, env)).collect(),
+            selectivity: conds
+                .iter()
+                .map(|f| self.model.selectivity(f, plan, env))
+                .collect(),
+            leaves,
+            offsets,
+            record,
+            conds: vec![],
+        };
+        for f in conds {
+            let mut mask = 0;
+            for s in f.symbols() {
+                let (k, _) = resolve_field(&region.record.0, s)?;
+                let i = region.record.0.get_index_of(k)?;
+                mask |= 1 << region.leaf_of(i);
+            }
+            region.conds.push((f, mask));
+        }
+        Some((region, tree))
+    }
+
+    fn cost(&self, region: &Region, tree: &Tree) -> f64 {
+        match tree {
+            Tree::Leaf(_) => 0.0,
+            Tree::Join(a, b) => {
+                let join = self.model.join_cost(
+                    region.rows(a.mask()),
+                    region.rows(b.mask()),
+                    region.rows(tree.mask()),
+                );
+                self.cost(region, a) + self.cost(region, b) + join
+            }
+        }
+    }
+
+    fn dynamic_programming(&self, region: &Region) -> Tree {
+        let n = region.leaves.len();
+        let full: u64 = (1 << n) - 1;
+        let mut best: Vec<Option<(f64, Tree)>> = vec![None; 1 << n];
+        for i in 0..n {
+            best[1 << i] = Some((0.0, Tree::Leaf(i)));
+        }
+        // 子集的编号总比自己小
+        for mask in 1..=full {
+            if mask.count_ones() < 2 {
+                continue;
+            }
+            let out = region.rows(mask);
+            let mut sub = (mask - 1) & mask;
+            while sub != 0 {
+                let rest = mask ^ sub;
+                if let (Some((lc, l)), Some((rc, r))) = (&best[sub as usize], &best[rest as usize])
+                {
+                    let cost = lc
+                        + rc
+                        + self
+                            .model
+                            .join_cost(region.rows(sub), region.rows(rest), out);
+                    if best[mask as usize].as_ref().is_none_or(|(c, _)| cost < *c) {
+                        let tree = Tree::Join(Box::new(l.clone()), Box::new(r.clone()));
+                        best[mask as usize] = Some((cost, tree));
+                    }
+                }
+                sub = (sub - 1) & mask;
+            }
+        }
+        best[full as usize].take().unwrap().1
+    }
+
+    fn greedy(&self, region: &Region) -> Tree {
+        let mut trees: Vec<(f64, Tree)> = (0..region.leaves.len())
+            .map(|i| (0.0, Tree::Leaf(i)))
+            .collect();
+        while trees.len() > 1 {
+            let mut best: Option<(f64, usize, usize)> = None;
+            for (i, (ic, it)) in trees.iter().enumerate() {
+                for (j, (jc, jt)) in trees.iter().enumerate() {
+                    if i == j {
+                        continue;
+                    }
+                    let (l, r) = (it.mask(), jt.mask());
+                    let cost = ic
+                        + jc
+                        + self
+                            .model
+                            .join_cost(region.rows(l), region.rows(r), region.rows(l | r));
+                    if best.is_none_or(|(c, _, _)| cost < c) {
+                        best = Some((cost, i, j));
+                    }
+                }
+            }
+            let (cost, i, j) = best.unwrap();
+            let (l, r) = if i < j {
+                let r = trees.remove(j);
+                (trees.remove(i), r)
+            } else {
+                let l = trees.remove(i);
+                (l, trees.remove(j))
+            };
+            // 放回原来的位置, 相邻的还是相邻的
+            trees.insert(i.min(j), (cost, Tree::Join(Box::new(l.1), Box::new(r.1))));
+        }
+        trees.pop().unwrap().1
+    }
+
+    // 返回计划和每一列在整串输出里的序号
+    fn build(
+        &self,
+        region: &Region,
+        tree: &Tree,
+        pos: Pos,
+        env: &Env,
+    ) -> Option<(LocPlan, Vec<usize>)> {
+        match tree {
+            Tree::Leaf(i) => {
+                let leaf = region.leaves[*i].clone();
+                let width = get_plan_table_type(&leaf, env).ok()?.0.len();
+                let offset = region.offsets[*i];
+                Some((leaf, (offset..offset + width).collect()))
+            }
+            Tree::Join(a, b) => {
+                let (ap, ai) = self.build(region, a, pos, env)?;
+                let (bp, bi) = self.build(region, b, pos, env)?;
+                let at = get_plan_table_type(&ap, env).ok()?;
+                let bt = get_plan_table_type(&bp, env).ok()?;
+                let names = joined_names(&at, &ai, &bt, &bi)?;
+                let (mask, am, bm) = (tree.mask(), a.mask(), b.mask());
+                let full = (1u64 << region.leaves.len()) - 1;
+                // 整串输出的名字 -> 这里的名字
+                let local: Vec<(Symbol, usize)> = {
+                    let mut v: Vec<(Symbol, usize)> = (0..region.record.0.len())
+                        .map(|i| (Symbol(String::new(), None), i))
+                        .collect();
+                    for (k, i) in names.iter() {
+                        v[*i].0 = k.clone();
+                    }
+                    v
+                };
+                let conds: Vec<FilterExpr> = region
+                    .conds
+                    .iter()
+                    .filter(|(_, m)| {
+                        if *m == 0 {
+                            mask == full
+                        } else {
+                            m & !mask == 0 && m & !am != 0 && m & !bm != 0
+                        }
+                    })
+                    .map(|(f, _)| rename(f.clone(), &region.record, &local))
+                    .collect();
+                let (a, b) = (Box::new(ap), Box::new(bp));
+                let p = if conds.is_empty() {
+                    Plan::Product(a, b)
+                } else {
+                    Plan::Join(a, b, JoinKind::Inner, conds)
+                };
+                Some((Loc(p, pos), names.into_iter().map(|(_, i)| i).collect()))
+            }
+        }
+    }
+
+    fn reorder_region(&self, plan: &LocPlan, keep_name: bool, env: &Env) -> Option<LocPlan> {
+        let (region, original) = self.region(plan, env)?;
+        let chosen = if region.leaves.len() <= self.dp_limit {
+            self.dynamic_programming(&region)
+        } else {
+            self.greedy(&region)
+        };
+        let tree = if self.cost(&region, &chosen) < self.cost(&region, &original) {
+            chosen
+        } else {
+            original.clone()
+        };
+        let (built, index) = self.build(&region, &tree, plan.1, env)?;
+        let restored = if index.iter().enumerate().all(|(i, j)| i == *j) {
+            built
+        } else {
+            let names = region.record.0.keys().cloned().collect();
+            Loc(Plan::Projection(Box::new(built), names), plan.1)
+        };
+        // 顺序换了以后名字可能不一样, 类型检查不过的不要
+        let same = get_plan_table_type(&restored, env).is_ok_and(|t| {
+            t.0 == region.record.0
+                && t.0.keys().eq(region.record.0.keys())
+                && (!keep_name || t.1 == region.record.1)
+        });
+        if same {
+            return Some(restored);
+        }
+        if tree == original {
+            return None;
+        }
+        let (built, _) = self.build(&region, &original, plan.1, env)?;
+        Some(built)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimizer::run_pass, physical::Semantics, testing::*};
+
+    fn product(a: LocPlan, b: LocPlan) -> LocPlan {
+        plan(Plan::Product(Box::new(a), Box::new(b)))
+    }
+
+    // 三张表的各种顺序, 最上面用等值条件连起来
+    fn chains() -> Vec<LocPlan> {
+        let mut v = vec![];
+        for [a, b, c] in [["R", "S", "T"], ["T", "R", "S"], ["S", "T", "R"]] {
+            let left = product(product(table(a), table(b)), table(c));
+            let right = product(table(a), product(table(b), table(c)));
+            for p in [left, right] {
+                v.push(select(p.clone(), eq(sym("c"), sym("c"))));
+                v.push(plan(Plan::Join(
+                    Box::new(p),
+                    Box::new(table("U")),
+                    JoinKind::Inner,
+                    vec![eq(Symbol("R".into(), Some("b".into())), sym("c"))],
+                )));
+            }
+        }
+        // 换了顺序以后 R*T.b 就没有了, 这样的顺序不要
+        let rt = product(table("R"), table("T"));
+        v.push(plan(Plan::Join(
+            Box::new(rt),
+            Box::new(table("S")),
+            JoinKind::Inner,
+            vec![eq(
+                Symbol("R*T".into(), Some("b".into())),
+                Symbol("S".into(), Some("b".into())),
+            )],
+        )));
+        // 先做笛卡尔积的顺序, 换了以后要投影恢复列的顺序
+        let st = product(table("S"), table("T"));
+        v.push(plan(Plan::Join(
+            Box::new(st),
+            Box::new(table("V")),
+            JoinKind::Inner,
+            vec![eq(sym("c"), sym("d"))],
+        )));
+        v
+    }
+
+    #[test]
+    fn reordered_joins_keep_rows() {
+        for semantics in [Semantics::Set, Semantics::Bag] {
+            let mut restored = 0;
+            for p in chains() {
+                let after = check_pass(&JoinOrder::default(), &p, semantics);
+                if matches!(after.0, Plan::Projection(..)) {
+                    restored += 1;
+                }
+            }
+            assert!(restored > 0, "{:?}", semantics);
+        }
+    }
+
+    fn join(a: LocPlan, b: LocPlan, f: FilterExpr) -> LocPlan {
+        plan(Plan::Join(
+            Box::new(a),
+            Box::new(b),
+            JoinKind::Inner,
+            vec![f],
+        ))
+    }
+
+    // (S × T) ⋈[c = d] V
+    fn product_first() -> LocPlan {
+        join(
+            product(table("S"), table("T")),
+            table("V"),
+            eq(sym("c"), sym("d")),
+        )
+    }
+
+    #[test]
+    fn product_is_joined_last() {
+        let after = run_pass(&JoinOrder::default(), product_first(), &env()).unwrap();
+        let vs = join(table("V"), table("S"), eq(sym("c"), sym("d")));
+        let names = [sym("b"), sym("c"), sym("a"), sym("d")];
+        assert_eq!(after, project(product(vs, table("T")), &names));
+    }
+
+    // 每种顺序都一样贵
+    struct Flat;
+
+    impl CostModel for Flat {
+        fn rows(&self, _: &LocPlan, _: &Env) -> f64 {
+            1.0
+        }
+
+        fn selectivity(&self, _: &FilterExpr, _: &LocPlan, _: &Env) -> f64 {
+            1.0
+        }
+    }
+
+    #[test]
+    fn order_is_kept_unless_the_model_finds_a_cheaper_one() {
+        let pass = JoinOrder {
+            model: Box::new(Flat),
+            ..Default::default()
+        };
+        let p = product_first();
+        assert_eq!(run_pass(&pass, p.clone(), &env()).unwrap(), p);
+    }
+}
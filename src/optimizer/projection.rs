@@ -0,0 +1,434 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Projection Pushdown of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use indexmap::IndexMap;
+
+use super::Pass;
+use crate::{
+    physical::Semantics,
+    structs::{
+        plan::{
+            type_check::{node_type, plan_schemas, Schemas},
+            LocPlan, Plan,
+        },
+        plan_group::ReduceOperator,
+        Aggregate, Loc, Pos, Symbol,
+    },
+    type_system::{product_fields, resolve_field, Env, Record},
+};
+
+/// Prune the columns nobody above uses.
+///
+/// The needed columns are computed from the top: selection predicates, join
+/// conditions, sort keys and reduce fields are added on the way down, and
+/// every table is narrowed by a Projection as soon as it is read.
+/// With bag semantics projections keep the number of rows. With set
+/// semantics they remove duplicates, so nothing under a reduce or a
+/// positional filter is narrowed and the projections of the plan are kept.
+/// A result the type checker does not accept gives back the input plan.
+#[derive(Default)]
+pub struct ProjectionPushdown {
+    pub semantics: Semantics,
+}
+
+impl Pass for ProjectionPushdown {
+    fn name(&self) -> &'static str {
+        "projection pushdown"
+    }
+
+    fn run(&self, plan: LocPlan, env: &Env) -> LocPlan {
+        let s = plan_schemas(&plan, env);
+        let t = match s.record.clone() {
+            Some(t) => t,
+            None => return plan,
+        };
+        let need: Vec<Symbol> = t.0.keys().cloned().collect();
+        let pruned = self.prune(plan.clone(), s, need.clone(), env);
+        match exact(pruned, need, env) {
+            (pruned, Some(after)) if after == t && after.0.keys().eq(t.0.keys()) => pruned,
+            _ => plan,
+        }
+    }
+}
+
+// 剪过的计划和它的表头, 类型检查不过时是 None
+type Typed = (LocPlan, Option<Record>);
+
+// 新节点的表头从下面的表头算
+fn typed(p: Plan, pos: Pos, inputs: Vec<Option<Record>>, env: &Env) -> Typed {
+    let plan = Loc(p, pos);
+    let r = inputs
+        .into_iter()
+        .collect::<Option<Vec<Record>>>()
+        .and_then(|rs| node_type(&plan, rs, env, None).ok());
+    (plan, r)
+}
+
+// 只留下 need 里的列, 多出来的用 Projection 去掉
+fn exact((plan, r): Typed, need: Vec<Symbol>, env: &Env) -> Typed {
+    match &r {
+        Some(t) if !t.0.keys().eq(need.iter()) => {
+            let pos = plan.1;
+            typed(Plan::Projection(Box::new(plan), need), pos, vec![r], env)
+        }
+        _ => (plan, r),
+    }
+}
+
+fn resolve(t: &Record, s: &Symbol) -> Option<Symbol> {
+    resolve_field(&t.0, s).map(|(k, _)| k.clone())
+}
+
+impl ProjectionPushdown {
+    // 输出至少包含 need 里的列, 它们的名字和顺序不变; s 是剪之前的表头
+    fn prune(&self, plan: LocPlan, s: Schemas, need: Vec<Symbol>, env: &Env) -> Typed {
+        let t = match s.record.clone() {
+            Some(t) => t,
+            None => return (plan, None),
+        };
+        let mut input = s.into_inputs();
+        // 按输出的顺序排好, 至少留一列, 不然行数就丢了
+        let mut need: Vec<Symbol> = t.0.keys().filter(|k| need.contains(k)).cloned().collect();
+        if need.is_empty() {
+            need.extend(t.0.keys().next().cloned());
+        }
+        let all: Vec<Symbol> = t.0.keys().cloned().collect();
+        let Loc(p, pos) = plan;
+        match p {
+            Plan::Table(_) if need == all => (Loc(p, pos), Some(t)),
+            Plan::Table(_) => {
+                let p = Plan::Projection(Box::new(Loc(p, pos)), need);
+                typed(p, pos, vec![Some(t)], env)
+            }
+            Plan::Empty(mut r) => {
+                r.0.retain(|k, _| need.contains(k));
+                typed(Plan::Empty(r), pos, vec![], env)
+            }
+            Plan::Projection(a, _) => {
+                let (a, at) = self.prune(*a, input(), need.clone(), env);
+                match at {
+                    // 集合语义的投影会去重, 留着它
+                    Some(at) if self.semantics == Semantics::Bag && at.0.keys().eq(need.iter()) => {
+                        (a, Some(at))
+                    }
+                    at => typed(Plan::Projection(Box::new(a), need), pos, vec![at], env),
+                }
+            }
+            // 集合语义下投影会去重, 按位置选的行就变了
+            Plan::Selection(a, f) if self.semantics == Semantics::Set && f.is_positional() => {
+                let (a, at) = self.prune_all(*a, input(), env);
+                typed(Plan::Selection(Box::new(a), f), pos, vec![at], env)
+            }
+            Plan::Selection(a, f) => {
+                let mut child = need;
+                child.extend(f.symbols().into_iter().filter_map(|s| resolve(&t, s)));
+                let (a, at) = self.prune(*a, input(), child, env);
+                typed(Plan::Selection(Box::new(a), f), pos, vec![at], env)
+            }
+            Plan::Product(a, b) => {
+                let ((a, at), (b, bt)) =
+                    self.prune_join((*a, input()), (*b, input()), need, &[], false, env);
+                typed(
+                    Plan::Product(Box::new(a), Box::new(b)),
+                    pos,
+                    vec![at, bt],
+                    env,
+                )
+            }
+            Plan::Join(a, b, kind, conds) => {
+                let used: Vec<&Symbol> = conds.iter().flat_map(|f| f.symbols()).collect();
+                let ((a, at), (b, bt)) = self.prune_join(
+                    (*a, input()),
+                    (*b, input()),
+                    need,
+                    &used,
+                    kind.is_semi(),
+                    env,
+                );
+                let p = Plan::Join(Box::new(a), Box::new(b), kind, conds);
+                typed(p, pos, vec![at, bt], env)
+            }
+            Plan::Union(a, b) => {
+                // 按位置对应到两边
+                let index: Vec<usize> = need.iter().filter_map(|k| t.0.get_index_of(k)).collect();
+                let side = |c: LocPlan, sc: Schemas| -> Typed {
+                    let names: Vec<Symbol> = match &sc.record {
+                        Some(ct) => index
+                            .iter()
+                            .filter_map(|i| ct.0.get_index(*i).map(|(k, _)| k.clone()))
+                            .collect(),
+                        None => return (c, None),
+                    };
+                    let c = self.prune(c, sc, names.clone(), env);
+                    exact(c, names, env)
+                };
+                let ((a, at), (b, bt)) = (side(*a, input()), side(*b, input()));
+                typed(
+                    Plan::Union(Box::new(a), Box::new(b)),
+                    pos,
+                    vec![at, bt],
+                    env,
+                )
+            }
+            Plan::Reduce(r) if self.semantics == Semantics::Bag => {
+                let field = ReduceOperator::from(&r).symbol().cloned();
+                let mut inputs = vec![];
+                let p = Plan::Reduce(r).map_children(|c| {
+                    let sc = input();
+                    let child = match &sc.record {
+                        Some(ct) => field.iter().filter_map(|s| resolve(ct, s)).collect(),
+                        None => {
+                            inputs.push(None);
+                            return c;
+                        }
+                    };
+                    let (c, ct) = self.prune(c, sc, child, env);
+                    inputs.push(ct);
+                    c
+                });
+                typed(p, pos, inputs, env)
+            }
+            Plan::Sort(a, keys) => {
+                let mut child = need;
+                child.extend(keys.iter().filter_map(|k| resolve(&t, &k.field)));
+                let (a, at) = self.prune(*a, input(), child, env);
+                typed(Plan::Sort(Box::new(a), keys), pos, vec![at], env)
+            }
+            Plan::GroupBy(a, keys, aggs) => {
+                // 没人用的聚合去掉, 输出里聚合排在分组属性后面
+                let aggs: Vec<Aggregate> = aggs
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| {
+                        t.0.get_index(keys.len() + i)
+                            .is_some_and(|(k, _)| need.contains(k))
+                    })
+                    .map(|(_, a)| a)
+                    .collect();
+                // 投影会去掉重复的行, 分组要数它们, 下面的列都要留着
+                let (a, at) = self.prune_all(*a, input(), env);
+                typed(Plan::GroupBy(Box::new(a), keys, aggs), pos, vec![at], env)
+            }
+            // 差, 交, 除法和去重要比较整行, 集合语义下聚合要数重复的行,
+            // 下面的列都要留着
+            p => {
+                let mut inputs = vec![];
+                let p = p.map_children(|c| {
+                    let (c, ct) = self.prune_all(c, input(), env);
+                    inputs.push(ct);
+                    c
+                });
+                typed(p, pos, inputs, env)
+            }
+        }
+    }
+
+    fn prune_all(&self, plan: LocPlan, s: Schemas, env: &Env) -> Typed {
+        match &s.record {
+            Some(t) => {
+                let need = t.0.keys().cloned().collect();
+                self.prune(plan, s, need, env)
+            }
+            None => (plan, None),
+        }
+    }
+
+    fn prune_join(
+        &self,
+        (a, sa): (LocPlan, Schemas),
+        (b, sb): (LocPlan, Schemas),
+        need: Vec<Symbol>,
+        used: &[&Symbol],
+        semi: bool,
+        env: &Env,
+    ) -> (Typed, Typed) {
+        let (at, bt) = match (sa.record.clone(), sb.record.clone()) {
+            (Some(at), Some(bt)) => (at, bt),
+            _ => return (self.prune_all(a, sa, env), self.prune_all(b, sb, env)),
+        };
+        // 连接结果的名字 -> (是否左边, 下面的名字)
+        let side = |t: &Record, left: bool| -> Vec<(Symbol, (bool, Symbol))> {
+            t.0.keys().map(|k| (k.clone(), (left, k.clone()))).collect()
+        };
+        let names: IndexMap<Symbol, (bool, Symbol)> =
+            match product_fields(side(&at, true), &at.1, side(&bt, false), &bt.1) {
+                Ok(names) => names.into_iter().collect(),
+                Err(_) => return (self.prune_all(a, sa, env), self.prune_all(b, sb, env)),
+            };
+        // 半连接输出的就是左边的列
+        let (mut left, need) = if semi { (need, vec![]) } else { (vec![], need) };
+        let mut right = vec![];
+        let wanted = need
+            .iter()
+            .chain(used.iter().copied())
+            .filter_map(|s| resolve_field(&names, s));
+        for (name, (is_left, child)) in wanted {
+            let (this, other) = if *is_left {
+                (&mut left, &mut right)
+            } else {
+                (&mut right, &mut left)
+            };
+            // 重名而加了表名的列, 另一边的同名列也要留着, 不然名字会变
+            if name != child {
+                other.push(child.clone());
+            }
+            this.push(child.clone());
+        }
+        let (pa, pb) = (
+            self.prune(a.clone(), sa.clone(), left, env),
+            self.prune(b.clone(), sb.clone(), right, env),
+        );
+        // 少了列以后别的列可能不再重名, 名字变了就不剪
+        let stable = match (&pa.1, &pb.1) {
+            (Some(pat), Some(pbt)) => {
+                match product_fields(side(pat, true), &pat.1, side(pbt, false), &pbt.1) {
+                    Ok(pruned) => {
+                        let pruned: IndexMap<Symbol, (bool, Symbol)> = pruned.into_iter().collect();
+                        let kept = |s: &Symbol| {
+                            resolve_field(&pruned, s).map(|(_, v)| v)
+                                == resolve_field(&names, s).map(|(_, v)| v)
+                        };
+                        need.iter().all(|s| pruned.get(s) == names.get(s))
+                            && used.iter().all(|s| kept(s))
+                    }
+                    Err(_) => false,
+                }
+            }
+            _ => false,
+        };
+        if stable {
+            (pa, pb)
+        } else {
+            (self.prune_all(a, sa, env), self.prune_all(b, sb, env))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        optimizer::run_pass,
+        structs::{
+            plan::{type_check::get_plan_table_type, FilterExpr, ItemReduce, JoinKind},
+            SortKey,
+        },
+        testing::*,
+    };
+
+    fn check(p: LocPlan) {
+        for semantics in [Semantics::Set, Semantics::Bag] {
+            check_pass(&ProjectionPushdown { semantics }, &p, semantics);
+        }
+    }
+
+    #[test]
+    fn counts_keep_duplicate_rows() {
+        check(plan(Plan::Reduce(ItemReduce::Count(Box::new(table("R"))))));
+        let big = select(table("R"), gt(sym("b"), 10));
+        check(plan(Plan::Reduce(ItemReduce::Count(Box::new(big)))));
+        check(plan(Plan::Reduce(ItemReduce::Sum(
+            Box::new(table("R")),
+            sym("b"),
+        ))));
+    }
+
+    #[test]
+    fn positional_filter_keeps_duplicate_rows() {
+        let r = sort(
+            table("R"),
+            vec![SortKey::asc(sym("a")), SortKey::asc(sym("b"))],
+        );
+        check(project(select(r, FilterExpr::GetItem(2)), &[sym("a")]));
+    }
+
+    #[test]
+    fn set_projection_is_kept() {
+        let p = project(table("R"), &[sym("a"), sym("b")]);
+        let after = ProjectionPushdown {
+            semantics: Semantics::Set,
+        }
+        .run(p.clone(), &fixture().1);
+        assert_eq!(after, p);
+        check(p);
+    }
+
+    #[test]
+    fn pruned_join_keeps_its_names() {
+        // π[b](((R × T) ⋉[b = a] T) × (T ∪ T))
+        let rt = plan(Plan::Product(Box::new(table("R")), Box::new(table("T"))));
+        let semi = plan(Plan::Join(
+            Box::new(rt),
+            Box::new(table("T")),
+            JoinKind::Semi,
+            vec![eq(sym("b"), sym("a"))],
+        ));
+        let union = plan(Plan::Union(Box::new(table("T")), Box::new(table("T"))));
+        let p = plan(Plan::Product(Box::new(semi), Box::new(union)));
+        check(project(p, &[sym("b")]));
+    }
+
+    fn pruned(p: LocPlan) -> LocPlan {
+        let pass = ProjectionPushdown {
+            semantics: Semantics::Bag,
+        };
+        run_pass(&pass, p, &env()).unwrap()
+    }
+
+    fn join(a: LocPlan, b: LocPlan) -> LocPlan {
+        let rs = eq(qualified("R", "b"), qualified("S", "b"));
+        plan(Plan::Join(
+            Box::new(a),
+            Box::new(b),
+            JoinKind::Inner,
+            vec![rs],
+        ))
+    }
+
+    #[test]
+    fn tables_are_narrowed_to_the_used_columns() {
+        let p = project(join(table("R"), table("S")), &[sym("c")]);
+        // S 的两列都要用到
+        let r = project(table("R"), &[sym("b")]);
+        assert_eq!(pruned(p), project(join(r, table("S")), &[sym("c")]));
+    }
+
+    #[test]
+    fn reduce_keeps_only_its_field() {
+        let sum = |p: LocPlan| plan(Plan::Reduce(ItemReduce::Sum(Box::new(p), sym("b"))));
+        let p = sum(table("R"));
+        assert_eq!(pruned(p), sum(project(table("R"), &[sym("b")])));
+    }
+
+    #[test]
+    fn pruned_schemas_match_the_type_checker() {
+        let (_, env) = fixture();
+        for semantics in [Semantics::Set, Semantics::Bag] {
+            for p in queries() {
+                let s = plan_schemas(&p, &env);
+                // 只要第一列
+                let need = s
+                    .record
+                    .iter()
+                    .flat_map(|r| r.0.keys().next().cloned())
+                    .collect();
+                let (pruned, r) = ProjectionPushdown { semantics }.prune(p, s, need, &env);
+                assert_eq!(r, get_plan_table_type(&pruned, &env).ok(), "{:?}", pruned);
+            }
+        }
+    }
+}
@@ -0,0 +1,411 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Predicate Pushdown of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use indexmap::IndexMap;
+
+use super::Pass;
+use crate::{
+    structs::{
+        ast::type_check::get_group_by_type,
+        plan::{
+            type_check::{plan_schemas, Schemas},
+            CompExpr, FilterExpr, JoinKind, LocPlan, Plan,
+        },
+        Aggregate, Expr, Loc, Pos, Symbol, Value,
+    },
+    type_system::{product_fields, resolve_field, Env},
+};
+
+/// Push selections down to the relations they filter.
+///
+/// Conjuncts are split and moved below Product, Join, Union, Intersect,
+/// Difference, Projection and Sort, and below a grouping when they only use
+/// its keys. Equality between the two sides of a Product
+/// turns the Product into an inner Join. Positional filters stay where they
+/// are, nothing is moved across them. Moving a selection does not change
+/// the type of any sub plan, so the types are computed once up front.
+pub struct PredicatePushdown;
+
+impl Pass for PredicatePushdown {
+    fn name(&self) -> &'static str {
+        "predicate pushdown"
+    }
+
+    fn run(&self, plan: LocPlan, env: &Env) -> LocPlan {
+        let schemas = plan_schemas(&plan, env);
+        push(plan, schemas, vec![], env)
+    }
+}
+
+type Preds = Vec<Loc<FilterExpr>>;
+
+// 放回不能再往下推的条件
+pub(crate) fn wrap(plan: LocPlan, preds: Preds) -> LocPlan {
+    let mut comps = vec![];
+    let mut r = plan;
+    for Loc(f, pos) in preds {
+        match f {
+            FilterExpr::Comp(c) => comps.push(Loc(c, pos)),
+            f => r = Loc(Plan::Selection(Box::new(r), Box::new(f)), pos),
+        }
+    }
+    if let Some(Loc(_, pos)) = comps.first() {
+        let pos = *pos;
+        let f = if comps.len() == 1 {
+            FilterExpr::Comp(comps.pop().unwrap().0)
+        } else {
+            FilterExpr::And(comps.into_iter().map(|Loc(c, _)| c).collect())
+        };
+        r = Loc(Plan::Selection(Box::new(r), Box::new(f)), pos);
+    }
+    r
+}
+
+fn push(plan: LocPlan, s: Schemas, mut preds: Preds, env: &Env) -> LocPlan {
+    let Loc(p, pos) = plan;
+    let mut input = s.into_inputs();
+    match p {
+        Plan::Selection(a, f) if !f.is_positional() => {
+            preds.extend(f.conjuncts().into_iter().map(|f| Loc(f, pos)));
+            push(*a, input(), preds, env)
+        }
+        Plan::Product(a, b) => {
+            let (a, b) = ((*a, input()), (*b, input()));
+            push_join(a, b, JoinKind::Inner, vec![], pos, preds, env)
+        }
+        Plan::Join(a, b, kind, fs) => {
+            let (a, b) = ((*a, input()), (*b, input()));
+            push_join(a, b, kind, fs, pos, preds, env)
+        }
+        Plan::Union(a, b) => {
+            let (a, b) = ((*a, input()), (*b, input()));
+            push_set_operation(a, b, pos, preds, env, Plan::Union)
+        }
+        Plan::Intersect(a, b) => {
+            let (a, b) = ((*a, input()), (*b, input()));
+            push_set_operation(a, b, pos, preds, env, Plan::Intersect)
+        }
+        Plan::Difference(a, b) => {
+            let (a, b) = ((*a, input()), (*b, input()));
+            push_set_operation(a, b, pos, preds, env, Plan::Difference)
+        }
+        // 投影出来的名字就是下面的名字
+        Plan::Projection(a, names) => {
+            let a = push(*a, input(), preds, env);
+            Loc(Plan::Projection(Box::new(a), names), pos)
+        }
+        // 先选择再去重, 结果一样
+        Plan::Distinct(a) => Loc(Plan::Distinct(Box::new(push(*a, input(), preds, env))), pos),
+        // 选择不改变剩下的行的顺序
+        Plan::Sort(a, keys) => {
+            let a = push(*a, input(), preds, env);
+            Loc(Plan::Sort(Box::new(a), keys), pos)
+        }
+        Plan::GroupBy(a, keys, aggs) => push_group_by((*a, input()), keys, aggs, pos, preds, env),
+        p => wrap(
+            Loc(p.map_children(|c| push(c, input(), vec![], env)), pos),
+            preds,
+        ),
+    }
+}
+
+// 只用到分组属性的条件先选择再分组, 用到聚合结果的条件 (having) 留在上面
+fn push_group_by(
+    (a, sa): (LocPlan, Schemas),
+    keys: Vec<Symbol>,
+    aggs: Vec<Aggregate>,
+    pos: Pos,
+    preds: Preds,
+    env: &Env,
+) -> LocPlan {
+    let rt = sa
+        .record
+        .clone()
+        .and_then(|at| get_group_by_type(at, &keys, &aggs, pos).ok());
+    let rt = match rt {
+        Some(rt) => rt,
+        None => {
+            let p = Plan::GroupBy(Box::new(push(a, sa, vec![], env)), keys, aggs);
+            return wrap(Loc(p, pos), preds);
+        }
+    };
+    let key = |s: &Symbol| {
+        resolve_field(&rt.0, s)
+            .map(|(k, _)| k)
+            .filter(|k| keys.contains(k))
+            .cloned()
+    };
+    let mut below = vec![];
+    let mut above = vec![];
+    for Loc(f, fpos) in preds {
+        // 没有名字的条件在没有分组属性时会去掉唯一的那一组
+        let pushable = {
+            let symbols = f.symbols();
+            !symbols.is_empty() && symbols.into_iter().all(|s| key(s).is_some())
+        };
+        if pushable {
+            below.push(Loc(f.map_symbols(&mut |s| key(&s).unwrap_or(s)), fpos));
+        } else {
+            above.push(Loc(f, fpos));
+        }
+    }
+    let p = Plan::GroupBy(Box::new(push(a, sa, below, env)), keys, aggs);
+    wrap(Loc(p, pos), above)
+}
+
+// a = b, 两边分别是左右两个表的列
+fn is_equi_condition(f: &FilterExpr, side: &dyn Fn(&Symbol) -> Option<bool>) -> bool {
+    if let FilterExpr::Comp(c) = f {
+        if let CompExpr::Eq(a, b) = c.as_ref() {
+            if let (Expr::Value(Loc(Value::Symbol(a), _)), Expr::Value(Loc(Value::Symbol(b), _))) =
+                (a.as_ref(), b.as_ref())
+            {
+                return matches!((side(a), side(b)), (Some(x), Some(y)) if x != y);
+            }
+        }
+    }
+    false
+}
+
+fn push_join(
+    (a, sa): (LocPlan, Schemas),
+    (b, sb): (LocPlan, Schemas),
+    kind: JoinKind,
+    conds: Vec<FilterExpr>,
+    pos: Pos,
+    preds: Preds,
+    env: &Env,
+) -> LocPlan {
+    let is_product = conds.is_empty() && kind == JoinKind::Inner;
+    // 连接结果的名字 -> (是否左边, 下面的名字)
+    let side = |t: &IndexMap<_, _>, left: bool| -> Vec<(Symbol, (bool, Symbol))> {
+        t.keys()
+            .map(|k: &Symbol| (k.clone(), (left, k.clone())))
+            .collect()
+    };
+    let names = match (&sa.record, &sb.record) {
+        (Some(at), Some(bt)) => {
+            product_fields(side(&at.0, true), &at.1, side(&bt.0, false), &bt.1).ok()
+        }
+        _ => None,
+    };
+    let names: IndexMap<Symbol, (bool, Symbol)> = match names {
+        Some(names) => names.into_iter().collect(),
+        None => {
+            let a = Box::new(push(a, sa, vec![], env));
+            let b = Box::new(push(b, sb, vec![], env));
+            let p = if is_product {
+                Plan::Product(a, b)
+            } else {
+                Plan::Join(a, b, kind, conds)
+            };
+            return wrap(Loc(p, pos), preds);
+        }
+    };
+    let lookup = |s: &Symbol| resolve_field(&names, s).map(|(_, v)| v.clone());
+    let which = |f: &FilterExpr| -> Option<Option<bool>> {
+        // None: 有找不到的名字; Some(None): 两边都有或者没有名字
+        let sides: Option<Vec<bool>> = f
+            .symbols()
+            .into_iter()
+            .map(|s| lookup(s).map(|x| x.0))
+            .collect();
+        let sides = sides?;
+        if !sides.is_empty() && sides.iter().all(|x| *x) {
+            Some(Some(true))
+        } else if !sides.is_empty() && sides.iter().all(|x| !*x) {
+            Some(Some(false))
+        } else {
+            Some(None)
+        }
+    };
+    let to_child = |f: FilterExpr| f.map_symbols(&mut |s| lookup(&s).map(|x| x.1).unwrap_or(s));
+
+    let mut left = vec![];
+    let mut right = vec![];
+    let mut above = vec![];
+    let mut new_conds = vec![];
+    // 外连接补 null 的一边不能先过滤, 连接条件只能推到被补 null 的一边
+    let (push_left, push_right) = match kind {
+        JoinKind::Inner | JoinKind::Semi => (true, true),
+        JoinKind::Left => (true, false),
+        JoinKind::Right => (false, true),
+        JoinKind::Full | JoinKind::Anti | JoinKind::NullAwareAnti => (false, false),
+    };
+    for Loc(f, fpos) in preds {
+        // 半连接只输出左边, 上面的条件都是左边的
+        if kind.is_semi() {
+            left.push(Loc(f, fpos));
+            continue;
+        }
+        match which(&f) {
+            Some(Some(true)) if push_left => left.push(Loc(to_child(f), fpos)),
+            Some(Some(false)) if push_right => right.push(Loc(to_child(f), fpos)),
+            Some(_) if kind == JoinKind::Inner => new_conds.push(f),
+            _ => above.push(Loc(f, fpos)),
+        }
+    }
+    for (i, f) in conds.into_iter().flat_map(|f| f.conjuncts()).enumerate() {
+        // not in 的第一个条件是 in 的比较
+        let fixed = i == 0 && kind == JoinKind::NullAwareAnti;
+        match which(&f) {
+            Some(Some(true))
+                if matches!(kind, JoinKind::Inner | JoinKind::Right | JoinKind::Semi) =>
+            {
+                left.push(Loc(to_child(f), pos))
+            }
+            Some(Some(false)) if !fixed && !matches!(kind, JoinKind::Right | JoinKind::Full) => {
+                right.push(Loc(to_child(f), pos))
+            }
+            _ => new_conds.push(f),
+        }
+    }
+    let a = Box::new(push(a, sa, left, env));
+    let b = Box::new(push(b, sb, right, env));
+    let side_of = |s: &Symbol| lookup(s).map(|x| x.0);
+    let keep_join = kind != JoinKind::Inner
+        || !is_product
+        || new_conds.iter().any(|f| is_equi_condition(f, &side_of));
+    let p = if keep_join {
+        Plan::Join(a, b, kind, new_conds)
+    } else {
+        // 没有等值条件, 保持笛卡尔积上的选择
+        above.extend(new_conds.into_iter().map(|f| Loc(f, pos)));
+        Plan::Product(a, b)
+    };
+    wrap(Loc(p, pos), above)
+}
+
+fn push_set_operation(
+    (a, sa): (LocPlan, Schemas),
+    (b, sb): (LocPlan, Schemas),
+    pos: Pos,
+    preds: Preds,
+    env: &Env,
+    f: fn(Box<LocPlan>, Box<LocPlan>) -> Plan,
+) -> LocPlan {
+    let (at, bt) = match (sa.record.clone(), sb.record.clone()) {
+        (Some(at), Some(bt)) => (at, bt),
+        _ => {
+            let p = f(
+                Box::new(push(a, sa, vec![], env)),
+                Box::new(push(b, sb, vec![], env)),
+            );
+            return wrap(Loc(p, pos), preds);
+        }
+    };
+    // 右边按位置换成自己的名字
+    let mut left = vec![];
+    let mut right = vec![];
+    let mut above = vec![];
+    for Loc(p, ppos) in preds {
+        let resolved = p
+            .symbols()
+            .into_iter()
+            .all(|s| resolve_field(&at.0, s).is_some());
+        if !resolved {
+            above.push(Loc(p, ppos));
+            continue;
+        }
+        let to_right = p.clone().map_symbols(&mut |s| {
+            resolve_field(&at.0, &s)
+                .and_then(|(k, _)| at.0.get_index_of(k))
+                .and_then(|i| bt.0.get_index(i))
+                .map(|(k, _)| k.clone())
+                .unwrap_or(s)
+        });
+        left.push(Loc(p, ppos));
+        right.push(Loc(to_right, ppos));
+    }
+    let p = f(
+        Box::new(push(a, sa, left, env)),
+        Box::new(push(b, sb, right, env)),
+    );
+    wrap(Loc(p, pos), above)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        optimizer::run_pass,
+        structs::{plan::type_check::get_plan_table_type, SortKey},
+        testing::*,
+    };
+
+    fn pushed(p: LocPlan) -> LocPlan {
+        run_pass(&PredicatePushdown, p, &env()).unwrap()
+    }
+
+    fn both(f: fn(Box<LocPlan>, Box<LocPlan>) -> Plan, a: LocPlan, b: LocPlan) -> LocPlan {
+        plan(f(Box::new(a), Box::new(b)))
+    }
+
+    fn and(fs: Vec<FilterExpr>) -> FilterExpr {
+        let comps = fs.into_iter().map(|f| match f {
+            FilterExpr::Comp(c) => c,
+            f => panic!("{:?}", f),
+        });
+        FilterExpr::And(comps.collect())
+    }
+
+    #[test]
+    fn equality_across_a_product_makes_a_join() {
+        let rs = || eq(qualified("R", "b"), qualified("S", "b"));
+        let p = both(Plan::Product, table("R"), table("S"));
+        let p = select(p, and(vec![rs(), gt(sym("a"), 1), gt(sym("c"), 2)]));
+        let join = Plan::Join(
+            Box::new(select(table("R"), gt(sym("a"), 1))),
+            Box::new(select(table("S"), gt(sym("c"), 2))),
+            JoinKind::Inner,
+            vec![rs()],
+        );
+        assert_eq!(pushed(p), plan(join));
+    }
+
+    #[test]
+    fn selections_go_below_set_operations_and_projections() {
+        let p = project(both(Plan::Union, table("R"), table("U")), &[sym("a")]);
+        let p = select(p, gt(sym("a"), 1));
+        let r = || select(table("R"), gt(sym("a"), 1));
+        let u = select(table("U"), gt(sym("a"), 1));
+        let expected = project(both(Plan::Union, r(), u), &[sym("a")]);
+        assert_eq!(pushed(p), expected);
+    }
+
+    #[test]
+    fn nothing_moves_across_a_positional_filter() {
+        let r = sort(table("R"), vec![SortKey::asc(sym("b"))]);
+        let p = select(select(r, FilterExpr::GetFirst), gt(sym("a"), 1));
+        assert_eq!(pushed(p.clone()), p);
+    }
+
+    fn same_schemas(p: &LocPlan, s: &Schemas, env: &Env) {
+        assert_eq!(s.record, get_plan_table_type(p, env).ok(), "{:?}", p);
+        for (p, s) in p.0.children().into_iter().zip(s.children.iter()) {
+            same_schemas(p, s, env);
+        }
+    }
+
+    #[test]
+    fn schemas_match_the_type_checker() {
+        let env = env();
+        for p in queries() {
+            same_schemas(&p, &plan_schemas(&p, &env), &env);
+        }
+    }
+}
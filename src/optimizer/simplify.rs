@@ -0,0 +1,452 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Constant Folding of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::cmp::Ordering;
+
+use super::Pass;
+use crate::{
+    physical::Semantics,
+    structs::{
+        plan::{type_check::get_plan_table_type, CompExpr, FilterExpr, JoinKind, LocPlan, Plan},
+        Expr, Loc, LocExpr, Pos, Value,
+    },
+    type_system::Env,
+};
+
+/// Fold constants and drop predicates that do not depend on the row.
+///
+/// A selection that always holds is removed, one that never holds becomes
+/// an empty relation with the same fields, and empty relations are
+/// propagated upwards. Folded expressions keep the position of the
+/// expression they replace. A union or a difference with an empty right
+/// side is its left side, with set semantics under a `Distinct`.
+#[derive(Default)]
+pub struct Simplify {
+    pub semantics: Semantics,
+}
+
+impl Pass for Simplify {
+    fn name(&self) -> &'static str {
+        "simplify"
+    }
+
+    fn run(&self, plan: LocPlan, env: &Env) -> LocPlan {
+        simplify_plan(plan, env, self.semantics)
+    }
+}
+
+/// Value of a comparison between constants. Comparing with null is unknown,
+/// and a selection drops the row just like for false.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Truth {
+    True,
+    False,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Simplified<T> {
+    Const(Truth),
+    Keep(T),
+}
+
+fn literal(e: &Expr) -> Option<&Value> {
+    match e {
+        Expr::Value(Loc(Value::Symbol(_), _)) => None,
+        Expr::Value(Loc(v, _)) => Some(v),
+        _ => None,
+    }
+}
+
+fn is_number(e: &Expr, n: i64) -> bool {
+    match literal(e) {
+        Some(Value::Int(v)) => *v == n,
+        Some(Value::Uint(v)) => n >= 0 && *v == n as u64,
+        Some(Value::Float(v)) => *v == n as f64,
+        _ => false,
+    }
+}
+
+fn simplify_loc(Loc(e, pos): LocExpr) -> Box<LocExpr> {
+    Box::new(Loc(simplify_expr(e, pos), pos))
+}
+
+/// Fold literal arithmetic and boolean identities. `pos` is given to the
+/// values made by folding.
+pub fn simplify_expr(e: Expr, pos: Pos) -> Expr {
+    let value = |v: Value| Expr::Value(Loc(v, pos));
+    match e {
+        Expr::Add(a, b) => arith(Expr::Add(simplify_loc(*a), simplify_loc(*b)), pos),
+        Expr::Sub(a, b) => arith(Expr::Sub(simplify_loc(*a), simplify_loc(*b)), pos),
+        Expr::Mul(a, b) => arith(Expr::Mul(simplify_loc(*a), simplify_loc(*b)), pos),
+        Expr::Div(a, b) => arith(Expr::Div(simplify_loc(*a), simplify_loc(*b)), pos),
+        Expr::Mod(a, b) => arith(Expr::Mod(simplify_loc(*a), simplify_loc(*b)), pos),
+        Expr::And(a, b) => {
+            let (a, b) = (simplify_loc(*a), simplify_loc(*b));
+            match (literal(&a.0), literal(&b.0)) {
+                (Some(Value::Bool(false)), _) | (_, Some(Value::Bool(false))) => {
+                    value(Value::Bool(false))
+                }
+                (Some(Value::Bool(true)), _) => b.0,
+                (_, Some(Value::Bool(true))) => a.0,
+                _ => Expr::And(a, b),
+            }
+        }
+        Expr::Or(a, b) => {
+            let (a, b) = (simplify_loc(*a), simplify_loc(*b));
+            match (literal(&a.0), literal(&b.0)) {
+                (Some(Value::Bool(true)), _) | (_, Some(Value::Bool(true))) => {
+                    value(Value::Bool(true))
+                }
+                (Some(Value::Bool(false)), _) => b.0,
+                (_, Some(Value::Bool(false))) => a.0,
+                _ => Expr::Or(a, b),
+            }
+        }
+        Expr::Not(a) => {
+            let a = simplify_loc(*a);
+            match a.0 {
+                Expr::Not(x) => x.0,
+                Expr::Value(Loc(Value::Bool(v), _)) => value(Value::Bool(!v)),
+                Expr::Value(Loc(Value::Null, _)) => value(Value::Null),
+                x => Expr::Not(Box::new(Loc(x, a.1))),
+            }
+        }
+        e @ Expr::Value(_) => e,
+    }
+}
+
+fn arith(e: Expr, pos: Pos) -> Expr {
+    let (a, b) = match &e {
+        Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b) | Expr::Mod(a, b) => {
+            (a, b)
+        }
+        _ => return e,
+    };
+    if let (Some(x), Some(y)) = (literal(&a.0), literal(&b.0)) {
+        if let Some(v) = e.arith_op().and_then(|op| x.arith(op, y)) {
+            return Expr::Value(Loc(v, pos));
+        }
+    }
+    // x + 0, x - 0, x * 1, x / 1
+    let keep_left = match &e {
+        Expr::Add(_, _) | Expr::Sub(_, _) => is_number(&b.0, 0),
+        Expr::Mul(_, _) | Expr::Div(_, _) => is_number(&b.0, 1),
+        _ => false,
+    };
+    let keep_right = match &e {
+        Expr::Add(_, _) => is_number(&a.0, 0),
+        Expr::Mul(_, _) => is_number(&a.0, 1),
+        _ => false,
+    };
+    match e {
+        Expr::Add(a, _) | Expr::Sub(a, _) | Expr::Mul(a, _) | Expr::Div(a, _) if keep_left => a.0,
+        Expr::Add(_, b) | Expr::Mul(_, b) if keep_right => b.0,
+        e => e,
+    }
+}
+
+// 比较式最外层没有位置, 用里面第一个位置
+fn expr_pos(e: &Expr) -> Pos {
+    match e {
+        Expr::Add(a, _)
+        | Expr::Sub(a, _)
+        | Expr::Mul(a, _)
+        | Expr::Div(a, _)
+        | Expr::Mod(a, _)
+        | Expr::And(a, _)
+        | Expr::Or(a, _)
+        | Expr::Not(a) => a.1,
+        Expr::Value(Loc(_, pos)) => *pos,
+    }
+}
+
+fn simplify_top(e: Expr) -> Box<Expr> {
+    let pos = expr_pos(&e);
+    Box::new(simplify_expr(e, pos))
+}
+
+fn truth(b: bool) -> Truth {
+    if b {
+        Truth::True
+    } else {
+        Truth::False
+    }
+}
+
+/// Simplify a comparison: fold both sides, put the field on the left and
+/// evaluate it when both sides are constants.
+pub fn simplify_comp(c: CompExpr, env: &Env) -> Simplified<CompExpr> {
+    let (a, b, want) = match c {
+        CompExpr::Eq(a, b) => (a, b, Ordering::Equal),
+        CompExpr::Lt(a, b) => (a, b, Ordering::Less),
+        CompExpr::Gt(a, b) => (a, b, Ordering::Greater),
+        CompExpr::In(a, p) => {
+            // 子查询只看有没有这个值, 重复的行不要紧
+            let p = simplify_plan(*p, env, Semantics::Bag);
+            if let Plan::Empty(_) = p.0 {
+                return Simplified::Const(Truth::False);
+            }
+            let a = simplify_top(*a);
+            if literal(&a) == Some(&Value::Null) {
+                return Simplified::Const(Truth::Unknown);
+            }
+            return Simplified::Keep(CompExpr::In(a, Box::new(p)));
+        }
+    };
+    let (a, b) = (simplify_top(*a), simplify_top(*b));
+    match (literal(&a), literal(&b)) {
+        (Some(Value::Null), _) | (_, Some(Value::Null)) => {
+            return Simplified::Const(Truth::Unknown)
+        }
+        (Some(x), Some(y)) => {
+            if let Some(o) = x.compare(y) {
+                return Simplified::Const(truth(o == want));
+            }
+        }
+        _ => (),
+    }
+    let swap = a.symbols().is_empty() && !b.symbols().is_empty();
+    let (a, b, want) = if swap {
+        (b, a, want.reverse())
+    } else {
+        (a, b, want)
+    };
+    Simplified::Keep(match want {
+        Ordering::Equal => CompExpr::Eq(a, b),
+        Ordering::Less => CompExpr::Lt(a, b),
+        Ordering::Greater => CompExpr::Gt(a, b),
+    })
+}
+
+/// Simplify a filter. Unknown counts as false here, since a filter only
+/// keeps the rows where it holds.
+pub fn simplify_filter(f: FilterExpr, env: &Env) -> Simplified<FilterExpr> {
+    let holds = |t: Truth| Simplified::Const(truth(t == Truth::True));
+    let comps = |v: Vec<Box<CompExpr>>| -> Vec<Simplified<CompExpr>> {
+        v.into_iter().map(|c| simplify_comp(*c, env)).collect()
+    };
+    let one_or = |mut v: Vec<Box<CompExpr>>, many: fn(Vec<Box<CompExpr>>) -> FilterExpr| {
+        if v.len() == 1 {
+            FilterExpr::Comp(v.pop().unwrap())
+        } else {
+            many(v)
+        }
+    };
+    match f {
+        FilterExpr::And(v) => {
+            let mut r = vec![];
+            for c in comps(v) {
+                match c {
+                    Simplified::Const(Truth::True) => (),
+                    Simplified::Const(_) => return Simplified::Const(Truth::False),
+                    Simplified::Keep(c) => r.push(Box::new(c)),
+                }
+            }
+            if r.is_empty() {
+                return Simplified::Const(Truth::True);
+            }
+            Simplified::Keep(one_or(r, FilterExpr::And))
+        }
+        FilterExpr::Or(v) => {
+            let mut r = vec![];
+            for c in comps(v) {
+                match c {
+                    Simplified::Const(Truth::True) => return Simplified::Const(Truth::True),
+                    Simplified::Const(_) => (),
+                    Simplified::Keep(c) => r.push(Box::new(c)),
+                }
+            }
+            if r.is_empty() {
+                return Simplified::Const(Truth::False);
+            }
+            Simplified::Keep(one_or(r, FilterExpr::Or))
+        }
+        FilterExpr::Not(c) => match simplify_comp(*c, env) {
+            Simplified::Const(Truth::True) => Simplified::Const(Truth::False),
+            Simplified::Const(Truth::False) => Simplified::Const(Truth::True),
+            Simplified::Const(Truth::Unknown) => Simplified::Const(Truth::False),
+            Simplified::Keep(c) => Simplified::Keep(FilterExpr::Not(Box::new(c))),
+        },
+        FilterExpr::Comp(c) => match simplify_comp(*c, env) {
+            Simplified::Const(t) => holds(t),
+            Simplified::Keep(c) => Simplified::Keep(FilterExpr::Comp(Box::new(c))),
+        },
+        f => Simplified::Keep(f),
+    }
+}
+
+fn is_empty(p: &LocPlan) -> bool {
+    matches!(p.0, Plan::Empty(_))
+}
+
+fn empty(plan: LocPlan, env: &Env) -> LocPlan {
+    match get_plan_table_type(&plan, env) {
+        Ok(r) => Loc(Plan::Empty(r), plan.1),
+        Err(_) => plan,
+    }
+}
+
+pub fn simplify_plan(plan: LocPlan, env: &Env, semantics: Semantics) -> LocPlan {
+    let Loc(p, pos) = plan;
+    let p = p.map_children(|c| simplify_plan(c, env, semantics));
+    let p = match p {
+        Plan::Selection(a, f) => match simplify_filter(*f, env) {
+            Simplified::Keep(f) => Plan::Selection(a, Box::new(f)),
+            Simplified::Const(Truth::True) => return *a,
+            Simplified::Const(_) => return empty(*a, env),
+        },
+        Plan::Join(a, b, kind, fs) => {
+            let mut conds = vec![];
+            let mut never = false;
+            for f in fs.iter().cloned() {
+                match simplify_filter(f, env) {
+                    Simplified::Keep(f) => conds.push(f),
+                    Simplified::Const(Truth::True) => (),
+                    Simplified::Const(_) => never = true,
+                }
+            }
+            match kind {
+                // 第一个条件是 in 的比较, 不能动
+                JoinKind::NullAwareAnti => Plan::Join(a, b, kind, fs),
+                JoinKind::Inner | JoinKind::Semi if never => {
+                    return empty(Loc(Plan::Join(a, b, kind, fs), pos), env)
+                }
+                JoinKind::Anti if never => return *a,
+                JoinKind::Inner if conds.is_empty() => Plan::Product(a, b),
+                // 外连接的条件不成立时还要补 null, 原样留着
+                _ if never => Plan::Join(a, b, kind, fs),
+                _ => Plan::Join(a, b, kind, conds),
+            }
+        }
+        p => p,
+    };
+    // 空关系往上传
+    let vanish = match &p {
+        Plan::Selection(a, _)
+        | Plan::Projection(a, _)
+        | Plan::Distinct(a)
+        | Plan::Sort(a, _)
+        | Plan::Division(a, _, _)
+        | Plan::Difference(a, _) => is_empty(a),
+        Plan::Product(a, b) | Plan::Intersect(a, b) => is_empty(a) || is_empty(b),
+        // 没有分组属性时空表也有一行
+        Plan::GroupBy(a, keys, _) => !keys.is_empty() && is_empty(a),
+        Plan::Join(a, b, kind, _) => match kind {
+            JoinKind::Inner => is_empty(a) || is_empty(b),
+            JoinKind::Left => is_empty(a),
+            JoinKind::Right => is_empty(b),
+            JoinKind::Full => is_empty(a) && is_empty(b),
+            JoinKind::Semi => is_empty(a) || is_empty(b),
+            JoinKind::Anti | JoinKind::NullAwareAnti => is_empty(a),
+        },
+        _ => false,
+    };
+    if vanish {
+        return empty(Loc(p, pos), env);
+    }
+    match p {
+        Plan::Union(a, b) | Plan::Difference(a, b) if is_empty(&b) => match semantics {
+            Semantics::Set => Loc(Plan::Distinct(a), pos),
+            Semantics::Bag => *a,
+        },
+        // 子查询为空时 not in 总是成立
+        Plan::Join(a, b, JoinKind::Anti | JoinKind::NullAwareAnti, _) if is_empty(&b) => *a,
+        p => Loc(p, pos),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimizer::run_pass, testing::*};
+
+    fn value(v: Value) -> Box<Expr> {
+        Box::new(Expr::Value(Loc(v, pos())))
+    }
+
+    fn add(a: i64, b: i64) -> Box<Expr> {
+        let v = |v| Box::new(Loc(Expr::Value(Loc(Value::Int(v), pos())), pos()));
+        Box::new(Expr::Add(v(a), v(b)))
+    }
+
+    fn comp(c: CompExpr) -> FilterExpr {
+        FilterExpr::Comp(Box::new(c))
+    }
+
+    fn simplified(p: LocPlan) -> LocPlan {
+        run_pass(&Simplify::default(), p, &env()).unwrap()
+    }
+
+    #[test]
+    fn constants_are_folded_and_the_field_put_left() {
+        let three = add(1, 2);
+        let p = select(table("R"), comp(CompExpr::Gt(field(sym("a")), three)));
+        assert_eq!(simplified(p), select(table("R"), gt(sym("a"), 3)));
+        let p = select(
+            table("R"),
+            comp(CompExpr::Lt(value(Value::Int(3)), field(sym("a")))),
+        );
+        assert_eq!(simplified(p), select(table("R"), gt(sym("a"), 3)));
+    }
+
+    #[test]
+    fn constant_selections_are_dropped_or_emptied() {
+        let always = comp(CompExpr::Lt(value(Value::Int(1)), value(Value::Int(2))));
+        assert_eq!(simplified(select(table("R"), always)), table("R"));
+        let never = comp(CompExpr::Gt(value(Value::Int(1)), value(Value::Int(2))));
+        let p = plan(Plan::Product(
+            Box::new(select(table("T"), never)),
+            Box::new(table("S")),
+        ));
+        assert!(matches!(simplified(p).0, Plan::Empty(_)));
+    }
+
+    #[test]
+    fn comparing_with_null_keeps_no_row() {
+        let null = || comp(CompExpr::Eq(field(sym("a")), value(Value::Null)));
+        assert!(matches!(
+            simplified(select(table("R"), null())).0,
+            Plan::Empty(_)
+        ));
+        let not = match null() {
+            FilterExpr::Comp(c) => FilterExpr::Not(c),
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            simplified(select(table("R"), not)).0,
+            Plan::Empty(_)
+        ));
+    }
+
+    #[test]
+    fn empty_right_sides_keep_the_left_rows() {
+        let never = || comp(CompExpr::Gt(value(Value::Int(1)), value(Value::Int(2))));
+        for f in [Plan::Union, Plan::Difference] {
+            let p = plan(f(
+                Box::new(table("R")),
+                Box::new(select(table("U"), never())),
+            ));
+            let distinct = plan(Plan::Distinct(Box::new(table("R"))));
+            for (semantics, expected) in [(Semantics::Set, distinct), (Semantics::Bag, table("R"))]
+            {
+                let after = check_pass(&Simplify { semantics }, &p, semantics);
+                assert_eq!(after, expected);
+            }
+        }
+    }
+}
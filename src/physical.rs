@@ -0,0 +1,385 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Physical Plan of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+pub mod planner;
+
+use crate::{
+    optimizer::cse::NodeId,
+    structs::{
+        plan::{FilterExpr, JoinKind},
+        plan_group::ReduceOperator,
+        Aggregate, DivisionKind, SortKey, Symbol, Value,
+    },
+    type_system::Record,
+};
+
+/// A plan made of concrete operators, built by the planner from a logical
+/// plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhysicalPlan {
+    pub op: Operator,
+    pub children: Vec<PhysicalPlan>,
+    /// Output fields, named as in the logical plan.
+    pub schema: Record,
+    /// The output is sorted on these fields, ascending with nulls first.
+    pub order: Vec<Symbol>,
+    /// Estimated output rows.
+    pub rows: f64,
+    /// Estimated cost of the whole sub tree.
+    pub cost: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operator {
+    TableScan(String),
+    IndexScan(IndexScan),
+    Filter(FilterExpr),
+    Project(Vec<Symbol>, Semantics),
+    /// Passes its input on, only the names of the fields change.
+    Rename(Vec<(Symbol, Symbol)>),
+    /// Drops duplicate rows.
+    Distinct,
+    /// Compares every pair of rows, works for any condition.
+    NestedLoopJoin(JoinKind, Vec<FilterExpr>),
+    HashJoin(EquiJoin),
+    /// Both inputs are sorted on their keys.
+    MergeJoin(EquiJoin),
+    HashAggregate(ReduceOperator),
+    /// One row per group of equal keys, the keys then the aggregates. A
+    /// null key is a value of its own. Without keys there is one group,
+    /// even when the input is empty.
+    HashGroupBy(Vec<Symbol>, Vec<Aggregate>),
+    /// Keeps the order of rows with equal keys.
+    Sort(Vec<SortKey>),
+    /// Skips `offset` rows, then passes at most `count` rows and stops
+    /// reading its input: `Limit(offset, count)`.
+    Limit(u64, u64),
+    /// The rows a sort followed by a limit gives, without sorting the
+    /// whole input: `TopN(keys, offset, count, last)`. With `last` the sort
+    /// is read from its end, `keys` are reversed and of the rows with equal
+    /// keys the later one comes first.
+    TopN(Vec<SortKey>, u64, u64, bool),
+    HashSetOp(SetOp, Semantics),
+    /// Both inputs are sorted on all their fields.
+    SortSetOp(SetOp, Semantics),
+    /// Hashes the divisor, then marks in a bitmap of every quotient
+    /// candidate the divisor rows it has.
+    HashDivision(DivisionKind),
+    /// The dividend is sorted on its quotient fields then its compared
+    /// fields, the divisor on its grouping fields then its compared fields.
+    SortDivision(DivisionKind),
+    /// Counts the divisor rows every quotient candidate has. Both inputs
+    /// have no duplicate rows.
+    CountDivision(DivisionKind),
+    Empty,
+    /// A sub plan found more than once in the plan. Its input is evaluated
+    /// the first time, the other uses read the same rows.
+    Shared(NodeId),
+}
+
+/// Fields of a division. `x` of a dividend row is in the quotient of a
+/// group when the dividend has `(x, y)` for every `y` of the group in the
+/// divisor. Values are compared as in set operations, a null is equal to
+/// a null. A simple division has one group even for an empty divisor, then
+/// every `x` of the dividend is in the quotient. The other kinds have no
+/// groups for an empty divisor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DivisionFields {
+    /// Quotient fields of the dividend.
+    pub x: Vec<Symbol>,
+    /// Compared fields, named the same in both inputs.
+    pub y: Vec<Symbol>,
+    /// Grouping fields of the divisor.
+    pub group: Vec<Symbol>,
+}
+
+/// How operators treat duplicate rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Semantics {
+    /// Relational algebra, no operator gives duplicate rows, not even a
+    /// scan of a table that has some.
+    #[default]
+    Set,
+    /// As in SQL: `union all`, `intersect all`, `except all`, and
+    /// projections that keep every row. A row found `m` times on the left
+    /// and `n` times on the right is in the intersection `min(m, n)` times
+    /// and in the difference `max(m - n, 0)` times.
+    Bag,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    Union,
+    Intersect,
+    Difference,
+}
+
+/// A join on `left = right` pairs of fields, the left field is named as in
+/// the left input and the right one as in the right input. `residual` is
+/// checked on the joined row. Rows with a null key match nothing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EquiJoin {
+    pub kind: JoinKind,
+    pub keys: Vec<(Symbol, Symbol)>,
+    pub residual: Vec<FilterExpr>,
+    /// The input a hash join reads first and hashes, the other input is
+    /// read row by row. Merge joins always use the right side.
+    pub build: Side,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexScan {
+    pub table: String,
+    pub index: String,
+    pub lookup: Lookup,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lookup {
+    /// Values of the first columns of the index.
+    Eq(Vec<Value>),
+    /// Bounds on the first column of an ordered index.
+    Range(Option<Bound>, Option<Bound>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bound {
+    pub value: Value,
+    pub inclusive: bool,
+}
+
+impl PhysicalPlan {
+    /// The output has no duplicate rows.
+    pub fn distinct(&self) -> bool {
+        let all = || self.children.iter().all(|c| c.distinct());
+        match &self.op {
+            Operator::Project(_, Semantics::Set)
+            | Operator::Distinct
+            | Operator::HashAggregate(_)
+            | Operator::HashGroupBy(..)
+            | Operator::HashSetOp(_, Semantics::Set)
+            | Operator::SortSetOp(_, Semantics::Set)
+            | Operator::HashDivision(_)
+            | Operator::SortDivision(_)
+            | Operator::CountDivision(_)
+            | Operator::Empty => true,
+            Operator::Filter(_)
+            | Operator::Rename(_)
+            | Operator::Sort(_)
+            | Operator::Limit(..)
+            | Operator::TopN(..)
+            | Operator::Shared(_) => all(),
+            // 包语义的交集和差集只会去掉左边的行
+            Operator::HashSetOp(op, Semantics::Bag) | Operator::SortSetOp(op, Semantics::Bag) => {
+                *op != SetOp::Union && self.children[0].distinct()
+            }
+            Operator::NestedLoopJoin(kind, _)
+            | Operator::HashJoin(EquiJoin { kind, .. })
+            | Operator::MergeJoin(EquiJoin { kind, .. }) => match kind {
+                // 两边都补 null 的行可能重复
+                JoinKind::Full => false,
+                k if k.is_semi() => self.children[0].distinct(),
+                _ => all(),
+            },
+            Operator::Project(_, Semantics::Bag)
+            | Operator::TableScan(_)
+            | Operator::IndexScan(_) => false,
+        }
+    }
+}
+
+impl DivisionFields {
+    pub fn new(dividend: &Record, divisor: &Record, kind: &DivisionKind) -> Self {
+        let group: Vec<Symbol> = match kind {
+            DivisionKind::Simple => vec![],
+            DivisionKind::Great => divisor
+                .0
+                .keys()
+                .filter(|k| !dividend.0.contains_key(*k))
+                .cloned()
+                .collect(),
+            DivisionKind::Grouped(g) => g.clone(),
+        };
+        let y: Vec<Symbol> = divisor
+            .0
+            .keys()
+            .filter(|k| !group.contains(k))
+            .cloned()
+            .collect();
+        let x = dividend
+            .0
+            .keys()
+            .filter(|k| !y.contains(k))
+            .cloned()
+            .collect();
+        DivisionFields { x, y, group }
+    }
+}
+
+impl Side {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Side::Left => "left",
+            Side::Right => "right",
+        }
+    }
+
+    pub fn other(&self) -> Side {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        }
+    }
+
+    /// Rows of this side without a match are kept by `kind`, with nulls for
+    /// the fields of the other side.
+    pub fn outer(&self, kind: JoinKind) -> bool {
+        matches!(
+            (kind, self),
+            (JoinKind::Full, _) | (JoinKind::Left, Side::Left) | (JoinKind::Right, Side::Right)
+        )
+    }
+}
+
+impl Semantics {
+    // 包语义的算子名字后面加 all
+    fn suffix(&self) -> &'static str {
+        match self {
+            Semantics::Set => "",
+            Semantics::Bag => " all",
+        }
+    }
+}
+
+impl SetOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            SetOp::Union => "∪",
+            SetOp::Intersect => "∩",
+            SetOp::Difference => "−",
+        }
+    }
+}
+
+fn names(v: &[Symbol]) -> String {
+    v.iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn sort_keys(v: &[SortKey]) -> String {
+    v.iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn skip(offset: u64) -> String {
+    match offset {
+        0 => String::new(),
+        n => format!(" offset {}", n),
+    }
+}
+
+impl Operator {
+    /// Name of the operator and what it works on, without its conditions.
+    pub fn label(&self) -> String {
+        match self {
+            Operator::TableScan(t) => format!("TableScan {}", t),
+            Operator::IndexScan(s) => format!("IndexScan {} using {}", s.table, s.index),
+            Operator::Filter(_) => "Filter".to_string(),
+            Operator::Project(v, sem) => format!("Project{} [{}]", sem.suffix(), names(v)),
+            Operator::Rename(v) => {
+                let v: Vec<String> = v.iter().map(|(a, b)| format!("{} → {}", a, b)).collect();
+                format!("Rename [{}]", v.join(", "))
+            }
+            Operator::Distinct => "Distinct".to_string(),
+            Operator::NestedLoopJoin(kind, _) => format!("NestedLoopJoin {}", kind.symbol()),
+            Operator::HashJoin(j) => {
+                format!("HashJoin {} build {}", j.kind.symbol(), j.build.name())
+            }
+            Operator::MergeJoin(j) => format!("MergeJoin {}", j.kind.symbol()),
+            Operator::HashAggregate(op) => match op.symbol() {
+                Some(s) => format!("HashAggregate {}[{}]", op.name(), s),
+                None => format!("HashAggregate {}", op.name()),
+            },
+            Operator::HashGroupBy(keys, aggs) => {
+                let aggs: Vec<String> = aggs.iter().map(|a| a.to_string()).collect();
+                format!("HashGroupBy [{}; {}]", names(keys), aggs.join(", "))
+            }
+            Operator::Sort(keys) => format!("Sort [{}]", sort_keys(keys)),
+            Operator::Limit(offset, count) => format!("Limit {}{}", count, skip(*offset)),
+            Operator::TopN(keys, offset, count, last) => {
+                let from = if *last { " from last" } else { "" };
+                format!(
+                    "TopN {}{} [{}]{}",
+                    count,
+                    skip(*offset),
+                    sort_keys(keys),
+                    from
+                )
+            }
+            Operator::HashSetOp(op, sem) => format!("HashSetOp {}{}", op.symbol(), sem.suffix()),
+            Operator::SortSetOp(op, sem) => format!("SortSetOp {}{}", op.symbol(), sem.suffix()),
+            Operator::HashDivision(kind) => format!("HashDivision {}", kind),
+            Operator::SortDivision(kind) => format!("SortDivision {}", kind),
+            Operator::CountDivision(kind) => format!("CountDivision {}", kind),
+            Operator::Empty => "Empty".to_string(),
+            Operator::Shared(id) => format!("#{}", id),
+        }
+    }
+
+    /// Conditions checked by the operator, one per line when explained.
+    pub fn conditions(&self) -> Vec<String> {
+        match self {
+            Operator::IndexScan(s) => match &s.lookup {
+                Lookup::Eq(v) => {
+                    let v: Vec<String> = v.iter().map(|x| x.to_string()).collect();
+                    vec![format!("= ({})", v.join(", "))]
+                }
+                Lookup::Range(l, u) => {
+                    let mut r = vec![];
+                    if let Some(b) = l {
+                        let op = if b.inclusive { ">=" } else { ">" };
+                        r.push(format!("{} {}", op, b.value));
+                    }
+                    if let Some(b) = u {
+                        let op = if b.inclusive { "<=" } else { "<" };
+                        r.push(format!("{} {}", op, b.value));
+                    }
+                    r
+                }
+            },
+            Operator::Filter(f) => vec![f.to_string()],
+            Operator::NestedLoopJoin(_, fs) => fs.iter().map(|f| f.to_string()).collect(),
+            Operator::HashJoin(j) | Operator::MergeJoin(j) => j
+                .keys
+                .iter()
+                .map(|(l, r)| format!("{} = {}", l, r))
+                .chain(j.residual.iter().map(|f| f.to_string()))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
@@ -0,0 +1,1009 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Physical Planner of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::{cell::RefCell, collections::HashMap};
+
+use indexmap::IndexMap;
+
+use super::{
+    Bound, DivisionFields, EquiJoin, IndexScan, Lookup, Operator, PhysicalPlan, Semantics, SetOp,
+    Side,
+};
+use crate::{
+    optimizer::{
+        cost::{CostModel, DefaultCost},
+        cse::{NodeId, PlanDag},
+        pushdown::wrap,
+    },
+    storage::{IndexDef, IndexKind},
+    structs::{
+        plan::{
+            type_check::{get_plan_table_type_in, node_type},
+            CompExpr, FilterExpr, JoinKind, LocPlan, Plan,
+        },
+        plan_group::ReduceOperator,
+        DivisionKind, Expr, Loc, SortKey, Symbol, Value,
+    },
+    type_system::{product_fields, resolve_field, Env, Record, TypeError},
+};
+
+// 建哈希表比扫一遍贵
+const HASH_BUILD_FACTOR: f64 = 2.0;
+
+/// Chooses a physical operator for every node of a logical plan.
+///
+/// Joins with equalities between their two sides may run as hash joins or
+/// merge joins, selections on a table may use an index. The cheapest choice
+/// under the cost model wins, the rows come from the same model.
+/// Under set semantics the output of every operator has no duplicate
+/// rows, under bag semantics projections and set operations keep them. A sub plan found more than once is planned once, under
+/// a `Shared` operator.
+pub struct Planner<'a> {
+    env: &'a Env,
+    model: &'a dyn CostModel,
+    indexes: &'a [IndexDef],
+    semantics: Semantics,
+    // 相关子查询里还能用外面的列
+    outer: Option<&'a Record>,
+    sharing: bool,
+    // 共享的子计划在原计划里的地址 -> 节点, 和已经选好的算子
+    shared: RefCell<HashMap<*const LocPlan, NodeId>>,
+    built: RefCell<HashMap<NodeId, PhysicalPlan>>,
+}
+
+fn sort_cost(rows: f64) -> f64 {
+    rows * rows.max(2.0).log2()
+}
+
+fn cheapest(candidates: Vec<PhysicalPlan>) -> PhysicalPlan {
+    candidates
+        .into_iter()
+        .min_by(|a, b| a.cost.total_cmp(&b.cost))
+        .unwrap()
+}
+
+// 按照连接结果的名字: 结果的名字 -> (是否左边, 下面的名字)
+fn join_names(l: &Record, r: &Record) -> IndexMap<Symbol, (bool, Symbol)> {
+    let side = |t: &Record, left: bool| -> Vec<(Symbol, (bool, Symbol))> {
+        t.0.keys().map(|k| (k.clone(), (left, k.clone()))).collect()
+    };
+    // 类型检查过的计划两边不会有同名的列
+    product_fields(side(l, true), &l.1, side(r, false), &r.1)
+        .unwrap_or_default()
+        .into_iter()
+        .collect()
+}
+
+/// The inputs and conditions of selections over a product or an inner
+/// join, like the ones an inner join with several filters lowers to.
+fn inner_join(plan: &LocPlan) -> Option<(&LocPlan, &LocPlan, Vec<FilterExpr>)> {
+    match &plan.0 {
+        Plan::Selection(a, f) if !f.is_positional() => {
+            let (l, r, mut conds) = inner_join(a)?;
+            conds.extend(f.as_ref().clone().conjuncts());
+            Some((l, r, conds))
+        }
+        Plan::Product(l, r) => Some((l, r, vec![])),
+        Plan::Join(l, r, JoinKind::Inner, fs) => Some((
+            l,
+            r,
+            fs.iter().flat_map(|f| f.clone().conjuncts()).collect(),
+        )),
+        _ => None,
+    }
+}
+
+fn column(e: &Expr) -> Option<&Symbol> {
+    match e {
+        Expr::Value(Loc(Value::Symbol(s), _)) => Some(s),
+        _ => None,
+    }
+}
+
+fn literal(e: &Expr) -> Option<&Value> {
+    match e {
+        Expr::Value(Loc(Value::Symbol(_), _)) | Expr::Value(Loc(Value::Null, _)) => None,
+        Expr::Value(Loc(v, _)) => Some(v),
+        _ => None,
+    }
+}
+
+/// What a conjunct says about one column of a table.
+enum Restriction {
+    Eq(Value),
+    Lower(Bound),
+    Upper(Bound),
+}
+
+// col = v, col < v, v < col, not (col < v) ...
+fn restriction(f: &FilterExpr) -> Option<(&Symbol, Restriction)> {
+    let (c, negated) = match f {
+        FilterExpr::Comp(c) => (c, false),
+        FilterExpr::Not(c) => (c, true),
+        _ => return None,
+    };
+    let (a, b, less) = match c.as_ref() {
+        CompExpr::Eq(a, b) if !negated => {
+            return match (column(a), literal(b), literal(a), column(b)) {
+                (Some(s), Some(v), _, _) | (_, _, Some(v), Some(s)) => {
+                    Some((s, Restriction::Eq(v.clone())))
+                }
+                _ => None,
+            };
+        }
+        CompExpr::Lt(a, b) => (a, b, true),
+        CompExpr::Gt(a, b) => (a, b, false),
+        _ => return None,
+    };
+    // 统一成 col < v 或 col > v
+    let (s, v, less) = match (column(a), literal(b), literal(a), column(b)) {
+        (Some(s), Some(v), _, _) => (s, v, less),
+        (_, _, Some(v), Some(s)) => (s, v, !less),
+        _ => return None,
+    };
+    let bound = Bound {
+        value: v.clone(),
+        inclusive: negated,
+    };
+    // not (col < v) 是 col >= v
+    Some(if less != negated {
+        (s, Restriction::Upper(bound))
+    } else {
+        (s, Restriction::Lower(bound))
+    })
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(env: &'a Env) -> Self {
+        Planner {
+            env,
+            model: &DefaultCost,
+            indexes: &[],
+            semantics: Semantics::Set,
+            outer: None,
+            sharing: true,
+            shared: RefCell::new(HashMap::new()),
+            built: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_semantics(mut self, semantics: Semantics) -> Self {
+        self.semantics = semantics;
+        self
+    }
+
+    pub fn with_model(mut self, model: &'a dyn CostModel) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn with_indexes(mut self, indexes: &'a [IndexDef]) -> Self {
+        self.indexes = indexes;
+        self
+    }
+
+    /// Plan the sub plan of an `in` filter, its filters may use the fields
+    /// of `outer`.
+    pub fn with_outer(mut self, outer: &'a Record) -> Self {
+        self.outer = Some(outer);
+        self
+    }
+
+    /// Whether equal sub plans are evaluated once, they are by default.
+    /// The ids of the `Shared` operators start from 0 in every plan.
+    pub fn with_sharing(mut self, sharing: bool) -> Self {
+        self.sharing = sharing;
+        self
+    }
+
+    pub fn plan(&self, plan: &LocPlan) -> Result<PhysicalPlan, Loc<TypeError>> {
+        self.schema(plan)?;
+        // 相关子查询的结果跟着外面的行变, 不共享; 表本来就存着, 也不用共享
+        let (dag, subs) = PlanDag::with_sub_plans(plan);
+        *self.shared.borrow_mut() = subs
+            .into_iter()
+            .filter(|(p, _)| !matches!(p.0, Plan::Table(_) | Plan::Empty(_)))
+            .filter(|(_, id)| self.sharing && self.outer.is_none() && dag.is_shared(*id))
+            .map(|(p, id)| (p as *const LocPlan, id))
+            .collect();
+        self.built.borrow_mut().clear();
+        self.build(plan)
+    }
+
+    fn schema(&self, plan: &LocPlan) -> Result<Record, Loc<TypeError>> {
+        get_plan_table_type_in(plan, self.env, self.outer)
+    }
+
+    // 这个节点自己的代价是 cost, 加上下面的
+    fn node(
+        &self,
+        op: Operator,
+        children: Vec<PhysicalPlan>,
+        plan: &LocPlan,
+        order: Vec<Symbol>,
+        cost: f64,
+    ) -> Result<PhysicalPlan, Loc<TypeError>> {
+        // 表头从下面的表头算, 不再检查整棵子树; 索引扫描没有输入, 它下面只有表
+        let schema = if children.len() == plan.0.children().len() {
+            let inputs = children.iter().map(|c| c.schema.clone()).collect();
+            node_type(plan, inputs, self.env, self.outer)?
+        } else {
+            self.schema(plan)?
+        };
+        Ok(PhysicalPlan {
+            op,
+            cost: cost + children.iter().map(|c| c.cost).sum::<f64>(),
+            children,
+            schema,
+            order,
+            rows: self.model.rows(plan, self.env),
+        })
+    }
+
+    fn sort(&self, input: PhysicalPlan, keys: Vec<Symbol>) -> PhysicalPlan {
+        if input.order.starts_with(&keys) {
+            return input;
+        }
+        PhysicalPlan {
+            op: Operator::Sort(keys.iter().cloned().map(SortKey::asc).collect()),
+            schema: input.schema.clone(),
+            order: keys,
+            rows: input.rows,
+            cost: input.cost + sort_cost(input.rows),
+            children: vec![input],
+        }
+    }
+
+    fn distinct(&self, input: PhysicalPlan) -> PhysicalPlan {
+        if input.distinct() {
+            return input;
+        }
+        PhysicalPlan {
+            op: Operator::Distinct,
+            schema: input.schema.clone(),
+            order: input.order.clone(),
+            rows: input.rows,
+            cost: input.cost + HASH_BUILD_FACTOR * input.rows,
+            children: vec![input],
+        }
+    }
+
+    // 共享的节点只选一次算子, 再用到时读它的结果
+    fn build(&self, plan: &LocPlan) -> Result<PhysicalPlan, Loc<TypeError>> {
+        let id = match self.shared.borrow().get(&(plan as *const LocPlan)) {
+            Some(id) => *id,
+            None => return self.build_set(plan),
+        };
+        if let Some(p) = self.built.borrow().get(&id) {
+            return Ok(PhysicalPlan {
+                cost: p.rows,
+                ..p.clone()
+            });
+        }
+        let input = self.build_set(plan)?;
+        let p = PhysicalPlan {
+            op: Operator::Shared(id),
+            schema: input.schema.clone(),
+            order: input.order.clone(),
+            rows: input.rows,
+            cost: input.cost,
+            children: vec![input],
+        };
+        self.built.borrow_mut().insert(id, p.clone());
+        Ok(p)
+    }
+
+    // 集合语义下每个算子的结果都没有重复的行, 表里和全外连接补 null 的行可能重复
+    fn build_set(&self, plan: &LocPlan) -> Result<PhysicalPlan, Loc<TypeError>> {
+        let p = self.build_node(plan)?;
+        Ok(match self.semantics {
+            Semantics::Set => self.distinct(p),
+            Semantics::Bag => p,
+        })
+    }
+
+    fn build_node(&self, plan: &LocPlan) -> Result<PhysicalPlan, Loc<TypeError>> {
+        match &plan.0 {
+            Plan::Table(t) => {
+                let rows = self.model.rows(plan, self.env);
+                self.node(Operator::TableScan(t.clone()), vec![], plan, vec![], rows)
+            }
+            Plan::Empty(_) => self.node(Operator::Empty, vec![], plan, vec![], 0.0),
+            Plan::Selection(..) if inner_join(plan).is_some() => {
+                let (l, r, conds) = inner_join(plan).unwrap();
+                self.join(plan, l, r, JoinKind::Inner, conds)
+            }
+            Plan::Selection(a, f) if !f.is_positional() => match &a.0 {
+                Plan::Table(t) => {
+                    let mut candidates = vec![self.filter(plan, a, f)?];
+                    for index in self.indexes.iter().filter(|i| &i.table == t) {
+                        if let Some(p) = self.index_scan(plan, a, index, f)? {
+                            candidates.push(p);
+                        }
+                    }
+                    Ok(cheapest(candidates))
+                }
+                _ => self.filter(plan, a, f),
+            },
+            Plan::Selection(a, f) => self.positional(plan, a, f),
+            Plan::Product(a, b) => self.join(plan, a, b, JoinKind::Inner, vec![]),
+            Plan::Join(a, b, kind, fs) => {
+                let conds = fs.iter().flat_map(|f| f.clone().conjuncts()).collect();
+                self.join(plan, a, b, *kind, conds)
+            }
+            Plan::Projection(a, names) => {
+                let input = self.build(a)?;
+                // 投影掉排序的列以后, 后面的顺序就不算了
+                let order = input
+                    .order
+                    .iter()
+                    .take_while(|k| names.contains(k))
+                    .cloned()
+                    .collect();
+                let cost = input.rows;
+                self.node(
+                    Operator::Project(names.clone(), self.semantics),
+                    vec![input],
+                    plan,
+                    order,
+                    cost,
+                )
+            }
+            Plan::Rename(a, names) => {
+                let input = self.build(a)?;
+                let rename = |k: &Symbol| match names.iter().find(|(old, _)| old == k) {
+                    Some((_, new)) => new.clone(),
+                    None => k.clone(),
+                };
+                let order = input.order.iter().map(rename).collect();
+                self.node(
+                    Operator::Rename(names.clone()),
+                    vec![input],
+                    plan,
+                    order,
+                    0.0,
+                )
+            }
+            Plan::Distinct(a) => {
+                let input = self.build(a)?;
+                if input.distinct() {
+                    return Ok(input);
+                }
+                let (order, cost) = (input.order.clone(), HASH_BUILD_FACTOR * input.rows);
+                self.node(Operator::Distinct, vec![input], plan, order, cost)
+            }
+            Plan::Sort(a, keys) => {
+                let input = self.build(a)?;
+                // 升序而且 null 在前的键才算在 order 里
+                let order: Vec<Symbol> = keys
+                    .iter()
+                    .take_while(|k| **k == SortKey::asc(k.field.clone()))
+                    .map(|k| k.field.clone())
+                    .collect();
+                if order.len() == keys.len() && input.order.starts_with(&order) {
+                    return Ok(input);
+                }
+                let cost = sort_cost(input.rows);
+                self.node(Operator::Sort(keys.clone()), vec![input], plan, order, cost)
+            }
+            Plan::GroupBy(a, keys, aggs) => {
+                let input = self.build(a)?;
+                // 没有分组属性时不用建哈希表
+                let cost = if keys.is_empty() {
+                    input.rows
+                } else {
+                    HASH_BUILD_FACTOR * input.rows
+                };
+                self.node(
+                    Operator::HashGroupBy(keys.clone(), aggs.clone()),
+                    vec![input],
+                    plan,
+                    vec![],
+                    cost,
+                )
+            }
+            Plan::Union(a, b) => self.set_operation(plan, a, b, SetOp::Union),
+            Plan::Intersect(a, b) => self.set_operation(plan, a, b, SetOp::Intersect),
+            Plan::Difference(a, b) => self.set_operation(plan, a, b, SetOp::Difference),
+            Plan::Division(a, b, kind) => self.division(plan, a, b, kind),
+            Plan::Reduce(r) => {
+                let input = self.build(r.sub_plan())?;
+                let cost = input.rows;
+                self.node(
+                    Operator::HashAggregate(ReduceOperator::from(r)),
+                    vec![input],
+                    plan,
+                    vec![],
+                    cost,
+                )
+            }
+        }
+    }
+
+    fn filter(
+        &self,
+        plan: &LocPlan,
+        a: &LocPlan,
+        f: &FilterExpr,
+    ) -> Result<PhysicalPlan, Loc<TypeError>> {
+        let input = self.build(a)?;
+        let (cost, order) = (input.rows, input.order.clone());
+        self.node(Operator::Filter(f.clone()), vec![input], plan, order, cost)
+    }
+
+    // 读到第 n 行就停; 下面是排序时只留前 n 行, 不用全部排好
+    fn positional(
+        &self,
+        plan: &LocPlan,
+        a: &LocPlan,
+        f: &FilterExpr,
+    ) -> Result<PhysicalPlan, Loc<TypeError>> {
+        let (offset, count, last) = match f {
+            FilterExpr::Range(from, to) => (*from, to.saturating_sub(*from), false),
+            FilterExpr::GetItem(i) => (*i, 1, false),
+            FilterExpr::GetFirst => (0, 1, false),
+            _ => (0, 1, true),
+        };
+        let input = self.build(a)?;
+        let n = offset.saturating_add(count) as f64;
+        let order = input.order.clone();
+        if let Operator::Sort(keys) = &input.op {
+            // 排好序的最后一行是反过来排的第一行
+            let keys = if last {
+                keys.iter().map(SortKey::reverse).collect()
+            } else {
+                keys.clone()
+            };
+            let op = Operator::TopN(keys, offset, count, last);
+            let child = input.children.into_iter().next().unwrap();
+            let cost = child.rows * n.min(child.rows).max(2.0).log2();
+            return self.node(op, vec![child], plan, order, cost);
+        }
+        if last {
+            // 最后一行要读完才知道
+            let cost = input.rows;
+            return self.node(Operator::Filter(f.clone()), vec![input], plan, order, cost);
+        }
+        let cost = n.min(input.rows);
+        self.node(
+            Operator::Limit(offset, count),
+            vec![input],
+            plan,
+            order,
+            cost,
+        )
+    }
+
+    fn index_scan(
+        &self,
+        plan: &LocPlan,
+        table: &LocPlan,
+        index: &IndexDef,
+        f: &FilterExpr,
+    ) -> Result<Option<PhysicalPlan>, Loc<TypeError>> {
+        let schema = self.schema(table)?;
+        let at = |s: &Symbol, i: usize| {
+            let col = index
+                .columns
+                .get(i)
+                .and_then(|c| resolve_field(&schema.0, c));
+            col.is_some() && col.map(|c| c.0) == resolve_field(&schema.0, s).map(|c| c.0)
+        };
+        let conjuncts = f.clone().conjuncts();
+        let found: Vec<Option<(&Symbol, Restriction)>> =
+            conjuncts.iter().map(restriction).collect();
+        let mut used = vec![false; conjuncts.len()];
+
+        // 按索引列的顺序找等值条件
+        let mut values = vec![];
+        for i in 0..index.columns.len() {
+            let hit = found.iter().enumerate().find_map(|(j, r)| match r {
+                Some((s, Restriction::Eq(v))) if !used[j] && at(s, i) => Some((j, v.clone())),
+                _ => None,
+            });
+            match hit {
+                Some((j, v)) => {
+                    used[j] = true;
+                    values.push(v);
+                }
+                None => break,
+            }
+        }
+        let lookup = if index.kind == IndexKind::Hash {
+            if values.len() < index.columns.len() {
+                return Ok(None);
+            }
+            Lookup::Eq(values)
+        } else if !values.is_empty() {
+            Lookup::Eq(values)
+        } else {
+            let (mut lower, mut upper) = (None, None);
+            for (j, r) in found.iter().enumerate() {
+                match r {
+                    Some((s, Restriction::Lower(b))) if lower.is_none() && at(s, 0) => {
+                        used[j] = true;
+                        lower = Some(b.clone());
+                    }
+                    Some((s, Restriction::Upper(b))) if upper.is_none() && at(s, 0) => {
+                        used[j] = true;
+                        upper = Some(b.clone());
+                    }
+                    _ => {}
+                }
+            }
+            if lower.is_none() && upper.is_none() {
+                return Ok(None);
+            }
+            Lookup::Range(lower, upper)
+        };
+
+        let (mut taken, mut rest) = (vec![], vec![]);
+        for (f, used) in conjuncts.into_iter().zip(used) {
+            if used {
+                taken.push(Loc(f, plan.1));
+            } else {
+                rest.push(f);
+            }
+        }
+        let scanned = wrap(table.clone(), taken);
+        let table_rows = self.model.rows(table, self.env);
+        let op = Operator::IndexScan(IndexScan {
+            table: index.table.clone(),
+            index: index.name.clone(),
+            lookup,
+        });
+        let rows = self.model.rows(&scanned, self.env);
+        let mut r = self.node(
+            op,
+            vec![],
+            &scanned,
+            vec![],
+            table_rows.max(2.0).log2() + rows,
+        )?;
+        // 剩下的条件在索引查出来的行上过滤
+        let mut input = scanned;
+        for f in rest {
+            let next = Loc(
+                Plan::Selection(Box::new(input), Box::new(f.clone())),
+                plan.1,
+            );
+            let cost = r.rows;
+            r = self.node(Operator::Filter(f), vec![r], &next, vec![], cost)?;
+            input = next;
+        }
+        Ok(Some(r))
+    }
+
+    fn join(
+        &self,
+        plan: &LocPlan,
+        a: &LocPlan,
+        b: &LocPlan,
+        kind: JoinKind,
+        conds: Vec<FilterExpr>,
+    ) -> Result<PhysicalPlan, Loc<TypeError>> {
+        let (l, r) = (self.build(a)?, self.build(b)?);
+        let names = join_names(&l.schema, &r.schema);
+        let lookup = |s: &Symbol| resolve_field(&names, s).map(|(_, v)| v.clone());
+        // 左边的顺序在结果里还在, 换成结果的名字
+        let left_order: Vec<Symbol> = l
+            .order
+            .iter()
+            .filter_map(|k| {
+                names
+                    .iter()
+                    .find(|(_, (left, c))| *left && c == k)
+                    .map(|(n, _)| n.clone())
+            })
+            .collect();
+        // 右边没有匹配的行补在最后
+        let keeps_order = !matches!(kind, JoinKind::Right | JoinKind::Full);
+        let order = if keeps_order && left_order.len() == l.order.len() {
+            left_order
+        } else {
+            vec![]
+        };
+
+        let mut keys = vec![];
+        let mut residual = vec![];
+        for (i, f) in conds.iter().enumerate() {
+            // not in 只有第一个条件是比较, 其余的条件只是挑出右边的行
+            if kind == JoinKind::NullAwareAnti && i > 0 {
+                residual.push(f.clone());
+                continue;
+            }
+            let key = match f {
+                FilterExpr::Comp(c) => match c.as_ref() {
+                    CompExpr::Eq(x, y) => {
+                        match (column(x).and_then(lookup), column(y).and_then(lookup)) {
+                            (Some((true, x)), Some((false, y)))
+                            | (Some((false, y)), Some((true, x))) => Some((x, y)),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            match key {
+                Some(k) => keys.push(k),
+                None => residual.push(f.clone()),
+            }
+        }
+
+        let out = self.model.rows(plan, self.env);
+        let mut candidates = vec![];
+        if !keys.is_empty() {
+            // 哈希表建在小的一边, not in 要看右边所有的行, 总是建在右边
+            let build = if l.rows < r.rows && kind != JoinKind::NullAwareAnti {
+                Side::Left
+            } else {
+                Side::Right
+            };
+            let (probe, table) = match build {
+                Side::Left => (r.rows, l.rows),
+                Side::Right => (l.rows, r.rows),
+            };
+            let join = EquiJoin {
+                kind,
+                keys: keys.clone(),
+                residual: residual.clone(),
+                build,
+            };
+            let cost = probe + HASH_BUILD_FACTOR * table + out;
+            // 按右边的行去找时左边的顺序就没有了
+            let hash_order = match build {
+                Side::Left => vec![],
+                Side::Right => order.clone(),
+            };
+            candidates.push(self.node(
+                Operator::HashJoin(join),
+                vec![l.clone(), r.clone()],
+                plan,
+                hash_order,
+                cost,
+            )?);
+        }
+        if kind == JoinKind::Inner && !keys.is_empty() {
+            let join = EquiJoin {
+                kind,
+                keys: keys.clone(),
+                residual,
+                build: Side::Right,
+            };
+            let (lkeys, rkeys): (Vec<Symbol>, Vec<Symbol>) = keys.into_iter().unzip();
+            let (ls, rs) = (
+                self.sort(l.clone(), lkeys.clone()),
+                self.sort(r.clone(), rkeys),
+            );
+            let cost = ls.rows + rs.rows + out;
+            let merge_order = lkeys
+                .iter()
+                .filter_map(|k| {
+                    names
+                        .iter()
+                        .find(|(_, (left, c))| *left && c == k)
+                        .map(|(n, _)| n.clone())
+                })
+                .collect();
+            candidates.push(self.node(
+                Operator::MergeJoin(join),
+                vec![ls, rs],
+                plan,
+                merge_order,
+                cost,
+            )?);
+        }
+        let cost = l.rows * r.rows;
+        candidates.push(self.node(
+            Operator::NestedLoopJoin(kind, conds),
+            vec![l, r],
+            plan,
+            order,
+            cost,
+        )?);
+        Ok(cheapest(candidates))
+    }
+
+    fn set_operation(
+        &self,
+        plan: &LocPlan,
+        a: &LocPlan,
+        b: &LocPlan,
+        op: SetOp,
+    ) -> Result<PhysicalPlan, Loc<TypeError>> {
+        let (l, r) = (self.build(a)?, self.build(b)?);
+        // 包语义的并集只是把两边接起来
+        let hash_cost = match (op, self.semantics) {
+            (SetOp::Union, Semantics::Bag) => l.rows + r.rows,
+            _ => l.rows + HASH_BUILD_FACTOR * r.rows,
+        };
+        let hash = self.node(
+            Operator::HashSetOp(op, self.semantics),
+            vec![l.clone(), r.clone()],
+            plan,
+            vec![],
+            hash_cost,
+        )?;
+        // 两边按位置对齐, 各自按全部列排序
+        let lkeys: Vec<Symbol> = l.schema.0.keys().cloned().collect();
+        let rkeys: Vec<Symbol> = r.schema.0.keys().cloned().collect();
+        let (ls, rs) = (self.sort(l, lkeys), self.sort(r, rkeys));
+        let cost = ls.rows + rs.rows;
+        let order = hash.schema.0.keys().cloned().collect();
+        let sorted = self.node(
+            Operator::SortSetOp(op, self.semantics),
+            vec![ls, rs],
+            plan,
+            order,
+            cost,
+        )?;
+        Ok(cheapest(vec![hash, sorted]))
+    }
+
+    fn division(
+        &self,
+        plan: &LocPlan,
+        a: &LocPlan,
+        b: &LocPlan,
+        kind: &DivisionKind,
+    ) -> Result<PhysicalPlan, Loc<TypeError>> {
+        Ok(cheapest(self.divisions(plan, a, b, kind)?))
+    }
+
+    // 能用的除法算法, 输入按各自的需要排好序或者去重
+    fn divisions(
+        &self,
+        plan: &LocPlan,
+        a: &LocPlan,
+        b: &LocPlan,
+        kind: &DivisionKind,
+    ) -> Result<Vec<PhysicalPlan>, Loc<TypeError>> {
+        let (l, r) = (self.build(a)?, self.build(b)?);
+        // 被除数每行查一次除数, 再在候选的位图里置一位
+        let cost = 2.0 * l.rows + HASH_BUILD_FACTOR * (l.rows + r.rows);
+        let mut candidates = vec![self.node(
+            Operator::HashDivision(kind.clone()),
+            vec![l.clone(), r.clone()],
+            plan,
+            vec![],
+            cost,
+        )?];
+        if r.distinct() {
+            let l = self.distinct(l.clone());
+            let cost = l.rows + HASH_BUILD_FACTOR * (l.rows + r.rows);
+            candidates.push(self.node(
+                Operator::CountDivision(kind.clone()),
+                vec![l, r.clone()],
+                plan,
+                vec![],
+                cost,
+            )?);
+        }
+        let f = DivisionFields::new(&l.schema, &r.schema, kind);
+        // 每组都要把被除数从头比一遍, 不知道有几组时按最多算
+        let groups = match kind {
+            DivisionKind::Simple => 1.0,
+            _ => r.rows.max(1.0),
+        };
+        let ls = self.sort(l, [f.x.clone(), f.y.clone()].concat());
+        let rs = self.sort(r, [f.group.clone(), f.y].concat());
+        let cost = groups * ls.rows + rs.rows;
+        let order = [f.group, f.x].concat();
+        candidates.push(self.node(
+            Operator::SortDivision(kind.clone()),
+            vec![ls, rs],
+            plan,
+            order,
+            cost,
+        )?);
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::{Executor, Row},
+        storage::IndexKind,
+        structs::plan::{type_check::get_plan_table_type, CompExpr},
+        testing::*,
+    };
+
+    fn join(a: LocPlan, b: LocPlan, f: FilterExpr) -> LocPlan {
+        plan(Plan::Join(
+            Box::new(a),
+            Box::new(b),
+            JoinKind::Inner,
+            vec![f],
+        ))
+    }
+
+    #[test]
+    fn equalities_between_the_sides_use_their_keys() {
+        let env = env();
+        // 两边都有几行的时候逐对比较更便宜
+        let right = plan(Plan::Product(Box::new(table("S")), Box::new(table("T"))));
+        let p = Planner::new(&env)
+            .plan(&join(table("V"), right, eq(sym("d"), sym("c"))))
+            .unwrap();
+        match p.op {
+            Operator::HashJoin(j) | Operator::MergeJoin(j) => {
+                assert_eq!(j.keys, vec![(sym("d"), sym("c"))]);
+                assert!(j.residual.is_empty());
+            }
+            op => panic!("{:?}", op),
+        }
+        let q = join(table("V"), table("S"), gt(sym("d"), 150));
+        let p = Planner::new(&env).plan(&q).unwrap();
+        assert!(matches!(p.op, Operator::NestedLoopJoin(JoinKind::Inner, _)));
+        assert_eq!(p.schema, get_plan_table_type(&q, &env).unwrap());
+    }
+
+    #[test]
+    fn selections_on_a_table_may_use_an_index() {
+        let env = env();
+        let index = [IndexDef {
+            name: "v_d".to_string(),
+            table: "V".to_string(),
+            columns: vec![sym("d")],
+            kind: IndexKind::Hash,
+        }];
+        let v = Box::new(Expr::Value(Loc(Value::Int(200), pos())));
+        let f = FilterExpr::Comp(Box::new(CompExpr::Eq(field(sym("d")), v)));
+        let p = select(table("V"), f);
+        assert!(matches!(
+            Planner::new(&env).plan(&p).unwrap().op,
+            Operator::Filter(_)
+        ));
+        let p = Planner::new(&env)
+            .with_indexes(&index)
+            .with_semantics(Semantics::Bag)
+            .plan(&p)
+            .unwrap();
+        match p.op {
+            Operator::IndexScan(s) => {
+                assert_eq!(s.index, "v_d");
+                assert_eq!(s.lookup, Lookup::Eq(vec![Value::Int(200)]));
+            }
+            op => panic!("{:?}", op),
+        }
+    }
+
+    #[test]
+    fn plans_that_do_not_type_check_are_rejected() {
+        let env = env();
+        assert!(Planner::new(&env).plan(&table("X")).is_err());
+    }
+
+    // R ÷ π[b](σ[f](R)), 每种算法的结果
+    fn quotients(f: FilterExpr) -> Vec<Vec<Row>> {
+        let (db, env) = fixture();
+        let divisor = project(select(table("R"), f), &[sym("b")]);
+        let (a, b) = (table("R"), divisor);
+        let p = plan(Plan::Division(
+            Box::new(a.clone()),
+            Box::new(b.clone()),
+            DivisionKind::Simple,
+        ));
+        let exec = Executor::new(&db, &env);
+        let plans = exec
+            .planner()
+            .divisions(&p, &a, &b, &DivisionKind::Simple)
+            .unwrap();
+        assert_eq!(plans.len(), 3);
+        plans
+            .iter()
+            .map(|p| sorted(exec.execute_physical(p).unwrap().rows))
+            .collect()
+    }
+
+    fn a_is(v: i64) -> FilterExpr {
+        let v = Box::new(Expr::Value(Loc(Value::Int(v), pos())));
+        FilterExpr::Comp(Box::new(CompExpr::Eq(field(sym("a")), v)))
+    }
+
+    #[test]
+    fn division_algorithms_agree() {
+        for q in [
+            quotients(a_is(2)),
+            quotients(a_is(3)),
+            quotients(gt(sym("a"), 9)),
+        ] {
+            assert_eq!(q[0], q[1]);
+            assert_eq!(q[0], q[2]);
+        }
+    }
+
+    #[test]
+    fn null_divisor_values_match_null() {
+        assert_eq!(quotients(a_is(2))[0], vec![vec![Value::Int(2)]]);
+        // 除数只有 null, 被除数里 b 为 null 的行在商里
+        assert_eq!(quotients(a_is(3))[0], vec![vec![Value::Int(3)]]);
+    }
+
+    #[test]
+    fn empty_divisor_keeps_every_quotient_candidate() {
+        let all = quotients(gt(sym("a"), 9)).remove(0);
+        let a = [Some(1), Some(2), Some(3), Some(4), None];
+        let expected: Vec<Row> = a.iter().map(|v| vec![int_value(*v)]).collect();
+        assert_eq!(all, sorted(expected));
+    }
+
+    #[test]
+    fn first_rows_of_a_sort_use_top_n() {
+        let env = env();
+        let sort = plan(Plan::Sort(
+            Box::new(table("V")),
+            vec![SortKey::desc(sym("d"))],
+        ));
+        let p = Planner::new(&env)
+            .with_semantics(Semantics::Bag)
+            .plan(&select(sort.clone(), FilterExpr::Range(1, 3)))
+            .unwrap();
+        assert_eq!(
+            p.op,
+            Operator::TopN(vec![SortKey::desc(sym("d"))], 1, 2, false)
+        );
+        assert!(matches!(p.children[0].op, Operator::TableScan(_)));
+        // 排序和选择中间隔了投影, 就只取前几行
+        let p = Planner::new(&env)
+            .with_semantics(Semantics::Bag)
+            .plan(&select(project(sort, &[sym("d")]), FilterExpr::GetFirst))
+            .unwrap();
+        assert_eq!(p.op, Operator::Limit(0, 1));
+    }
+
+    #[test]
+    fn schemas_match_the_type_checker() {
+        let (_, env) = fixture();
+        for semantics in [Semantics::Set, Semantics::Bag] {
+            for p in queries() {
+                let physical = Planner::new(&env)
+                    .with_semantics(semantics)
+                    .plan(&p)
+                    .unwrap();
+                let r = get_plan_table_type(&p, &env).unwrap();
+                assert!(physical.schema.0.keys().eq(r.0.keys()), "{:?}", p);
+                assert_eq!(physical.schema, r);
+            }
+        }
+    }
+
+    #[test]
+    fn last_row_of_a_sort_is_a_reversed_top_n() {
+        let (db, env) = fixture();
+        // b = 10 的两行键相同, 最后一行是后来的那行
+        let b_gt_15 = CompExpr::Gt(
+            field(sym("b")),
+            Box::new(Expr::Value(Loc(Value::Int(15), pos()))),
+        );
+        let small = select(table("R"), FilterExpr::Not(Box::new(b_gt_15)));
+        let p = select(
+            sort(small, vec![SortKey::asc(sym("b"))]),
+            FilterExpr::GetLast,
+        );
+        let physical = Planner::new(&env).plan(&p).unwrap();
+        let keys = vec![SortKey::asc(sym("b")).reverse()];
+        assert_eq!(physical.op, Operator::TopN(keys, 0, 1, true));
+        for semantics in [Semantics::Set, Semantics::Bag] {
+            let rows = run(&db, &env, &p, semantics);
+            assert_eq!(rows, vec![vec![Value::Int(4), Value::Int(10)]]);
+        }
+    }
+}
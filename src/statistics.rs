@@ -0,0 +1,282 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Table Statistics of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::{cmp::Ordering, convert::TryFrom};
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    storage::Relation,
+    structs::{Symbol, Value},
+    type_system::{Domain, SimpleType, Type},
+};
+
+pub const DEFAULT_BUCKETS: usize = 16;
+
+// ANALYZE 的结果
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "TableStatsForm", into = "TableStatsForm")]
+pub struct TableStats {
+    pub rows: u64,
+    pub columns: IndexMap<Symbol, ColumnStats>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableStatsForm {
+    rows: u64,
+    columns: Vec<(Symbol, ColumnStats)>,
+}
+
+impl From<TableStats> for TableStatsForm {
+    fn from(t: TableStats) -> Self {
+        TableStatsForm {
+            rows: t.rows,
+            columns: t.columns.into_iter().collect(),
+        }
+    }
+}
+
+impl TryFrom<TableStatsForm> for TableStats {
+    type Error = String;
+
+    fn try_from(form: TableStatsForm) -> Result<Self, Self::Error> {
+        let mut columns = IndexMap::new();
+        for (k, c) in form.columns {
+            if columns.insert(k.clone(), c).is_some() {
+                return Err(format!("duplicate column statistics for {}", k));
+            }
+        }
+        Ok(TableStats {
+            rows: form.rows,
+            columns,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    pub null_fraction: f64,
+    // 只有声明了范围的浮点数不知道
+    pub distinct: Option<f64>,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    pub histogram: Histogram,
+}
+
+/// Equi-depth histogram: every bucket holds about the same number of rows.
+/// A run of equal values is never split over two buckets.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Histogram(pub Vec<Bucket>);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub lower: Value,
+    pub upper: Value,
+    pub rows: u64,
+    pub distinct: u64,
+}
+
+fn compare(a: &Value, b: &Value) -> Ordering {
+    a.compare(b).unwrap_or(Ordering::Equal)
+}
+
+fn same(a: &Value, b: &Value) -> bool {
+    compare(a, b) == Ordering::Equal
+}
+
+fn count_distinct(sorted: &[&Value]) -> u64 {
+    let mut n = 0;
+    for (i, v) in sorted.iter().enumerate() {
+        if i == 0 || !same(sorted[i - 1], v) {
+            n += 1;
+        }
+    }
+    n
+}
+
+impl Histogram {
+    pub fn build(sorted: &[&Value], buckets: usize) -> Self {
+        let mut r = vec![];
+        if sorted.is_empty() || buckets == 0 {
+            return Histogram(r);
+        }
+        let depth = sorted.len().div_ceil(buckets);
+        let mut begin = 0;
+        while begin < sorted.len() {
+            let mut end = (begin + depth).min(sorted.len());
+            while end < sorted.len() && same(sorted[end - 1], sorted[end]) {
+                end += 1;
+            }
+            let part = &sorted[begin..end];
+            r.push(Bucket {
+                lower: part[0].clone(),
+                upper: part[part.len() - 1].clone(),
+                rows: part.len() as u64,
+                distinct: count_distinct(part),
+            });
+            begin = end;
+        }
+        Histogram(r)
+    }
+}
+
+impl ColumnStats {
+    pub fn analyze<'a>(values: impl Iterator<Item = &'a Value>, buckets: usize) -> Self {
+        let mut total = 0;
+        let mut sorted: Vec<&Value> = vec![];
+        for v in values {
+            total += 1;
+            if *v != Value::Null {
+                sorted.push(v);
+            }
+        }
+        sorted.sort_by(|a, b| compare(a, b));
+        let nulls = total - sorted.len();
+        ColumnStats {
+            null_fraction: if total == 0 {
+                0.0
+            } else {
+                nulls as f64 / total as f64
+            },
+            distinct: Some(count_distinct(&sorted) as f64),
+            min: sorted.first().map(|v| (*v).clone()),
+            max: sorted.last().map(|v| (*v).clone()),
+            histogram: Histogram::build(&sorted, buckets),
+        }
+    }
+
+    /// What the declared type tells when the table was never analyzed.
+    pub fn from_type(t: &Type) -> Option<Self> {
+        let t = match t {
+            Type::Optional(o) => o.0.as_ref(),
+            t => t,
+        };
+        fn bounds<T: Clone>(d: &Domain<T>) -> (T, T) {
+            match d {
+                Domain::Value(v) => (v.clone(), v.clone()),
+                Domain::Range(l, r) => (l.clone(), r.clone()),
+            }
+        }
+        let (min, max, distinct) = match t.get_simple_type()? {
+            SimpleType::Bool => (Value::Bool(false), Value::Bool(true), Some(2.0)),
+            SimpleType::Int(Some(d)) => {
+                let (l, r) = bounds(d);
+                let n = (r as f64 - l as f64 + 1.0).max(0.0);
+                (Value::Int(l), Value::Int(r), Some(n))
+            }
+            SimpleType::Uint(Some(d)) => {
+                let (l, r) = bounds(d);
+                let n = (r as f64 - l as f64 + 1.0).max(0.0);
+                (Value::Uint(l), Value::Uint(r), Some(n))
+            }
+            SimpleType::Float(Some(d)) => {
+                let (l, r) = bounds(d);
+                let n = if l == r { Some(1.0) } else { None };
+                (Value::Float(l), Value::Float(r), n)
+            }
+            SimpleType::String(e) if !e.is_empty() => {
+                let mut e: Vec<&String> = e.iter().collect();
+                e.sort();
+                e.dedup();
+                let (l, r) = (e[0].clone(), e[e.len() - 1].clone());
+                (Value::String(l), Value::String(r), Some(e.len() as f64))
+            }
+            _ => return None,
+        };
+        Some(ColumnStats {
+            null_fraction: 0.0,
+            distinct,
+            min: Some(min),
+            max: Some(max),
+            histogram: Histogram::default(),
+        })
+    }
+}
+
+impl TableStats {
+    pub fn analyze(rel: &Relation, buckets: usize) -> Self {
+        let columns = rel
+            .header
+            .iter()
+            .enumerate()
+            .map(|(i, k)| (k.clone(), ColumnStats::analyze(rel.column(i), buckets)))
+            .collect();
+        TableStats {
+            rows: rel.rows.len() as u64,
+            columns,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        testing::*,
+        type_system::{Env, Lines, Record, TableName},
+    };
+
+    #[test]
+    fn analyze_counts_rows_nulls_and_distinct_values() {
+        let env = env();
+        let r = TableName("R".to_string());
+        assert_eq!(env.get_stats(&r).unwrap().rows, 6);
+        let a = env.column_stats(&r, &sym("a")).unwrap();
+        assert_eq!(a.null_fraction, 1.0 / 6.0);
+        assert_eq!(a.distinct, Some(4.0));
+        assert_eq!(a.min, Some(Value::Int(1)));
+        assert_eq!(a.max, Some(Value::Int(4)));
+        // 带前缀的名字也能找到
+        let b = env.column_stats(&r, &qualified("R", "b")).unwrap();
+        assert_eq!(b.distinct, Some(3.0));
+    }
+
+    #[test]
+    fn histogram_never_splits_equal_values() {
+        let values: Vec<Value> = [1, 2, 2, 3, 4].iter().map(|v| Value::Int(*v)).collect();
+        let sorted: Vec<&Value> = values.iter().collect();
+        let Histogram(buckets) = Histogram::build(&sorted, 4);
+        let bounds: Vec<(Value, Value, u64, u64)> = buckets
+            .into_iter()
+            .map(|b| (b.lower, b.upper, b.rows, b.distinct))
+            .collect();
+        assert_eq!(
+            bounds,
+            [
+                (Value::Int(1), Value::Int(2), 3, 2),
+                (Value::Int(3), Value::Int(4), 2, 2),
+            ]
+        );
+        assert_eq!(Histogram::build(&[], 4), Histogram::default());
+    }
+
+    #[test]
+    fn declared_domain_is_used_before_analyze() {
+        let t = Type::Simple(SimpleType::Int(Some(Domain::Range(1, 10))));
+        let fields = vec![(sym("x"), nullable(t))].into_iter().collect();
+        let name = TableName("W".to_string());
+        let tables: HashMap<_, _> = vec![(name.clone(), Lines(Record(fields, "W".to_string())))]
+            .into_iter()
+            .collect();
+        let x = Env::new(tables).column_stats(&name, &sym("x")).unwrap();
+        assert_eq!(x.distinct, Some(10.0));
+        assert_eq!((x.min, x.max), (Some(Value::Int(1)), Some(Value::Int(10))));
+    }
+}
@@ -0,0 +1,299 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Storage of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::structs::{SortKey, Symbol, Value};
+
+// 内存里的一张表, 列的顺序和 header 一致
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub name: String,
+    pub header: Vec<Symbol>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Relation {
+    pub fn new(name: &str, header: Vec<Symbol>) -> Self {
+        Relation {
+            name: name.to_string(),
+            header,
+            rows: vec![],
+        }
+    }
+
+    pub fn column_index(&self, name: &Symbol) -> Option<usize> {
+        self.header.iter().position(|k| k == name)
+    }
+
+    pub fn column(&self, index: usize) -> impl Iterator<Item = &Value> {
+        self.rows.iter().map(move |r| &r[index])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    Hash,    // 只能按所有列等值查找
+    Ordered, // 按列的前缀等值查找, 或者按第一列查范围
+}
+
+/// An index declared on some columns of a table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexDef {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<Symbol>,
+    pub kind: IndexKind,
+}
+
+/// Total order used for sorting: null first, then by `Value::compare`.
+/// Values that cannot be compared are treated as equal.
+pub fn order(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Less,
+        (_, Value::Null) => Ordering::Greater,
+        (a, b) => a.compare(b).unwrap_or(Ordering::Equal),
+    }
+}
+
+pub fn order_rows(a: &[Value], b: &[Value]) -> Ordering {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| order(x, y))
+        .find(|o| *o != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Order of two rows on sort keys, each key with the column it reads.
+pub fn order_by(a: &[Value], b: &[Value], keys: &[(usize, &SortKey)]) -> Ordering {
+    keys.iter()
+        .map(|(i, k)| match (&a[*i], &b[*i]) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            // null 的位置和升降序无关
+            (Value::Null, _) if k.nulls_first => Ordering::Less,
+            (Value::Null, _) => Ordering::Greater,
+            (_, Value::Null) if k.nulls_first => Ordering::Greater,
+            (_, Value::Null) => Ordering::Less,
+            (x, y) if k.desc => order(y, x),
+            (x, y) => order(x, y),
+        })
+        .find(|o| *o != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum KeyPart {
+    Null,
+    Bool(bool),
+    // Int, Uint 和整数值的 Float 相等时要落到同一个桶里
+    Int(i128),
+    Float(u64),
+    String(String),
+    Symbol(Symbol),
+}
+
+impl From<&Value> for KeyPart {
+    fn from(v: &Value) -> Self {
+        match v {
+            Value::Null => KeyPart::Null,
+            Value::Bool(b) => KeyPart::Bool(*b),
+            Value::Int(i) => KeyPart::Int(*i as i128),
+            Value::Uint(u) => KeyPart::Int(*u as i128),
+            Value::Float(f) if f.fract() == 0.0 && f.abs() < 1e38 => KeyPart::Int(*f as i128),
+            // -0.0 已经在上面变成了 0
+            Value::Float(f) if f.is_nan() => KeyPart::Float(f64::NAN.to_bits()),
+            Value::Float(f) => KeyPart::Float(f.to_bits()),
+            Value::String(s) => KeyPart::String(s.clone()),
+            Value::Symbol(s) => KeyPart::Symbol(s.clone()),
+        }
+    }
+}
+
+/// Hashable form of a list of values. Values that `Value::compare` finds
+/// equal have equal keys, and null is equal to null.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HashKey(Vec<KeyPart>);
+
+impl HashKey {
+    pub fn new<'a>(values: impl IntoIterator<Item = &'a Value>) -> Self {
+        HashKey(values.into_iter().map(KeyPart::from).collect())
+    }
+
+    pub fn has_null(&self) -> bool {
+        self.0.contains(&KeyPart::Null)
+    }
+}
+
+/// An index built over the rows of a table, it keeps row numbers. Rows
+/// with a null in an indexed column are left out, null is never equal to
+/// anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Index {
+    pub def: IndexDef,
+    columns: Vec<usize>,
+    hash: HashMap<HashKey, Vec<usize>>,
+    // 按索引列排好序的行号
+    sorted: Vec<usize>,
+}
+
+impl Index {
+    pub fn build(def: IndexDef, rel: &Relation) -> Option<Self> {
+        let columns: Vec<usize> = def
+            .columns
+            .iter()
+            .map(|c| rel.column_index(c))
+            .collect::<Option<_>>()?;
+        let key =
+            |r: &Vec<Value>| -> Vec<Value> { columns.iter().map(|i| r[*i].clone()).collect() };
+        let rows =
+            (0..rel.rows.len()).filter(|i| columns.iter().all(|c| rel.rows[*i][*c] != Value::Null));
+        let mut index = Index {
+            def,
+            columns: columns.clone(),
+            hash: HashMap::new(),
+            sorted: vec![],
+        };
+        match index.def.kind {
+            IndexKind::Hash => {
+                for i in rows {
+                    let k = HashKey::new(key(&rel.rows[i]).iter());
+                    index.hash.entry(k).or_default().push(i);
+                }
+            }
+            IndexKind::Ordered => {
+                index.sorted = rows.collect();
+                index
+                    .sorted
+                    .sort_by(|a, b| order_rows(&key(&rel.rows[*a]), &key(&rel.rows[*b])));
+            }
+        }
+        Some(index)
+    }
+
+    fn prefix<'a>(&self, rel: &'a Relation, i: usize, len: usize) -> Vec<&'a Value> {
+        self.columns[..len]
+            .iter()
+            .map(|c| &rel.rows[i][*c])
+            .collect()
+    }
+
+    /// Rows whose first columns equal `values`.
+    pub fn lookup(&self, rel: &Relation, values: &[Value]) -> Vec<usize> {
+        if values.contains(&Value::Null) {
+            return vec![];
+        }
+        match self.def.kind {
+            IndexKind::Hash => self
+                .hash
+                .get(&HashKey::new(values.iter()))
+                .cloned()
+                .unwrap_or_default(),
+            IndexKind::Ordered => {
+                let n = values.len().min(self.columns.len());
+                let cmp = |i: &usize| {
+                    let k = self.prefix(rel, *i, n);
+                    k.iter()
+                        .zip(values.iter())
+                        .map(|(x, y)| order(x, y))
+                        .find(|o| *o != Ordering::Equal)
+                        .unwrap_or(Ordering::Equal)
+                };
+                let begin = self.sorted.partition_point(|i| cmp(i) == Ordering::Less);
+                let end = self.sorted.partition_point(|i| cmp(i) != Ordering::Greater);
+                self.sorted[begin..end].to_vec()
+            }
+        }
+    }
+
+    /// Rows whose first column lies between the bounds, a bound is a value
+    /// and whether it is included. Only for ordered indexes.
+    pub fn range(
+        &self,
+        rel: &Relation,
+        lower: Option<(&Value, bool)>,
+        upper: Option<(&Value, bool)>,
+    ) -> Vec<usize> {
+        let first = |i: &usize| &rel.rows[*i][self.columns[0]];
+        let begin = match lower {
+            Some((v, inclusive)) => self.sorted.partition_point(|i| match order(first(i), v) {
+                Ordering::Less => true,
+                Ordering::Equal => !inclusive,
+                Ordering::Greater => false,
+            }),
+            None => 0,
+        };
+        let end = match upper {
+            Some((v, inclusive)) => self.sorted.partition_point(|i| match order(first(i), v) {
+                Ordering::Less => true,
+                Ordering::Equal => inclusive,
+                Ordering::Greater => false,
+            }),
+            None => self.sorted.len(),
+        };
+        self.sorted[begin..end.max(begin)].to_vec()
+    }
+}
+
+/// The tables and indexes the executor reads from.
+#[derive(Debug, Clone, Default)]
+pub struct Database {
+    tables: HashMap<String, Relation>,
+    indexes: Vec<Index>,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Database::default()
+    }
+
+    /// Add or replace a table, its indexes are built again.
+    pub fn insert(&mut self, rel: Relation) {
+        let defs: Vec<IndexDef> = self
+            .indexes
+            .iter()
+            .filter(|i| i.def.table == rel.name)
+            .map(|i| i.def.clone())
+            .collect();
+        self.indexes.retain(|i| i.def.table != rel.name);
+        self.indexes
+            .extend(defs.into_iter().filter_map(|d| Index::build(d, &rel)));
+        self.tables.insert(rel.name.clone(), rel);
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Relation> {
+        self.tables.get(name)
+    }
+
+    /// Build an index, None when the table or one of the columns is
+    /// missing.
+    pub fn create_index(&mut self, def: IndexDef) -> Option<()> {
+        let rel = self.tables.get(&def.table)?;
+        let index = Index::build(def, rel)?;
+        self.indexes.retain(|i| i.def.name != index.def.name);
+        self.indexes.push(index);
+        Some(())
+    }
+
+    pub fn index(&self, name: &str) -> Option<&Index> {
+        self.indexes.iter().find(|i| i.def.name == name)
+    }
+
+    pub fn index_defs(&self) -> Vec<IndexDef> {
+        self.indexes.iter().map(|i| i.def.clone()).collect()
+    }
+}
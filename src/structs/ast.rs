@@ -15,15 +15,22 @@ You should have received a copy of the GNU General Public License
 along with RAE; see the file COPYING3.  If not see
 <http://www.gnu.org/licenses/>.  */
 
+pub mod lower;
 pub mod type_check;
 
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
 use crate::type_system::TableName;
 
-use super::{Loc, LocExpr, Symbol};
+use super::{
+    group_label, rename_label, sort_label, Aggregate, DivisionKind, Loc, LocExpr, SortKey, Symbol,
+};
 
 pub type LocNode = Loc<Node>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Node {
     CrossProduct(Box<LocNode>, Box<LocNode>),    // 笛卡尔积
     Union(Box<LocNode>, Box<LocNode>),           // 并集
@@ -31,21 +38,30 @@ pub enum Node {
     Intersect(Box<LocNode>, Box<LocNode>),       // 交集
     Selection(Box<LocNode>, Vec<LocFilterExpr>), // 选择
     Projection(Box<LocNode>, Vec<Symbol>),       // 投影
-    Division(Box<LocNode>, Box<LocNode>),        // 除
+    Division(Box<LocNode>, Box<LocNode>, DivisionKind), // 除
     Rename(Box<LocNode>, Vec<(Symbol, Symbol)>), // 重命名
     InnerJoin(Box<LocNode>, Box<LocNode>, Vec<FilterExpr>), // 内连接
-    EquiJoin(Box<LocNode>, Box<LocNode>, Vec<String>), // 等值连接
-    NatureJoin(Box<LocNode>, Box<LocNode>),      // 自然连接
-    LeftJoin(),                                  // 左连接 todo
-    RightJoin(),                                 // 右连接 todo
-    FullJoin(),                                  // 全连接 todo
-    Reduce(LocItemReduce),                       // 聚合
+    EquiJoin(Box<LocNode>, Box<LocNode>, Vec<LocEquiKey>, bool), // 等值连接, bool: 同名键列只保留一份
+    NatureJoin(Box<LocNode>, Box<LocNode>),                      // 自然连接
+    LeftJoin(Box<LocNode>, Box<LocNode>, Vec<FilterExpr>),       // 左连接
+    RightJoin(Box<LocNode>, Box<LocNode>, Vec<FilterExpr>),      // 右连接
+    FullJoin(Box<LocNode>, Box<LocNode>, Vec<FilterExpr>),       // 全连接
+    Reduce(LocItemReduce),                                       // 聚合
     Table(TableName),
+    Distinct(Box<LocNode>),                             // 去重
+    GroupBy(Box<LocNode>, Vec<Symbol>, Vec<Aggregate>), // 分组聚合
+    Sort(Box<LocNode>, Vec<SortKey>),                   // 排序
 }
 
+pub type LocEquiKey = Loc<EquiKey>;
+
+// R ⋈[R.sid = S.id] S 中的一对键, 左边属于左表, 右边属于右表
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EquiKey(pub Symbol, pub Symbol);
+
 pub type LocItemReduce = Loc<ItemReduce>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ItemReduce {
     Count(Box<LocNode>),
     Sum(Box<LocNode>, Symbol),
@@ -56,7 +72,7 @@ pub enum ItemReduce {
 
 pub type LocFilterExpr = Loc<FilterExpr>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum FilterExpr {
     And(Vec<Box<LocCompExpr>>),
     Or(Vec<Box<LocCompExpr>>),
@@ -70,10 +86,125 @@ pub enum FilterExpr {
 
 pub type LocCompExpr = Loc<CompExpr>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CompExpr {
     Eq(Box<LocExpr>, Box<LocExpr>),
     Lt(Box<LocExpr>, Box<LocExpr>),
     Gt(Box<LocExpr>, Box<LocExpr>),
     In(Box<LocExpr>, Box<LocExpr>),
 }
+
+impl Node {
+    pub fn children(&self) -> Vec<&LocNode> {
+        match self {
+            Node::CrossProduct(a, b)
+            | Node::Union(a, b)
+            | Node::Difference(a, b)
+            | Node::Intersect(a, b)
+            | Node::Division(a, b, _)
+            | Node::InnerJoin(a, b, _)
+            | Node::EquiJoin(a, b, _, _)
+            | Node::NatureJoin(a, b)
+            | Node::LeftJoin(a, b, _)
+            | Node::RightJoin(a, b, _)
+            | Node::FullJoin(a, b, _) => vec![a, b],
+            Node::Selection(a, _)
+            | Node::Projection(a, _)
+            | Node::Distinct(a)
+            | Node::GroupBy(a, _, _)
+            | Node::Sort(a, _)
+            | Node::Rename(a, _) => vec![a],
+            Node::Reduce(Loc(r, _)) => match r {
+                ItemReduce::Count(a)
+                | ItemReduce::Sum(a, _)
+                | ItemReduce::Avg(a, _)
+                | ItemReduce::Max(a, _)
+                | ItemReduce::Min(a, _) => vec![a],
+            },
+            Node::Table(_) => vec![],
+        }
+    }
+
+    /// The operator with its arguments, without the sub nodes.
+    pub fn label(&self) -> String {
+        let join = |op: &str, fs: &[FilterExpr]| {
+            if fs.is_empty() {
+                op.to_string()
+            } else {
+                format!("{}[{}]", op, join_with(fs, " and "))
+            }
+        };
+        match self {
+            Node::CrossProduct(_, _) => "×".to_string(),
+            Node::Union(_, _) => "∪".to_string(),
+            Node::Difference(_, _) => "−".to_string(),
+            Node::Intersect(_, _) => "∩".to_string(),
+            Node::Selection(_, fs) => {
+                let fs: Vec<&FilterExpr> = fs.iter().map(|f| &f.0).collect();
+                format!("σ[{}]", join_with(&fs, ", "))
+            }
+            Node::Projection(_, names) => format!("π[{}]", join_with(names, ", ")),
+            Node::Distinct(_) => "δ".to_string(),
+            Node::GroupBy(_, keys, aggs) => group_label(keys, aggs),
+            Node::Sort(_, keys) => sort_label(keys),
+            Node::Division(_, _, kind) => kind.to_string(),
+            Node::Rename(_, names) => rename_label(names),
+            Node::InnerJoin(_, _, fs) => join("⋈", fs),
+            Node::EquiJoin(_, _, ks, merge) => {
+                let ks: Vec<String> = ks
+                    .iter()
+                    .map(|Loc(EquiKey(a, b), _)| format!("{} = {}", a, b))
+                    .collect();
+                let op = if *merge { "⋈=" } else { "⋈" };
+                format!("{}[{}]", op, ks.join(", "))
+            }
+            Node::NatureJoin(_, _) => "⋈*".to_string(),
+            Node::LeftJoin(_, _, fs) => join("⟕", fs),
+            Node::RightJoin(_, _, fs) => join("⟖", fs),
+            Node::FullJoin(_, _, fs) => join("⟗", fs),
+            Node::Reduce(Loc(r, _)) => match r {
+                ItemReduce::Count(_) => "count".to_string(),
+                ItemReduce::Sum(_, s) => format!("sum[{}]", s),
+                ItemReduce::Avg(_, s) => format!("avg[{}]", s),
+                ItemReduce::Max(_, s) => format!("max[{}]", s),
+                ItemReduce::Min(_, s) => format!("min[{}]", s),
+            },
+            Node::Table(TableName(t)) => t.clone(),
+        }
+    }
+}
+
+fn join_with<T: fmt::Display>(v: &[T], sep: &str) -> String {
+    v.iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+impl fmt::Display for CompExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompExpr::Eq(a, b) => write!(f, "{} = {}", a.0, b.0),
+            CompExpr::Lt(a, b) => write!(f, "{} < {}", a.0, b.0),
+            CompExpr::Gt(a, b) => write!(f, "{} > {}", a.0, b.0),
+            CompExpr::In(a, b) => write!(f, "{} in {}", a.0, b.0),
+        }
+    }
+}
+
+impl fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let comps =
+            |v: &[Box<LocCompExpr>]| -> Vec<String> { v.iter().map(|c| c.0.to_string()).collect() };
+        match self {
+            FilterExpr::And(v) => write!(f, "{}", comps(v).join(" and ")),
+            FilterExpr::Or(v) => write!(f, "{}", comps(v).join(" or ")),
+            FilterExpr::Not(c) => write!(f, "not ({})", c.0),
+            FilterExpr::Comp(c) => write!(f, "{}", c.0),
+            FilterExpr::Range(from, to) => write!(f, "{}..{}", from, to),
+            FilterExpr::GetItem(i) => write!(f, "{}", i),
+            FilterExpr::GetFirst => write!(f, "first"),
+            FilterExpr::GetLast => write!(f, "last"),
+        }
+    }
+}
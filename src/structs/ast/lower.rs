@@ -0,0 +1,832 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is AST to Plan lowering of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use indexmap::IndexMap;
+
+use super::type_check::{get_in_table, TypeInfer};
+use super::*;
+use crate::{
+    structs::{plan, plan::LocPlan, plan::Plan, Aggregate, Expr, Pos, SortKey, Value},
+    type_system::{product_fields, resolve_field, Env, Lines, Record, TypeError},
+};
+
+pub trait Lower {
+    fn lower(&self, env: &Env) -> Result<LocPlan, Loc<TypeError>>;
+}
+
+/// Lower a type checked query into a plan.
+///
+/// References to renamed fields are remapped to the source name, and a
+/// merged join key keeps the name of its left copy. Where the source names
+/// clash, like in a product of a table with itself or a division on
+/// renamed fields, the inputs get a Rename to their names in the query.
+/// The plan is type checked before it is returned.
+impl Lower for LocNode {
+    fn lower(&self, env: &Env) -> Result<LocPlan, Loc<TypeError>> {
+        self.type_infer(env)?;
+        let (p, _) = lower_node(self, env)?;
+        p.type_infer(env)?;
+        Ok(p)
+    }
+}
+
+// ast 中的名字 -> plan 中的名字, 按列的顺序
+#[derive(Debug, Clone)]
+pub(crate) struct Scope {
+    fields: IndexMap<Symbol, Symbol>,
+    name: String,
+}
+
+impl Scope {
+    fn resolve(&self, name: &Symbol, pos: Pos) -> Result<(&Symbol, &Symbol), Loc<TypeError>> {
+        resolve_field(&self.fields, name)
+            .ok_or_else(|| Loc(TypeError::FieldNotFound(name.clone()), pos))
+    }
+}
+
+// 笛卡尔积两边的 plan 名字, 按 (是否左边, ast 名字) 查.
+// 重命名后两边可能是同名的表, plan 里分不清的列不支持
+fn product_plan_names(
+    sa: &Scope,
+    sb: &Scope,
+    pos: Pos,
+) -> Result<IndexMap<(bool, Symbol), Symbol>, Loc<TypeError>> {
+    let side = |s: &Scope, left: bool| -> Vec<(Symbol, (bool, Symbol))> {
+        s.fields
+            .iter()
+            .map(|(k, v)| (v.clone(), (left, k.clone())))
+            .collect()
+    };
+    let names = product_fields(side(sa, true), &sa.name, side(sb, false), &sb.name)
+        .map_err(|k| Loc(TypeError::UnsupportedRename(k), pos))?;
+    Ok(names.into_iter().map(|(k, v)| (v, k)).collect())
+}
+
+// plan 里的列改成 ast 里的名字
+fn aliased((p, s): (LocPlan, Scope)) -> (LocPlan, Scope) {
+    let names: Vec<(Symbol, Symbol)> = s
+        .fields
+        .iter()
+        .filter(|(k, v)| k != v)
+        .map(|(k, v)| (v.clone(), k.clone()))
+        .collect();
+    if names.is_empty() {
+        return (p, s);
+    }
+    let pos = p.1;
+    let scope = Scope {
+        fields: s.fields.keys().map(|k| (k.clone(), k.clone())).collect(),
+        name: s.name,
+    };
+    (Loc(Plan::Rename(Box::new(p), names), pos), scope)
+}
+
+pub(crate) type Lowered = (LocPlan, Scope);
+
+// 连接的两边, plan 里分不清的列先改名
+fn pair(l: Lowered, r: Lowered, pos: Pos) -> (Lowered, Lowered) {
+    match product_plan_names(&l.1, &r.1, pos) {
+        Ok(_) => (l, r),
+        Err(_) => (aliased(l), aliased(r)),
+    }
+}
+
+// 和类型检查一样: 去掉合并的键再做笛卡尔积, 合并的键放在最后
+fn merged_scope(
+    sa: &Scope,
+    sb: &Scope,
+    merged: &[(Symbol, Symbol)],
+    pos: Pos,
+) -> Result<Scope, Loc<TypeError>> {
+    let names = product_plan_names(sa, sb, pos)?;
+    let side = |s: &Scope, left: bool| -> Vec<(Symbol, (bool, Symbol))> {
+        s.fields
+            .keys()
+            .filter(|k| {
+                !merged
+                    .iter()
+                    .any(|(m1, m2)| if left { m1 == *k } else { m2 == *k })
+            })
+            .map(|k| (k.clone(), (left, k.clone())))
+            .collect()
+    };
+    let mut fields: IndexMap<Symbol, Symbol> =
+        product_fields(side(sa, true), &sa.name, side(sb, false), &sb.name)
+            .map_err(|k| Loc(TypeError::DuplicateField(k), pos))?
+            .into_iter()
+            .map(|(k, v)| (k, names[&v].clone()))
+            .collect();
+    fields.extend(merged.iter().map(|(m, _)| {
+        (
+            Symbol(m.column().to_string(), None),
+            names[&(true, m.clone())].clone(),
+        )
+    }));
+    Ok(Scope {
+        fields,
+        name: format!("{}*{}", sa.name, sb.name),
+    })
+}
+
+#[inline]
+fn symbol_expr(name: Symbol, pos: Pos) -> Box<Expr> {
+    Box::new(Expr::Value(Loc(Value::Symbol(name), pos)))
+}
+
+fn lower_expr(e: &LocExpr, s: &Scope) -> Result<LocExpr, Loc<TypeError>> {
+    let Loc(e, pos) = e;
+    let bin = |a: &LocExpr, b: &LocExpr| -> Result<_, Loc<TypeError>> {
+        Ok((Box::new(lower_expr(a, s)?), Box::new(lower_expr(b, s)?)))
+    };
+    let r = match e {
+        Expr::Add(a, b) => bin(a, b).map(|(a, b)| Expr::Add(a, b))?,
+        Expr::Sub(a, b) => bin(a, b).map(|(a, b)| Expr::Sub(a, b))?,
+        Expr::Mul(a, b) => bin(a, b).map(|(a, b)| Expr::Mul(a, b))?,
+        Expr::Div(a, b) => bin(a, b).map(|(a, b)| Expr::Div(a, b))?,
+        Expr::Mod(a, b) => bin(a, b).map(|(a, b)| Expr::Mod(a, b))?,
+        Expr::And(a, b) => bin(a, b).map(|(a, b)| Expr::And(a, b))?,
+        Expr::Or(a, b) => bin(a, b).map(|(a, b)| Expr::Or(a, b))?,
+        Expr::Not(a) => Expr::Not(Box::new(lower_expr(a, s)?)),
+        Expr::Value(Loc(Value::Symbol(name), vpos)) => {
+            let (_, name) = s.resolve(name, *vpos)?;
+            Expr::Value(Loc(Value::Symbol(name.clone()), *vpos))
+        }
+        Expr::Value(v) => Expr::Value(v.clone()),
+    };
+    Ok(Loc(r, *pos))
+}
+
+fn lower_comp(c: &LocCompExpr, s: &Scope, env: &Env) -> Result<plan::CompExpr, Loc<TypeError>> {
+    let expr = |e: &LocExpr| lower_expr(e, s).map(|Loc(e, _)| Box::new(e));
+    let r = match &c.0 {
+        CompExpr::Eq(a, b) => plan::CompExpr::Eq(expr(a)?, expr(b)?),
+        CompExpr::Lt(a, b) => plan::CompExpr::Lt(expr(a)?, expr(b)?),
+        CompExpr::Gt(a, b) => plan::CompExpr::Gt(expr(a)?, expr(b)?),
+        CompExpr::In(a, b) => {
+            // 右边的表名变成子计划
+            let (tname, _) = get_in_table(b, env)?;
+            plan::CompExpr::In(expr(a)?, Box::new(Loc(Plan::Table(tname.0), b.1)))
+        }
+    };
+    Ok(r)
+}
+
+fn lower_filter(f: &FilterExpr, s: &Scope, env: &Env) -> Result<plan::FilterExpr, Loc<TypeError>> {
+    let comps = |v: &Vec<Box<LocCompExpr>>| -> Result<Vec<Box<plan::CompExpr>>, Loc<TypeError>> {
+        v.iter()
+            .map(|c| lower_comp(c, s, env).map(Box::new))
+            .collect()
+    };
+    let r = match f {
+        FilterExpr::And(v) => plan::FilterExpr::And(comps(v)?),
+        FilterExpr::Or(v) => plan::FilterExpr::Or(comps(v)?),
+        FilterExpr::Not(c) => plan::FilterExpr::Not(Box::new(lower_comp(c, s, env)?)),
+        FilterExpr::Comp(c) => plan::FilterExpr::Comp(Box::new(lower_comp(c, s, env)?)),
+        FilterExpr::Range(a, b) => plan::FilterExpr::Range(*a, *b),
+        FilterExpr::GetItem(i) => plan::FilterExpr::GetItem(*i),
+        FilterExpr::GetFirst => plan::FilterExpr::GetFirst,
+        FilterExpr::GetLast => plan::FilterExpr::GetLast,
+    };
+    Ok(r)
+}
+
+// 集合运算按位置对齐, 右边列的顺序和左边不同时先投影
+fn lower_set_operation(
+    (p1, s1): Lowered,
+    (p2, s2): Lowered,
+    r2: &LocNode,
+    pos: Pos,
+    f: fn(Box<LocPlan>, Box<LocPlan>) -> Plan,
+) -> Result<(LocPlan, Scope), Loc<TypeError>> {
+    let p2 = if s1.fields.keys().eq(s2.fields.keys()) {
+        p2
+    } else {
+        let names = s1.fields.keys().map(|k| s2.fields[k].clone()).collect();
+        Loc(Plan::Projection(Box::new(p2), names), r2.1)
+    };
+    Ok((Loc(f(Box::new(p1), Box::new(p2)), pos), s1))
+}
+
+fn lower_node(node: &LocNode, env: &Env) -> Result<(LocPlan, Scope), Loc<TypeError>> {
+    let inputs = node
+        .0
+        .children()
+        .into_iter()
+        .map(|c| lower_node(c, env))
+        .collect::<Result<_, _>>()?;
+    lower_step(node, inputs, env)
+}
+
+/// Lower `node` from its lowered sub nodes, in the order of
+/// `Node::children`. The sub nodes are not lowered again.
+pub(crate) fn lower_step(
+    node: &LocNode,
+    inputs: Vec<Lowered>,
+    env: &Env,
+) -> Result<Lowered, Loc<TypeError>> {
+    let pos = node.1;
+    let mut inputs = inputs.into_iter();
+    let mut input = || inputs.next().ok_or(Loc(TypeError::IsNotTable, pos));
+    match &node.0 {
+        Node::Table(tname) => {
+            let Lines(Record(rt, name)) = env
+                .get_table(tname)
+                .ok_or_else(|| Loc(TypeError::TableNotFound(tname.clone()), pos))?;
+            let scope = Scope {
+                fields: rt.keys().map(|k| (k.clone(), k.clone())).collect(),
+                name: name.clone(),
+            };
+            Ok((Loc(Plan::Table(tname.0.clone()), pos), scope))
+        }
+        Node::CrossProduct(_, _) => {
+            let ((p1, s1), (p2, s2)) = pair(input()?, input()?, pos);
+            let scope = merged_scope(&s1, &s2, &[], pos)?;
+            Ok((Loc(Plan::Product(Box::new(p1), Box::new(p2)), pos), scope))
+        }
+        Node::Union(_, r2) => lower_set_operation(input()?, input()?, r2, pos, Plan::Union),
+        Node::Difference(_, r2) => {
+            lower_set_operation(input()?, input()?, r2, pos, Plan::Difference)
+        }
+        Node::Intersect(_, r2) => lower_set_operation(input()?, input()?, r2, pos, Plan::Intersect),
+        Node::Selection(_, fs) => {
+            let (mut p, s) = input()?;
+            for Loc(f, fpos) in fs {
+                let f = lower_filter(f, &s, env)?;
+                p = Loc(Plan::Selection(Box::new(p), Box::new(f)), *fpos);
+            }
+            Ok((p, s))
+        }
+        Node::Projection(_, names) => {
+            let (p, s) = input()?;
+            let fields: IndexMap<Symbol, Symbol> = names
+                .iter()
+                .map(|k| {
+                    s.fields
+                        .get(k)
+                        .map(|v| (k.clone(), v.clone()))
+                        .ok_or(Loc(TypeError::InValidProjectionNames, pos))
+                })
+                .collect::<Result<_, _>>()?;
+            let names = fields.values().cloned().collect();
+            let scope = Scope {
+                fields,
+                name: s.name,
+            };
+            Ok((Loc(Plan::Projection(Box::new(p), names), pos), scope))
+        }
+        Node::Distinct(_) => {
+            let (p, s) = input()?;
+            Ok((Loc(Plan::Distinct(Box::new(p)), pos), s))
+        }
+        Node::Sort(_, keys) => {
+            let (p, s) = input()?;
+            let keys = keys
+                .iter()
+                .map(|k| {
+                    let (_, v) = s.resolve(&k.field, pos)?;
+                    Ok(SortKey {
+                        field: v.clone(),
+                        ..k.clone()
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            Ok((Loc(Plan::Sort(Box::new(p), keys), pos), s))
+        }
+        Node::GroupBy(_, keys, aggs) => {
+            let (p, s) = input()?;
+            match lower_group_by(&p, &s, keys, aggs, pos) {
+                Err(Loc(TypeError::UnsupportedRename(_), _)) => {
+                    let (p, s) = aliased((p, s));
+                    lower_group_by(&p, &s, keys, aggs, pos)
+                }
+                r => r,
+            }
+        }
+        Node::Rename(_, names) => {
+            let (p, mut s) = input()?;
+            for (old, new) in names {
+                let (old, _) = s.resolve(old, pos)?;
+                let old = old.clone();
+                s.fields = s
+                    .fields
+                    .into_iter()
+                    .map(|(k, v)| if k == old { (new.clone(), v) } else { (k, v) })
+                    .collect();
+            }
+            Ok((p, s))
+        }
+        Node::Division(_, _, kind) => {
+            let (l, r) = (input()?, input()?);
+            match lower_division(&l, &r, kind, pos) {
+                Err(Loc(TypeError::UnsupportedRename(_), _)) => {
+                    lower_division(&aliased(l), &aliased(r), kind, pos)
+                }
+                r => r,
+            }
+        }
+        Node::InnerJoin(_, _, fs) => {
+            let ((p1, s1), (p2, s2)) = pair(input()?, input()?, pos);
+            let scope = merged_scope(&s1, &s2, &[], pos)?;
+            let mut p = Loc(Plan::Product(Box::new(p1), Box::new(p2)), pos);
+            for f in fs {
+                let f = lower_filter(f, &scope, env)?;
+                p = Loc(Plan::Selection(Box::new(p), Box::new(f)), pos);
+            }
+            Ok((p, scope))
+        }
+        Node::EquiJoin(_, _, ks, merge) => {
+            let ((p1, s1), (p2, s2)) = pair(input()?, input()?, pos);
+            let names = product_plan_names(&s1, &s2, pos)?;
+            let mut conds = vec![];
+            let mut merged = vec![];
+            for Loc(EquiKey(k1, k2), kpos) in ks {
+                let (a1, _) = s1.resolve(k1, *kpos)?;
+                let (a2, _) = s2.resolve(k2, *kpos)?;
+                let n1 = names[&(true, a1.clone())].clone();
+                let n2 = names[&(false, a2.clone())].clone();
+                conds.push(Box::new(plan::CompExpr::Eq(
+                    symbol_expr(n1, *kpos),
+                    symbol_expr(n2, *kpos),
+                )));
+                if *merge && a1.column() == a2.column() {
+                    merged.push((a1.clone(), a2.clone()));
+                }
+            }
+            let p = Loc(Plan::Product(Box::new(p1), Box::new(p2)), pos);
+            let p = Loc(
+                Plan::Selection(Box::new(p), Box::new(plan::FilterExpr::And(conds))),
+                pos,
+            );
+            lower_merged_join(p, &s1, &s2, &merged)
+        }
+        Node::NatureJoin(_, _) => {
+            let ((p1, s1), (p2, s2)) = pair(input()?, input()?, pos);
+            let names = product_plan_names(&s1, &s2, pos)?;
+            let merged: Vec<(Symbol, Symbol)> = s1
+                .fields
+                .keys()
+                .filter(|k| s2.fields.contains_key(*k))
+                .map(|k| (k.clone(), k.clone()))
+                .collect();
+            let conds: Vec<Box<plan::CompExpr>> = merged
+                .iter()
+                .map(|(k1, k2)| {
+                    Box::new(plan::CompExpr::Eq(
+                        symbol_expr(names[&(true, k1.clone())].clone(), pos),
+                        symbol_expr(names[&(false, k2.clone())].clone(), pos),
+                    ))
+                })
+                .collect();
+            let mut p = Loc(Plan::Product(Box::new(p1), Box::new(p2)), pos);
+            if !conds.is_empty() {
+                p = Loc(
+                    Plan::Selection(Box::new(p), Box::new(plan::FilterExpr::And(conds))),
+                    pos,
+                );
+            }
+            lower_merged_join(p, &s1, &s2, &merged)
+        }
+        Node::LeftJoin(_, _, fs) | Node::RightJoin(_, _, fs) | Node::FullJoin(_, _, fs) => {
+            let kind = match &node.0 {
+                Node::LeftJoin(..) => plan::JoinKind::Left,
+                Node::RightJoin(..) => plan::JoinKind::Right,
+                _ => plan::JoinKind::Full,
+            };
+            let ((p1, s1), (p2, s2)) = pair(input()?, input()?, pos);
+            let scope = merged_scope(&s1, &s2, &[], pos)?;
+            let fs = fs
+                .iter()
+                .map(|f| lower_filter(f, &scope, env))
+                .collect::<Result<_, _>>()?;
+            let p = Plan::Join(Box::new(p1), Box::new(p2), kind, fs);
+            Ok((Loc(p, pos), scope))
+        }
+        Node::Reduce(Loc(reduce, _)) => {
+            let (name, op) = match reduce {
+                ItemReduce::Count(_) => (None, "count"),
+                ItemReduce::Sum(_, name) => (Some(name), "sum"),
+                ItemReduce::Avg(_, name) => (Some(name), "avg"),
+                ItemReduce::Max(_, name) => (Some(name), "max"),
+                ItemReduce::Min(_, name) => (Some(name), "min"),
+            };
+            let (p, s) = input()?;
+            let p = Box::new(p);
+            let (field, reduce) = if let Some(name) = name {
+                let (k, v) = s.resolve(name, pos)?;
+                let reduce = match reduce {
+                    ItemReduce::Sum(..) => plan::ItemReduce::Sum(p, v.clone()),
+                    ItemReduce::Avg(..) => plan::ItemReduce::Avg(p, v.clone()),
+                    ItemReduce::Max(..) => plan::ItemReduce::Max(p, v.clone()),
+                    _ => plan::ItemReduce::Min(p, v.clone()),
+                };
+                ((k.clone(), v.clone()), reduce)
+            } else {
+                let count = Symbol("count".to_string(), None);
+                ((count.clone(), count), plan::ItemReduce::Count(p))
+            };
+            let scope = Scope {
+                fields: vec![field].into_iter().collect(),
+                name: format!("{}({})", op, s.name),
+            };
+            Ok((Loc(Plan::Reduce(reduce), pos), scope))
+        }
+    }
+}
+
+// 合并同名的键: 投影掉右边的那一份
+// 重命名过的分组属性可能和别名撞上, 撞上时先给输入改名
+fn lower_group_by(
+    p: &LocPlan,
+    s: &Scope,
+    keys: &[Symbol],
+    aggs: &[Aggregate],
+    pos: Pos,
+) -> Result<(LocPlan, Scope), Loc<TypeError>> {
+    let mut fields: IndexMap<Symbol, Symbol> = IndexMap::new();
+    let mut plan_keys = vec![];
+    for key in keys {
+        let (k, v) = s.resolve(key, pos)?;
+        fields.insert(k.clone(), v.clone());
+        plan_keys.push(v.clone());
+    }
+    let mut plan_aggs = vec![];
+    for a in aggs {
+        let (name, op) = match a.op.symbol() {
+            Some(field) => {
+                let (k, v) = s.resolve(field, pos)?;
+                (k.clone(), a.op.with_symbol(v.clone()))
+            }
+            None => (Symbol("count".to_string(), None), a.op.clone()),
+        };
+        // 没有别名时 plan 中的名字是聚合的那一列
+        let plan_name = match (&a.alias, op.symbol()) {
+            (Some(alias), _) => alias.clone(),
+            (None, Some(v)) => v.clone(),
+            (None, None) => name.clone(),
+        };
+        // 重命名过的分组属性可能和别名撞上
+        if fields.values().any(|v| *v == plan_name) {
+            return Err(Loc(TypeError::UnsupportedRename(plan_name), pos));
+        }
+        fields.insert(a.alias.clone().unwrap_or(name), plan_name);
+        plan_aggs.push(Aggregate {
+            op,
+            alias: a.alias.clone(),
+        });
+    }
+    let scope = Scope {
+        fields,
+        name: s.name.clone(),
+    };
+    let p = Plan::GroupBy(Box::new(p.clone()), plan_keys, plan_aggs);
+    Ok((Loc(p, pos), scope))
+}
+
+// 除法按名字比较, 对不上时先给两边改名
+fn lower_division(
+    (p1, s1): &Lowered,
+    (p2, s2): &Lowered,
+    kind: &DivisionKind,
+    pos: Pos,
+) -> Result<(LocPlan, Scope), Loc<TypeError>> {
+    let grouping: Vec<Symbol> = match kind {
+        DivisionKind::Simple => vec![],
+        DivisionKind::Great => s2
+            .fields
+            .keys()
+            .filter(|k| !s1.fields.contains_key(*k))
+            .cloned()
+            .collect(),
+        DivisionKind::Grouped(g) => g.clone(),
+    };
+    let mut quotient = s1.fields.clone();
+    for (k, v) in s2.fields.iter().filter(|(k, _)| !grouping.contains(k)) {
+        // 除法按名字比较, 重命名过的键没法对上
+        if quotient.shift_remove(k).as_ref() != Some(v) {
+            return Err(Loc(TypeError::UnsupportedRename(k.clone()), pos));
+        }
+    }
+    for k in grouping.iter() {
+        let v = &s2.fields[k];
+        // 分组属性在 plan 里和商的列同名
+        if quotient.values().any(|q| q == v) {
+            return Err(Loc(TypeError::UnsupportedRename(k.clone()), pos));
+        }
+        quotient.insert(k.clone(), v.clone());
+    }
+    // 重命名后 plan 里的列名和 ast 的不一样, ÷* 要按 ast 的名字定分组属性
+    let kind = match kind {
+        DivisionKind::Simple => DivisionKind::Simple,
+        _ => DivisionKind::Grouped(grouping.iter().map(|k| s2.fields[k].clone()).collect()),
+    };
+    let scope = Scope {
+        fields: quotient,
+        name: format!("{}/{}", s1.name, s2.name),
+    };
+    let p = Plan::Division(Box::new(p1.clone()), Box::new(p2.clone()), kind);
+    Ok((Loc(p, pos), scope))
+}
+
+fn lower_merged_join(
+    p: LocPlan,
+    s1: &Scope,
+    s2: &Scope,
+    merged: &[(Symbol, Symbol)],
+) -> Result<(LocPlan, Scope), Loc<TypeError>> {
+    let scope = merged_scope(s1, s2, merged, p.1)?;
+    if merged.is_empty() {
+        return Ok((p, scope));
+    }
+    let pos = p.1;
+    let names = scope.fields.values().cloned().collect();
+    Ok((Loc(Plan::Projection(Box::new(p), names), pos), scope))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        physical::Semantics,
+        structs::{ast::type_check::TypeWarn, plan_group::ReduceOperator, LocExpr},
+        testing::*,
+        type_system::Type,
+    };
+
+    fn rename(n: LocNode, from: &str, to: &str) -> LocNode {
+        node(Node::Rename(Box::new(n), vec![(sym(from), sym(to))]))
+    }
+
+    fn lowered(n: LocNode) -> LocPlan {
+        n.lower(&env()).unwrap()
+    }
+
+    fn ast_gt(a: Symbol, v: i64) -> Loc<FilterExpr> {
+        let e = |v: Value| Box::new(Loc(Expr::Value(Loc(v, pos())), pos()));
+        let c = CompExpr::Gt(e(Value::Symbol(a)), e(Value::Int(v)));
+        Loc(FilterExpr::Comp(Box::new(Loc(c, pos()))), pos())
+    }
+
+    #[test]
+    fn renamed_fields_use_the_source_names() {
+        let q = node(Node::Projection(
+            Box::new(rename(table_node("R"), "b", "y")),
+            vec![sym("y")],
+        ));
+        assert_eq!(lowered(q), project(table("R"), &[sym("b")]));
+        let q = node(Node::Selection(
+            Box::new(rename(table_node("R"), "b", "y")),
+            vec![ast_gt(sym("y"), 1)],
+        ));
+        assert_eq!(lowered(q), select(table("R"), gt(sym("b"), 1)));
+    }
+
+    #[test]
+    fn natural_join_is_a_filtered_product_without_the_right_keys() {
+        let q = node(Node::NatureJoin(
+            Box::new(table_node("R")),
+            Box::new(table_node("S")),
+        ));
+        let product = plan(Plan::Product(Box::new(table("R")), Box::new(table("S"))));
+        let cond = plan::CompExpr::Eq(field(qualified("R", "b")), field(qualified("S", "b")));
+        let filtered = select(product, plan::FilterExpr::And(vec![Box::new(cond)]));
+        let names = [sym("a"), sym("c"), qualified("R", "b")];
+        assert_eq!(lowered(q), project(filtered, &names));
+    }
+
+    #[test]
+    fn lowering_checks_the_query_first() {
+        let q = node(Node::Projection(Box::new(table_node("R")), vec![sym("c")]));
+        let e = q.lower(&env()).unwrap_err();
+        assert_eq!(e.0, TypeError::InValidProjectionNames);
+    }
+
+    fn product(a: LocNode, b: LocNode) -> LocNode {
+        node(Node::CrossProduct(Box::new(a), Box::new(b)))
+    }
+
+    fn width(t: Type) -> usize {
+        match t {
+            Type::Table(Lines(Record(fields, _))) => fields.len(),
+            t => panic!("{:?} is not a table", t),
+        }
+    }
+
+    #[test]
+    fn self_product_renames_one_side() {
+        let (db, env) = fixture();
+        let q = product(rename(table_node("T"), "a", "c"), table_node("T"));
+        let p = q.lower(&env).unwrap();
+        assert_eq!(p.type_infer(&env).unwrap(), q.type_infer(&env).unwrap());
+        assert_eq!(run(&db, &env, &p, Semantics::Bag).len(), 5 * 5);
+        assert_eq!(run(&db, &env, &p, Semantics::Set).len(), 4 * 4);
+    }
+
+    #[test]
+    fn product_with_clashing_fields_is_rejected() {
+        let (_, env) = fixture();
+        let q = product(table_node("R"), table_node("R"));
+        let e = q.type_infer(&env).unwrap_err();
+        assert!(matches!(e.0, TypeError::DuplicateField(_)), "{:?}", e);
+    }
+
+    #[test]
+    fn renamed_product_keeps_every_column() {
+        let (db, env) = fixture();
+        let q = product(rename(table_node("T"), "a", "c"), table_node("R"));
+        let p = q.lower(&env).unwrap();
+        assert_eq!(width(q.type_infer(&env).unwrap()), 3);
+        assert_eq!(width(p.type_infer(&env).unwrap()), 3);
+        let rows = run(&db, &env, &p, Semantics::Bag);
+        assert_eq!(rows.len(), 5 * 6);
+        assert!(rows.iter().all(|r| r.len() == 3));
+        // T 和 R 里都有一行重复
+        let set = run(&db, &env, &p, Semantics::Set);
+        assert_eq!(set.len(), 4 * 5);
+        assert!(set.iter().all(|r| rows.contains(r)));
+    }
+
+    fn value(v: Value) -> Box<LocExpr> {
+        Box::new(Loc(Expr::Value(Loc(v, pos())), pos()))
+    }
+
+    fn comp(c: CompExpr) -> FilterExpr {
+        FilterExpr::Comp(Box::new(Loc(c, pos())))
+    }
+
+    fn both(f: fn(Box<LocNode>, Box<LocNode>) -> Node, a: &str, b: &str) -> LocNode {
+        node(f(Box::new(table_node(a)), Box::new(table_node(b))))
+    }
+
+    fn on(f: fn(Box<LocNode>, Box<LocNode>, Vec<FilterExpr>) -> Node) -> LocNode {
+        let b = |r: &str| value(Value::Symbol(Symbol(r.to_string(), Some("b".to_string()))));
+        let fs = vec![comp(CompExpr::Eq(b("R"), b("S")))];
+        node(f(Box::new(table_node("R")), Box::new(table_node("S")), fs))
+    }
+
+    // 每种节点至少一个
+    fn queries() -> Vec<LocNode> {
+        let a_gt_1 = comp(CompExpr::Gt(
+            value(Value::Symbol(sym("a"))),
+            value(Value::Int(1)),
+        ));
+        let select =
+            |n: LocNode, f: FilterExpr| node(Node::Selection(Box::new(n), vec![Loc(f, pos())]));
+        let project = |n: LocNode, names: &[&str]| {
+            node(Node::Projection(
+                Box::new(n),
+                names.iter().map(|k| sym(k)).collect(),
+            ))
+        };
+        let sorted = || {
+            node(Node::Sort(
+                Box::new(table_node("R")),
+                vec![SortKey::asc(sym("a"))],
+            ))
+        };
+        let reduce = |r: ItemReduce| node(Node::Reduce(Loc(r, pos())));
+        let equi = |merge: bool| {
+            let keys = vec![Loc(EquiKey(sym("b"), sym("b")), pos())];
+            node(Node::EquiJoin(
+                Box::new(table_node("R")),
+                Box::new(table_node("S")),
+                keys,
+                merge,
+            ))
+        };
+        vec![
+            table_node("R"),
+            select(table_node("R"), a_gt_1),
+            project(table_node("R"), &["b"]),
+            product(rename(table_node("T"), "a", "x"), table_node("S")),
+            project(rename(table_node("R"), "b", "y"), &["y"]),
+            both(Node::NatureJoin, "R", "S"),
+            equi(true),
+            equi(false),
+            on(Node::InnerJoin),
+            on(Node::LeftJoin),
+            on(Node::RightJoin),
+            on(Node::FullJoin),
+            both(Node::Union, "R", "R"),
+            both(Node::Difference, "R", "R"),
+            both(Node::Intersect, "R", "R"),
+            node(Node::Distinct(Box::new(table_node("R")))),
+            node(Node::GroupBy(
+                Box::new(table_node("R")),
+                vec![sym("a")],
+                vec![Aggregate {
+                    op: ReduceOperator::Count,
+                    alias: None,
+                }],
+            )),
+            sorted(),
+            select(sorted(), FilterExpr::GetLast),
+            node(Node::Division(
+                Box::new(table_node("R")),
+                Box::new(project(table_node("S"), &["b"])),
+                DivisionKind::Simple,
+            )),
+            reduce(ItemReduce::Count(Box::new(table_node("R")))),
+            reduce(ItemReduce::Max(Box::new(table_node("R")), sym("a"))),
+        ]
+    }
+
+    fn types(t: Type) -> Vec<Type> {
+        match t {
+            Type::Table(Lines(Record(fields, _))) => fields.into_values().collect(),
+            t => panic!("{:?} is not a table", t),
+        }
+    }
+
+    #[test]
+    fn lowering_keeps_the_type() {
+        let (db, env) = fixture();
+        for q in queries() {
+            let p = q.lower(&env).unwrap();
+            let before = types(q.type_infer(&env).unwrap());
+            assert_eq!(before, types(p.type_infer(&env).unwrap()), "{:?}", p);
+            let rows = run(&db, &env, &p, Semantics::Bag);
+            assert!(rows.iter().all(|r| r.len() == before.len()));
+        }
+    }
+
+    #[test]
+    fn positional_filter_on_unordered_rows_does_not_lower() {
+        let (_, env) = fixture();
+        let first = vec![Loc(FilterExpr::GetFirst, pos())];
+        let q = node(Node::Selection(Box::new(table_node("R")), first));
+        assert!(q.type_infer(&env).is_ok());
+        assert_eq!(q.type_warnings(&env).len(), 1);
+        let e = q.lower(&env).unwrap_err();
+        assert_eq!(e.0, TypeError::PositionalFilterOnUnordered("R".to_string()));
+    }
+
+    fn great_division(a: LocNode, b: LocNode) -> LocNode {
+        node(Node::Division(
+            Box::new(a),
+            Box::new(b),
+            DivisionKind::Great,
+        ))
+    }
+
+    #[test]
+    fn great_division_groups_by_plan_names() {
+        let (db, env) = fixture();
+        let q = great_division(table_node("R"), rename(table_node("S"), "c", "d"));
+        let p = q.lower(&env).unwrap();
+        match &p.0 {
+            Plan::Division(_, _, kind) => {
+                assert_eq!(*kind, DivisionKind::Grouped(vec![sym("c")]))
+            }
+            p => panic!("{:?}", p),
+        }
+        assert_eq!(width(p.type_infer(&env).unwrap()), 2);
+        run(&db, &env, &p, Semantics::Set);
+    }
+
+    #[test]
+    fn great_division_with_clashing_grouping_renames_its_inputs() {
+        let (db, env) = fixture();
+        let q = great_division(table_node("R"), rename(table_node("U"), "b", "x"));
+        let p = q.lower(&env).unwrap();
+        assert_eq!(width(p.type_infer(&env).unwrap()), 2);
+        run(&db, &env, &p, Semantics::Set);
+    }
+
+    fn divide(a: LocNode, b: LocNode) -> LocNode {
+        node(Node::Division(
+            Box::new(a),
+            Box::new(b),
+            DivisionKind::Simple,
+        ))
+    }
+
+    #[test]
+    fn division_over_renamed_keys() {
+        let (db, env) = fixture();
+        let q = divide(table_node("R"), rename(table_node("V"), "d", "b"));
+        let both = divide(
+            rename(table_node("R"), "b", "e"),
+            rename(table_node("V"), "d", "e"),
+        );
+        let (p, both) = (q.lower(&env).unwrap(), both.lower(&env).unwrap());
+        assert_eq!(width(p.type_infer(&env).unwrap()), 1);
+        assert_eq!(
+            sorted(run(&db, &env, &p, Semantics::Bag)),
+            sorted(run(&db, &env, &both, Semantics::Bag))
+        );
+    }
+}
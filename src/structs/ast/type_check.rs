@@ -15,12 +15,16 @@ You should have received a copy of the GNU General Public License
 along with RAE; see the file COPYING3.  If not see
 <http://www.gnu.org/licenses/>.  */
 
-use std::collections::{HashMap, HashSet};
+use indexmap::IndexMap;
 
 use super::*;
+use crate::structs::Pos;
 use crate::{
-    structs::Loc,
-    type_system::{Env, Lines, Record, Type, TypeError},
+    structs::{plan::JoinKind, plan_group::ReduceOperator, Aggregate, Expr, Loc, SortKey, Value},
+    type_system::{
+        product_fields, unify::Unify, Env, Lines, Optional, Record, SimpleType, TableName, Type,
+        TypeError, TypeWarning,
+    },
 };
 
 pub trait TypeInfer {
@@ -31,196 +35,979 @@ pub trait TypeCheck {
     fn type_check(&self, env: &Env) -> Result<Env, Loc<TypeError>>;
 }
 
+pub trait TypeWarn {
+    fn type_warnings(&self, env: &Env) -> Vec<Loc<TypeWarning>>;
+}
+
 #[inline]
-fn get_node_table_type(r: &Box<Loc<Node>>, env: &Env) -> Result<Record, Loc<TypeError>> {
-    let rt = r.as_ref().type_infer(env)?;
-    let Lines(rt) = rt
-        .get_table()
-        .ok_or_else(|| Loc(TypeError::IsNotTable, r.as_ref().1.clone()))?;
+fn get_node_table_type(r: &LocNode, env: &Env) -> Result<Record, Loc<TypeError>> {
+    let rt = r.type_infer(env)?;
+    let Lines(rt) = rt.get_table().ok_or(Loc(TypeError::IsNotTable, r.1))?;
     Ok(rt.clone())
 }
 
 #[inline]
-fn get_double_node_to_cross_product(
-    r1t: HashMap<Symbol, Type>,
-    r2t: HashMap<Symbol, Type>,
-    name1: &String,
-    name2: &String,
-) -> Result<HashMap<Symbol, Type>, Loc<TypeError>> {
-    let record_intersect_keys: Vec<&Symbol> = r1t
-        .keys()
-        .flat_map(|x| r2t.keys().map(move |y| (x, y)))
-        .filter(|(x, y)| x == y)
-        .map(|(x, _)| x)
-        .collect();
-    let mut r: HashMap<Symbol, Type> = HashMap::new();
-    r.extend(
-        r1t.iter()
-            .filter(|(k, _)| !record_intersect_keys.contains(k))
-            .map(|(k, v)| (k.clone(), v.clone())),
-    );
-    r.extend(
-        r2t.iter()
-            .filter(|(k, _)| !record_intersect_keys.contains(k))
-            .map(|(k, v)| (k.clone(), v.clone())),
-    );
-    let intersect: HashMap<Symbol, Type> = record_intersect_keys
-        .into_iter()
-        .flat_map(|k| {
-            assert!(k.1.is_none());
-            vec![
-                (
-                    Symbol(name1.clone(), Some(k.0.clone())),
-                    r1t.get(k).unwrap().clone(),
-                ),
-                (
-                    Symbol(name2.clone(), Some(k.0.clone())),
-                    r2t.get(k).unwrap().clone(),
-                ),
-            ]
-            .into_iter()
-        })
-        .collect();
-    r.extend(intersect);
+pub(crate) fn get_double_node_to_cross_product(
+    r1t: IndexMap<Symbol, Type>,
+    r2t: IndexMap<Symbol, Type>,
+    name1: &str,
+    name2: &str,
+    pos: Pos,
+) -> Result<IndexMap<Symbol, Type>, Loc<TypeError>> {
+    let r = product_fields(
+        r1t.into_iter().collect(),
+        name1,
+        r2t.into_iter().collect(),
+        name2,
+    )
+    .map_err(|k| Loc(TypeError::DuplicateField(k), pos))?;
+    Ok(r.into_iter().collect())
+}
+
+#[inline]
+pub(crate) fn nullable(t: Type) -> Type {
+    if t.is_optional() {
+        t
+    } else {
+        Type::Optional(Optional(Box::new(t)))
+    }
+}
+
+#[inline]
+pub(crate) fn strip_optional(t: &Type) -> Type {
+    match t {
+        Type::Optional(Optional(t)) => strip_optional(t),
+        t => t.clone(),
+    }
+}
+
+// None 表示 null 字面量, 可以和任何类型统一
+pub(crate) fn unify_expr_type(
+    t1: Option<Type>,
+    t2: Option<Type>,
+    pos: Pos,
+) -> Result<Option<Type>, Loc<TypeError>> {
+    match (t1, t2) {
+        (Some(t1), Some(t2)) => t1.unify(&t2).map(Some).map_err(|e| Loc(e, pos)),
+        (Some(t), None) | (None, Some(t)) => Ok(Some(t)),
+        (None, None) => Ok(None),
+    }
+}
+
+pub(crate) fn get_expr_type(
+    e: &Expr,
+    pos: Pos,
+    rt: &Record,
+) -> Result<Option<Type>, Loc<TypeError>> {
+    let sub = |Loc(e, pos): &LocExpr| get_expr_type(e, *pos, rt);
+    match e {
+        Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b) | Expr::Mod(a, b) => {
+            let t = unify_expr_type(sub(a)?, sub(b)?, pos)?;
+            match &t {
+                Some(Type::Simple(SimpleType::Int(_)))
+                | Some(Type::Simple(SimpleType::Uint(_)))
+                | Some(Type::Simple(SimpleType::Float(_)))
+                | None => Ok(t),
+                Some(t) => Err(Loc(TypeError::IsNotNumeric(Box::new(t.clone())), pos)),
+            }
+        }
+        Expr::And(a, b) | Expr::Or(a, b) => {
+            let t = unify_expr_type(sub(a)?, sub(b)?, pos)?;
+            unify_expr_type(t, Some(Type::Simple(SimpleType::Bool)), pos)
+        }
+        Expr::Not(a) => unify_expr_type(sub(a)?, Some(Type::Simple(SimpleType::Bool)), pos),
+        Expr::Value(Loc(v, pos)) => {
+            let t = match v {
+                Value::Null => return Ok(None),
+                Value::Bool(_) => SimpleType::Bool,
+                Value::Int(_) => SimpleType::Int(None),
+                Value::Uint(_) => SimpleType::Uint(None),
+                Value::Float(_) => SimpleType::Float(None),
+                Value::String(_) => SimpleType::String(vec![]),
+                Value::Symbol(name) => {
+                    let (_, t) = rt
+                        .resolve(name)
+                        .ok_or_else(|| Loc(TypeError::FieldNotFound(name.clone()), *pos))?;
+                    return Ok(Some(strip_optional(t)));
+                }
+            };
+            Ok(Some(Type::Simple(t)))
+        }
+    }
+}
+
+// `a in T` 右边是只有一列的表名
+pub(crate) fn get_in_table<'a>(
+    e: &LocExpr,
+    env: &'a Env,
+) -> Result<(TableName, &'a Lines), Loc<TypeError>> {
+    if let Expr::Value(Loc(Value::Symbol(Symbol(name, None)), _)) = &e.0 {
+        let tname = TableName(name.clone());
+        let r = env
+            .get_table(&tname)
+            .ok_or_else(|| Loc(TypeError::TableNotFound(tname.clone()), e.1))?;
+        Ok((tname, r))
+    } else {
+        Err(Loc(TypeError::IsNotTable, e.1))
+    }
+}
+
+fn comp_check(c: &LocCompExpr, rt: &Record, env: &Env) -> Result<(), Loc<TypeError>> {
+    match &c.0 {
+        CompExpr::Eq(a, b) | CompExpr::Lt(a, b) | CompExpr::Gt(a, b) => {
+            let t1 = get_expr_type(&a.0, a.1, rt)?;
+            unify_expr_type(t1, get_expr_type(&b.0, b.1, rt)?, c.1)?;
+        }
+        CompExpr::In(a, b) => {
+            let (tname, Lines(Record(t, _))) = get_in_table(b, env)?;
+            if t.len() != 1 {
+                return Err(Loc(TypeError::IsNotSingleColumnTable(tname), b.1));
+            }
+            let t = t.values().next().map(strip_optional);
+            unify_expr_type(get_expr_type(&a.0, a.1, rt)?, t, c.1)?;
+        }
+    }
+    Ok(())
+}
+
+fn filter_check(f: &FilterExpr, rt: &Record, env: &Env) -> Result<(), Loc<TypeError>> {
+    match f {
+        FilterExpr::And(v) | FilterExpr::Or(v) => v.iter().try_for_each(|c| comp_check(c, rt, env)),
+        FilterExpr::Not(c) | FilterExpr::Comp(c) => comp_check(c, rt, env),
+        FilterExpr::Range(_, _)
+        | FilterExpr::GetItem(_)
+        | FilterExpr::GetFirst
+        | FilterExpr::GetLast => Ok(()),
+    }
+}
+
+// 连接条件里不能有依赖行顺序的过滤
+fn join_filter_check(
+    fs: &[FilterExpr],
+    rt: &Record,
+    env: &Env,
+    pos: Pos,
+) -> Result<(), Loc<TypeError>> {
+    fs.iter().try_for_each(|f| match f {
+        FilterExpr::Range(_, _)
+        | FilterExpr::GetItem(_)
+        | FilterExpr::GetFirst
+        | FilterExpr::GetLast => Err(Loc(TypeError::InvalidJoinFilter, pos)),
+        f => filter_check(f, rt, env),
+    })
+}
+
+// 合并的键不能同名
+fn merge_field(
+    merged: &mut IndexMap<Symbol, Type>,
+    k: Symbol,
+    t: Type,
+    pos: Pos,
+) -> Result<(), Loc<TypeError>> {
+    if merged.contains_key(&k) {
+        return Err(Loc(TypeError::DuplicateField(k), pos));
+    }
+    merged.insert(k, t);
+    Ok(())
+}
+
+// 合并的键放在两边剩下的属性后面, 和它们也不能同名
+fn merge_fields(
+    mut r: IndexMap<Symbol, Type>,
+    merged: IndexMap<Symbol, Type>,
+    pos: Pos,
+) -> Result<IndexMap<Symbol, Type>, Loc<TypeError>> {
+    for (k, t) in merged {
+        merge_field(&mut r, k, t, pos)?;
+    }
     Ok(r)
 }
 
+pub(crate) fn get_division_type(
+    Record(mut r1t, name1): Record,
+    Record(mut r2t, name2): Record,
+    kind: &DivisionKind,
+    pos: Pos,
+) -> Result<Record, Loc<TypeError>> {
+    // 先从除数中拿走分组属性, 剩下的是要和被除数比较的属性
+    let grouping: Vec<Symbol> = match kind {
+        DivisionKind::Simple => vec![],
+        DivisionKind::Great => r2t
+            .keys()
+            .filter(|k| !r1t.contains_key(*k))
+            .cloned()
+            .collect(),
+        DivisionKind::Grouped(g) => g.clone(),
+    };
+    let mut group: IndexMap<Symbol, Type> = IndexMap::new();
+    for k in grouping {
+        let t = r2t
+            .shift_remove(&k)
+            .ok_or_else(|| Loc(TypeError::FieldNotFound(k.clone()), pos))?;
+        if r1t.contains_key(&k) {
+            return Err(Loc(TypeError::DivisionGroupingClash(k), pos));
+        }
+        group.insert(k, t);
+    }
+    for (k, t2) in r2t {
+        let t1 = r1t
+            .shift_remove(&k)
+            .ok_or_else(|| Loc(TypeError::DivisionAttributeNotFound(k.clone()), pos))?;
+        // 比较的属性可为空与否都行
+        strip_optional(&t1)
+            .unify(&strip_optional(&t2))
+            .map_err(|_| {
+                Loc(
+                    TypeError::DivisionKeysTypeUnifyError(k, Box::new(t1.clone()), Box::new(t2)),
+                    pos,
+                )
+            })?;
+    }
+    if r1t.is_empty() {
+        return Err(Loc(TypeError::DivisionIsNotProperSubset, pos));
+    }
+    r1t.extend(group);
+    Ok(Record(r1t, format!("{}/{}", name1, name2)))
+}
+
+// 连接的结果: 没有匹配的一边补 null
+pub(crate) fn get_join_type(
+    Record(r1t, name1): Record,
+    Record(r2t, name2): Record,
+    kind: JoinKind,
+    pos: Pos,
+) -> Result<Record, Loc<TypeError>> {
+    if kind.is_semi() {
+        return Ok(Record(r1t, name1));
+    }
+    let (left_null, right_null) = match kind {
+        JoinKind::Inner | JoinKind::Semi | JoinKind::Anti | JoinKind::NullAwareAnti => {
+            (false, false)
+        }
+        JoinKind::Left => (false, true),
+        JoinKind::Right => (true, false),
+        JoinKind::Full => (true, true),
+    };
+    let side = |t: IndexMap<Symbol, Type>, null: bool| -> Vec<(Symbol, Type)> {
+        t.into_iter()
+            .map(|(k, t)| if null { (k, nullable(t)) } else { (k, t) })
+            .collect()
+    };
+    let r = product_fields(side(r1t, left_null), &name1, side(r2t, right_null), &name2)
+        .map_err(|k| Loc(TypeError::DuplicateField(k), pos))?;
+    Ok(Record(
+        r.into_iter().collect(),
+        format!("{}*{}", name1, name2),
+    ))
+}
+
+pub(crate) fn get_reduce_type(
+    op: &ReduceOperator,
+    rt: Record,
+    pos: Pos,
+) -> Result<Record, Loc<TypeError>> {
+    let field = if let Some(name) = op.symbol() {
+        let (k, t) = rt
+            .resolve(name)
+            .ok_or_else(|| Loc(TypeError::FieldNotFound(name.clone()), pos))?;
+        let st = strip_optional(t);
+        let numeric = matches!(
+            st,
+            Type::Simple(SimpleType::Int(_))
+                | Type::Simple(SimpleType::Uint(_))
+                | Type::Simple(SimpleType::Float(_))
+        );
+        // 没有行时结果是 null
+        let t = match op {
+            ReduceOperator::Sum(_) if numeric => nullable(st),
+            ReduceOperator::Avg(_) if numeric => nullable(Type::Simple(SimpleType::Float(None))),
+            ReduceOperator::Max(_) | ReduceOperator::Min(_) if st.is_simple_type() => {
+                nullable(t.clone())
+            }
+            _ => {
+                return Err(Loc(
+                    TypeError::InvalidReduceType(k.clone(), Box::new(t.clone())),
+                    pos,
+                ))
+            }
+        };
+        (k.clone(), t)
+    } else {
+        (
+            Symbol("count".to_string(), None),
+            Type::Simple(SimpleType::Uint(None)),
+        )
+    };
+    Ok(Record(
+        vec![field].into_iter().collect(),
+        format!("{}({})", op.name(), rt.1),
+    ))
+}
+
+// 分组的结果: 分组属性在前, 然后每个聚合一列
+pub(crate) fn get_group_by_type(
+    rt: Record,
+    keys: &[Symbol],
+    aggs: &[Aggregate],
+    pos: Pos,
+) -> Result<Record, Loc<TypeError>> {
+    let mut r: IndexMap<Symbol, Type> = IndexMap::new();
+    let mut insert = |k: Symbol, t: Type| {
+        if r.contains_key(&k) {
+            return Err(Loc(TypeError::DuplicateField(k), pos));
+        }
+        r.insert(k, t);
+        Ok(())
+    };
+    for key in keys {
+        let (k, t) = rt
+            .resolve(key)
+            .ok_or_else(|| Loc(TypeError::FieldNotFound(key.clone()), pos))?;
+        insert(k.clone(), t.clone())?;
+    }
+    for a in aggs {
+        let Record(field, _) = get_reduce_type(&a.op, rt.clone(), pos)?;
+        let (k, t) = field.into_iter().next().unwrap();
+        insert(a.alias.clone().unwrap_or(k), t)?;
+    }
+    Ok(Record(r, rt.1))
+}
+
+// 排序不改变类型, 键要能比较大小
+pub(crate) fn get_sort_type(
+    rt: Record,
+    keys: &[SortKey],
+    pos: Pos,
+) -> Result<Record, Loc<TypeError>> {
+    for key in keys {
+        let (k, t) = rt
+            .resolve(&key.field)
+            .ok_or_else(|| Loc(TypeError::FieldNotFound(key.field.clone()), pos))?;
+        if !strip_optional(t).is_simple_type() {
+            return Err(Loc(
+                TypeError::InvalidSortType(k.clone(), Box::new(t.clone())),
+                pos,
+            ));
+        }
+    }
+    Ok(rt)
+}
+
+/// The rows have a defined order: the relation is sorted, and only
+/// selections, projections, renames and distinct come after the sort.
+pub(crate) fn is_ordered(node: &Node) -> bool {
+    match node {
+        Node::Sort(_, _) => true,
+        Node::Selection(r, _) | Node::Projection(r, _) | Node::Distinct(r) | Node::Rename(r, _) => {
+            is_ordered(&r.0)
+        }
+        _ => false,
+    }
+}
+
 impl TypeInfer for LocNode {
     fn type_infer(&self, env: &Env) -> Result<Type, Loc<TypeError>> {
-        match &self.0 {
-            Node::CrossProduct(r1, r2) => {
-                let Record(r1t, name1) = get_node_table_type(r1, env)?;
-                let Record(r2t, name2) = get_node_table_type(r2, env)?;
-                let r = get_double_node_to_cross_product(r1t, r2t, &name1, &name2)?;
-                Ok(Type::Table(Lines(Record(
-                    r,
-                    format!("{}*{}", name1, name2),
-                ))))
+        let children = self
+            .0
+            .children()
+            .into_iter()
+            .map(|c| get_node_table_type(c, env))
+            .collect::<Result<_, _>>()?;
+        node_type(self, children, env)
+    }
+}
+
+/// Type of `node` from the types of its sub nodes, in the order of
+/// `Node::children`. The sub nodes are not checked again.
+pub(crate) fn node_type(
+    node: &LocNode,
+    children: Vec<Record>,
+    env: &Env,
+) -> Result<Type, Loc<TypeError>> {
+    let pos = node.1;
+    let mut children = children.into_iter();
+    let mut child = || children.next().ok_or(Loc(TypeError::IsNotTable, pos));
+    match &node.0 {
+        Node::CrossProduct(_, _) => {
+            let Record(r1t, name1) = child()?;
+            let Record(r2t, name2) = child()?;
+            let r = get_double_node_to_cross_product(r1t, r2t, &name1, &name2, pos)?;
+            Ok(Type::Table(Lines(Record(
+                r,
+                format!("{}*{}", name1, name2),
+            ))))
+        }
+        Node::Union(_, _) => {
+            let r1t = child()?;
+            let r2t = child()?;
+            if r1t != r2t {
+                return Err(Loc(
+                    TypeError::DoubleTableIsNotStyleLike(Box::new(r1t), Box::new(r2t)),
+                    pos,
+                ));
             }
-            Node::Union(r1, r2) => {
-                let r1t = get_node_table_type(r1, env)?;
-                let r2t = get_node_table_type(r2, env)?;
-                if r1t != r2t {
-                    return Err(Loc(TypeError::DoubleTableIsNotStyleLike(r1t, r2t), self.1));
-                }
-                Ok(Type::Table(Lines(r1t)))
+            Ok(Type::Table(Lines(r1t)))
+        }
+        Node::Difference(_, _) => {
+            let r1t = child()?;
+            let r2t = child()?;
+            if r1t != r2t {
+                return Err(Loc(
+                    TypeError::DoubleTableIsNotStyleLike(Box::new(r1t), Box::new(r2t)),
+                    pos,
+                ));
             }
-            Node::Difference(r1, r2) => {
-                let r1t = get_node_table_type(r1, env)?;
-                let r2t = get_node_table_type(r2, env)?;
-                if r1t != r2t {
-                    return Err(Loc(TypeError::DoubleTableIsNotStyleLike(r1t, r2t), self.1));
-                }
-                Ok(Type::Table(Lines(r1t)))
+            Ok(Type::Table(Lines(r1t)))
+        }
+        Node::Intersect(_, _) => {
+            let r1t = child()?;
+            let r2t = child()?;
+            if r1t != r2t {
+                return Err(Loc(
+                    TypeError::DoubleTableIsNotStyleLike(Box::new(r1t), Box::new(r2t)),
+                    pos,
+                ));
             }
-            Node::Intersect(r1, r2) => {
-                let r1t = get_node_table_type(r1, env)?;
-                let r2t = get_node_table_type(r2, env)?;
-                if r1t != r2t {
-                    return Err(Loc(TypeError::DoubleTableIsNotStyleLike(r1t, r2t), self.1));
-                }
-                Ok(Type::Table(Lines(r1t)))
+            Ok(Type::Table(Lines(r1t)))
+        }
+        Node::Selection(_, fs) => {
+            let rt = child()?;
+            fs.iter()
+                .try_for_each(|Loc(f, _)| filter_check(f, &rt, env))?;
+            Ok(Type::Table(Lines(rt)))
+        }
+        Node::Projection(_, names) => {
+            let rt = child()?;
+            if !names.iter().all(|name| rt.0.contains_key(name)) {
+                return Err(Loc(TypeError::InValidProjectionNames, pos));
             }
-            Node::Selection(r, f) => {
-                let rt = get_node_table_type(r, env)?;
-                todo!("verify filter type");
-                Ok(Type::Record(rt))
+            let r = names
+                .iter()
+                .map(|name| {
+                    let r = rt.0.get_key_value(name).unwrap();
+                    (r.0.clone(), r.1.clone())
+                })
+                .collect();
+            Ok(Type::Table(Lines(Record(r, rt.1))))
+        }
+        Node::Distinct(_) => child().map(|r| Type::Table(Lines(r))),
+        Node::Sort(_, keys) => {
+            let rt = child()?;
+            get_sort_type(rt, keys, pos).map(|r| Type::Table(Lines(r)))
+        }
+        Node::GroupBy(_, keys, aggs) => {
+            let rt = child()?;
+            get_group_by_type(rt, keys, aggs, pos).map(|r| Type::Table(Lines(r)))
+        }
+        Node::Division(_, _, kind) => {
+            let r1t = child()?;
+            let r2t = child()?;
+            get_division_type(r1t, r2t, kind, pos).map(|r| Type::Table(Lines(r)))
+        }
+        Node::InnerJoin(_, _, fs) => {
+            let Record(r1t, name1) = child()?;
+            let Record(r2t, name2) = child()?;
+            let r = Record(
+                get_double_node_to_cross_product(r1t, r2t, &name1, &name2, pos)?,
+                format!("{}*{}", name1, name2),
+            );
+            join_filter_check(fs, &r, env, pos)?;
+            Ok(Type::Table(Lines(r)))
+        }
+        Node::EquiJoin(_, _, ks, merge) => {
+            let Record(mut r1t, name1) = child()?;
+            let Record(mut r2t, name2) = child()?;
+            if ks.is_empty() {
+                return Err(Loc(TypeError::EquiJoinWithoutKeys, pos));
             }
-            Node::Projection(r, names) => {
-                let rt = get_node_table_type(r, env)?;
-                if !names.iter().all(|name| rt.0.contains_key(name)) {
-                    return Err(Loc(TypeError::InValidProjectionNames, self.1.clone()));
+            let mut merged: IndexMap<Symbol, Type> = IndexMap::new();
+            for Loc(EquiKey(k1, k2), pos) in ks {
+                let left = Record(r1t.clone(), name1.clone());
+                let right = Record(r2t.clone(), name2.clone());
+                let (n1, t1) = left
+                    .resolve(k1)
+                    .ok_or_else(|| Loc(TypeError::FieldNotFound(k1.clone()), *pos))?;
+                let (n2, t2) = right
+                    .resolve(k2)
+                    .ok_or_else(|| Loc(TypeError::FieldNotFound(k2.clone()), *pos))?;
+                let t = t1.unify(t2).map_err(|_| {
+                    Loc(
+                        TypeError::EquiJoinKeysTypeUnifyError(Box::new((
+                            n1.clone(),
+                            t1.clone(),
+                            n2.clone(),
+                            t2.clone(),
+                        ))),
+                        *pos,
+                    )
+                })?;
+                if *merge && n1.column() == n2.column() {
+                    let (n1, n2) = (n1.clone(), n2.clone());
+                    r1t.shift_remove(&n1);
+                    r2t.shift_remove(&n2);
+                    merge_field(&mut merged, Symbol(n1.column().to_string(), None), t, *pos)?;
                 }
-                let r = names
-                    .iter()
-                    .map(|name| {
-                        let r = rt.0.get_key_value(name).unwrap();
-                        (r.0.clone(), r.1.clone())
-                    })
-                    .collect();
-                Ok(Type::Table(Lines(Record(r, rt.1))))
-            }
-            Node::Division(r1, r2) => {
-                let Record(mut r1t, name1) = get_node_table_type(r1, env)?;
-                let Record(r2t, name2) = get_node_table_type(r2, env)?;
-                r2t.keys().for_each(|k| {
-                    r1t.remove(k);
-                });
-                Ok(Type::Table(Lines(Record(
-                    r1t,
-                    format!("{}/{}", name1, name2),
-                ))))
             }
-            Node::InnerJoin(r1, r2, f) => {
-                let Record(r1t, name1) = get_node_table_type(r1, env)?;
-                let Record(r2t, name2) = get_node_table_type(r2, env)?;
-                let r = get_double_node_to_cross_product(r1t, r2t, &name1, &name2)?;
-                todo!("verify filter type");
-                Ok(Type::Table(Lines(Record(
-                    r,
-                    format!("{}><{}", name1, name2),
-                ))))
-            }
-            Node::EquiJoin(r1, r2, ks) => {
-                let Record(r1t, name1) = get_node_table_type(r1, env)?;
-                let Record(r2t, name2) = get_node_table_type(r2, env)?;
-                let r = get_double_node_to_cross_product(r1t, r2t, &name1, &name2)?;
-                ks.into_iter().try_for_each(|k| {
-                    let n1 = Symbol(name1.clone(), Some(k.clone()));
-                    let n2 = Symbol(name1.clone(), Some(k.clone()));
-                    let r1 = r.get(&n1).unwrap();
-                    let r2 = r.get(&n2).unwrap();
-                    if r1 == r2 {
-                        Ok(())
-                    } else {
-                        Err(Loc(
-                            TypeError::EquiJoinKeysTypeUnifyError(n1, r1.clone(), n2, r2.clone()),
-                            self.1.clone(),
-                        ))
-                    }
+            let r = get_double_node_to_cross_product(r1t, r2t, &name1, &name2, pos)?;
+            let r = merge_fields(r, merged, pos)?;
+            Ok(Type::Table(Lines(Record(
+                r,
+                format!("{}*{}", name1, name2),
+            ))))
+        }
+        Node::NatureJoin(_, _) => {
+            let Record(mut r1t, name1) = child()?;
+            let Record(mut r2t, name2) = child()?;
+            let common: Vec<Symbol> = r1t
+                .keys()
+                .filter(|k| r2t.contains_key(*k))
+                .cloned()
+                .collect();
+            let mut merged: IndexMap<Symbol, Type> = IndexMap::new();
+            for k in common {
+                let t1 = r1t.shift_remove(&k).unwrap();
+                let t2 = r2t.shift_remove(&k).unwrap();
+                let t = t1.unify(&t2).map_err(|_| {
+                    Loc(
+                        TypeError::NatureJoinKeysTypeUnifyError(
+                            k.clone(),
+                            Box::new(t1.clone()),
+                            Box::new(t2.clone()),
+                        ),
+                        pos,
+                    )
                 })?;
-                Ok(Type::Table(Lines(Record(
-                    r,
-                    format!("{}*{}", name1, name2),
-                ))))
+                merge_field(&mut merged, Symbol(k.column().to_string(), None), t, pos)?;
             }
-            Node::NatureJoin(r1, r2) => {
-                let Record(r1t, name1) = get_node_table_type(r1, env)?;
-                let Record(r2t, name2) = get_node_table_type(r2, env)?;
-                let mut r = get_double_node_to_cross_product(r1t, r2t, &name1, &name2)?;
-                let items = r.iter().filter(|(Symbol(_, k), _)| k.is_some());
-                let deleted_items: Vec<Symbol> = items
-                    .clone()
-                    .flat_map(move |x| items.clone().map(move |y| (x, y)))
-                    .filter(|((xk, xv), (yk, yv))| xk.1 == yk.1 && xv == yv)
-                    .flat_map(|((xk, _), (yk, _))| vec![xk, yk])
-                    .cloned()
+            // 没有公共属性时退化为笛卡尔积
+            let r = get_double_node_to_cross_product(r1t, r2t, &name1, &name2, pos)?;
+            let r = merge_fields(r, merged, pos)?;
+            Ok(Type::Table(Lines(Record(
+                r,
+                format!("{}*{}", name1, name2),
+            ))))
+        }
+        Node::LeftJoin(_, _, fs) | Node::RightJoin(_, _, fs) | Node::FullJoin(_, _, fs) => {
+            let r1t = child()?;
+            let r2t = child()?;
+            let r = Record(
+                get_double_node_to_cross_product(
+                    r1t.0.clone(),
+                    r2t.0.clone(),
+                    &r1t.1,
+                    &r2t.1,
+                    pos,
+                )?,
+                format!("{}*{}", r1t.1, r2t.1),
+            );
+            join_filter_check(fs, &r, env, pos)?;
+            let kind = match &node.0 {
+                Node::LeftJoin(..) => JoinKind::Left,
+                Node::RightJoin(..) => JoinKind::Right,
+                _ => JoinKind::Full,
+            };
+            Ok(Type::Table(Lines(get_join_type(r1t, r2t, kind, pos)?)))
+        }
+        Node::Reduce(Loc(reduce, rpos)) => {
+            let op = match reduce {
+                ItemReduce::Count(_) => ReduceOperator::Count,
+                ItemReduce::Sum(_, name) => ReduceOperator::Sum(name.clone()),
+                ItemReduce::Avg(_, name) => ReduceOperator::Avg(name.clone()),
+                ItemReduce::Max(_, name) => ReduceOperator::Max(name.clone()),
+                ItemReduce::Min(_, name) => ReduceOperator::Min(name.clone()),
+            };
+            get_reduce_type(&op, child()?, *rpos).map(|r| Type::Table(Lines(r)))
+        }
+        Node::Table(tname) => {
+            let r = env
+                .get_table(tname)
+                .ok_or_else(|| Loc(TypeError::TableNotFound(tname.clone()), pos))?;
+            Ok(Type::Table(r.clone()))
+        }
+        Node::Rename(_, names) => {
+            let Record(mut rt, name) = child()?;
+            for (old, new) in names {
+                let (old, _) = Record(rt.clone(), name.clone())
+                    .resolve(old)
+                    .map(|(k, t)| (k.clone(), t.clone()))
+                    .ok_or_else(|| Loc(TypeError::FieldNotFound(old.clone()), pos))?;
+                if old != *new && rt.contains_key(new) {
+                    return Err(Loc(TypeError::DuplicateField(new.clone()), pos));
+                }
+                rt = rt
+                    .into_iter()
+                    .map(|(k, t)| if k == old { (new.clone(), t) } else { (k, t) })
                     .collect();
-                deleted_items.into_iter().for_each(|k| {
-                    r.remove(&k);
-                });
-                Ok(Type::Table(Lines(Record(
-                    r,
-                    format!("{}*{}", name1, name2),
-                ))))
             }
-            Node::LeftJoin() => todo!(),
-            Node::RightJoin() => todo!(),
-            Node::FullJoin() => todo!(),
-            Node::Reduce(reduce) => reduce.type_infer(env),
-            Node::Table(tname) => {
-                let r = env
-                    .get_table(tname)
-                    .ok_or_else(|| Loc(TypeError::TableNotFound(tname.clone()), self.1.clone()))?;
-                Ok(Type::Table(r.clone()))
-            }
-            Node::Rename(_, _) => unreachable!(),
+            Ok(Type::Table(Lines(Record(rt, name))))
         }
     }
 }
 
 impl TypeInfer for LocItemReduce {
     fn type_infer(&self, env: &Env) -> Result<Type, Loc<TypeError>> {
-        todo!()
+        let (r, op) = match &self.0 {
+            ItemReduce::Count(r) => (r, ReduceOperator::Count),
+            ItemReduce::Sum(r, name) => (r, ReduceOperator::Sum(name.clone())),
+            ItemReduce::Avg(r, name) => (r, ReduceOperator::Avg(name.clone())),
+            ItemReduce::Max(r, name) => (r, ReduceOperator::Max(name.clone())),
+            ItemReduce::Min(r, name) => (r, ReduceOperator::Min(name.clone())),
+        };
+        let rt = get_node_table_type(r, env)?;
+        get_reduce_type(&op, rt, self.1).map(|r| Type::Table(Lines(r)))
+    }
+}
+
+impl TypeWarn for LocNode {
+    fn type_warnings(&self, env: &Env) -> Vec<Loc<TypeWarning>> {
+        let mut r: Vec<Loc<TypeWarning>> = self
+            .0
+            .children()
+            .into_iter()
+            .flat_map(|x| x.type_warnings(env))
+            .collect();
+        if let Node::NatureJoin(r1, r2) = &self.0 {
+            if let (Ok(Record(r1t, name1)), Ok(Record(r2t, name2))) =
+                (get_node_table_type(r1, env), get_node_table_type(r2, env))
+            {
+                if !r1t.keys().any(|k| r2t.contains_key(k)) {
+                    r.push(Loc(
+                        TypeWarning::NatureJoinWithoutCommonAttributes(name1, name2),
+                        self.1,
+                    ));
+                }
+            }
+        }
+        if let Node::Selection(a, fs) = &self.0 {
+            let positional = fs.iter().find(|f| {
+                matches!(
+                    f.0,
+                    FilterExpr::Range(_, _)
+                        | FilterExpr::GetItem(_)
+                        | FilterExpr::GetFirst
+                        | FilterExpr::GetLast
+                )
+            });
+            if let (Some(f), false) = (positional, is_ordered(&a.0)) {
+                if let Ok(Record(_, name)) = get_node_table_type(a, env) {
+                    r.push(Loc(TypeWarning::PositionalFilterOnUnordered(name), f.1));
+                }
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{env, int, node, nullable, pos, qualified, sym, table_node},
+        type_system::Domain,
+    };
+
+    fn equi_join(l: &str, r: &str, keys: &[(Symbol, Symbol)], merge: bool) -> LocNode {
+        let keys = keys
+            .iter()
+            .map(|(a, b)| Loc(EquiKey(a.clone(), b.clone()), pos()))
+            .collect();
+        node(Node::EquiJoin(
+            Box::new(table_node(l)),
+            Box::new(table_node(r)),
+            keys,
+            merge,
+        ))
+    }
+
+    fn fields(n: &LocNode) -> Result<Record, TypeError> {
+        n.type_infer(&env())
+            .map(|t| t.get_table().unwrap().0.clone())
+            .map_err(|Loc(e, _)| e)
+    }
+
+    #[test]
+    fn equi_join_resolves_keys_on_each_side() {
+        let keys = [(qualified("R", "b"), sym("b"))];
+        let Record(r, _) = fields(&equi_join("R", "S", &keys, false)).unwrap();
+        assert_eq!(r.len(), 4);
+        assert_eq!(r[&qualified("R", "b")], nullable(int()));
+        assert_eq!(r[&qualified("S", "b")], nullable(int()));
+        assert_eq!(r[&sym("c")], int());
+    }
+
+    #[test]
+    fn equi_join_merges_equal_named_keys() {
+        let keys = [(sym("b"), sym("b"))];
+        let Record(r, _) = fields(&equi_join("R", "S", &keys, true)).unwrap();
+        let mut names: Vec<&Symbol> = r.keys().collect();
+        names.sort_by_key(|s| s.column().to_string());
+        assert_eq!(names, [&sym("a"), &sym("b"), &sym("c")]);
+        // 名字不同的键不合并
+        let keys = [(sym("a"), sym("b"))];
+        let Record(r, _) = fields(&equi_join("T", "S", &keys, true)).unwrap();
+        assert!(r.contains_key(&sym("a")) && r.contains_key(&sym("b")));
+    }
+
+    #[test]
+    fn equi_join_rejects_bad_keys() {
+        assert_eq!(
+            fields(&equi_join("R", "S", &[], false)),
+            Err(TypeError::EquiJoinWithoutKeys)
+        );
+        // c 属于右表, 不能作为左键
+        let keys = [(sym("c"), sym("b"))];
+        assert_eq!(
+            fields(&equi_join("R", "S", &keys, false)),
+            Err(TypeError::FieldNotFound(sym("c")))
+        );
+    }
+
+    fn natural_join(l: &str, r: &str) -> LocNode {
+        node(Node::NatureJoin(
+            Box::new(table_node(l)),
+            Box::new(table_node(r)),
+        ))
+    }
+
+    #[test]
+    fn natural_join_merges_common_attributes() {
+        let n = natural_join("R", "S");
+        let Record(r, _) = fields(&n).unwrap();
+        let mut names: Vec<&Symbol> = r.keys().collect();
+        names.sort_by_key(|s| s.column().to_string());
+        assert_eq!(names, [&sym("a"), &sym("b"), &sym("c")]);
+        assert_eq!(r[&sym("b")], nullable(int()));
+        assert!(n.type_warnings(&env()).is_empty());
+    }
+
+    #[test]
+    fn natural_join_without_common_attributes_warns() {
+        let n = natural_join("T", "V");
+        let Record(r, _) = fields(&n).unwrap();
+        assert!(r.contains_key(&sym("a")) && r.contains_key(&sym("d")));
+        let w: Vec<TypeWarning> = n
+            .type_warnings(&env())
+            .into_iter()
+            .map(|Loc(w, _)| w)
+            .collect();
+        assert_eq!(
+            w,
+            [TypeWarning::NatureJoinWithoutCommonAttributes(
+                "T".to_string(),
+                "V".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn merged_keys_come_last_in_key_order() {
+        let keys = [(sym("b"), sym("b")), (sym("a"), sym("a"))];
+        let Record(r, _) = fields(&equi_join("U", "R", &keys, true)).unwrap();
+        let names: Vec<&Symbol> = r.keys().collect();
+        assert_eq!(names, [&sym("b"), &sym("a")]);
+        let Record(r, _) = fields(&natural_join("R", "S")).unwrap();
+        let names: Vec<&Symbol> = r.keys().collect();
+        assert_eq!(names, [&sym("a"), &sym("c"), &sym("b")]);
+    }
+
+    #[test]
+    fn a_nullable_key_on_either_side_gives_a_nullable_field() {
+        // U.b 不可为空, S.b 可以
+        for (l, r) in [("U", "S"), ("S", "U")] {
+            let Record(f, _) = fields(&natural_join(l, r)).unwrap();
+            assert_eq!(f[&sym("b")], nullable(int()));
+            let keys = [(sym("b"), sym("b"))];
+            let Record(f, _) = fields(&equi_join(l, r, &keys, true)).unwrap();
+            assert_eq!(f[&sym("b")], nullable(int()));
+        }
+    }
+
+    // 表名, 列名和类型
+    fn tables(tables: Vec<(&str, Vec<(&str, Type)>)>) -> Env {
+        Env::new(
+            tables
+                .into_iter()
+                .map(|(name, cols)| {
+                    let fields = cols.into_iter().map(|(c, t)| (sym(c), t)).collect();
+                    (
+                        TableName(name.to_string()),
+                        Lines(Record(fields, name.to_string())),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    // 只有一列 k 的表, k 的取值范围各不相同
+    fn domains() -> Env {
+        let ints = |d| vec![("k", Type::Simple(SimpleType::Int(Some(d))))];
+        tables(vec![
+            ("A", ints(Domain::Range(0, 10))),
+            ("B", ints(Domain::Value(3))),
+            ("C", ints(Domain::Range(20, 30))),
+        ])
+    }
+
+    #[test]
+    fn natural_join_keys_unify_their_domains() {
+        let env = domains();
+        let k = |l: &str, r: &str| {
+            natural_join(l, r)
+                .type_infer(&env)
+                .map(|t| t.get_table().unwrap().0 .0[&sym("k")].clone())
+                .map_err(|Loc(e, _)| e)
+        };
+        let range = Type::Simple(SimpleType::Int(Some(Domain::Range(0, 10))));
+        assert_eq!(k("A", "B"), Ok(range.clone()));
+        assert_eq!(k("B", "A"), Ok(range));
+        assert!(matches!(
+            k("A", "C"),
+            Err(TypeError::NatureJoinKeysTypeUnifyError(..))
+        ));
+    }
+
+    #[test]
+    fn merged_keys_must_not_clash_with_the_other_fields() {
+        // T × U 有 T.a, U.a 和 b, 把 U.a 改名成 a
+        let tu = node(Node::Rename(
+            Box::new(node(Node::CrossProduct(
+                Box::new(table_node("T")),
+                Box::new(table_node("U")),
+            ))),
+            vec![(qualified("U", "a"), sym("a"))],
+        ));
+        let keys = vec![Loc(EquiKey(qualified("T", "a"), sym("a")), pos())];
+        let n = node(Node::EquiJoin(
+            Box::new(tu),
+            Box::new(table_node("T")),
+            keys,
+            true,
+        ));
+        assert_eq!(fields(&n), Err(TypeError::DuplicateField(sym("a"))));
+    }
+
+    fn division(l: &str, r: &str, kind: DivisionKind) -> Result<Record, TypeError> {
+        let n = node(Node::Division(
+            Box::new(table_node(l)),
+            Box::new(table_node(r)),
+            kind,
+        ));
+        fields(&n)
+    }
+
+    fn names(Record(r, _): Record) -> Vec<Symbol> {
+        let mut r: Vec<Symbol> = r.into_keys().collect();
+        r.sort_by_key(|s| s.column().to_string());
+        r
+    }
+
+    #[test]
+    fn division_keeps_the_attributes_not_in_the_divisor() {
+        let r = division("R", "T", DivisionKind::Simple).unwrap();
+        assert_eq!(names(r), [sym("b")]);
+        assert_eq!(
+            division("R", "S", DivisionKind::Simple),
+            Err(TypeError::DivisionAttributeNotFound(sym("c")))
+        );
+        assert_eq!(
+            division("R", "U", DivisionKind::Simple),
+            Err(TypeError::DivisionIsNotProperSubset)
+        );
+    }
+
+    // X(a, b) 都不可为空, 除数只有 b 一列, 类型各不相同
+    fn divisors() -> Env {
+        let bool = Type::Simple(SimpleType::Bool);
+        tables(vec![
+            ("X", vec![("a", int()), ("b", int())]),
+            ("N", vec![("b", nullable(int()))]),
+            ("M", vec![("b", int()), ("c", int())]),
+            ("B", vec![("b", bool)]),
+        ])
+    }
+
+    fn divide(l: &str, r: &str) -> Result<Record, TypeError> {
+        let n = node(Node::Division(
+            Box::new(table_node(l)),
+            Box::new(table_node(r)),
+            DivisionKind::Simple,
+        ));
+        n.type_infer(&divisors())
+            .map(|t| t.get_table().unwrap().0.clone())
+            .map_err(|Loc(e, _)| e)
+    }
+
+    #[test]
+    fn division_ignores_nullability() {
+        let Record(r, _) = divide("X", "N").unwrap();
+        assert_eq!(r.into_iter().collect::<Vec<_>>(), [(sym("a"), int())]);
+    }
+
+    #[test]
+    fn division_rejects_divisors_that_do_not_fit() {
+        assert_eq!(
+            divide("X", "M"),
+            Err(TypeError::DivisionAttributeNotFound(sym("c")))
+        );
+        assert!(matches!(
+            divide("X", "B"),
+            Err(TypeError::DivisionKeysTypeUnifyError(..))
+        ));
+    }
+
+    #[test]
+    fn great_division_groups_by_the_divisor_only_attributes() {
+        let r = division("R", "S", DivisionKind::Great).unwrap();
+        assert_eq!(names(r), [sym("a"), sym("c")]);
+        let r = division("R", "S", DivisionKind::Grouped(vec![sym("c")])).unwrap();
+        assert_eq!(names(r), [sym("a"), sym("c")]);
+        assert_eq!(
+            division("R", "U", DivisionKind::Grouped(vec![sym("a")])),
+            Err(TypeError::DivisionGroupingClash(sym("a")))
+        );
+    }
+
+    fn group_by(aggs: Vec<Aggregate>) -> LocNode {
+        node(Node::GroupBy(
+            Box::new(table_node("U")),
+            vec![sym("a")],
+            aggs,
+        ))
+    }
+
+    #[test]
+    fn grouping_has_the_keys_then_one_field_per_aggregate() {
+        let aggs = vec![
+            Aggregate {
+                op: ReduceOperator::Count,
+                alias: None,
+            },
+            Aggregate {
+                op: ReduceOperator::Sum(sym("b")),
+                alias: Some(sym("total")),
+            },
+        ];
+        let Record(r, _) = fields(&group_by(aggs)).unwrap();
+        let names: Vec<&Symbol> = r.keys().collect();
+        assert_eq!(names, [&sym("a"), &sym("count"), &sym("total")]);
+        // 分组的键可以是 null, 组里的 b 都不是 null 时和也可能没有
+        assert_eq!(r[&sym("a")], nullable(int()));
+        assert_eq!(r[&sym("total")], nullable(int()));
+    }
+
+    #[test]
+    fn grouping_rejects_clashing_names() {
+        let sum = |alias: &str| Aggregate {
+            op: ReduceOperator::Sum(sym("b")),
+            alias: Some(sym(alias)),
+        };
+        assert_eq!(
+            fields(&group_by(vec![sum("a")])),
+            Err(TypeError::DuplicateField(sym("a")))
+        );
+        assert_eq!(
+            fields(&group_by(vec![sum("x"), sum("x")])),
+            Err(TypeError::DuplicateField(sym("x")))
+        );
     }
 }
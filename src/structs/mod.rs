@@ -18,19 +18,25 @@ pub mod ast;
 pub mod plan;
 pub mod plan_group;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+use std::{cmp::Ordering, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use self::plan_group::ReduceOperator;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
 pub struct Pos {
     offset: usize,
     line: usize,
     col: usize,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Loc<T>(pub T, pub Pos);
 
 pub type LocExpr = Loc<Expr>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Expr {
     Add(Box<LocExpr>, Box<LocExpr>),
     Sub(Box<LocExpr>, Box<LocExpr>),
@@ -43,9 +49,67 @@ pub enum Expr {
     Value(LocValue),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl Expr {
+    pub fn arith_op(&self) -> Option<ArithOp> {
+        match self {
+            Expr::Add(_, _) => Some(ArithOp::Add),
+            Expr::Sub(_, _) => Some(ArithOp::Sub),
+            Expr::Mul(_, _) => Some(ArithOp::Mul),
+            Expr::Div(_, _) => Some(ArithOp::Div),
+            Expr::Mod(_, _) => Some(ArithOp::Mod),
+            _ => None,
+        }
+    }
+
+    /// Field names used by the expression.
+    pub fn symbols(&self) -> Vec<&Symbol> {
+        match self {
+            Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Mod(a, b)
+            | Expr::And(a, b)
+            | Expr::Or(a, b) => {
+                let mut r = a.0.symbols();
+                r.extend(b.0.symbols());
+                r
+            }
+            Expr::Not(a) => a.0.symbols(),
+            Expr::Value(Loc(Value::Symbol(s), _)) => vec![s],
+            Expr::Value(_) => vec![],
+        }
+    }
+
+    pub fn map_symbols<F: FnMut(Symbol) -> Symbol>(self, f: &mut F) -> Expr {
+        let mut g = |Loc(e, pos): LocExpr| Box::new(Loc(e.map_symbols(f), pos));
+        match self {
+            Expr::Add(a, b) => Expr::Add(g(*a), g(*b)),
+            Expr::Sub(a, b) => Expr::Sub(g(*a), g(*b)),
+            Expr::Mul(a, b) => Expr::Mul(g(*a), g(*b)),
+            Expr::Div(a, b) => Expr::Div(g(*a), g(*b)),
+            Expr::Mod(a, b) => Expr::Mod(g(*a), g(*b)),
+            Expr::And(a, b) => Expr::And(g(*a), g(*b)),
+            Expr::Or(a, b) => Expr::Or(g(*a), g(*b)),
+            Expr::Not(a) => Expr::Not(g(*a)),
+            Expr::Value(Loc(Value::Symbol(s), pos)) => Expr::Value(Loc(Value::Symbol(f(s)), pos)),
+            Expr::Value(v) => Expr::Value(v),
+        }
+    }
+}
+
 pub type LocValue = Loc<Value>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Value {
     Null,
     Bool(bool),
@@ -56,5 +120,229 @@ pub enum Value {
     Symbol(Symbol),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+impl Value {
+    /// Order of two values, numbers compare across Int, Uint and Float.
+    /// Null and values of different kinds are not ordered.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Uint(a), Value::Uint(b)) => a.partial_cmp(b),
+            (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+            (a, b) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        }
+    }
+
+    /// Arithmetic on two values, null if either side is null. None on
+    /// overflow, division by zero and values that are not numbers of the
+    /// same kind.
+    pub fn arith(&self, op: ArithOp, other: &Value) -> Option<Value> {
+        macro_rules! checked {
+            ($x:expr, $y:expr, $v:path) => {
+                match op {
+                    ArithOp::Add => $x.checked_add(*$y),
+                    ArithOp::Sub => $x.checked_sub(*$y),
+                    ArithOp::Mul => $x.checked_mul(*$y),
+                    ArithOp::Div => $x.checked_div(*$y),
+                    ArithOp::Mod => $x.checked_rem(*$y),
+                }
+                .map($v)
+            };
+        }
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => Some(Value::Null),
+            (Value::Int(x), Value::Int(y)) => checked!(x, y, Value::Int),
+            (Value::Uint(x), Value::Uint(y)) => checked!(x, y, Value::Uint),
+            (Value::Float(_), _) | (_, Value::Float(_)) => {
+                let (x, y) = (self.as_f64()?, other.as_f64()?);
+                let r = match op {
+                    ArithOp::Add => x + y,
+                    ArithOp::Sub => x - y,
+                    ArithOp::Mul => x * y,
+                    ArithOp::Div if y != 0.0 => x / y,
+                    ArithOp::Mod if y != 0.0 => x % y,
+                    _ => return None,
+                };
+                Some(Value::Float(r))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(v) => Some(*v as f64),
+            Value::Uint(v) => Some(*v as f64),
+            Value::Float(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+// 除法的种类, ast 和 plan 共用
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DivisionKind {
+    Simple,               // R ÷ S, S 的属性是 R 的真子集
+    Great,                // R ÷* S, S 中不属于 R 的属性作为分组属性
+    Grouped(Vec<Symbol>), // R ÷[g..] S, 显式给出 S 的分组属性
+}
+
+/// One aggregate of a grouping, ast and plan share it. Without an alias the
+/// output field is named as the result of the same reduce: `count`, or the
+/// aggregated field.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub op: ReduceOperator,
+    pub alias: Option<Symbol>,
+}
+
+/// One key of a sort, ast and plan share it. Nulls sort before every
+/// value with `nulls_first`, after every value without it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SortKey {
+    pub field: Symbol,
+    pub desc: bool,
+    pub nulls_first: bool,
+}
+
+impl SortKey {
+    /// Ascending, nulls first as null is the smallest value.
+    pub fn asc(field: Symbol) -> Self {
+        SortKey {
+            field,
+            desc: false,
+            nulls_first: true,
+        }
+    }
+
+    /// Descending, nulls last.
+    pub fn desc(field: Symbol) -> Self {
+        SortKey {
+            field,
+            desc: true,
+            nulls_first: false,
+        }
+    }
+
+    /// The key that sorts the other way round.
+    pub fn reverse(&self) -> Self {
+        SortKey {
+            field: self.field.clone(),
+            desc: !self.desc,
+            nulls_first: !self.nulls_first,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Symbol(pub String, pub Option<String>);
+
+impl Symbol {
+    /// column name without the table qualifier
+    pub fn column(&self) -> &str {
+        self.1.as_deref().unwrap_or(&self.0)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.1 {
+            Some(col) => write!(f, "{}.{}", self.0, col),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl fmt::Display for DivisionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DivisionKind::Simple => write!(f, "÷"),
+            DivisionKind::Great => write!(f, "÷*"),
+            DivisionKind::Grouped(g) => {
+                let g: Vec<String> = g.iter().map(|s| s.to_string()).collect();
+                write!(f, "÷[{}]", g.join(", "))
+            }
+        }
+    }
+}
+
+/// `γ[k, ..; a, ..]`, the label of a grouping.
+pub fn group_label(keys: &[Symbol], aggs: &[Aggregate]) -> String {
+    let keys: Vec<String> = keys.iter().map(|s| s.to_string()).collect();
+    let aggs: Vec<String> = aggs.iter().map(|a| a.to_string()).collect();
+    format!("γ[{}; {}]", keys.join(", "), aggs.join(", "))
+}
+
+/// `ρ[a → b, ..]`, the label of a rename.
+pub fn rename_label(names: &[(Symbol, Symbol)]) -> String {
+    let names: Vec<String> = names
+        .iter()
+        .map(|(from, to)| format!("{} → {}", from, to))
+        .collect();
+    format!("ρ[{}]", names.join(", "))
+}
+
+/// `τ[k, ..]`, the label of a sort.
+pub fn sort_label(keys: &[SortKey]) -> String {
+    let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+    format!("τ[{}]", keys.join(", "))
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.field)?;
+        if self.desc {
+            write!(f, " desc")?;
+        }
+        // 和默认的不一样时才写出来
+        match (self.desc, self.nulls_first) {
+            (false, false) => write!(f, " nulls last"),
+            (true, true) => write!(f, " nulls first"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.op.symbol() {
+            Some(s) => write!(f, "{}({})", self.op.name(), s)?,
+            None => write!(f, "{}", self.op.name())?,
+        }
+        match &self.alias {
+            Some(a) => write!(f, " as {}", a),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Uint(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{:?}", v),
+            Value::String(v) => write!(f, "{:?}", v),
+            Value::Symbol(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (a, op, b) = match self {
+            Expr::Add(a, b) => (a, "+", b),
+            Expr::Sub(a, b) => (a, "-", b),
+            Expr::Mul(a, b) => (a, "*", b),
+            Expr::Div(a, b) => (a, "/", b),
+            Expr::Mod(a, b) => (a, "%", b),
+            Expr::And(a, b) => (a, "and", b),
+            Expr::Or(a, b) => (a, "or", b),
+            Expr::Not(a) => return write!(f, "not {}", a.0),
+            Expr::Value(v) => return write!(f, "{}", v.0),
+        };
+        write!(f, "({} {} {})", a.0, op, b.0)
+    }
+}
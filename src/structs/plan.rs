@@ -15,31 +15,86 @@ You should have received a copy of the GNU General Public License
 along with RAE; see the file COPYING3.  If not see
 <http://www.gnu.org/licenses/>.  */
 
-use super::{Expr, Symbol};
+pub mod type_check;
 
-#[derive(Debug, Clone, PartialEq)]
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    group_label, plan_group::ReduceOperator, rename_label, sort_label, Aggregate, DivisionKind,
+    Expr, Loc, SortKey, Symbol,
+};
+use crate::type_system::Record;
+
+pub type LocPlan = Loc<Plan>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Plan {
-    Product(Box<Plan>, Box<Plan>),         // 笛卡尔积
-    Union(Box<Plan>, Box<Plan>),           // 并集
-    Difference(Box<Plan>, Box<Plan>),      // 差集
-    Intersect(Box<Plan>, Box<Plan>),       // 交集
-    Selection(Box<Plan>, Box<FilterExpr>), // 选择
-    Projection(Box<Plan>, Vec<Symbol>),    // 投影
-    Division(Box<Plan>, Box<Plan>),        // 除
-    Reduce(ItemReduce),                    // 聚合
+    Product(Box<LocPlan>, Box<LocPlan>), // 笛卡尔积
+    Join(Box<LocPlan>, Box<LocPlan>, JoinKind, Vec<FilterExpr>), // 连接
+    Union(Box<LocPlan>, Box<LocPlan>),   // 并集
+    Difference(Box<LocPlan>, Box<LocPlan>), // 差集
+    Intersect(Box<LocPlan>, Box<LocPlan>), // 交集
+    Selection(Box<LocPlan>, Box<FilterExpr>), // 选择
+    Projection(Box<LocPlan>, Vec<Symbol>), // 投影
+    Division(Box<LocPlan>, Box<LocPlan>, DivisionKind), // 除
+    Reduce(ItemReduce),                  // 聚合
     Table(String),
+    Empty(Record),                                      // 空关系, 只有表头
+    Distinct(Box<LocPlan>),                             // 去重
+    GroupBy(Box<LocPlan>, Vec<Symbol>, Vec<Aggregate>), // 分组聚合
+    Sort(Box<LocPlan>, Vec<SortKey>),                   // 排序
+    Rename(Box<LocPlan>, Vec<(Symbol, Symbol)>),        // 重命名, 旧名 -> 新名
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JoinKind {
+    Inner,
+    Left,
+    Right,
+    Full,
+    Semi, // 左边有匹配的行, 只输出左边
+    Anti, // 左边没有匹配的行, 只输出左边
+    /// `not in`: the first condition is the `in` comparison, the others pick
+    /// the right rows it is compared with. A left row is kept when no picked
+    /// row is equal to it and no comparison is unknown because of a null. A
+    /// row with nothing picked is always kept.
+    NullAwareAnti,
+}
+
+impl JoinKind {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            JoinKind::Inner => "⋈",
+            JoinKind::Left => "⟕",
+            JoinKind::Right => "⟖",
+            JoinKind::Full => "⟗",
+            JoinKind::Semi => "⋉",
+            JoinKind::Anti => "▷",
+            JoinKind::NullAwareAnti => "▷ₙ",
+        }
+    }
+
+    /// Semi and anti joins output the left side only.
+    pub fn is_semi(&self) -> bool {
+        matches!(
+            self,
+            JoinKind::Semi | JoinKind::Anti | JoinKind::NullAwareAnti
+        )
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ItemReduce {
-    Count(Box<Plan>),
-    Sum(Box<Plan>, Symbol),
-    Avg(Box<Plan>, Symbol),
-    Max(Box<Plan>, Symbol),
-    Min(Box<Plan>, Symbol),
+    Count(Box<LocPlan>),
+    Sum(Box<LocPlan>, Symbol),
+    Avg(Box<LocPlan>, Symbol),
+    Max(Box<LocPlan>, Symbol),
+    Min(Box<LocPlan>, Symbol),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum FilterExpr {
     And(Vec<Box<CompExpr>>),
     Or(Vec<Box<CompExpr>>),
@@ -51,10 +106,215 @@ pub enum FilterExpr {
     GetLast,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CompExpr {
     Eq(Box<Expr>, Box<Expr>),
     Lt(Box<Expr>, Box<Expr>),
     Gt(Box<Expr>, Box<Expr>),
-    In(Box<Expr>, Box<Plan>),
+    In(Box<Expr>, Box<LocPlan>),
+}
+
+impl Plan {
+    /// Sub plans of the node. Sub plans of `in` filters are not included.
+    pub fn children(&self) -> Vec<&LocPlan> {
+        match self {
+            Plan::Product(a, b)
+            | Plan::Join(a, b, _, _)
+            | Plan::Union(a, b)
+            | Plan::Difference(a, b)
+            | Plan::Intersect(a, b)
+            | Plan::Division(a, b, _) => vec![a, b],
+            Plan::Selection(a, _)
+            | Plan::Projection(a, _)
+            | Plan::Distinct(a)
+            | Plan::GroupBy(a, _, _)
+            | Plan::Sort(a, _)
+            | Plan::Rename(a, _) => vec![a],
+            Plan::Reduce(r) => vec![r.sub_plan()],
+            Plan::Table(_) | Plan::Empty(_) => vec![],
+        }
+    }
+
+    /// Rebuild the node with every sub plan replaced by `f`.
+    pub fn map_children<F: FnMut(LocPlan) -> LocPlan>(self, mut f: F) -> Plan {
+        let mut g = |x: Box<LocPlan>| Box::new(f(*x));
+        match self {
+            Plan::Product(a, b) => Plan::Product(g(a), g(b)),
+            Plan::Join(a, b, k, fs) => Plan::Join(g(a), g(b), k, fs),
+            Plan::Union(a, b) => Plan::Union(g(a), g(b)),
+            Plan::Difference(a, b) => Plan::Difference(g(a), g(b)),
+            Plan::Intersect(a, b) => Plan::Intersect(g(a), g(b)),
+            Plan::Division(a, b, k) => Plan::Division(g(a), g(b), k),
+            Plan::Selection(a, f) => Plan::Selection(g(a), f),
+            Plan::Projection(a, names) => Plan::Projection(g(a), names),
+            Plan::Distinct(a) => Plan::Distinct(g(a)),
+            Plan::GroupBy(a, keys, aggs) => Plan::GroupBy(g(a), keys, aggs),
+            Plan::Sort(a, keys) => Plan::Sort(g(a), keys),
+            Plan::Rename(a, names) => Plan::Rename(g(a), names),
+            Plan::Reduce(r) => Plan::Reduce(match r {
+                ItemReduce::Count(a) => ItemReduce::Count(g(a)),
+                ItemReduce::Sum(a, s) => ItemReduce::Sum(g(a), s),
+                ItemReduce::Avg(a, s) => ItemReduce::Avg(g(a), s),
+                ItemReduce::Max(a, s) => ItemReduce::Max(g(a), s),
+                ItemReduce::Min(a, s) => ItemReduce::Min(g(a), s),
+            }),
+            Plan::Table(t) => Plan::Table(t),
+            Plan::Empty(r) => Plan::Empty(r),
+        }
+    }
+}
+
+impl ItemReduce {
+    pub fn sub_plan(&self) -> &LocPlan {
+        match self {
+            ItemReduce::Count(a)
+            | ItemReduce::Sum(a, _)
+            | ItemReduce::Avg(a, _)
+            | ItemReduce::Max(a, _)
+            | ItemReduce::Min(a, _) => a,
+        }
+    }
+}
+
+impl CompExpr {
+    /// Field names used by the comparison, not including the ones inside a
+    /// sub plan.
+    pub fn symbols(&self) -> Vec<&Symbol> {
+        match self {
+            CompExpr::Eq(a, b) | CompExpr::Lt(a, b) | CompExpr::Gt(a, b) => {
+                let mut r = a.symbols();
+                r.extend(b.symbols());
+                r
+            }
+            CompExpr::In(a, _) => a.symbols(),
+        }
+    }
+
+    pub fn map_symbols<F: FnMut(Symbol) -> Symbol>(self, f: &mut F) -> CompExpr {
+        let mut g = |e: Box<Expr>| Box::new(e.map_symbols(f));
+        match self {
+            CompExpr::Eq(a, b) => CompExpr::Eq(g(a), g(b)),
+            CompExpr::Lt(a, b) => CompExpr::Lt(g(a), g(b)),
+            CompExpr::Gt(a, b) => CompExpr::Gt(g(a), g(b)),
+            CompExpr::In(a, p) => CompExpr::In(g(a), p),
+        }
+    }
+}
+
+impl FilterExpr {
+    pub fn symbols(&self) -> Vec<&Symbol> {
+        match self {
+            FilterExpr::And(v) | FilterExpr::Or(v) => v.iter().flat_map(|c| c.symbols()).collect(),
+            FilterExpr::Not(c) | FilterExpr::Comp(c) => c.symbols(),
+            _ => vec![],
+        }
+    }
+
+    pub fn map_symbols<F: FnMut(Symbol) -> Symbol>(self, f: &mut F) -> FilterExpr {
+        let mut g = |c: Box<CompExpr>| Box::new(c.map_symbols(f));
+        match self {
+            FilterExpr::And(v) => FilterExpr::And(v.into_iter().map(g).collect()),
+            FilterExpr::Or(v) => FilterExpr::Or(v.into_iter().map(g).collect()),
+            FilterExpr::Not(c) => FilterExpr::Not(g(c)),
+            FilterExpr::Comp(c) => FilterExpr::Comp(g(c)),
+            f => f,
+        }
+    }
+
+    /// Range, GetItem, GetFirst and GetLast depend on the row order.
+    pub fn is_positional(&self) -> bool {
+        matches!(
+            self,
+            FilterExpr::Range(_, _)
+                | FilterExpr::GetItem(_)
+                | FilterExpr::GetFirst
+                | FilterExpr::GetLast
+        )
+    }
+
+    /// Split `a and b and ..` into conjuncts.
+    pub fn conjuncts(self) -> Vec<FilterExpr> {
+        match self {
+            FilterExpr::And(v) => v.into_iter().map(FilterExpr::Comp).collect(),
+            f => vec![f],
+        }
+    }
+}
+
+fn join_with<T: fmt::Display>(v: &[T], sep: &str) -> String {
+    v.iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+impl Plan {
+    /// The operator of this node alone, without its sub plans.
+    pub fn label(&self) -> String {
+        match self {
+            Plan::Product(_, _) => "×".to_string(),
+            Plan::Join(_, _, kind, fs) => {
+                let op = kind.symbol();
+                if fs.is_empty() {
+                    op.to_string()
+                } else {
+                    format!("{}[{}]", op, join_with(fs, " and "))
+                }
+            }
+            Plan::Union(_, _) => "∪".to_string(),
+            Plan::Difference(_, _) => "−".to_string(),
+            Plan::Intersect(_, _) => "∩".to_string(),
+            Plan::Selection(_, f) => format!("σ[{}]", f),
+            Plan::Projection(_, names) => format!("π[{}]", join_with(names, ", ")),
+            Plan::Distinct(_) => "δ".to_string(),
+            Plan::GroupBy(_, keys, aggs) => group_label(keys, aggs),
+            Plan::Sort(_, keys) => sort_label(keys),
+            Plan::Rename(_, names) => rename_label(names),
+            Plan::Division(_, _, kind) => kind.to_string(),
+            Plan::Reduce(r) => match ReduceOperator::from(r) {
+                ReduceOperator::Count => "count".to_string(),
+                op => format!("{}[{}]", op.name(), op.symbol().unwrap()),
+            },
+            Plan::Table(name) => name.clone(),
+            Plan::Empty(_) => "∅".to_string(),
+        }
+    }
+}
+
+// 写在一行里, 用于 in 的子查询
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.children().as_slice() {
+            [] => write!(f, "{}", self.label()),
+            [a] => write!(f, "{}({})", self.label(), a.0),
+            [a, b] => write!(f, "({} {} {})", a.0, self.label(), b.0),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Display for CompExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompExpr::Eq(a, b) => write!(f, "{} = {}", a, b),
+            CompExpr::Lt(a, b) => write!(f, "{} < {}", a, b),
+            CompExpr::Gt(a, b) => write!(f, "{} > {}", a, b),
+            CompExpr::In(a, p) => write!(f, "{} in {}", a, p.0),
+        }
+    }
+}
+
+impl fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterExpr::And(v) => write!(f, "{}", join_with(v, " and ")),
+            FilterExpr::Or(v) => write!(f, "{}", join_with(v, " or ")),
+            FilterExpr::Not(c) => write!(f, "not ({})", c),
+            FilterExpr::Comp(c) => write!(f, "{}", c),
+            FilterExpr::Range(from, to) => write!(f, "{}..{}", from, to),
+            FilterExpr::GetItem(i) => write!(f, "{}", i),
+            FilterExpr::GetFirst => write!(f, "first"),
+            FilterExpr::GetLast => write!(f, "last"),
+        }
+    }
 }
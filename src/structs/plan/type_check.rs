@@ -0,0 +1,285 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is Plan type infer of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+use indexmap::IndexMap;
+
+use super::*;
+use crate::{
+    structs::{
+        ast::type_check::{
+            get_division_type, get_double_node_to_cross_product, get_expr_type, get_group_by_type,
+            get_join_type, get_reduce_type, get_sort_type, strip_optional, unify_expr_type,
+            TypeInfer,
+        },
+        plan_group::ReduceOperator,
+        Pos,
+    },
+    type_system::{resolve_field, unify::Unify, Env, Lines, Record, TableName, Type, TypeError},
+};
+
+#[inline]
+pub fn get_plan_table_type(r: &LocPlan, env: &Env) -> Result<Record, Loc<TypeError>> {
+    let rt = r.type_infer(env)?;
+    let Lines(rt) = rt.get_table().ok_or(Loc(TypeError::IsNotTable, r.1))?;
+    Ok(rt.clone())
+}
+
+/// Type of a plan whose filters may also use the fields of `outer`, as the
+/// sub plan of a correlated `in` does.
+pub fn get_plan_table_type_in(
+    r: &LocPlan,
+    env: &Env,
+    outer: Option<&Record>,
+) -> Result<Record, Loc<TypeError>> {
+    plan_type(r, env, outer)
+}
+
+// 外面的列也能用, 重名时里面的优先
+fn scope(rt: &Record, outer: &Record) -> Record {
+    let mut r = rt.clone();
+    for (k, t) in outer.0.iter() {
+        if resolve_field(&rt.0, k).is_none() {
+            r.0.insert(k.clone(), t.clone());
+        }
+    }
+    r
+}
+
+fn comp_check(c: &CompExpr, rt: &Record, env: &Env, pos: Pos) -> Result<(), Loc<TypeError>> {
+    match c {
+        CompExpr::Eq(a, b) | CompExpr::Lt(a, b) | CompExpr::Gt(a, b) => {
+            let t1 = get_expr_type(a, pos, rt)?;
+            unify_expr_type(t1, get_expr_type(b, pos, rt)?, pos)?;
+        }
+        CompExpr::In(a, p) => {
+            let Record(t, name) = plan_type(p, env, Some(rt))?;
+            if t.len() != 1 {
+                return Err(Loc(TypeError::IsNotSingleColumnTable(TableName(name)), p.1));
+            }
+            let t = t.values().next().map(strip_optional);
+            unify_expr_type(get_expr_type(a, pos, rt)?, t, pos)?;
+        }
+    }
+    Ok(())
+}
+
+fn filter_check(
+    f: &FilterExpr,
+    rt: &Record,
+    outer: Option<&Record>,
+    env: &Env,
+    pos: Pos,
+) -> Result<(), Loc<TypeError>> {
+    let merged;
+    let rt = match outer {
+        Some(o) => {
+            merged = scope(rt, o);
+            &merged
+        }
+        None => rt,
+    };
+    match f {
+        FilterExpr::And(v) | FilterExpr::Or(v) => {
+            v.iter().try_for_each(|c| comp_check(c, rt, env, pos))
+        }
+        FilterExpr::Not(c) | FilterExpr::Comp(c) => comp_check(c, rt, env, pos),
+        FilterExpr::Range(_, _)
+        | FilterExpr::GetItem(_)
+        | FilterExpr::GetFirst
+        | FilterExpr::GetLast => Ok(()),
+    }
+}
+
+/// The rows have a defined order, the same in every executor: the plan is
+/// sorted, and only selections, projections, renames and distinct come
+/// after the sort. An empty relation has no rows to order.
+pub(crate) fn is_ordered(plan: &Plan) -> bool {
+    match plan {
+        Plan::Sort(_, _) | Plan::Empty(_) => true,
+        Plan::Selection(a, _) | Plan::Projection(a, _) | Plan::Distinct(a) | Plan::Rename(a, _) => {
+            is_ordered(&a.0)
+        }
+        _ => false,
+    }
+}
+
+// 所有列同时改名, 改名的列留在原来的位置
+fn rename_type(
+    Record(rt, name): Record,
+    names: &[(Symbol, Symbol)],
+    pos: Pos,
+) -> Result<Record, Loc<TypeError>> {
+    if let Some((old, _)) = names.iter().find(|(old, _)| !rt.contains_key(old)) {
+        return Err(Loc(TypeError::FieldNotFound(old.clone()), pos));
+    }
+    let mut r = IndexMap::new();
+    for (k, t) in rt {
+        let k = match names.iter().find(|(old, _)| *old == k) {
+            Some((_, new)) => new.clone(),
+            None => k,
+        };
+        if r.contains_key(&k) {
+            return Err(Loc(TypeError::DuplicateField(k), pos));
+        }
+        r.insert(k, t);
+    }
+    Ok(Record(r, name))
+}
+
+// 集合运算按位置对齐, 列名取左边的
+fn set_operation_type(r1t: Record, r2t: Record, pos: Pos) -> Result<Record, Loc<TypeError>> {
+    let style_like = r1t.0.len() == r2t.0.len()
+        && r1t
+            .0
+            .values()
+            .zip(r2t.0.values())
+            .all(|(t1, t2)| strip_optional(t1).unify(&strip_optional(t2)).is_ok());
+    if !style_like {
+        return Err(Loc(
+            TypeError::DoubleTableIsNotStyleLike(Box::new(r1t), Box::new(r2t)),
+            pos,
+        ));
+    }
+    Ok(r1t)
+}
+
+impl TypeInfer for LocPlan {
+    fn type_infer(&self, env: &Env) -> Result<Type, Loc<TypeError>> {
+        Ok(Type::Table(Lines(plan_type(self, env, None)?)))
+    }
+}
+
+fn plan_type(plan: &LocPlan, env: &Env, outer: Option<&Record>) -> Result<Record, Loc<TypeError>> {
+    let children = plan
+        .0
+        .children()
+        .into_iter()
+        .map(|c| plan_type(c, env, outer))
+        .collect::<Result<_, _>>()?;
+    node_type(plan, children, env, outer)
+}
+
+/// Types of a plan and of each of its sub plans, `None` where the type
+/// check fails.
+#[derive(Debug, Clone, Default)]
+pub struct Schemas {
+    pub record: Option<Record>,
+    /// In the order of `Plan::children`.
+    pub children: Vec<Schemas>,
+}
+
+impl Schemas {
+    /// The schemas of the sub plans one by one, empty ones past the end.
+    pub fn into_inputs(self) -> impl FnMut() -> Schemas {
+        let mut children = self.children.into_iter();
+        move || children.next().unwrap_or_default()
+    }
+}
+
+/// The types of every node, computed once from the bottom.
+pub fn plan_schemas(plan: &LocPlan, env: &Env) -> Schemas {
+    let children: Vec<Schemas> = plan
+        .0
+        .children()
+        .into_iter()
+        .map(|c| plan_schemas(c, env))
+        .collect();
+    let record = children
+        .iter()
+        .map(|c| c.record.clone())
+        .collect::<Option<Vec<Record>>>()
+        .and_then(|rs| node_type(plan, rs, env, None).ok());
+    Schemas { record, children }
+}
+
+/// Type of the top node of `plan` from the types of its sub plans, in the
+/// order of `Plan::children`. The sub plans are not checked again.
+pub fn node_type(
+    plan: &LocPlan,
+    children: Vec<Record>,
+    env: &Env,
+    outer: Option<&Record>,
+) -> Result<Record, Loc<TypeError>> {
+    let pos = plan.1;
+    let mut children = children.into_iter();
+    let mut child = || children.next().ok_or(Loc(TypeError::IsNotTable, pos));
+    let r = match &plan.0 {
+        Plan::Product(_, _) => {
+            let Record(r1t, name1) = child()?;
+            let Record(r2t, name2) = child()?;
+            Record(
+                get_double_node_to_cross_product(r1t, r2t, &name1, &name2, pos)?,
+                format!("{}*{}", name1, name2),
+            )
+        }
+        Plan::Join(_, _, kind, fs) => {
+            let (r1t, r2t) = (child()?, child()?);
+            let r = Record(
+                get_double_node_to_cross_product(
+                    r1t.0.clone(),
+                    r2t.0.clone(),
+                    &r1t.1,
+                    &r2t.1,
+                    pos,
+                )?,
+                format!("{}*{}", r1t.1, r2t.1),
+            );
+            fs.iter()
+                .try_for_each(|f| filter_check(f, &r, outer, env, pos))?;
+            get_join_type(r1t, r2t, *kind, pos)?
+        }
+        Plan::Union(_, _) | Plan::Difference(_, _) | Plan::Intersect(_, _) => {
+            set_operation_type(child()?, child()?, pos)?
+        }
+        Plan::Selection(a, f) => {
+            let rt = child()?;
+            filter_check(f, &rt, outer, env, pos)?;
+            // 没排过序的行, 按位置选出来的是哪几行由执行器决定
+            if f.is_positional() && !is_ordered(&a.0) {
+                return Err(Loc(TypeError::PositionalFilterOnUnordered(rt.1), pos));
+            }
+            rt
+        }
+        Plan::Projection(_, names) => {
+            let Record(rt, name) = child()?;
+            let r = names
+                .iter()
+                .map(|k| {
+                    rt.get_key_value(k)
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .ok_or(Loc(TypeError::InValidProjectionNames, pos))
+                })
+                .collect::<Result<_, _>>()?;
+            Record(r, name)
+        }
+        Plan::Distinct(_) => child()?,
+        Plan::Sort(_, keys) => get_sort_type(child()?, keys, pos)?,
+        Plan::Rename(_, names) => rename_type(child()?, names, pos)?,
+        Plan::GroupBy(_, keys, aggs) => get_group_by_type(child()?, keys, aggs, pos)?,
+        Plan::Division(_, _, kind) => get_division_type(child()?, child()?, kind, pos)?,
+        Plan::Reduce(r) => get_reduce_type(&ReduceOperator::from(r), child()?, pos)?,
+        Plan::Table(t) => {
+            let tname = TableName(t.clone());
+            let Lines(r) = env
+                .get_table(&tname)
+                .ok_or(Loc(TypeError::TableNotFound(tname), pos))?;
+            r.clone()
+        }
+        Plan::Empty(r) => r.clone(),
+    };
+    Ok(r)
+}
@@ -15,39 +15,99 @@ You should have received a copy of the GNU General Public License
 along with RAE; see the file COPYING3.  If not see
 <http://www.gnu.org/licenses/>.  */
 
+use serde::{Deserialize, Serialize};
+
 use super::plan;
-use super::plan::Plan;
-use super::{Expr, Symbol};
+use super::plan::{JoinKind, LocPlan, Plan};
+use super::{Aggregate, DivisionKind, Expr, Loc, SortKey, Symbol};
+use crate::type_system::Record;
 
-#[derive(Debug, Clone, PartialEq)]
-struct PlanGroup {
-    oper_item: OperItem,
-    selection: Vec<FilterExpr>,
-    projection: Vec<Symbol>,
-    item_reduce: Option<ReduceOperator>,
+/// Normalized form of a plan: an operator followed by its selections (in the
+/// order they apply), at most one projection and at most one reduce.
+/// A selection over a projection or a stacked reduce starts a nested group,
+/// so converting back into a plan gives the original one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlanGroup {
+    pub oper_item: Loc<OperItem>,
+    pub selection: Vec<Loc<FilterExpr>>,
+    pub projection: Option<Loc<Vec<Symbol>>>,
+    pub item_reduce: Option<Loc<ReduceOperator>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum OperItem {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum OperItem {
     Product(Box<PlanGroup>, Box<PlanGroup>),
+    Join(Box<PlanGroup>, Box<PlanGroup>, JoinKind, Vec<FilterExpr>),
     Difference(Box<PlanGroup>, Box<PlanGroup>),
     Intersect(Box<PlanGroup>, Box<PlanGroup>),
-    Division(Box<PlanGroup>, Box<PlanGroup>),
+    Division(Box<PlanGroup>, Box<PlanGroup>, DivisionKind),
     Union(Box<PlanGroup>, Box<PlanGroup>),
+    Group(Box<PlanGroup>),
     Table(String),
+    Empty(Record),
+    Distinct(Box<PlanGroup>),
+    GroupBy(Box<PlanGroup>, Vec<Symbol>, Vec<Aggregate>),
+    Sort(Box<PlanGroup>, Vec<SortKey>),
+    Rename(Box<PlanGroup>, Vec<(Symbol, Symbol)>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum ReduceOperator {
-    Sum,
-    Avg,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReduceOperator {
+    Sum(Symbol),
+    Avg(Symbol),
     Count,
-    Max,
-    Min,
+    Max(Symbol),
+    Min(Symbol),
+}
+
+impl ReduceOperator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReduceOperator::Sum(_) => "sum",
+            ReduceOperator::Avg(_) => "avg",
+            ReduceOperator::Count => "count",
+            ReduceOperator::Max(_) => "max",
+            ReduceOperator::Min(_) => "min",
+        }
+    }
+
+    pub fn symbol(&self) -> Option<&Symbol> {
+        match self {
+            ReduceOperator::Sum(s)
+            | ReduceOperator::Avg(s)
+            | ReduceOperator::Max(s)
+            | ReduceOperator::Min(s) => Some(s),
+            ReduceOperator::Count => None,
+        }
+    }
+
+    /// The same reduce over another field, count stays count.
+    pub fn with_symbol(&self, s: Symbol) -> ReduceOperator {
+        match self {
+            ReduceOperator::Sum(_) => ReduceOperator::Sum(s),
+            ReduceOperator::Avg(_) => ReduceOperator::Avg(s),
+            ReduceOperator::Max(_) => ReduceOperator::Max(s),
+            ReduceOperator::Min(_) => ReduceOperator::Min(s),
+            ReduceOperator::Count => ReduceOperator::Count,
+        }
+    }
+}
+
+impl From<&plan::ItemReduce> for ReduceOperator {
+    fn from(i: &plan::ItemReduce) -> Self {
+        match i {
+            plan::ItemReduce::Count(_) => ReduceOperator::Count,
+            plan::ItemReduce::Sum(_, s) => ReduceOperator::Sum(s.clone()),
+            plan::ItemReduce::Avg(_, s) => ReduceOperator::Avg(s.clone()),
+            plan::ItemReduce::Max(_, s) => ReduceOperator::Max(s.clone()),
+            plan::ItemReduce::Min(_, s) => ReduceOperator::Min(s.clone()),
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum FilterExpr {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FilterExpr {
+    And(Vec<Box<CompExpr>>),
     Or(Vec<Box<CompExpr>>),
     Not(Box<CompExpr>),
     Comp(Box<CompExpr>),
@@ -57,36 +117,52 @@ enum FilterExpr {
     GetLast,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum CompExpr {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CompExpr {
     Eq(Box<Expr>, Box<Expr>),
     Lt(Box<Expr>, Box<Expr>),
     Gt(Box<Expr>, Box<Expr>),
     In(Box<Expr>, Box<PlanGroup>),
 }
 
-impl From<Box<Plan>> for Box<PlanGroup> {
-    fn from(i: Box<Plan>) -> Self {
+impl From<Box<LocPlan>> for Box<PlanGroup> {
+    fn from(i: Box<LocPlan>) -> Self {
         Box::new(i.into())
     }
 }
 
-impl From<Box<Plan>> for PlanGroup {
-    fn from(i: Box<Plan>) -> Self {
+impl From<Box<LocPlan>> for PlanGroup {
+    fn from(i: Box<LocPlan>) -> Self {
         (*i).into()
     }
 }
 
-impl From<Plan> for PlanGroup {
-    fn from(i: Plan) -> Self {
-        let mut selection: Vec<FilterExpr> = vec![];
-        let mut projection: Vec<Symbol> = vec![];
-        let mut item_reduce: Option<ReduceOperator> = None;
-        let oper_item: OperItem = load_plan(i, &mut selection, &mut projection, &mut item_reduce);
+impl From<LocPlan> for PlanGroup {
+    fn from(i: LocPlan) -> Self {
+        let (i, item_reduce) = match i {
+            Loc(Plan::Reduce(r), pos) => {
+                let (a, r) = load_reduce(r);
+                (*a, Some(Loc(r, pos)))
+            }
+            i => (i, None),
+        };
+        let (mut i, projection) = match i {
+            Loc(Plan::Projection(a, b), pos) => (*a, Some(Loc(b, pos))),
+            i => (i, None),
+        };
+        let mut selection = vec![];
+        let i = loop {
+            i = match i {
+                Loc(Plan::Selection(a, b), pos) => {
+                    selection.push(Loc(b.into(), pos));
+                    *a
+                }
+                i => break i,
+            }
+        };
         selection.reverse();
-        projection.reverse();
         Self {
-            oper_item,
+            oper_item: load_plan(i),
             selection,
             projection,
             item_reduce,
@@ -94,64 +170,41 @@ impl From<Plan> for PlanGroup {
     }
 }
 
-fn load_plan(
-    i: Plan,
-    selection: &mut Vec<FilterExpr>,
-    projection: &mut Vec<Symbol>,
-    item_reduce: &mut Option<ReduceOperator>,
-) -> OperItem {
+fn load_reduce(i: plan::ItemReduce) -> (Box<LocPlan>, ReduceOperator) {
     match i {
+        plan::ItemReduce::Count(a) => (a, ReduceOperator::Count),
+        plan::ItemReduce::Sum(a, s) => (a, ReduceOperator::Sum(s)),
+        plan::ItemReduce::Avg(a, s) => (a, ReduceOperator::Avg(s)),
+        plan::ItemReduce::Max(a, s) => (a, ReduceOperator::Max(s)),
+        plan::ItemReduce::Min(a, s) => (a, ReduceOperator::Min(s)),
+    }
+}
+
+fn load_plan(Loc(i, pos): LocPlan) -> Loc<OperItem> {
+    let r = match i {
         Plan::Product(a, b) => OperItem::Product(a.into(), b.into()),
+        Plan::Join(a, b, k, f) => OperItem::Join(
+            a.into(),
+            b.into(),
+            k,
+            f.into_iter().map(|x| x.into()).collect(),
+        ),
         Plan::Difference(a, b) => OperItem::Difference(a.into(), b.into()),
         Plan::Intersect(a, b) => OperItem::Intersect(a.into(), b.into()),
-        Plan::Division(a, b) => OperItem::Division(a.into(), b.into()),
+        Plan::Division(a, b, k) => OperItem::Division(a.into(), b.into(), k),
         Plan::Union(a, b) => OperItem::Union(a.into(), b.into()),
+        Plan::Distinct(a) => OperItem::Distinct(a.into()),
+        Plan::GroupBy(a, keys, aggs) => OperItem::GroupBy(a.into(), keys, aggs),
+        Plan::Sort(a, keys) => OperItem::Sort(a.into(), keys),
+        Plan::Rename(a, names) => OperItem::Rename(a.into(), names),
         Plan::Table(t) => OperItem::Table(t),
-        Plan::Selection(a, b) => {
-            if let plan::FilterExpr::And(v) = *b {
-                let iter = v.into_iter().rev().map(|x| FilterExpr::Comp(x.into()));
-                selection.extend(iter);
-            } else {
-                selection.push(b.into());
-            }
-            load_plan(*a, selection, projection, item_reduce)
-        }
-        Plan::Projection(a, mut b) => {
-            projection.append(&mut b);
-            load_plan(*a, selection, projection, item_reduce)
-        }
-        Plan::Reduce(a) => {
-            if item_reduce.is_some() {
-                panic!("Type check does not exist?"); // 要么是有人乱搞，要么是没过类型检查
-            }
-            match a {
-                plan::ItemReduce::Count(a) => {
-                    item_reduce.replace(ReduceOperator::Count);
-                    load_plan(*a, selection, projection, item_reduce)
-                }
-                plan::ItemReduce::Sum(a, s) => {
-                    item_reduce.replace(ReduceOperator::Sum);
-                    projection.push(s);
-                    load_plan(*a, selection, projection, item_reduce)
-                }
-                plan::ItemReduce::Avg(a, s) => {
-                    item_reduce.replace(ReduceOperator::Avg);
-                    projection.push(s);
-                    load_plan(*a, selection, projection, item_reduce)
-                }
-                plan::ItemReduce::Max(a, s) => {
-                    item_reduce.replace(ReduceOperator::Max);
-                    projection.push(s);
-                    load_plan(*a, selection, projection, item_reduce)
-                }
-                plan::ItemReduce::Min(a, s) => {
-                    item_reduce.replace(ReduceOperator::Min);
-                    projection.push(s);
-                    load_plan(*a, selection, projection, item_reduce)
-                }
-            }
+        Plan::Empty(r) => OperItem::Empty(r),
+        // 选择在投影或聚合之上, 或者聚合叠在一起: 放进下一层
+        i @ Plan::Selection(_, _) | i @ Plan::Projection(_, _) | i @ Plan::Reduce(_) => {
+            OperItem::Group(Box::new(Loc(i, pos).into()))
         }
-    }
+    };
+    Loc(r, pos)
 }
 
 impl From<Box<plan::FilterExpr>> for Box<FilterExpr> {
@@ -169,7 +222,7 @@ impl From<Box<plan::FilterExpr>> for FilterExpr {
 impl From<plan::FilterExpr> for FilterExpr {
     fn from(i: plan::FilterExpr) -> Self {
         match i {
-            plan::FilterExpr::And(_) => unreachable!(),
+            plan::FilterExpr::And(a) => FilterExpr::And(a.into_iter().map(|x| x.into()).collect()),
             plan::FilterExpr::Or(a) => FilterExpr::Or(a.into_iter().map(|x| x.into()).collect()),
             plan::FilterExpr::Not(a) => FilterExpr::Not(a.into()),
             plan::FilterExpr::Comp(a) => FilterExpr::Comp(a.into()),
@@ -203,3 +256,124 @@ impl From<plan::CompExpr> for CompExpr {
         }
     }
 }
+
+// PlanGroup -> Plan
+
+impl From<Box<PlanGroup>> for Box<LocPlan> {
+    fn from(i: Box<PlanGroup>) -> Self {
+        Box::new((*i).into())
+    }
+}
+
+impl From<PlanGroup> for LocPlan {
+    fn from(i: PlanGroup) -> Self {
+        let mut r = store_plan(i.oper_item);
+        for Loc(f, pos) in i.selection {
+            r = Loc(Plan::Selection(Box::new(r), Box::new(f.into())), pos);
+        }
+        if let Some(Loc(names, pos)) = i.projection {
+            r = Loc(Plan::Projection(Box::new(r), names), pos);
+        }
+        if let Some(Loc(reduce, pos)) = i.item_reduce {
+            let a = Box::new(r);
+            let reduce = match reduce {
+                ReduceOperator::Count => plan::ItemReduce::Count(a),
+                ReduceOperator::Sum(s) => plan::ItemReduce::Sum(a, s),
+                ReduceOperator::Avg(s) => plan::ItemReduce::Avg(a, s),
+                ReduceOperator::Max(s) => plan::ItemReduce::Max(a, s),
+                ReduceOperator::Min(s) => plan::ItemReduce::Min(a, s),
+            };
+            r = Loc(Plan::Reduce(reduce), pos);
+        }
+        r
+    }
+}
+
+fn store_plan(Loc(i, pos): Loc<OperItem>) -> LocPlan {
+    let r = match i {
+        OperItem::Product(a, b) => Plan::Product(a.into(), b.into()),
+        OperItem::Join(a, b, k, f) => Plan::Join(
+            a.into(),
+            b.into(),
+            k,
+            f.into_iter().map(|x| x.into()).collect(),
+        ),
+        OperItem::Difference(a, b) => Plan::Difference(a.into(), b.into()),
+        OperItem::Intersect(a, b) => Plan::Intersect(a.into(), b.into()),
+        OperItem::Division(a, b, k) => Plan::Division(a.into(), b.into(), k),
+        OperItem::Union(a, b) => Plan::Union(a.into(), b.into()),
+        OperItem::Distinct(a) => Plan::Distinct(a.into()),
+        OperItem::GroupBy(a, keys, aggs) => Plan::GroupBy(a.into(), keys, aggs),
+        OperItem::Sort(a, keys) => Plan::Sort(a.into(), keys),
+        OperItem::Rename(a, names) => Plan::Rename(a.into(), names),
+        OperItem::Table(t) => Plan::Table(t),
+        OperItem::Empty(r) => Plan::Empty(r),
+        OperItem::Group(g) => return (*g).into(),
+    };
+    Loc(r, pos)
+}
+
+impl From<FilterExpr> for plan::FilterExpr {
+    fn from(i: FilterExpr) -> Self {
+        let comps = |a: Vec<Box<CompExpr>>| a.into_iter().map(|x| Box::new((*x).into())).collect();
+        match i {
+            FilterExpr::And(a) => plan::FilterExpr::And(comps(a)),
+            FilterExpr::Or(a) => plan::FilterExpr::Or(comps(a)),
+            FilterExpr::Not(a) => plan::FilterExpr::Not(Box::new((*a).into())),
+            FilterExpr::Comp(a) => plan::FilterExpr::Comp(Box::new((*a).into())),
+            FilterExpr::Range(a, b) => plan::FilterExpr::Range(a, b),
+            FilterExpr::GetItem(s) => plan::FilterExpr::GetItem(s),
+            FilterExpr::GetFirst => plan::FilterExpr::GetFirst,
+            FilterExpr::GetLast => plan::FilterExpr::GetLast,
+        }
+    }
+}
+
+impl From<CompExpr> for plan::CompExpr {
+    fn from(i: CompExpr) -> Self {
+        match i {
+            CompExpr::Eq(a, b) => plan::CompExpr::Eq(a, b),
+            CompExpr::Lt(a, b) => plan::CompExpr::Lt(a, b),
+            CompExpr::Gt(a, b) => plan::CompExpr::Gt(a, b),
+            CompExpr::In(a, b) => plan::CompExpr::In(a, b.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn round_trip(p: LocPlan) -> PlanGroup {
+        let g: PlanGroup = p.clone().into();
+        assert_eq!(LocPlan::from(g.clone()), p);
+        g
+    }
+
+    #[test]
+    fn selections_and_projection_gather_over_the_operator() {
+        let p = select(select(table("R"), gt(sym("a"), 1)), gt(sym("b"), 2));
+        let g = round_trip(project(p, &[sym("a")]));
+        assert_eq!(g.oper_item.0, OperItem::Table("R".to_string()));
+        assert_eq!(g.selection.len(), 2);
+        assert_eq!(g.selection[0].0, gt(sym("a"), 1).into());
+        assert_eq!(g.projection.map(|Loc(p, _)| p), Some(vec![sym("a")]));
+        assert_eq!(g.item_reduce, None);
+    }
+
+    #[test]
+    fn selection_over_projection_starts_a_nested_group() {
+        let p = select(project(table("R"), &[sym("a")]), gt(sym("a"), 1));
+        let g = round_trip(p);
+        assert!(matches!(g.oper_item.0, OperItem::Group(_)));
+        assert_eq!(g.projection, None);
+        let count = |p| plan(Plan::Reduce(plan::ItemReduce::Count(Box::new(p))));
+        let g = round_trip(count(count(table("R"))));
+        assert!(matches!(g.oper_item.0, OperItem::Group(_)));
+        assert_eq!(
+            g.item_reduce.map(|Loc(r, _)| r),
+            Some(ReduceOperator::Count)
+        );
+    }
+}
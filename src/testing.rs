@@ -0,0 +1,355 @@
+/*
+   Copyright (C) 2021-2021 imlyzh.
+
+This file is part of RAE(Relational Algebra Engine).
+This file is test fixtures of RAE.
+RAE is free software; you can redistribute it and/or modify it under
+the terms of the GNU General Public License as published by the Free
+Software Foundation; either version 3, or (at your option) any later
+version.
+RAE is distributed in the hope that it will be useful, but WITHOUT ANY
+WARRANTY; without even the implied warranty of MERCHANTABILITY or
+FITNESS FOR A PARTICULAR PURPOSE.  See the GNU General Public License
+for more details.
+You should have received a copy of the GNU General Public License
+along with RAE; see the file COPYING3.  If not see
+<http://www.gnu.org/licenses/>.  */
+
+//! Small tables and plan builders the unit tests share.
+
+use std::collections::HashMap;
+
+use crate::{
+    executor::{Executor, Row},
+    optimizer::{run_pass, Pass},
+    physical::Semantics,
+    storage::{Database, Relation},
+    structs::{
+        ast::{LocNode, Node},
+        plan::{CompExpr, FilterExpr, ItemReduce, JoinKind, LocPlan, Plan},
+        plan_group::ReduceOperator,
+        Aggregate, DivisionKind, Expr, Loc, Pos, SortKey, Symbol, Value,
+    },
+    type_system::{Env, Lines, Optional, Record, SimpleType, TableName, Type},
+};
+
+pub fn pos() -> Pos {
+    Pos::default()
+}
+
+pub fn sym(name: &str) -> Symbol {
+    Symbol(name.to_string(), None)
+}
+
+/// `R.a`, a field qualified by its relation.
+pub fn qualified(r: &str, a: &str) -> Symbol {
+    Symbol(r.to_string(), Some(a.to_string()))
+}
+
+pub fn int() -> Type {
+    Type::Simple(SimpleType::Int(None))
+}
+
+pub fn nullable(t: Type) -> Type {
+    Type::Optional(Optional(Box::new(t)))
+}
+
+type Rows = &'static [&'static [Option<i64>]];
+// 列名和列是否可为空
+type Columns = &'static [(&'static str, bool)];
+
+// 表名, 列, 每行的值
+const TABLES: &[(&str, Columns, Rows)] = &[
+    (
+        "R",
+        &[("a", true), ("b", true)],
+        &[
+            &[Some(1), Some(10)],
+            &[Some(2), Some(20)],
+            &[Some(2), Some(20)],
+            &[Some(3), None],
+            &[None, Some(30)],
+            &[Some(4), Some(10)],
+        ],
+    ),
+    (
+        "S",
+        &[("b", true), ("c", false)],
+        &[
+            &[Some(10), Some(100)],
+            &[Some(20), Some(200)],
+            &[Some(20), Some(200)],
+            &[None, Some(300)],
+            &[Some(50), Some(500)],
+        ],
+    ),
+    (
+        "T",
+        &[("a", true)],
+        &[&[Some(1)], &[Some(2)], &[Some(2)], &[Some(5)], &[None]],
+    ),
+    (
+        "U",
+        &[("a", true), ("b", false)],
+        &[
+            &[Some(1), Some(10)],
+            &[Some(5), Some(50)],
+            &[None, Some(30)],
+        ],
+    ),
+    (
+        "V",
+        &[("d", false)],
+        &[&[Some(100)], &[Some(200)], &[Some(900)]],
+    ),
+];
+
+pub fn int_value(v: Option<i64>) -> Value {
+    v.map_or(Value::Null, Value::Int)
+}
+
+/// Rows of the fixture tables.
+pub fn relations() -> Vec<Relation> {
+    TABLES
+        .iter()
+        .map(|(name, cols, rows)| {
+            let mut rel = Relation::new(name, cols.iter().map(|(c, _)| sym(c)).collect());
+            rel.rows = rows
+                .iter()
+                .map(|r| r.iter().copied().map(int_value).collect())
+                .collect();
+            rel
+        })
+        .collect()
+}
+
+/// Tables `R(a, b)`, `S(b, c)`, `T(a)`, `U(a, b)` and `V(d)` of ints with
+/// duplicate rows and nulls, analyzed. `S.c`, `U.b` and `V.d` are not
+/// nullable.
+pub fn env() -> Env {
+    let tables = TABLES
+        .iter()
+        .map(|(name, cols, _)| {
+            let fields = cols
+                .iter()
+                .map(|(c, null)| (sym(c), if *null { nullable(int()) } else { int() }))
+                .collect();
+            (
+                TableName(name.to_string()),
+                Lines(Record(fields, name.to_string())),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let mut env = Env::new(tables);
+    for rel in relations() {
+        env.analyze(&TableName(rel.name.clone()), &rel);
+    }
+    env
+}
+
+/// The fixture tables loaded into a database, with their `env()`.
+pub fn fixture() -> (Database, Env) {
+    let mut db = Database::new();
+    for rel in relations() {
+        db.insert(rel);
+    }
+    (db, env())
+}
+
+/// Rows of `p`, the vectorized executor must give the same ones and the
+/// parallel executor the same ones in the same order.
+pub fn run(db: &Database, env: &Env, p: &LocPlan, semantics: Semantics) -> Vec<Row> {
+    let exec = Executor::new(db, env)
+        .with_semantics(semantics)
+        .with_threads(2);
+    let physical = exec.planner().plan(p).unwrap();
+    let rows = exec.execute_physical(&physical).unwrap().rows;
+    let vectorized = exec.execute_physical_vectorized(&physical).unwrap().rows;
+    let parallel = exec.execute_physical_parallel(&physical).unwrap().rows;
+    assert_eq!(sorted(rows.clone()), sorted(vectorized), "vectorized");
+    assert_eq!(rows, parallel, "parallel");
+    rows
+}
+
+/// Rows in a fixed order, for results whose order is not defined.
+pub fn sorted(mut rows: Vec<Row>) -> Vec<Row> {
+    rows.sort_by_key(|r| format!("{:?}", r));
+    rows
+}
+
+/// Run `pass` on `p`, the result must type check to the same schema and
+/// give the same rows under `semantics`.
+pub fn check_pass(pass: &dyn Pass, p: &LocPlan, semantics: Semantics) -> LocPlan {
+    let (db, env) = fixture();
+    let after = run_pass(pass, p.clone(), &env).unwrap();
+    assert_eq!(
+        sorted(run(&db, &env, p, semantics)),
+        sorted(run(&db, &env, &after, semantics)),
+        "{} under {:?}",
+        pass.name(),
+        semantics
+    );
+    after
+}
+
+pub fn node(n: Node) -> LocNode {
+    Loc(n, pos())
+}
+
+pub fn table_node(name: &str) -> LocNode {
+    node(Node::Table(TableName(name.to_string())))
+}
+
+pub fn plan(p: Plan) -> LocPlan {
+    Loc(p, pos())
+}
+
+pub fn table(name: &str) -> LocPlan {
+    plan(Plan::Table(name.to_string()))
+}
+
+pub fn project(p: LocPlan, names: &[Symbol]) -> LocPlan {
+    plan(Plan::Projection(Box::new(p), names.to_vec()))
+}
+
+pub fn select(p: LocPlan, f: FilterExpr) -> LocPlan {
+    plan(Plan::Selection(Box::new(p), Box::new(f)))
+}
+
+pub fn field(s: Symbol) -> Box<Expr> {
+    Box::new(Expr::Value(Loc(Value::Symbol(s), pos())))
+}
+
+/// `a = b` on two fields.
+pub fn eq(a: Symbol, b: Symbol) -> FilterExpr {
+    FilterExpr::Comp(Box::new(CompExpr::Eq(field(a), field(b))))
+}
+
+/// `a > v` on a field and a literal.
+pub fn gt(a: Symbol, v: i64) -> FilterExpr {
+    let v = Box::new(Expr::Value(Loc(Value::Int(v), pos())));
+    FilterExpr::Comp(Box::new(CompExpr::Gt(field(a), v)))
+}
+
+fn join(a: LocPlan, b: LocPlan, kind: JoinKind, fs: Vec<FilterExpr>) -> LocPlan {
+    plan(Plan::Join(Box::new(a), Box::new(b), kind, fs))
+}
+
+pub fn sort(p: LocPlan, keys: Vec<SortKey>) -> LocPlan {
+    plan(Plan::Sort(Box::new(p), keys))
+}
+
+// a in sub
+fn is_in(a: Symbol, sub: LocPlan) -> Box<CompExpr> {
+    Box::new(CompExpr::In(field(a), Box::new(sub)))
+}
+
+/// Plans over the fixture that type check, one or more for every kind of
+/// node: joins of each kind, set operations, groupings, positional filters,
+/// sub plans on the same table as the outer plan and a sub plan used twice.
+pub fn queries() -> Vec<LocPlan> {
+    let rs = || vec![eq(qualified("R", "b"), qualified("S", "b"))];
+    let two = |f: fn(Box<LocPlan>, Box<LocPlan>) -> Plan, a: &str, b: &str| {
+        plan(f(Box::new(table(a)), Box::new(table(b))))
+    };
+    let by_a = || {
+        sort(
+            table("R"),
+            vec![SortKey::asc(sym("a")), SortKey::desc(sym("b"))],
+        )
+    };
+    let count = Aggregate {
+        op: ReduceOperator::Count,
+        alias: None,
+    };
+    let sum = Aggregate {
+        op: ReduceOperator::Sum(sym("b")),
+        alias: Some(sym("total")),
+    };
+    let shared = select(table("R"), gt(sym("a"), 1));
+    vec![
+        table("R"),
+        select(table("R"), gt(sym("a"), 1)),
+        project(table("R"), &[sym("b")]),
+        join(table("R"), table("S"), JoinKind::Inner, rs()),
+        join(table("R"), table("S"), JoinKind::Left, rs()),
+        join(table("R"), table("S"), JoinKind::Right, rs()),
+        join(table("R"), table("S"), JoinKind::Full, rs()),
+        join(table("R"), table("S"), JoinKind::Semi, rs()),
+        join(table("R"), table("S"), JoinKind::Anti, rs()),
+        select(
+            plan(Plan::Product(
+                Box::new(two(Plan::Product, "R", "S")),
+                Box::new(table("V")),
+            )),
+            FilterExpr::And(vec![
+                Box::new(CompExpr::Eq(field(sym("c")), field(sym("d")))),
+                Box::new(CompExpr::Eq(
+                    field(qualified("R", "b")),
+                    field(qualified("S", "b")),
+                )),
+            ]),
+        ),
+        join(
+            two(Plan::Product, "S", "T"),
+            table("V"),
+            JoinKind::Inner,
+            vec![eq(sym("c"), sym("d"))],
+        ),
+        project(
+            select(two(Plan::Product, "T", "S"), gt(sym("c"), 150)),
+            &[sym("a"), sym("c")],
+        ),
+        two(Plan::Union, "R", "U"),
+        two(Plan::Difference, "R", "U"),
+        two(Plan::Intersect, "R", "U"),
+        plan(Plan::Distinct(Box::new(table("R")))),
+        plan(Plan::GroupBy(
+            Box::new(table("R")),
+            vec![sym("a")],
+            vec![count, sum],
+        )),
+        by_a(),
+        select(by_a(), FilterExpr::GetLast),
+        select(by_a(), FilterExpr::GetFirst),
+        select(by_a(), FilterExpr::Range(1, 3)),
+        select(by_a(), FilterExpr::Range(3, 1)),
+        plan(Plan::Division(
+            Box::new(table("R")),
+            Box::new(project(
+                select(select(table("R"), gt(sym("b"), 15)), gt(sym("a"), 1)),
+                &[sym("b")],
+            )),
+            DivisionKind::Simple,
+        )),
+        plan(Plan::Reduce(ItemReduce::Count(Box::new(table("R"))))),
+        plan(Plan::Reduce(ItemReduce::Sum(
+            Box::new(table("R")),
+            sym("a"),
+        ))),
+        // 子查询和外面是同一张表
+        select(
+            table("R"),
+            FilterExpr::Comp(is_in(
+                sym("a"),
+                project(select(table("R"), gt(sym("b"), 10)), &[sym("a")]),
+            )),
+        ),
+        select(
+            table("R"),
+            FilterExpr::Not(is_in(sym("a"), project(table("T"), &[sym("a")]))),
+        ),
+        // 子查询里的 b 是外面的列
+        select(
+            table("R"),
+            FilterExpr::Comp(is_in(
+                sym("a"),
+                project(select(table("T"), gt(sym("b"), 15)), &[sym("a")]),
+            )),
+        ),
+        plan(Plan::Difference(
+            Box::new(shared.clone()),
+            Box::new(select(shared, gt(sym("b"), 10))),
+        )),
+    ]
+}
@@ -18,34 +18,98 @@ along with RAE; see the file COPYING3.  If not see
 pub mod domain;
 pub mod unify;
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt,
+};
 
-use crate::structs::Symbol;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    statistics::{ColumnStats, TableStats, DEFAULT_BUCKETS},
+    storage::Relation,
+    structs::Symbol,
+};
 
 // type check\infer and unify error
+#[derive(Debug, Clone, PartialEq)]
 pub enum TypeError {
     IsNotTable,
     InValidProjectionNames,
-    EquiJoinKeysTypeUnifyError(Symbol, Type, Symbol, Type),
+    EquiJoinWithoutKeys,
+    EquiJoinKeysTypeUnifyError(Box<(Symbol, Type, Symbol, Type)>),
+    NatureJoinKeysTypeUnifyError(Symbol, Box<Type>, Box<Type>),
+    DivisionAttributeNotFound(Symbol),
+    DivisionIsNotProperSubset,
+    DivisionKeysTypeUnifyError(Symbol, Box<Type>, Box<Type>),
+    DivisionGroupingClash(Symbol),
+    IsNotNumeric(Box<Type>),
+    IsNotSingleColumnTable(TableName),
+    InvalidJoinFilter,
+    InvalidReduceType(Symbol, Box<Type>),
+    InvalidSortType(Symbol, Box<Type>),
+    DuplicateField(Symbol),
+    PositionalFilterOnUnordered(String),
+    UnsupportedRename(Symbol),
+    SchemaChanged(Box<Record>, Box<Record>),
     NameNotFound(Symbol),
     FieldNotFound(Symbol),
     TableNotFound(TableName),
-    TypeUnifyError(Type, Type),
-    DoubleTableIsNotStyleLike(Record, Record),
+    TypeUnifyError(Box<Type>, Box<Type>),
+    DoubleTableIsNotStyleLike(Box<Record>, Box<Record>),
+}
+
+// type check warning, the query is still valid
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeWarning {
+    NatureJoinWithoutCommonAttributes(String, String),
+    // 按位置的选择, 但是输入没有排过序, 行的顺序由实现决定
+    PositionalFilterOnUnordered(String),
 }
 
 // table info
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "EnvForm", into = "EnvForm")]
 pub struct Env(
     pub HashMap<TableName, Lines>,
-    // pub HashMap<Symbol, Type>
+    // 统计信息, ANALYZE 之后才有
+    pub HashMap<TableName, TableStats>,
 );
 
 impl Env {
+    pub fn new(tables: HashMap<TableName, Lines>) -> Self {
+        Env(tables, HashMap::new())
+    }
+
     pub fn get_table(&self, name: &TableName) -> Option<&Lines> {
         self.0.get(name)
     }
+
+    pub fn get_stats(&self, name: &TableName) -> Option<&TableStats> {
+        self.1.get(name)
+    }
+
+    /// Recompute the statistics of a table from its rows.
+    pub fn analyze(&mut self, name: &TableName, rel: &Relation) {
+        self.1
+            .insert(name.clone(), TableStats::analyze(rel, DEFAULT_BUCKETS));
+    }
+
+    /// Statistics of a column. Tables that were never analyzed fall back to
+    /// the domain declared in the schema.
+    pub fn column_stats(&self, table: &TableName, column: &Symbol) -> Option<ColumnStats> {
+        if let Some(c) = self
+            .get_stats(table)
+            .and_then(|t| resolve_field(&t.columns, column))
+        {
+            return Some(c.1.clone());
+        }
+        let Lines(Record(fields, _)) = self.get_table(table)?;
+        ColumnStats::from_type(resolve_field(fields, column)?.1)
+    }
     /*
     pub fn get_type(&self, name: &Symbol) -> Option<Type> {
         self.1.get(name).cloned()
@@ -53,7 +117,33 @@ impl Env {
     //  */
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// 序列化时按表名排序, 同一个 Env 总是得到同样的结果
+#[derive(Serialize, Deserialize)]
+struct EnvForm {
+    tables: Vec<(TableName, Lines)>,
+    stats: Vec<(TableName, TableStats)>,
+}
+
+impl From<Env> for EnvForm {
+    fn from(env: Env) -> Self {
+        let mut tables: Vec<_> = env.0.into_iter().collect();
+        let mut stats: Vec<_> = env.1.into_iter().collect();
+        tables.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+        stats.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+        EnvForm { tables, stats }
+    }
+}
+
+impl From<EnvForm> for Env {
+    fn from(form: EnvForm) -> Self {
+        Env(
+            form.tables.into_iter().collect(),
+            form.stats.into_iter().collect(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Type {
     Optional(Optional),
     Record(Record),
@@ -112,18 +202,112 @@ impl Type {
 
 // type or null
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Optional(pub Box<Type>);
 
 // adhoc-union type
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Union(pub Vec<Type>);
 
 // record(struct) type
 
-#[derive(Debug, Clone)]
-pub struct Record(pub HashMap<Symbol, Type>, pub String);
+// fields keep their column order
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RecordForm", into = "RecordForm")]
+pub struct Record(pub IndexMap<Symbol, Type>, pub String);
+
+// 列名不是字符串, 按列的顺序存成数组
+#[derive(Serialize, Deserialize)]
+struct RecordForm {
+    name: String,
+    fields: Vec<(Symbol, Type)>,
+}
+
+impl From<Record> for RecordForm {
+    fn from(r: Record) -> Self {
+        RecordForm {
+            name: r.1,
+            fields: r.0.into_iter().collect(),
+        }
+    }
+}
+
+impl TryFrom<RecordForm> for Record {
+    type Error = String;
+
+    fn try_from(form: RecordForm) -> Result<Self, Self::Error> {
+        let mut fields = IndexMap::new();
+        for (k, t) in form.fields {
+            if fields.contains_key(&k) {
+                return Err(format!("duplicate field {} in {}", k, form.name));
+            }
+            fields.insert(k, t);
+        }
+        Ok(Record(fields, form.name))
+    }
+}
+
+impl Record {
+    pub fn resolve(&self, name: &Symbol) -> Option<(&Symbol, &Type)> {
+        resolve_field(&self.0, name)
+    }
+}
+
+/// Find a field by name. `R.a` falls back to a plain `a`, and a plain `a`
+/// falls back to the only qualified `X.a` when it is unambiguous.
+pub fn resolve_field<'a, V>(
+    fields: &'a IndexMap<Symbol, V>,
+    name: &Symbol,
+) -> Option<(&'a Symbol, &'a V)> {
+    if let Some(r) = fields.get_key_value(name) {
+        return Some(r);
+    }
+    match name {
+        Symbol(_, Some(col)) => fields.get_key_value(&Symbol(col.clone(), None)),
+        Symbol(col, None) => {
+            let mut it = fields.iter().filter(|(k, _)| k.1.as_ref() == Some(col));
+            let r = it.next()?;
+            if it.next().is_some() {
+                None
+            } else {
+                Some(r)
+            }
+        }
+    }
+}
+
+/// Fields of `l × r`, left side first. A plain field found on both sides is
+/// qualified with its table name, as `R.a` and `S.a`. When both tables have
+/// the same name the qualified fields are the same too, the error is the
+/// first field found twice.
+pub fn product_fields<T>(
+    l: Vec<(Symbol, T)>,
+    lname: &str,
+    r: Vec<(Symbol, T)>,
+    rname: &str,
+) -> Result<Vec<(Symbol, T)>, Symbol> {
+    let clash: Vec<Symbol> = l
+        .iter()
+        .filter(|(k, _)| k.1.is_none() && r.iter().any(|(k2, _)| k2 == k))
+        .map(|(k, _)| k.clone())
+        .collect();
+    let qualify = |name: &str, (k, v): (Symbol, T)| {
+        if clash.contains(&k) {
+            (Symbol(name.to_string(), Some(k.0)), v)
+        } else {
+            (k, v)
+        }
+    };
+    let mut ret: Vec<(Symbol, T)> = l.into_iter().map(|x| qualify(lname, x)).collect();
+    ret.extend(r.into_iter().map(|x| qualify(rname, x)));
+    let mut seen = HashSet::new();
+    match ret.iter().find(|(k, _)| !seen.insert(k)) {
+        Some((k, _)) => Err(k.clone()),
+        None => Ok(ret),
+    }
+}
 
 impl PartialEq for Record {
     fn eq(&self, other: &Self) -> bool {
@@ -141,16 +325,60 @@ impl PartialEq for Record {
     }
 }
 
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Optional(Optional(t)) => write!(f, "{}?", t),
+            Type::Record(r) => write!(f, "{}", r),
+            Type::Simple(t) => write!(f, "{}", t),
+            Type::TableName(TableName(n)) => write!(f, "{}", n),
+            Type::Table(Lines(r)) => write!(f, "[{}]", r),
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<String> = self
+            .0
+            .iter()
+            .map(|(k, t)| format!("{}: {}", k, t))
+            .collect();
+        write!(f, "{{{}}}", fields.join(", "))
+    }
+}
+
+impl fmt::Display for SimpleType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn domain<T: fmt::Display>(d: &Option<Domain<T>>) -> String {
+            match d {
+                None => String::new(),
+                Some(Domain::Value(v)) => format!("[{}]", v),
+                Some(Domain::Range(l, r)) => format!("[{}..{}]", l, r),
+            }
+        }
+        match self {
+            SimpleType::Bool => write!(f, "bool"),
+            SimpleType::Int(d) => write!(f, "int{}", domain(d)),
+            SimpleType::Uint(d) => write!(f, "uint{}", domain(d)),
+            SimpleType::Float(d) => write!(f, "float{}", domain(d)),
+            SimpleType::String(e) if e.is_empty() => write!(f, "string"),
+            SimpleType::String(e) => write!(f, "string[{}]", e.join(" | ")),
+        }
+    }
+}
+
 // Reletation etc.
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Lines(pub Record);
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct TableName(pub String);
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SimpleType {
+    Bool,
     Int(Option<Domain<i64>>),
     Uint(Option<Domain<u64>>),
     Float(Option<Domain<f64>>),
@@ -158,9 +386,26 @@ pub enum SimpleType {
 }
 
 // refinement type
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum Domain<T> {
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Domain<T> {
     Range(T, T),
     // Enum(Vec<Domain<T>>),
     Value(T),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sym;
+
+    #[test]
+    fn product_fields_qualifies_clashes() {
+        let l = vec![(sym("a"), 0), (sym("b"), 1)];
+        let r = vec![(sym("a"), 2)];
+        let f = product_fields(l.clone(), "R", r.clone(), "T").unwrap();
+        let keys: Vec<String> = f.iter().map(|(k, _)| k.to_string()).collect();
+        assert_eq!(keys, ["R.a", "b", "T.a"]);
+        let e = product_fields(l, "R", r, "R").unwrap_err();
+        assert_eq!(e, Symbol("R".to_string(), Some("a".to_string())));
+    }
+}
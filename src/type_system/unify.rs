@@ -15,10 +15,11 @@ You should have received a copy of the GNU General Public License
 along with RAE; see the file COPYING3.  If not see
 <http://www.gnu.org/licenses/>.  */
 
-use std::collections::HashMap;
 use std::cmp;
 
-use crate::structs::Symbol;
+use indexmap::IndexMap;
+
+use crate::structs::{ast::type_check::strip_optional, Symbol};
 
 use super::{Domain, Lines, Optional, Record, SimpleType, Type, TypeError};
 
@@ -33,8 +34,10 @@ impl Unify for Type {
     type Error = TypeError;
     fn unify(&self, r: &Self) -> Result<Self::Output, Self::Error> {
         match (self, r) {
-            (Type::Optional(t1), Type::Optional(t2)) => t1.unify(t2).map(Type::Optional),
-            (Type::Optional(Optional(t1)), t2) => t1.unify(t2),
+            // 有一边可为空, 结果就可为空
+            (Type::Optional(_), _) | (_, Type::Optional(_)) => strip_optional(self)
+                .unify(&strip_optional(r))
+                .map(|t| Type::Optional(Optional(Box::new(t)))),
             (Type::Record(t1), Type::Record(t2)) => t1.unify(t2).map(Type::Record),
             (Type::Simple(t1), Type::Simple(t2)) => t1.unify(t2).map(Type::Simple),
             (Type::Table(t1), Type::Table(t2)) => t1.unify(t2).map(Type::Table),
@@ -42,13 +45,13 @@ impl Unify for Type {
                 if t1 == t2 {
                     Ok(self.clone())
                 } else {
-                    Err(TypeError::TypeUnifyError(self.clone(), r.clone()))
+                    Err(TypeError::TypeUnifyError(Box::new(self.clone()), Box::new(r.clone())))
                 }
             }
             (Type::TableName(_t1), Type::Table(_t2)) | (Type::Table(_t2), Type::TableName(_t1)) => {
                 unreachable!()
             }
-            _ => Err(TypeError::TypeUnifyError(self.clone(), r.clone())),
+            _ => Err(TypeError::TypeUnifyError(Box::new(self.clone()), Box::new(r.clone()))),
         }
     }
 }
@@ -57,8 +60,7 @@ impl Unify for Optional {
     type Output = Self;
     type Error = TypeError;
     fn unify(&self, r: &Self) -> Result<Self::Output, Self::Error> {
-        self.0.unify(&r.0)?;
-        Ok(self.clone())
+        self.0.unify(&r.0).map(|t| Optional(Box::new(t)))
     }
 }
 
@@ -68,8 +70,8 @@ macro_rules! impl_edt {
     ($id:ident, $tp:ident, $en:ident) => {
         fn $id((l, r): (Domain<$tp>, Domain<$tp>)) -> TypeError {
             TypeError::TypeUnifyError(
-                Type::Simple(SimpleType::$en(Some(l))),
-                Type::Simple(SimpleType::$en(Some(r)))
+                Box::new(Type::Simple(SimpleType::$en(Some(l)))),
+                Box::new(Type::Simple(SimpleType::$en(Some(r))))
             )
         }
     };
@@ -85,6 +87,7 @@ impl Unify for SimpleType {
     type Error = TypeError;
     fn unify(&self, r: &Self) -> Result<Self::Output, Self::Error> {
         let r = match (self.clone(), r.clone()) {
+            (SimpleType::Bool, SimpleType::Bool) => SimpleType::Bool,
             (SimpleType::Int(d), SimpleType::Int(None))
             | (SimpleType::Int(None), SimpleType::Int(d)) => SimpleType::Int(d.clone()),
             (SimpleType::Int(d1), SimpleType::Int(d2)) =>
@@ -97,23 +100,23 @@ impl Unify for SimpleType {
             | (SimpleType::Float(None), SimpleType::Float(d)) => SimpleType::Float(d.clone()),
             (SimpleType::Float(d1), SimpleType::Float(d2)) =>
                 SimpleType::Float(Some(d1.unwrap().unify(&d2.unwrap()).map_err(edtf)?)),
+            // 空的取值列表是任意字符串, 否则取包含另一边的那个
             (SimpleType::String(d1), SimpleType::String(d2)) => {
-                if d1.len() == 0 {
-                    SimpleType::String(d1.clone())
+                if d1.is_empty() || d2.iter().all(|x| d1.contains(x)) {
+                    SimpleType::String(d1)
+                } else if d2.is_empty() || d1.iter().all(|x| d2.contains(x)) {
+                    SimpleType::String(d2)
                 } else {
-                    if d2.iter().all(|x| d2.contains(x)) {
-                        return Ok(SimpleType::String(d1.clone()));
-                    }
                     return Err(TypeError::TypeUnifyError(
-                        Type::Simple(self.clone()),
-                        Type::Simple(r.clone()),
+                        Box::new(Type::Simple(self.clone())),
+                        Box::new(Type::Simple(r.clone())),
                     ));
                 }
             }
             _ => {
                 return Err(TypeError::TypeUnifyError(
-                    Type::Simple(self.clone()),
-                    Type::Simple(r.clone()),
+                    Box::new(Type::Simple(self.clone())),
+                    Box::new(Type::Simple(r.clone())),
                 ))
             }
         };
@@ -121,22 +124,33 @@ impl Unify for SimpleType {
     }
 }
 
-impl<T: Clone + cmp::PartialOrd + cmp::PartialOrd> Unify for Domain<T> {
+impl<T: Clone + cmp::PartialOrd> Domain<T> {
+    // 取值范围
+    fn bounds(&self) -> (&T, &T) {
+        match self {
+            Domain::Range(l, r) => (l, r),
+            Domain::Value(v) => (v, v),
+        }
+    }
+
+    fn contains(&self, r: &Self) -> bool {
+        let ((l1, r1), (l2, r2)) = (self.bounds(), r.bounds());
+        l1 <= l2 && r1 >= r2
+    }
+}
+
+// 取包含另一边的那个, 谁也不包含谁时出错
+impl<T: Clone + cmp::PartialOrd> Unify for Domain<T> {
     type Output = Self;
     type Error = (Domain<T>, Domain<T>);
     fn unify(&self, r: &Self) -> Result<Self::Output, Self::Error> {
-        let ret = match (self, r) {
-            (Domain::Range(l1, r1), Domain::Range(l2, r2)) =>
-                if l1 <= l2 && r1 >= r2 {
-                    self
-                } else {
-                    return Err((self.clone(), r.clone()));
-                },
-            (Domain::Range(l, r), Domain::Value(_)) => todo!(),
-            (Domain::Value(_), Domain::Range(_, _)) => todo!(),
-            (Domain::Value(_), Domain::Value(_)) => todo!(),
-        };
-        Ok(ret.clone())
+        if self.contains(r) {
+            Ok(self.clone())
+        } else if r.contains(self) {
+            Ok(r.clone())
+        } else {
+            Err((self.clone(), r.clone()))
+        }
     }
 }
 
@@ -151,8 +165,8 @@ impl Unify for Lines {
 #[inline]
 fn merge_double_map_from_key(
     k: &Symbol,
-    l: &HashMap<Symbol, Type>,
-    r: &HashMap<Symbol, Type>,
+    l: &IndexMap<Symbol, Type>,
+    r: &IndexMap<Symbol, Type>,
 ) -> Result<(Symbol, Type), TypeError> {
     let l = l.get(k).unwrap();
     let r = r
@@ -167,7 +181,7 @@ impl Unify for Record {
     fn unify(&self, r: &Self) -> Result<Self::Output, Self::Error> {
         let nullables: Vec<_> = self.0.iter().filter(|(_, v)| v.is_optional()).collect();
         if self.0.len() == r.0.len() {
-            let r: Result<HashMap<Symbol, Type>, _> = self
+            let r: Result<IndexMap<Symbol, Type>, _> = self
                 .0
                 .keys()
                 .map(|k| merge_double_map_from_key(k, &self.0, &r.0))
@@ -175,7 +189,7 @@ impl Unify for Record {
             let r = r?;
             Ok(Record(r, self.1.clone()))
         } else if self.0.len() - nullables.len() == r.0.len() {
-            let nonnulls: Result<HashMap<Symbol, Type>, _> = self
+            let nonnulls: Result<IndexMap<Symbol, Type>, _> = self
                 .0
                 .iter()
                 .filter(|(_, v)| !v.is_optional())
@@ -183,7 +197,7 @@ impl Unify for Record {
                 .map(|k| merge_double_map_from_key(k, &self.0, &r.0))
                 .collect();
             let mut ret = nonnulls?;
-            let nullables: Result<HashMap<Symbol, Type>, _> = nullables
+            let nullables: Result<IndexMap<Symbol, Type>, _> = nullables
                 .into_iter()
                 .map(|(k, _)| k)
                 .map(|k| {
@@ -200,9 +214,42 @@ impl Unify for Record {
             Ok(Record(ret, self.1.clone()))
         } else {
             Err(TypeError::TypeUnifyError(
-                Type::Record(self.clone()),
-                Type::Record(r.clone()),
+                Box::new(Type::Record(self.clone())),
+                Box::new(Type::Record(r.clone())),
             ))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{int, nullable};
+
+    fn ints(d: Domain<i64>) -> Type {
+        Type::Simple(SimpleType::Int(Some(d)))
+    }
+
+    #[test]
+    fn optional_on_either_side_gives_optional() {
+        assert_eq!(nullable(int()).unify(&int()), Ok(nullable(int())));
+        assert_eq!(int().unify(&nullable(int())), Ok(nullable(int())));
+        assert_eq!(nullable(int()).unify(&nullable(int())), Ok(nullable(int())));
+        let b = Type::Simple(SimpleType::Bool);
+        assert!(int().unify(&nullable(b.clone())).is_err());
+        assert!(nullable(b).unify(&int()).is_err());
+    }
+
+    #[test]
+    fn domains_unify_to_the_one_that_contains_the_other() {
+        let range = ints(Domain::Range(0, 10));
+        let three = ints(Domain::Value(3));
+        assert_eq!(range.unify(&three), Ok(range.clone()));
+        assert_eq!(three.unify(&range), Ok(range.clone()));
+        assert_eq!(three.unify(&three), Ok(three.clone()));
+        assert_eq!(three.unify(&int()), Ok(three.clone()));
+        assert!(three.unify(&ints(Domain::Value(4))).is_err());
+        assert!(range.unify(&ints(Domain::Range(5, 20))).is_err());
+        assert!(ints(Domain::Value(11)).unify(&range).is_err());
+    }
+}